@@ -1,11 +1,24 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+};
 
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode, Throughput,
+};
 use p256::elliptic_curve::rand_core::CryptoRngCore;
 use rand::SeedableRng;
 use rand_chacha::ChaChaRng;
 use uuid::Uuid;
-use zk_cds::{Client, Server};
+use zk_cds::{decode_bucket, encode_bucket, Client, Server, ServerBuilder, SmallBucketPolicy};
+
+/// The directory sizes used by the parameterized benchmarks.
+const SIZES: [usize; 2] = [10_000, 1_000_000];
 
 fn build(c: &mut Criterion) {
     let mut g = c.benchmark_group("build");
@@ -18,6 +31,32 @@ fn build(c: &mut Criterion) {
     g.finish();
 }
 
+fn builder(c: &mut Criterion) {
+    let mut g = c.benchmark_group("builder");
+    g.throughput(criterion::Throughput::Elements(100));
+    g.bench_function("100", |b| {
+        let rng = ChaChaRng::seed_from_u64(0xDEADBEEF);
+        let id = Uuid::new_v4();
+        b.iter(|| {
+            ServerBuilder::new()
+                .build(rng.clone(), (0..100).map(|p| (p, id)))
+                .expect("should build")
+        });
+    });
+    g.sample_size(10);
+    g.throughput(criterion::Throughput::Elements(10_000));
+    g.bench_function("10000", |b| {
+        let rng = ChaChaRng::seed_from_u64(0xDEADBEEF);
+        let id = Uuid::new_v4();
+        b.iter(|| {
+            ServerBuilder::new()
+                .build(rng.clone(), (0..10_000).map(|p| (p, id)))
+                .expect("should build")
+        });
+    });
+    g.finish();
+}
+
 fn lookup(c: &mut Criterion) {
     let mut g = c.benchmark_group("lookup");
     g.throughput(criterion::Throughput::Elements(100));
@@ -29,13 +68,129 @@ fn lookup(c: &mut Criterion) {
         b.iter(|| {
             let (prefix, c_p) = client.request_phone_number(22);
             let bucket = server.find_bucket(prefix);
-            let sc_p = server.blind_phone_number(&c_p);
-            client.find_user_id(&sc_p, &bucket, 1234567890).expect("should be a valid phone number")
+            let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+            client
+                .find_user_id(&sc_p, &bucket, 22)
+                .expect("should be a valid response")
+                .expect("should be a valid phone number")
         });
     });
+    for n in SIZES {
+        g.throughput(Throughput::Elements(1));
+        g.bench_with_input(BenchmarkId::new("directory", n), &n, |b, &n| {
+            let server = directory(n);
+            let client = Client::new(ChaChaRng::seed_from_u64(0xDEADBEEF));
+            b.iter(|| {
+                let (prefix, c_p) = client.request_phone_number(22);
+                let bucket = server.find_bucket(prefix);
+                let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+                client.find_user_id(&sc_p, &bucket, 22).expect("should be a valid response")
+            });
+        });
+    }
     g.finish();
 }
 
+fn batch(c: &mut Criterion) {
+    let mut g = c.benchmark_group("batch");
+    g.sample_size(10);
+    g.throughput(Throughput::Elements(1_000));
+    for n in SIZES {
+        g.bench_with_input(BenchmarkId::new("1000 contacts", n), &n, |b, &n| {
+            let server = directory(n);
+            let client = Client::new(ChaChaRng::seed_from_u64(0xDEADBEEF));
+            b.iter(|| {
+                // Look up an address book of contacts, half of which are in the directory.
+                (n as u64 - 500..n as u64 + 500)
+                    .filter_map(|p| {
+                        let (prefix, c_p) = client.request_phone_number(p);
+                        let bucket = server.find_bucket(prefix);
+                        let sc_p =
+                            server.blind_phone_number(&c_p).expect("should be a valid point");
+                        client.find_user_id(&sc_p, &bucket, p).expect("should be a valid response")
+                    })
+                    .count()
+            });
+        });
+    }
+    g.finish();
+}
+
+fn rotate(c: &mut Criterion) {
+    let mut g = c.benchmark_group("rotate");
+    g.sample_size(10);
+    g.sampling_mode(SamplingMode::Flat);
+    for n in SIZES {
+        // Re-blind every entry in the directory with a new secret.
+        g.throughput(Throughput::Elements(n as u64));
+        g.bench_with_input(BenchmarkId::new("re-blind", n), &n, |b, &n| {
+            let server = directory(n);
+            let rng = ChaChaRng::seed_from_u64(0xDEADBEEF);
+            b.iter(|| server.rotate(rng.clone()));
+        });
+    }
+    for n in SIZES {
+        // Look up contacts during the dual-epoch window: both epochs are resident and being
+        // served, while the next rotation runs on another thread. Compare with lookup/directory.
+        g.throughput(Throughput::Elements(1));
+        g.bench_with_input(BenchmarkId::new("lookup during rotation", n), &n, |b, &n| {
+            let previous = directory(n);
+            let current = previous.rotate(ChaChaRng::seed_from_u64(0xDEADBEEF));
+            let client = Client::new(ChaChaRng::seed_from_u64(0xDEADBEEF));
+            let done = AtomicBool::new(false);
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let mut rng = ChaChaRng::seed_from_u64(0xDEADBEEF);
+                    while !done.load(Ordering::Relaxed) {
+                        black_box(current.rotate(&mut rng));
+                    }
+                });
+                let mut epochs = [previous, &current].into_iter().cycle();
+                b.iter(|| {
+                    let server = epochs.next().expect("should cycle");
+                    let (prefix, c_p) = client.request_phone_number(22);
+                    let bucket = server.find_bucket(prefix);
+                    let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+                    client.find_user_id(&sc_p, &bucket, 22).expect("should be a valid response")
+                });
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+    }
+    g.finish();
+}
+
+fn encoding(c: &mut Criterion) {
+    // Pad every bucket to 1,000 entries, so there's something to encode.
+    let id = Uuid::new_v4();
+    let (server, _) = ServerBuilder::new()
+        .min_bucket_size(1_000, SmallBucketPolicy::Pad)
+        .build(ChaChaRng::seed_from_u64(0xDEADBEEF), [(22, id)])
+        .expect("should build");
+    let (prefix, _) = Client::new(ChaChaRng::seed_from_u64(0xDEADBEEF)).request_phone_number(22);
+    let bucket = server.find_bucket(prefix);
+    let encoded = encode_bucket(&bucket);
+
+    let mut g = c.benchmark_group("encoding");
+    g.throughput(Throughput::Bytes(encoded.len() as u64));
+    g.bench_function("encode 1000", |b| b.iter(|| encode_bucket(&bucket)));
+    g.bench_function("decode 1000", |b| {
+        b.iter(|| decode_bucket(&encoded).expect("should be a valid bucket"))
+    });
+    g.finish();
+}
+
+/// Return a shared server with `n` users, building it and reporting its heap usage on first use.
+fn directory(n: usize) -> &'static Server {
+    static DIRECTORIES: [OnceLock<Server>; SIZES.len()] = [const { OnceLock::new() }; SIZES.len()];
+    let i = SIZES.iter().position(|&size| size == n).expect("should be a benchmark size");
+    DIRECTORIES[i].get_or_init(|| {
+        let server = create_server(ChaChaRng::seed_from_u64(0xDEADBEEF), n, Uuid::new_v4());
+        eprintln!("directory of {n} users uses {} bytes of heap", server.heap_size());
+        server
+    })
+}
+
 fn create_server(rng: impl CryptoRngCore, n: usize, id: Uuid) -> Server {
     let mut users = HashMap::new();
     for i in 0..(n as u64) {
@@ -44,5 +199,5 @@ fn create_server(rng: impl CryptoRngCore, n: usize, id: Uuid) -> Server {
     Server::new(rng, &users)
 }
 
-criterion_group!(benches, build, lookup);
+criterion_group!(benches, build, builder, lookup, batch, rotate, encoding);
 criterion_main!(benches);
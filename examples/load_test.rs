@@ -0,0 +1,68 @@
+//! Drive concurrent synthetic clients against an in-process server and report lookup latency and
+//! throughput.
+//!
+//! Usage: `cargo run --release --example load_test -- [USERS] [CLIENTS] [LOOKUPS_PER_CLIENT]`
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::OsRng, Rng};
+use uuid::Uuid;
+use zk_cds::{Client, Server};
+
+fn main() {
+    let mut args = env::args().skip(1).map(|arg| arg.parse::<usize>().expect("should be a number"));
+    let users = args.next().unwrap_or(100_000);
+    let clients = args.next().unwrap_or(8);
+    let lookups = args.next().unwrap_or(1_000);
+
+    // Build a directory of users with consecutive phone numbers.
+    let start = Instant::now();
+    let directory = (0..users as u64).map(|p| (p, Uuid::new_v4())).collect::<HashMap<_, _>>();
+    let server = Arc::new(Server::new(OsRng, &directory));
+    println!("built {users} users in {:?}", start.elapsed());
+
+    // Run each client on its own thread, timing every lookup.
+    let start = Instant::now();
+    let mut latencies = (0..clients)
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || {
+                let client = Client::new(OsRng);
+                (0..lookups)
+                    .map(|_| {
+                        let p = OsRng.gen_range(0..users as u64 * 2);
+                        let start = Instant::now();
+                        let (prefix, c_p) = client.request_phone_number(p);
+                        let bucket = server.find_bucket(prefix);
+                        let sc_p =
+                            server.blind_phone_number(&c_p).expect("should be a valid point");
+                        client.find_user_id(&sc_p, &bucket, p).expect("should be a valid response");
+                        start.elapsed()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .flat_map(|t| t.join().expect("should not panic"))
+        .collect::<Vec<_>>();
+    let elapsed = start.elapsed();
+
+    // Report latency percentiles and overall throughput.
+    latencies.sort_unstable();
+    println!("{} lookups by {clients} clients in {elapsed:?}", latencies.len());
+    println!("p50: {:?}", percentile(&latencies, 0.50));
+    println!("p99: {:?}", percentile(&latencies, 0.99));
+    println!("throughput: {:.0} lookups/s", latencies.len() as f64 / elapsed.as_secs_f64());
+}
+
+/// Return the given percentile of the sorted latencies.
+fn percentile(latencies: &[Duration], q: f64) -> Duration {
+    latencies[((latencies.len() - 1) as f64 * q).round() as usize]
+}
@@ -0,0 +1,83 @@
+#ifndef ZK_CDS_H
+#define ZK_CDS_H
+
+/* The C interface to src/ffi.rs, generated by cbindgen from cbindgen.toml. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The length of a compressed point, in bytes.
+#define ZK_CDS_POINT_LEN 33
+
+// The length of a hash prefix, in bytes.
+#define ZK_CDS_PREFIX_LEN 8
+
+// The result of a client operation.
+typedef enum ZkCdsStatus {
+  // The operation succeeded.
+  ZK_CDS_STATUS_OK = 0,
+  // The phone number was not found in the bucket.
+  ZK_CDS_STATUS_NOT_FOUND = 1,
+  // A required pointer argument was null.
+  ZK_CDS_STATUS_NULL_POINTER = -1,
+  // A point or bucket was not validly encoded.
+  ZK_CDS_STATUS_INVALID_POINT = -2,
+} ZkCdsStatus;
+
+// An opaque handle to a client.
+typedef struct ZkCdsClient ZkCdsClient;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create a new client using a random secret. The returned client must be released with
+// [`zk_cds_client_free`].
+struct ZkCdsClient *zk_cds_client_new(void);
+
+// Release a client created by [`zk_cds_client_new`]. Passing null is a no-op.
+//
+// # Safety
+//
+// `client` must be null or a pointer returned by [`zk_cds_client_new`] which has not already been
+// released.
+void zk_cds_client_free(struct ZkCdsClient *client);
+
+// Initiate a client request for the given phone number, writing the hash prefix of the phone
+// number to `prefix_out` and the blinded phone number point to `c_p_out`.
+//
+// # Safety
+//
+// `client` must be a live client, `prefix_out` must be valid for writes of
+// [`ZK_CDS_PREFIX_LEN`] bytes, and `c_p_out` must be valid for writes of [`ZK_CDS_POINT_LEN`]
+// bytes.
+enum ZkCdsStatus zk_cds_client_request(const struct ZkCdsClient *client,
+                                       uint64_t p,
+                                       uint8_t *prefix_out,
+                                       uint8_t *c_p_out);
+
+// Given a double-blinded phone number point and bucket of users from the server, write the
+// unblinded user ID point to `s_u_out` if one can be found.
+//
+// Returns [`ZkCdsStatus::NotFound`] and leaves `s_u_out` untouched if the phone number is not in
+// the bucket.
+//
+// # Safety
+//
+// `client` must be a live client, `sc_p` must be valid for reads of [`ZK_CDS_POINT_LEN`] bytes,
+// `bucket` must be valid for reads of `bucket_len` bytes, and `s_u_out` must be valid for writes
+// of [`ZK_CDS_POINT_LEN`] bytes.
+enum ZkCdsStatus zk_cds_client_find(const struct ZkCdsClient *client,
+                                    const uint8_t *sc_p,
+                                    const uint8_t *bucket,
+                                    uintptr_t bucket_len,
+                                    uint64_t p,
+                                    uint8_t *s_u_out);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ZK_CDS_H */
@@ -0,0 +1,82 @@
+//! Hooks for operators' fraud systems.
+//!
+//! Abuse policies differ between deployments, so the crate doesn't hard-code one. Instead, a
+//! [`LookupService`](crate::service::LookupService) with an
+//! [abuse hook](crate::service::LookupService::with_abuse_hook) calls an [`AbuseHook`] with the
+//! [`RequestFeatures`] of each lookup request, which an operator can feed to their fraud systems
+//! (e.g. to lower an account's [`AbuseTier`](crate::budget::AbuseTier)).
+//!
+//! The features are anonymized: they contain no points, prefixes, or tokens, only aggregate
+//! properties of the request, so they can't be used to reconstruct who looked up whom.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{Error, Prefix};
+
+/// The kind of a lookup request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// A request for buckets.
+    FindBuckets,
+    /// A request to blind phone numbers.
+    Blind,
+    /// A request to unblind a user ID.
+    Unblind,
+}
+
+/// The anonymized features of a lookup request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestFeatures {
+    /// The kind of request.
+    pub kind: RequestKind,
+    /// The number of prefixes or phone numbers in the request, or one for unblind requests.
+    pub batch_size: usize,
+    /// The Shannon entropy, in bits, of the first bytes of the requested prefixes. Batches which
+    /// request the same few buckets over and over have low entropy.
+    pub prefix_entropy: f64,
+    /// The number of tokens in the request which had already been spent.
+    pub reused_tokens: usize,
+    /// The error the request failed with, if any.
+    pub error: Option<Error>,
+}
+
+/// A hook which is called with the features of each lookup request.
+pub trait AbuseHook: Send + Sync {
+    /// Observe a lookup request which has been answered.
+    fn observe(&self, features: &RequestFeatures);
+}
+
+impl fmt::Debug for dyn AbuseHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AbuseHook").finish_non_exhaustive()
+    }
+}
+
+impl<F: Fn(&RequestFeatures) + Send + Sync> AbuseHook for F {
+    fn observe(&self, features: &RequestFeatures) {
+        self(features)
+    }
+}
+
+/// Return the Shannon entropy, in bits, of the first bytes of the given prefixes.
+pub fn prefix_entropy(prefixes: &[Prefix]) -> f64 {
+    let mut counts = HashMap::new();
+    for prefix in prefixes {
+        *counts.entry(prefix[0]).or_insert(0usize) += 1;
+    }
+    let n = prefixes.len() as f64;
+    counts.values().map(|&c| c as f64 / n).map(|p| -p * p.log2()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entropy() {
+        assert_eq!(prefix_entropy(&[]), 0.0);
+        assert_eq!(prefix_entropy(&[[7; 8]; 16]), 0.0);
+        let spread = (0..=255).map(|i| [i; 8]).collect::<Vec<_>>();
+        assert!((prefix_entropy(&spread) - 8.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,124 @@
+//! An administrative API for operators.
+//!
+//! [`Admin`] answers [`AdminRequest`]s about a server's [jobs](crate::jobs): listing them, checking
+//! one's progress, and cancelling or removing one. [`AdminRequest::from_http`] maps the
+//! `GET /admin/jobs`, `GET /admin/jobs/{id}`, `POST /admin/jobs/{id}/cancel`, and
+//! `DELETE /admin/jobs/{id}` endpoints to requests. The API isn't authenticated, so it should only
+//! be served to operators, never to clients.
+
+use std::sync::Arc;
+
+use crate::jobs::{JobId, JobQueue, JobStatus};
+
+/// A request to an [`Admin`] API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRequest {
+    /// List every job.
+    ListJobs,
+    /// Return the status of the given job.
+    JobStatus(JobId),
+    /// Cancel the given job.
+    CancelJob(JobId),
+    /// Remove the given job, if it isn't running.
+    RemoveJob(JobId),
+}
+
+impl AdminRequest {
+    /// Return the request for the given HTTP method and path, if it's an admin endpoint. Job IDs
+    /// are hex-encoded.
+    pub fn from_http(method: &str, path: &str) -> Option<AdminRequest> {
+        let rest = path.strip_prefix("/admin/jobs")?;
+        if rest.is_empty() {
+            return (method == "GET").then_some(AdminRequest::ListJobs);
+        }
+
+        // Parse the job ID and the optional action following it.
+        let rest = rest.strip_prefix('/')?;
+        let (id, action) = rest.split_once('/').unwrap_or((rest, ""));
+        let id = JobId(u64::from_str_radix(id, 16).ok()?);
+        match (method, action) {
+            ("GET", "") => Some(AdminRequest::JobStatus(id)),
+            ("POST", "cancel") => Some(AdminRequest::CancelJob(id)),
+            ("DELETE", "") => Some(AdminRequest::RemoveJob(id)),
+            _ => None,
+        }
+    }
+}
+
+/// A response from an [`Admin`] API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminResponse {
+    /// Every job and its status, in order of ID.
+    Jobs(Vec<(JobId, JobStatus)>),
+    /// The job's status, or `None` if it doesn't exist.
+    Job(Option<JobStatus>),
+    /// Whether the job was running and is now cancelled.
+    Cancelled(bool),
+    /// The removed job's status, or `None` if it doesn't exist or is still running.
+    Removed(Option<JobStatus>),
+}
+
+/// An administrative API over a server's jobs.
+#[derive(Clone)]
+pub struct Admin {
+    jobs: Arc<dyn JobQueue>,
+}
+
+impl Admin {
+    /// Create an API over the given jobs.
+    pub fn new(jobs: Arc<dyn JobQueue>) -> Admin {
+        Admin { jobs }
+    }
+
+    /// Answer the given request.
+    pub fn handle(&self, req: AdminRequest) -> AdminResponse {
+        match req {
+            AdminRequest::ListJobs => AdminResponse::Jobs(self.jobs.list()),
+            AdminRequest::JobStatus(id) => AdminResponse::Job(self.jobs.status(id)),
+            AdminRequest::CancelJob(id) => AdminResponse::Cancelled(self.jobs.cancel(id)),
+            AdminRequest::RemoveJob(id) => AdminResponse::Removed(self.jobs.remove(id)),
+        }
+    }
+}
+
+impl std::fmt::Debug for Admin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Admin").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{InMemoryJobQueue, JobKind, JobState};
+
+    #[test]
+    fn admin() {
+        let jobs = Arc::new(InMemoryJobQueue::new());
+        let admin = Admin::new(jobs.clone());
+        let id = jobs.create(JobKind::Rebuild, 3);
+        let path = format!("/admin/jobs/{id}");
+
+        // Jobs can be inspected and cancelled over HTTP.
+        let req = |method, path: &str| {
+            admin.handle(AdminRequest::from_http(method, path).expect("should be an endpoint"))
+        };
+        let AdminResponse::Jobs(all) = req("GET", "/admin/jobs") else {
+            panic!("should list jobs");
+        };
+        assert_eq!(all.len(), 1);
+        assert_eq!(req("DELETE", &path), AdminResponse::Removed(None));
+        assert_eq!(req("POST", &format!("{path}/cancel")), AdminResponse::Cancelled(true));
+        let AdminResponse::Job(Some(status)) = req("GET", &path) else {
+            panic!("should return the job");
+        };
+        assert_eq!(status.state, JobState::Cancelled);
+        assert!(matches!(req("DELETE", &path), AdminResponse::Removed(Some(_))));
+        assert_eq!(req("GET", &path), AdminResponse::Job(None));
+
+        // Other paths aren't admin endpoints.
+        assert_eq!(AdminRequest::from_http("POST", "/admin/jobs"), None);
+        assert_eq!(AdminRequest::from_http("GET", "/admin/jobs/xyz"), None);
+        assert_eq!(AdminRequest::from_http("GET", "/v1/info"), None);
+    }
+}
@@ -0,0 +1,293 @@
+//! Bucket storage backed by a single arena of entries.
+//!
+//! Storing each bucket in its own map means millions of small allocations for a large directory.
+//! Instead, every `(sP, hsU)` entry is stored in one vector, grouped by bucket and sorted by `sP`
+//! within each bucket, and buckets are ranges of it. Points are stored as fixed-size compressed
+//! encodings, rather than as [`EncodedPoint`]s, so entries are small, `Copy`, and quick to compare.
+
+use std::{collections::HashMap, hash::BuildHasher, mem, ops::Range};
+
+use p256::EncodedPoint;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+use crate::{
+    encoding::{CtPointBytes, POINT_LEN},
+    sub_bucket_prefix, table_size, BuildError, Prefix,
+};
+
+/// A compressed SEC1 encoding of a point.
+pub(crate) type PointBytes = [u8; POINT_LEN];
+
+/// An `(sP, hsU)` entry.
+pub(crate) type Entry = (PointBytes, PointBytes);
+
+/// Return the compressed encoding of the given point.
+///
+/// # Panics
+///
+/// Panics if the point isn't compressed or is the identity.
+pub(crate) fn point_bytes(p: &EncodedPoint) -> PointBytes {
+    p.as_bytes().try_into().expect("should be a compressed point")
+}
+
+/// Return the given compressed encoding as an [`EncodedPoint`].
+pub(crate) fn encoded_point(b: &PointBytes) -> EncodedPoint {
+    EncodedPoint::from_bytes(b).expect("should be a valid encoding")
+}
+
+/// Buckets of entries, stored contiguously.
+#[derive(Debug, Clone)]
+pub(crate) struct Arena<S> {
+    ranges: HashMap<Prefix, Range<usize>, S>,
+    entries: Vec<Entry>,
+    /// The prefixes of buckets split into sub-buckets, in order.
+    split: Vec<Prefix>,
+    /// The prefixes of every possible sub-bucket of the split buckets, in order.
+    sub_buckets: Vec<Prefix>,
+}
+
+impl<S: BuildHasher> Arena<S> {
+    /// Create an empty arena with room for the given numbers of buckets and entries.
+    pub(crate) fn with_capacity_and_hasher(buckets: usize, entries: usize, hasher: S) -> Arena<S> {
+        Arena {
+            ranges: HashMap::with_capacity_and_hasher(buckets, hasher),
+            entries: Vec::with_capacity(entries),
+            split: Vec::new(),
+            sub_buckets: Vec::new(),
+        }
+    }
+
+    /// Create an arena from `(prefix, sP, hsU)` rows in any order.
+    pub(crate) fn from_rows(
+        mut rows: Vec<(Prefix, EncodedPoint, EncodedPoint)>,
+        hasher: S,
+    ) -> Arena<S> {
+        rows.sort_unstable_by_key(|&(prefix, _, _)| prefix);
+        let buckets = rows.chunk_by(|a, b| a.0 == b.0).count();
+        let mut arena = Arena::with_capacity_and_hasher(buckets, rows.len(), hasher);
+        for bucket in rows.chunk_by(|a, b| a.0 == b.0) {
+            arena.push(
+                bucket[0].0,
+                bucket.iter().map(|(_, s_p, hs_u)| (point_bytes(s_p), point_bytes(hs_u))),
+            );
+        }
+        arena
+    }
+
+    /// Create an arena from `(prefix, sP, hsU)` rows in any order, given the number of rows with
+    /// each prefix, without holding every row in memory at once.
+    ///
+    /// Returns [`BuildError::CheckpointMismatch`] if the rows don't match the sizes.
+    pub(crate) fn from_sized_rows(
+        sizes: impl IntoIterator<Item = (Prefix, usize)>,
+        rows: impl IntoIterator<Item = Result<(Prefix, EncodedPoint, EncodedPoint), BuildError>>,
+        hasher: S,
+    ) -> Result<Arena<S>, BuildError> {
+        // Lay out the buckets in order of prefix, tracking the next free slot and end of each.
+        let mut sizes = sizes.into_iter().collect::<Vec<_>>();
+        sizes.sort_unstable();
+        let mut slots = HashMap::with_capacity_and_hasher(sizes.len(), hasher);
+        let mut start = 0;
+        for &(prefix, n) in &sizes {
+            slots.insert(prefix, start..start + n);
+            start += n;
+        }
+        let mut entries = vec![([0; POINT_LEN], [0; POINT_LEN]); start];
+
+        // Fill each bucket, checking none overflows.
+        for row in rows {
+            let (prefix, s_p, hs_u) = row?;
+            let slot = slots.get_mut(&prefix).ok_or(BuildError::CheckpointMismatch)?;
+            let i = slot.next().ok_or(BuildError::CheckpointMismatch)?;
+            entries[i] = (point_bytes(&s_p), point_bytes(&hs_u));
+        }
+
+        // Check every bucket was filled, and sort each by sP.
+        let mut ranges = slots;
+        let mut start = 0;
+        for (prefix, n) in sizes {
+            let range = ranges.get_mut(&prefix).expect("should have a slot");
+            if range.start != range.end {
+                return Err(BuildError::CheckpointMismatch);
+            }
+            *range = start..start + n;
+            entries[range.clone()].sort_unstable_by_key(|&(s_p, _)| s_p);
+            start += n;
+        }
+        Ok(Arena { ranges, entries, split: Vec::new(), sub_buckets: Vec::new() })
+    }
+
+    /// Append a bucket with the given prefix and entries.
+    pub(crate) fn push(&mut self, prefix: Prefix, bucket: impl IntoIterator<Item = Entry>) {
+        let start = self.entries.len();
+        self.entries.extend(bucket);
+        self.entries[start..].sort_unstable_by_key(|&(s_p, _)| s_p);
+        let previous = self.ranges.insert(prefix, start..self.entries.len());
+        debug_assert!(previous.is_none(), "should not push a bucket twice");
+    }
+
+    /// Return the entries of the bucket with the given prefix, sorted by `sP`.
+    pub(crate) fn get(&self, prefix: &Prefix) -> &[Entry] {
+        self.ranges.get(prefix).map_or(&[], |range| &self.entries[range.clone()])
+    }
+
+    /// Return the `hsU` of the entry with the given prefix and `sP`, if any.
+    ///
+    /// Every entry in the bucket is compared in constant time, so the time taken doesn't depend on
+    /// whether or where the entry is found.
+    pub(crate) fn find(&self, prefix: &Prefix, s_p: &EncodedPoint) -> Option<&PointBytes> {
+        let bucket = self.get(prefix);
+        let s_p = CtPointBytes::new(s_p)?;
+        let (mut found, mut index) = (Choice::from(0), 0u64);
+        for (i, (k, _)) in bucket.iter().enumerate() {
+            let eq = CtPointBytes(*k).ct_eq(&s_p);
+            index.conditional_assign(&(i as u64), eq);
+            found |= eq;
+        }
+        bool::from(found).then(|| &bucket[index as usize].1)
+    }
+
+    /// Return every bucket's prefix and entries, in order of prefix.
+    pub(crate) fn sorted(&self) -> Vec<(Prefix, &[Entry])> {
+        let mut buckets = self
+            .ranges
+            .iter()
+            .map(|(&prefix, range)| (prefix, &self.entries[range.clone()]))
+            .collect::<Vec<_>>();
+        buckets.sort_unstable_by_key(|&(prefix, _)| prefix);
+        buckets
+    }
+
+    /// Return an arena with the same buckets, with every entry transformed by `f`.
+    pub(crate) fn map(&self, mut f: impl FnMut(&Entry) -> Entry) -> Arena<S>
+    where
+        S: Clone,
+    {
+        let mut entries = Vec::with_capacity(self.entries.len());
+        entries.extend(self.entries.iter().map(&mut f));
+
+        // Transformed entries must be re-sorted within each bucket.
+        for range in self.ranges.values() {
+            entries[range.clone()].sort_unstable_by_key(|&(s_p, _)| s_p);
+        }
+        Arena {
+            ranges: self.ranges.clone(),
+            entries,
+            split: self.split.clone(),
+            sub_buckets: self.sub_buckets.clone(),
+        }
+    }
+
+    /// Return an arena with the given buckets replaced, dropping any which are left empty.
+    pub(crate) fn replace(&self, changed: HashMap<Prefix, Vec<Entry>>) -> Arena<S>
+    where
+        S: Clone,
+    {
+        let mut arena = Arena::with_capacity_and_hasher(
+            self.ranges.len() + changed.len(),
+            self.entries.len(),
+            self.ranges.hasher().clone(),
+        );
+
+        // Copy the unchanged buckets, then add the changed ones.
+        for (&prefix, range) in &self.ranges {
+            if !changed.contains_key(&prefix) {
+                arena.push(prefix, self.entries[range.clone()].iter().copied());
+            }
+        }
+        for (prefix, bucket) in changed {
+            if !bucket.is_empty() {
+                arena.push(prefix, bucket);
+            }
+        }
+        arena.split = self.split.clone();
+        arena.sub_buckets = self.sub_buckets.clone();
+        arena
+    }
+
+    /// Return the arena with the given buckets marked as split into sub-buckets.
+    pub(crate) fn with_split(mut self, mut split: Vec<Prefix>) -> Arena<S> {
+        split.sort_unstable();
+        self.sub_buckets = split
+            .iter()
+            .flat_map(|prefix| (0..=u8::MAX).map(|b| sub_bucket_prefix(prefix, &[b])))
+            .collect();
+        self.sub_buckets.sort_unstable();
+        self.split = split;
+        self
+    }
+
+    /// The prefixes of the buckets split into sub-buckets, in order.
+    pub(crate) fn split(&self) -> &[Prefix] {
+        &self.split
+    }
+
+    /// Whether the bucket with the given prefix was split into sub-buckets.
+    pub(crate) fn is_split(&self, prefix: &Prefix) -> bool {
+        self.split.binary_search(prefix).is_ok()
+    }
+
+    /// Whether the given prefix is that of a sub-bucket of a split bucket.
+    pub(crate) fn is_sub_bucket(&self, prefix: &Prefix) -> bool {
+        self.sub_buckets.binary_search(prefix).is_ok()
+    }
+
+    /// The hasher used for the bucket index.
+    pub(crate) fn hasher(&self) -> &S {
+        self.ranges.hasher()
+    }
+
+    /// Return an estimate of the number of bytes of heap memory used by the arena.
+    pub(crate) fn heap_size(&self) -> usize {
+        table_size::<Prefix, Range<usize>>(self.ranges.capacity())
+            + self.entries.capacity() * mem::size_of::<Entry>()
+            + (self.split.capacity() + self.sub_buckets.capacity()) * mem::size_of::<Prefix>()
+    }
+}
+
+impl<S> Arena<S> {
+    /// The number of buckets.
+    pub(crate) fn bucket_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The number of entries in every bucket.
+    pub(crate) fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::RandomState;
+
+    use p256::{
+        elliptic_curve::{sec1::ToEncodedPoint, Group},
+        ProjectivePoint,
+    };
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let point = || ProjectivePoint::random(OsRng).to_affine().to_encoded_point(true);
+        let rows = (0..100u8).map(|i| ([i % 7; 8], point(), point())).collect::<Vec<_>>();
+        let arena = Arena::from_rows(rows.clone(), RandomState::new());
+        assert_eq!(arena.ranges.len(), 7);
+        assert_eq!(arena.get(&[0; 8]).len(), 15);
+        assert_eq!(arena.get(&[7; 8]), &[]);
+
+        // Every row can be found by its prefix and sP.
+        for (prefix, s_p, hs_u) in &rows {
+            assert_eq!(arena.find(prefix, s_p), Some(&point_bytes(hs_u)));
+        }
+        assert_eq!(arena.find(&[0; 8], &point()), None);
+
+        // Mapped entries can be found too.
+        let swapped = arena.map(|&(s_p, hs_u)| (hs_u, s_p));
+        for (prefix, s_p, hs_u) in &rows {
+            assert_eq!(swapped.find(prefix, hs_u), Some(&point_bytes(s_p)));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Privacy-preserving audit counters for abuse analysis.
+//!
+//! Abuse teams need to see how often each client makes requests, but a log of client identifiers
+//! and requested prefixes would let an operator reconstruct who looked up whom. An [`AuditLog`]
+//! records only a short fingerprint of each client's identifier, salted with a random value which is
+//! replaced (and its counters discarded) every rotation period. Fingerprints are truncated so that
+//! many identifiers share each one, and salts are never exposed, so fingerprints from one period
+//! can't be linked to another's or reversed by guessing identifiers. Requested prefixes aren't
+//! recorded at all.
+//!
+//! Callers should log the [`Fingerprint`] returned by [`AuditLog::record`] in place of the client's
+//! identifier.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// The length of a client fingerprint, in bytes.
+pub const FINGERPRINT_LEN: usize = 3;
+
+/// A salted, truncated hash of a client identifier.
+pub type Fingerprint = [u8; FINGERPRINT_LEN];
+
+/// Per-client request counters keyed by salted fingerprints.
+#[derive(Debug)]
+pub struct AuditLog<R> {
+    rotation_period: Duration,
+    state: Mutex<AuditState<R>>,
+}
+
+#[derive(Debug)]
+struct AuditState<R> {
+    rng: R,
+    salt: [u8; 32],
+    started_at: Instant,
+    counts: HashMap<Fingerprint, u64>,
+}
+
+impl<R: CryptoRng + RngCore> AuditLog<R> {
+    /// Create a new audit log which rotates its salt every `rotation_period`, starting at `now`.
+    pub fn new(mut rng: R, rotation_period: Duration, now: Instant) -> AuditLog<R> {
+        let mut salt = [0u8; 32];
+        rng.fill_bytes(&mut salt);
+        AuditLog {
+            rotation_period,
+            state: Mutex::new(AuditState { rng, salt, started_at: now, counts: HashMap::new() }),
+        }
+    }
+
+    /// Record a request from the client with the given identifier (e.g. an IP address or account
+    /// ID) at `now`, returning its fingerprint for the current period.
+    pub fn record(&self, client_id: &[u8], now: Instant) -> Fingerprint {
+        let mut state = self.state.lock().expect("should not be poisoned");
+        state.rotate_if_expired(self.rotation_period, now);
+
+        // Hash the identifier with the salt and truncate it.
+        let h = Sha256::new().chain_update(state.salt).chain_update(client_id).finalize();
+        let fingerprint = h[..FINGERPRINT_LEN].try_into().expect("should be long enough");
+
+        *state.counts.entry(fingerprint).or_default() += 1;
+        fingerprint
+    }
+
+    /// Return the request counts for the current period, ordered from most to fewest requests.
+    pub fn counts(&self, now: Instant) -> Vec<(Fingerprint, u64)> {
+        let mut state = self.state.lock().expect("should not be poisoned");
+        state.rotate_if_expired(self.rotation_period, now);
+
+        let mut counts = state.counts.iter().map(|(&f, &n)| (f, n)).collect::<Vec<_>>();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+}
+
+impl<R: CryptoRng + RngCore> AuditState<R> {
+    /// If the current period has ended, discard the counters and replace the salt.
+    fn rotate_if_expired(&mut self, rotation_period: Duration, now: Instant) {
+        if now.saturating_duration_since(self.started_at) >= rotation_period {
+            self.rng.fill_bytes(&mut self.salt);
+            self.started_at = now;
+            self.counts.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let start = Instant::now();
+        let log = AuditLog::new(OsRng, Duration::from_secs(60), start);
+
+        // Requests from the same client share a fingerprint within a period.
+        let a = log.record(b"10.0.0.1", start);
+        assert_eq!(log.record(b"10.0.0.1", start + Duration::from_secs(1)), a);
+        log.record(b"10.0.0.2", start);
+        assert_eq!(log.counts(start)[0], (a, 2));
+
+        // But not across periods, and counters are discarded on rotation.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(log.counts(later), vec![]);
+        assert_ne!(log.record(b"10.0.0.1", later), a);
+    }
+}
@@ -0,0 +1,274 @@
+//! Offline verification of exported epoch bundles, for auditors who don't run the server.
+//!
+//! ```text
+//! zk-cds-verify BUNDLE [--id HEX] [--verifying-key HEX] [--min-bucket-size N] [--max-bucket-size N]
+//! ```
+//!
+//! Checks the bundle's structure and every digest, and that every bucket conforms to the padding
+//! policy the bundle commits to, then optionally that its ID matches a published one, that it's
+//! signed by the primary's verifying key, and that its buckets conform to the given bucket sizes.
+//! The overflow bucket, which holds the entries spilled from buckets which exceeded the maximum
+//! bucket size, is held to it too.
+
+use std::{env, fs, process::ExitCode};
+
+use p256::EncodedPoint;
+use zk_cds::bundle::Bundle;
+
+const USAGE: &str = "usage: zk-cds-verify BUNDLE [--id HEX] [--verifying-key HEX] \
+                     [--min-bucket-size N] [--max-bucket-size N]";
+
+#[derive(Debug, Default)]
+struct Args {
+    path: String,
+    id: Option<[u8; 32]>,
+    verifying_key: Option<EncodedPoint>,
+    min_bucket_size: usize,
+    max_bucket_size: Option<usize>,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match verify(&args) {
+        Ok(()) => {
+            println!("OK");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("FAILED: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Verify the bundle, printing a summary of it.
+fn verify(args: &Args) -> Result<(), String> {
+    let b = fs::read(&args.path).map_err(|e| format!("can't read {}: {e}", args.path))?;
+    let bundle = Bundle::decode(b).map_err(|e| format!("malformed bundle: {e}"))?;
+    summarize(&bundle);
+    check(&bundle, args)
+}
+
+/// Print the bundle's epoch, ID, size, and bucket size limits.
+fn summarize(bundle: &Bundle) {
+    let header = bundle.header();
+    println!("epoch:   {}", header.epoch);
+    println!("id:      {}", to_hex(&header.id()));
+    println!("buckets: {}", header.buckets);
+    println!("entries: {}", bundle.index().entries().iter().map(|e| e.entries()).sum::<usize>());
+    println!("min:     {}", header.min_bucket_size);
+    match header.max_bucket_size {
+        Some(max) => println!("max:     {max}"),
+        None => println!("max:     none"),
+    }
+}
+
+/// Check a decoded bundle against the expected ID, verifying key, and bucket sizes.
+fn check(bundle: &Bundle, args: &Args) -> Result<(), String> {
+    let header = bundle.header();
+    let index = bundle.index();
+
+    // Check the bundle is the one which was published.
+    if let Some(id) = args.id {
+        if header.id() != id {
+            return Err(format!("bundle ID doesn't match {}", to_hex(&id)));
+        }
+    }
+    if let Some(key) = &args.verifying_key {
+        header.verify(key).map_err(|e| format!("bad signature: {e}"))?;
+    }
+
+    // Check every bucket conforms to the committed padding policy, and the expected one.
+    for entry in index.entries() {
+        let n = entry.entries();
+        if !header.permits(entry) {
+            let prefix = to_hex(&entry.prefix);
+            return Err(format!("bucket {prefix} has {n} entries, violating the bundle's policy"));
+        }
+        if n < args.min_bucket_size {
+            let (prefix, min) = (to_hex(&entry.prefix), args.min_bucket_size);
+            return Err(format!(
+                "bucket {prefix} has {n} entries, fewer than the minimum of {min}"
+            ));
+        }
+        if args.max_bucket_size.is_some_and(|max| n > max) {
+            let prefix = to_hex(&entry.prefix);
+            return Err(format!("bucket {prefix} has {n} entries, more than the maximum"));
+        }
+    }
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--id" => {
+                let id = from_hex(&value()?)?;
+                parsed.id = Some(id.try_into().map_err(|_| "IDs are 32 bytes".to_string())?);
+            }
+            "--verifying-key" => {
+                let key = EncodedPoint::from_bytes(from_hex(&value()?)?);
+                parsed.verifying_key = Some(key.map_err(|_| "invalid verifying key")?);
+            }
+            "--min-bucket-size" => {
+                parsed.min_bucket_size = value()?.parse().map_err(|e| format!("{arg}: {e}"))?;
+            }
+            "--max-bucket-size" => {
+                parsed.max_bucket_size = Some(value()?.parse().map_err(|e| format!("{arg}: {e}"))?);
+            }
+            _ if arg.starts_with("--") || !parsed.path.is_empty() => {
+                return Err(format!("unexpected argument {arg}"));
+            }
+            _ => parsed.path = arg,
+        }
+    }
+    if parsed.path.is_empty() {
+        return Err("missing bundle".to_string());
+    }
+    Ok(parsed)
+}
+
+fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex: {s}"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid hex: {s}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+    use zk_cds::{
+        replication::Primary, Client, ConflictPolicy, OverflowPolicy, ServerBuilder,
+        SmallBucketPolicy,
+    };
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|s| s.to_string()))
+    }
+
+    fn export(builder: ServerBuilder, users: &[(u64, Uuid)]) -> Bundle {
+        let (server, _) = builder.build(OsRng, users.iter().copied()).expect("should build");
+        server.export_bundle()
+    }
+
+    #[test]
+    fn arguments() {
+        let id = "ab".repeat(32);
+        let parsed =
+            args(&["b.bin", "--id", &id, "--min-bucket-size", "2", "--max-bucket-size", "9"])
+                .expect("should be valid arguments");
+        assert_eq!(parsed.path, "b.bin");
+        assert_eq!(parsed.id, Some([0xab; 32]));
+        assert_eq!((parsed.min_bucket_size, parsed.max_bucket_size), (2, Some(9)));
+        assert_eq!(parsed.verifying_key, None);
+
+        let key = Primary::new(OsRng).verifying_key();
+        let parsed = args(&["--verifying-key", &to_hex(key.as_bytes()), "b.bin"])
+            .expect("should be valid arguments");
+        assert_eq!(parsed.verifying_key, Some(key));
+
+        assert_eq!(args(&[]).err(), Some("missing bundle".to_string()));
+        assert_eq!(args(&["b.bin", "--id"]).err(), Some("missing value for --id".to_string()));
+        assert_eq!(args(&["b.bin", "--id", "abab"]).err(), Some("IDs are 32 bytes".to_string()));
+        assert_eq!(
+            args(&["b.bin", "--verifying-key", "abab"]).err(),
+            Some("invalid verifying key".to_string())
+        );
+        assert!(args(&["b.bin", "--min-bucket-size", "-1"]).is_err());
+        assert_eq!(args(&["b.bin", "c.bin"]).err(), Some("unexpected argument c.bin".to_string()));
+        assert_eq!(
+            args(&["b.bin", "--verbose"]).err(),
+            Some("unexpected argument --verbose".to_string())
+        );
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex("00ff7A"), Ok(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(to_hex(&[0x00, 0xff, 0x7a]), "00ff7a");
+        assert_eq!(from_hex("abc"), Err("invalid hex: abc".to_string()));
+        assert_eq!(from_hex("zz"), Err("invalid hex: zz".to_string()));
+        assert_eq!(from_hex("é0"), Err("invalid hex: é0".to_string()));
+    }
+
+    #[test]
+    fn ids_and_signatures() {
+        let mut bundle = export(ServerBuilder::new(), &[(22, Uuid::new_v4())]);
+        let primary = Primary::new(OsRng);
+
+        // Bundles match their own IDs, and no others.
+        let args = Args { id: Some(bundle.id()), ..Args::default() };
+        assert_eq!(check(&bundle, &args), Ok(()));
+        let args = Args { id: Some([0; 32]), ..Args::default() };
+        assert_eq!(
+            check(&bundle, &args),
+            Err(format!("bundle ID doesn't match {}", "00".repeat(32)))
+        );
+
+        // Signed bundles verify with the primary's key, and no others.
+        let args = Args { verifying_key: Some(primary.verifying_key()), ..Args::default() };
+        assert!(check(&bundle, &args).is_err_and(|e| e.starts_with("bad signature")));
+        primary.sign_bundle(&mut bundle);
+        assert_eq!(check(&bundle, &args), Ok(()));
+        let args =
+            Args { verifying_key: Some(Primary::new(OsRng).verifying_key()), ..Args::default() };
+        assert!(check(&bundle, &args).is_err_and(|e| e.starts_with("bad signature")));
+    }
+
+    #[test]
+    fn bucket_sizes() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let users = [(22, a), (22, b), (22, c), (23, a)];
+        let builder = || ServerBuilder::new().conflict_policy(ConflictPolicy::KeepAll);
+        let bundle = export(builder(), &users);
+        let prefix = to_hex(&Client::new(OsRng).request_phone_number(23).0);
+
+        // Buckets must be at least the minimum size.
+        let args = Args { min_bucket_size: 2, ..Args::default() };
+        assert_eq!(
+            check(&bundle, &args),
+            Err(format!("bucket {prefix} has 1 entries, fewer than the minimum of 2"))
+        );
+        let args = Args { min_bucket_size: 1, ..Args::default() };
+        assert_eq!(check(&bundle, &args), Ok(()));
+
+        // And at most the maximum size.
+        let args = Args { max_bucket_size: Some(2), ..Args::default() };
+        assert!(check(&bundle, &args).is_err_and(|e| e.ends_with("more than the maximum")));
+
+        // Including the overflow bucket, which holds the entries spilled from the other buckets.
+        let spilled = export(builder().max_bucket_size(2, OverflowPolicy::Spill), &users);
+        assert_eq!(check(&spilled, &args), Ok(()));
+
+        // Padded buckets pass the minimum they were padded to.
+        let padded =
+            export(ServerBuilder::new().min_bucket_size(4, SmallBucketPolicy::Pad), &[(22, a)]);
+        let args = Args { min_bucket_size: 4, ..Args::default() };
+        assert_eq!(check(&padded, &args), Ok(()));
+    }
+
+    #[test]
+    fn unreadable_bundles() {
+        let args = Args { path: "/nonexistent/bundle.bin".to_string(), ..Args::default() };
+        assert!(verify(&args).is_err_and(|e| e.starts_with("can't read")));
+    }
+}
@@ -0,0 +1,65 @@
+//! Offloading of the scalar multiplications which dominate server builds.
+//!
+//! Blinding an entry takes two scalar multiplications, so building a directory of hundreds of
+//! millions of entries is CPU-bound. A [`BulkBlinder`] performs a chunk's multiplications at once,
+//! which lets [`ServerBuilder::bulk_blinder`](crate::ServerBuilder::bulk_blinder) delegate them to
+//! an accelerator. The builder recomputes a randomly chosen entry of every chunk on the CPU and
+//! fails with [`BuildError::BlinderMismatch`](crate::BuildError::BlinderMismatch) if it differs.
+
+use p256::{ProjectivePoint, Scalar};
+
+/// An implementation of bulk scalar multiplication.
+pub trait BulkBlinder: Send + Sync {
+    /// Return `k·P` for every `(k, P)` pair, in order.
+    fn multiply(&self, pairs: &[(Scalar, ProjectivePoint)]) -> Vec<ProjectivePoint>;
+}
+
+/// A [`BulkBlinder`] which multiplies on the CPU, one pair at a time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuBlinder;
+
+impl BulkBlinder for CpuBlinder {
+    fn multiply(&self, pairs: &[(Scalar, ProjectivePoint)]) -> Vec<ProjectivePoint> {
+        pairs.iter().map(|&(k, p)| p * k).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{BuildError, ServerBuilder};
+
+    /// A blinder which gets the given pair wrong.
+    struct FaultyBlinder(usize);
+
+    impl BulkBlinder for FaultyBlinder {
+        fn multiply(&self, pairs: &[(Scalar, ProjectivePoint)]) -> Vec<ProjectivePoint> {
+            let mut products = CpuBlinder.multiply(pairs);
+            if let Some(p) = products.get_mut(self.0) {
+                *p += ProjectivePoint::GENERATOR;
+            }
+            products
+        }
+    }
+
+    #[test]
+    fn cross_checks() {
+        let users = (0..10).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+
+        // A correct blinder builds the same directory as the default.
+        let (server, _) = ServerBuilder::new()
+            .bulk_blinder(FaultyBlinder(usize::MAX))
+            .build(OsRng, users.clone())
+            .expect("should build");
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+
+        // A faulty one is caught when its mistake is in the checked entry.
+        assert!(matches!(
+            ServerBuilder::new().chunk_size(1).bulk_blinder(FaultyBlinder(1)).build(OsRng, users),
+            Err(BuildError::BlinderMismatch)
+        ));
+    }
+}
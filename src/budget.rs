@@ -0,0 +1,415 @@
+//! Lookup budgets enforced with anonymous tokens.
+//!
+//! A rate limit keyed by account or IP address only bounds how fast a client enumerates phone
+//! numbers, and ties each lookup to the client's identity. Instead, an application can issue each
+//! client a budget of [`LookupToken`]s (e.g. a number per epoch, over its own authenticated API),
+//! and the server requires one token per phone number it blinds. Tokens are issued blindly, so the
+//! server can't link a spent token to the client it was issued to:
+//!
+//! 1. The client picks a random nonce `t` and a blinding scalar `r`, and sends `B = [r]H(t)`.
+//! 2. The [`TokenIssuer`] responds with `[k]B`, using its secret key `k`.
+//! 3. The client unblinds the response, giving the token `(t, [k]H(t))`.
+//! 4. To spend it, the client sends `(t, [k]H(t))`, which the issuer checks against `k` and its set
+//!    of spent nonces.
+//!
+//! A [`TokenWallet`] holds a client's unspent tokens and tells the application how many remain.
+//!
+//! How many tokens each account is issued per epoch is up to the application: a [`TokenMint`]
+//! asks its [`IssuancePolicy`] for each account's allowance (e.g. fewer for new or suspicious
+//! accounts, as with [`TieredPolicy`]), and refuses to issue more. The mint counts each account's
+//! issued tokens, and can call a persistence hook on every change and
+//! [restore](TokenMint::restore) the counts after a restart, so restarting a server doesn't reset
+//! everyone's budget.
+//!
+//! **N.B.:** Clients can't verify which key signed their tokens, so an issuer which used a
+//! different key for each client could link spent tokens to clients. Deployments which don't trust
+//! the issuer not to do so should publish and pin a single issuer key per epoch.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use p256::{
+    elliptic_curve::{sec1::ToEncodedPoint, Field},
+    EncodedPoint, ProjectivePoint, Scalar,
+};
+use rand::{CryptoRng, RngCore};
+
+use crate::{decode_point, encoding::POINT_LEN, hash_to_curve, Epoch, Error};
+
+/// The length of a token's nonce, in bytes.
+const NONCE_LEN: usize = 32;
+
+/// The length of an encoded [`LookupToken`], in bytes.
+pub const LOOKUP_TOKEN_LEN: usize = NONCE_LEN + POINT_LEN;
+
+/// A token which pays for the lookup of one phone number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LookupToken {
+    nonce: [u8; NONCE_LEN],
+    point: EncodedPoint,
+}
+
+impl LookupToken {
+    /// Encode the token as its nonce followed by its point.
+    pub fn encode(&self) -> [u8; LOOKUP_TOKEN_LEN] {
+        let mut b = [0u8; LOOKUP_TOKEN_LEN];
+        b[..NONCE_LEN].copy_from_slice(&self.nonce);
+        b[NONCE_LEN..].copy_from_slice(self.point.as_bytes());
+        b
+    }
+
+    /// Decode a token. Its validity is only checked when it's [redeemed](TokenIssuer::redeem).
+    pub fn decode(b: &[u8]) -> Result<LookupToken, Error> {
+        if b.len() != LOOKUP_TOKEN_LEN {
+            return Err(Error::InvalidMessage);
+        }
+        let point = EncodedPoint::from_bytes(&b[NONCE_LEN..]).map_err(|_| Error::InvalidPoint)?;
+        Ok(LookupToken { nonce: b[..NONCE_LEN].try_into().expect("should be 32 bytes"), point })
+    }
+}
+
+/// A server which issues and redeems the lookup tokens for an epoch.
+#[derive(Debug)]
+pub struct TokenIssuer {
+    k: Scalar,
+    epoch: Epoch,
+    spent: Mutex<HashSet<[u8; NONCE_LEN]>>,
+}
+
+impl TokenIssuer {
+    /// Create a new issuer for the given epoch with a random key. Each epoch should have its own
+    /// issuer, so tokens issued for one epoch can't be spent in another.
+    pub fn new(rng: impl CryptoRng + RngCore, epoch: Epoch) -> TokenIssuer {
+        TokenIssuer { k: Scalar::random(rng), epoch, spent: Mutex::new(HashSet::new()) }
+    }
+
+    /// The epoch the issuer's tokens are for.
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    /// Sign a batch of blinded token points from a [`TokenRequest`], returning the points to send
+    /// back in order.
+    ///
+    /// The issuer doesn't limit how many tokens it signs, so applications should issue tokens
+    /// through a [`TokenMint`], which does.
+    pub fn issue(&self, blinded: &[EncodedPoint]) -> Result<Vec<EncodedPoint>, Error> {
+        blinded
+            .iter()
+            .map(|b| Ok((ProjectivePoint::from(decode_point(b)?) * self.k).to_encoded_point(true)))
+            .collect()
+    }
+
+    /// Redeem a batch of tokens, failing with [`Error::Unauthorized`] if any is invalid or has
+    /// already been spent, in which case none of them are spent.
+    pub fn redeem(&self, tokens: &[LookupToken]) -> Result<(), Error> {
+        let mut spent = self.spent.lock().expect("should not be poisoned");
+        let mut batch = HashSet::with_capacity(tokens.len());
+        for token in tokens {
+            let point = decode_point(&token.point).map_err(|_| Error::Unauthorized)?;
+            if token_point(&token.nonce) * self.k != ProjectivePoint::from(point)
+                || spent.contains(&token.nonce)
+                || !batch.insert(token.nonce)
+            {
+                return Err(Error::Unauthorized);
+            }
+        }
+        spent.extend(batch);
+        Ok(())
+    }
+
+    /// Returns `true` if the given token has already been spent.
+    pub fn is_spent(&self, token: &LookupToken) -> bool {
+        self.spent.lock().expect("should not be poisoned").contains(&token.nonce)
+    }
+
+    /// The number of tokens spent so far.
+    pub fn spent(&self) -> usize {
+        self.spent.lock().expect("should not be poisoned").len()
+    }
+}
+
+/// An application's identifier for an account which is issued tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AccountId(pub u64);
+
+/// How much an application trusts an account, e.g. according to its fraud systems.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AbuseTier {
+    /// The account is trusted.
+    Trusted,
+    /// Nothing is known against the account.
+    #[default]
+    Standard,
+    /// The account has shown signs of abuse.
+    Suspicious,
+    /// The account may not look up anyone.
+    Blocked,
+}
+
+/// An account requesting tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    /// The account's ID.
+    pub id: AccountId,
+    /// How long ago the account was registered.
+    pub age: Duration,
+    /// How much the application trusts the account.
+    pub tier: AbuseTier,
+}
+
+/// A policy deciding how many tokens each account may be issued per epoch.
+pub trait IssuancePolicy: Send + Sync {
+    /// The number of tokens the given account may be issued in the given epoch.
+    fn allowance(&self, account: &Account, epoch: Epoch) -> u32;
+}
+
+/// An [`IssuancePolicy`] which issues fewer tokens to new and suspicious accounts, and none to
+/// blocked ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TieredPolicy {
+    /// The allowance of established, standard accounts.
+    pub standard: u32,
+    /// The allowance of trusted accounts.
+    pub trusted: u32,
+    /// The allowance of suspicious accounts, and of accounts younger than `probation`.
+    pub restricted: u32,
+    /// How old an account must be before it gets more than the restricted allowance.
+    pub probation: Duration,
+}
+
+impl IssuancePolicy for TieredPolicy {
+    fn allowance(&self, account: &Account, _epoch: Epoch) -> u32 {
+        match account.tier {
+            AbuseTier::Blocked => 0,
+            AbuseTier::Suspicious => self.restricted,
+            _ if account.age < self.probation => self.restricted,
+            AbuseTier::Trusted => self.trusted,
+            AbuseTier::Standard => self.standard,
+        }
+    }
+}
+
+/// A callback for persisting the number of tokens issued to an account in an epoch.
+type IssuanceHook = Box<dyn Fn(Epoch, AccountId, u32) + Send + Sync>;
+
+/// Issues tokens to accounts up to the allowance given by an [`IssuancePolicy`].
+pub struct TokenMint {
+    issuer: Arc<TokenIssuer>,
+    policy: Box<dyn IssuancePolicy>,
+    issued: Mutex<HashMap<AccountId, u32>>,
+    persist: Option<IssuanceHook>,
+}
+
+impl TokenMint {
+    /// Create a mint which issues tokens from the given issuer, for its epoch, according to the
+    /// given policy.
+    pub fn new(issuer: Arc<TokenIssuer>, policy: impl IssuancePolicy + 'static) -> TokenMint {
+        TokenMint { issuer, policy: Box::new(policy), issued: Mutex::default(), persist: None }
+    }
+
+    /// Call `f` with the epoch, the account, and its new total whenever tokens are issued.
+    pub fn with_persistence(
+        self,
+        f: impl Fn(Epoch, AccountId, u32) + Send + Sync + 'static,
+    ) -> TokenMint {
+        TokenMint { persist: Some(Box::new(f)), ..self }
+    }
+
+    /// Restore persisted counts of issued tokens, ignoring those from other epochs.
+    pub fn restore(&self, counts: impl IntoIterator<Item = (Epoch, AccountId, u32)>) {
+        let mut issued = self.issued.lock().expect("should not be poisoned");
+        let epoch = self.issuer.epoch();
+        issued.extend(counts.into_iter().filter(|&(e, ..)| e == epoch).map(|(_, id, n)| (id, n)));
+    }
+
+    /// The number of tokens the given account may still be issued this epoch.
+    pub fn remaining(&self, account: &Account) -> u32 {
+        let issued = self.issued.lock().expect("should not be poisoned");
+        let allowance = self.policy.allowance(account, self.issuer.epoch());
+        allowance.saturating_sub(issued.get(&account.id).copied().unwrap_or(0))
+    }
+
+    /// Sign a batch of blinded token points for the given account, failing with
+    /// [`Error::Unauthorized`] if it would exceed the account's allowance.
+    pub fn issue(
+        &self,
+        account: &Account,
+        blinded: &[EncodedPoint],
+    ) -> Result<Vec<EncodedPoint>, Error> {
+        let epoch = self.issuer.epoch();
+        let mut issued = self.issued.lock().expect("should not be poisoned");
+        let n = issued.get(&account.id).copied().unwrap_or(0);
+        let total = u32::try_from(blinded.len())
+            .ok()
+            .and_then(|len| n.checked_add(len))
+            .filter(|&total| total <= self.policy.allowance(account, epoch))
+            .ok_or(Error::Unauthorized)?;
+        let signed = self.issuer.issue(blinded)?;
+
+        // Only count the tokens once they've been signed.
+        issued.insert(account.id, total);
+        if let Some(f) = &self.persist {
+            f(epoch, account.id, total);
+        }
+        Ok(signed)
+    }
+}
+
+impl fmt::Debug for TokenMint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenMint").field("issuer", &self.issuer).finish_non_exhaustive()
+    }
+}
+
+/// The client's state for a batch of tokens being issued.
+#[derive(Debug)]
+pub struct TokenRequest {
+    pending: Vec<([u8; NONCE_LEN], Scalar)>,
+}
+
+impl TokenRequest {
+    /// Start a request for `n` tokens, returning it and the blinded points to send to the issuer.
+    pub fn new(mut rng: impl CryptoRng + RngCore, n: usize) -> (TokenRequest, Vec<EncodedPoint>) {
+        let (pending, blinded) = (0..n)
+            .map(|_| {
+                let mut nonce = [0u8; NONCE_LEN];
+                rng.fill_bytes(&mut nonce);
+                let r = Scalar::random(&mut rng);
+                ((nonce, r), (token_point(&nonce) * r).to_encoded_point(true))
+            })
+            .unzip();
+        (TokenRequest { pending }, blinded)
+    }
+
+    /// Given the issuer's signed points, unblind them and return the tokens.
+    pub fn finish(self, signed: &[EncodedPoint]) -> Result<Vec<LookupToken>, Error> {
+        if signed.len() != self.pending.len() {
+            return Err(Error::InvalidMessage);
+        }
+        self.pending
+            .into_iter()
+            .zip(signed)
+            .map(|((nonce, r), signed)| {
+                let r_inv = Option::<Scalar>::from(r.invert()).ok_or(Error::InvalidMessage)?;
+                let point = ProjectivePoint::from(decode_point(signed)?) * r_inv;
+                Ok(LookupToken { nonce, point: point.to_encoded_point(true) })
+            })
+            .collect()
+    }
+}
+
+/// A client's unspent lookup tokens.
+#[derive(Debug, Default)]
+pub struct TokenWallet {
+    tokens: VecDeque<LookupToken>,
+}
+
+impl TokenWallet {
+    /// Create an empty wallet.
+    pub fn new() -> TokenWallet {
+        TokenWallet::default()
+    }
+
+    /// Add newly issued tokens to the wallet.
+    pub fn add(&mut self, tokens: impl IntoIterator<Item = LookupToken>) {
+        self.tokens.extend(tokens);
+    }
+
+    /// The number of phone numbers the client can still look up.
+    pub fn remaining(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Take the tokens to look up `n` phone numbers, or `None` if fewer than `n` remain. Tokens
+    /// which weren't spent (e.g. because the request failed) should be returned with
+    /// [`TokenWallet::add`].
+    pub fn take(&mut self, n: usize) -> Option<Vec<LookupToken>> {
+        (n <= self.tokens.len()).then(|| self.tokens.drain(..n).collect())
+    }
+}
+
+/// Hash a token's nonce to a point.
+fn token_point(nonce: &[u8; NONCE_LEN]) -> ProjectivePoint {
+    hash_to_curve(&[b"zk-cds-lookup-token".as_slice(), nonce].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn lookup_tokens() {
+        let issuer = TokenIssuer::new(OsRng, 3);
+        let mut wallet = TokenWallet::new();
+
+        // The client is issued a budget of three tokens.
+        let (request, blinded) = TokenRequest::new(OsRng, 3);
+        let signed = issuer.issue(&blinded).expect("should be valid points");
+        wallet.add(request.finish(&signed).expect("should be a valid response"));
+        assert_eq!(wallet.remaining(), 3);
+
+        // Tokens can be spent once each.
+        let tokens = wallet.take(2).expect("should have enough tokens");
+        let tokens = tokens
+            .iter()
+            .map(|t| LookupToken::decode(&t.encode()).expect("should be a valid token"))
+            .collect::<Vec<_>>();
+        assert_eq!(issuer.redeem(&tokens), Ok(()));
+        assert_eq!(issuer.redeem(&tokens[..1]), Err(Error::Unauthorized));
+        assert_eq!(issuer.spent(), 2);
+        assert_eq!(wallet.remaining(), 1);
+        assert_eq!(wallet.take(2), None);
+
+        // Tokens from another issuer, or repeated in a batch, aren't accepted, and spend nothing.
+        let last = wallet.take(1).expect("should have a token");
+        assert_eq!(TokenIssuer::new(OsRng, 3).redeem(&last), Err(Error::Unauthorized));
+        assert_eq!(issuer.redeem(&[last[0], last[0]]), Err(Error::Unauthorized));
+        assert_eq!(issuer.redeem(&last), Ok(()));
+    }
+
+    #[test]
+    fn issuance_policy() {
+        let policy = TieredPolicy {
+            standard: 10,
+            trusted: 20,
+            restricted: 2,
+            probation: Duration::from_secs(30 * 24 * 60 * 60),
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let hook = log.clone();
+        let issuer = Arc::new(TokenIssuer::new(OsRng, 4));
+        let mint = TokenMint::new(issuer.clone(), policy).with_persistence(move |epoch, id, n| {
+            hook.lock().expect("should not be poisoned").push((epoch, id, n));
+        });
+        let old = Duration::from_secs(365 * 24 * 60 * 60);
+        let account = Account { id: AccountId(1), age: old, tier: AbuseTier::Standard };
+        let newbie = Account { id: AccountId(2), age: Duration::ZERO, tier: AbuseTier::Trusted };
+        let blocked = Account { id: AccountId(3), age: old, tier: AbuseTier::Blocked };
+
+        // New accounts get fewer tokens than established ones, and blocked accounts get none.
+        assert_eq!(mint.remaining(&account), 10);
+        assert_eq!(mint.remaining(&newbie), 2);
+        assert_eq!(mint.remaining(&blocked), 0);
+        let (_, blinded) = TokenRequest::new(OsRng, 3);
+        assert_eq!(mint.issue(&newbie, &blinded), Err(Error::Unauthorized));
+        assert!(mint.issue(&account, &blinded).is_ok());
+        assert_eq!(mint.remaining(&account), 7);
+
+        // The accounting survives a restart, but not a new epoch.
+        let restarted = TokenMint::new(issuer, policy);
+        let counts = log.lock().expect("should not be poisoned").clone();
+        assert_eq!(counts, vec![(4, AccountId(1), 3)]);
+        restarted.restore(counts.iter().copied());
+        assert_eq!(restarted.remaining(&account), 7);
+        let next = TokenMint::new(Arc::new(TokenIssuer::new(OsRng, 5)), policy);
+        next.restore(counts);
+        assert_eq!(next.remaining(&account), 10);
+    }
+}
@@ -0,0 +1,1117 @@
+//! A configurable builder for servers.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    env, fmt,
+    fmt::Write,
+    fs,
+    hash::{BuildHasher, Hash, RandomState},
+    mem,
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use p256::{
+    elliptic_curve::{sec1::ToEncodedPoint, Field},
+    EncodedPoint, Scalar,
+};
+use rand::{CryptoRng, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    arena::{Arena, Entry as ArenaEntry},
+    blind_row,
+    blinder::{BulkBlinder, CpuBlinder},
+    changes::ChangeLog,
+    checkpoint::Checkpoint,
+    dummy::dummy_entries,
+    encoding::BucketCache,
+    hash::hashed_identity,
+    prefix, sub_prefix,
+    suite::Ciphersuite,
+    table_size, unblinded_row, BuildError, Epoch, HashFunction, PreHash, Prefix, Server,
+    DEFAULT_BUCKET_CACHE_CAPACITY, OVERFLOW_PREFIX,
+};
+
+/// How to handle a phone number which appears more than once in an address book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Fail the build with [`BuildError::DuplicatePhoneNumber`].
+    #[default]
+    Reject,
+    /// Keep the last user ID listed for the phone number.
+    LastWriteWins,
+    /// Keep every distinct user ID listed for the phone number. Clients can find all of them with
+    /// [`Client::find_user_ids`](crate::Client::find_user_ids).
+    KeepAll,
+}
+
+/// How to handle buckets with fewer entries than the minimum bucket size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SmallBucketPolicy {
+    /// Fail the build with [`BuildError::BucketsTooSmall`].
+    #[default]
+    Reject,
+    /// Pad the bucket with dummy entries, which are indistinguishable from real ones.
+    Pad,
+}
+
+/// How to handle buckets with more real entries than the maximum bucket size, e.g. because an
+/// adversary registered many phone numbers with the same hash prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail the build with [`BuildError::BucketsTooLarge`].
+    #[default]
+    Reject,
+    /// Split the bucket into sub-buckets by [`SUB_PREFIX_LEN`](crate::SUB_PREFIX_LEN) more bytes
+    /// of the hash, failing the build if any sub-bucket is still too large. Clients find their
+    /// entries under their [sub-prefixes](crate::Client::sub_prefix).
+    Split,
+    /// Keep the first entries of the bucket, in address book order, and move the rest to the
+    /// [overflow bucket](Server::overflow_bucket), failing the build if the overflow bucket is then
+    /// too large. Every client fetches the overflow bucket, so it's capped like any other.
+    Spill,
+}
+
+/// Statistics about a server build.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildStats {
+    /// The number of phone number and user ID pairs in the address book.
+    pub rows: usize,
+    /// The number of distinct phone numbers (or hashed identifiers) in the address book.
+    pub phone_numbers: usize,
+    /// The number of rows which listed an already-seen phone number.
+    pub conflicts: usize,
+    /// The number of rows dropped due to conflicts.
+    pub dropped: usize,
+    /// The number of buckets built.
+    pub buckets: usize,
+    /// The hash prefixes of buckets with fewer real entries than the minimum bucket size, and their
+    /// number of real entries, in order of prefix.
+    pub small_buckets: Vec<(Prefix, usize)>,
+    /// The number of dummy entries added to small buckets.
+    pub dummies: usize,
+    /// The hash prefixes of buckets with more real entries than the maximum bucket size, and their
+    /// number of real entries, in order of prefix.
+    pub large_buckets: Vec<(Prefix, usize)>,
+}
+
+/// A machine-readable report of a server build, for operators to archive with each epoch and diff
+/// across builds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildReport {
+    /// The server's epoch.
+    pub epoch: Epoch,
+    /// The server's ciphersuite.
+    pub suite: Ciphersuite,
+    /// The server's [digest](Server::digest).
+    pub digest: [u8; 32],
+    /// The statistics of the build.
+    pub stats: BuildStats,
+    /// The number of buckets of each size, including padding, in order of size.
+    pub bucket_sizes: BTreeMap<usize, usize>,
+    /// How long the build took.
+    pub duration: Duration,
+}
+
+impl BuildReport {
+    /// Create a report for the given server, built with the given statistics.
+    pub fn new<S: BuildHasher + Clone>(
+        server: &Server<S>,
+        stats: BuildStats,
+        duration: Duration,
+    ) -> BuildReport {
+        let mut bucket_sizes = BTreeMap::new();
+        for (_, bucket) in server.buckets.sorted() {
+            *bucket_sizes.entry(bucket.len()).or_insert(0) += 1;
+        }
+        BuildReport {
+            epoch: server.epoch(),
+            suite: server.ciphersuite(),
+            digest: server.digest(),
+            stats,
+            bucket_sizes,
+            duration,
+        }
+    }
+
+    /// Encode the report as JSON, with one field per line in a fixed order, so reports can be
+    /// compared with a line-based diff.
+    ///
+    /// Small and large buckets are reported by number, not by prefix, so reports don't reveal which
+    /// prefixes are sparsely or densely populated.
+    pub fn to_json(&self) -> String {
+        let digest = self.digest.iter().fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        });
+        let sizes = self.bucket_sizes.iter().map(|(size, n)| format!("\"{size}\": {n}"));
+        let stats = &self.stats;
+        let fields = [
+            ("epoch", self.epoch.to_string()),
+            ("suite", format!("\"{}\"", self.suite)),
+            ("digest", format!("\"{digest}\"")),
+            ("rows", stats.rows.to_string()),
+            ("phone_numbers", stats.phone_numbers.to_string()),
+            ("conflicts", stats.conflicts.to_string()),
+            ("dropped", stats.dropped.to_string()),
+            ("buckets", stats.buckets.to_string()),
+            ("small_buckets", stats.small_buckets.len().to_string()),
+            ("dummies", stats.dummies.to_string()),
+            ("large_buckets", stats.large_buckets.len().to_string()),
+            ("bucket_sizes", format!("{{{}}}", sizes.collect::<Vec<_>>().join(", "))),
+            ("duration_ms", self.duration.as_millis().to_string()),
+        ];
+        let fields = fields.map(|(name, value)| format!("  \"{name}\": {value}"));
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+}
+
+/// The estimated peak memory use of a build, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MemoryEstimate {
+    /// With every blinded row held in memory.
+    in_memory: usize,
+    /// With blinded rows spilled to disk.
+    streaming: usize,
+}
+
+impl MemoryEstimate {
+    /// Return whether a build within the given limit must spill blinded rows to disk, or
+    /// [`BuildError::HeapLimitExceeded`] if it can't fit at all.
+    fn check(&self, limit: usize) -> Result<bool, BuildError> {
+        if self.streaming > limit {
+            return Err(BuildError::HeapLimitExceeded { required: self.streaming, limit });
+        }
+        Ok(self.in_memory > limit)
+    }
+}
+
+/// A temporary file for spilling a build's blinded rows, which is removed when dropped so a failed
+/// build doesn't leave the server secret behind.
+struct SpillFile(PathBuf);
+
+impl SpillFile {
+    /// Return a fresh path in the temporary directory.
+    fn new(rng: &mut (impl CryptoRng + RngCore)) -> SpillFile {
+        SpillFile(env::temp_dir().join(format!("zk-cds-{:016x}.spill", rng.next_u64())))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // A complete build has already removed it, and a failed one may not have created it.
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// A builder for [`Server`]s.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder<S = RandomState> {
+    conflict_policy: ConflictPolicy,
+    hash: HashFunction,
+    pre_hash: PreHash,
+    min_bucket_size: usize,
+    small_bucket_policy: SmallBucketPolicy,
+    max_bucket_size: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    bucket_cache_capacity: usize,
+    chunk_size: usize,
+    progress: Option<Progress>,
+    report: Option<Report>,
+    checkpoint: Option<PathBuf>,
+    memory_limit: Option<usize>,
+    blinder: Blinder,
+    hasher: S,
+}
+
+/// A callback which is passed the number of entries blinded so far and the total.
+#[derive(Clone)]
+struct Progress(Arc<dyn Fn(usize, usize) + Send + Sync>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// A callback which is passed the report of a completed build.
+#[derive(Clone)]
+struct Report(Arc<dyn Fn(&BuildReport) + Send + Sync>);
+
+impl fmt::Debug for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Report")
+    }
+}
+
+/// A shared bulk blinder.
+#[derive(Clone)]
+struct Blinder(Arc<dyn BulkBlinder>);
+
+impl fmt::Debug for Blinder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Blinder")
+    }
+}
+
+/// The default number of entries blinded between progress reports.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            conflict_policy: ConflictPolicy::default(),
+            hash: HashFunction::default(),
+            pre_hash: PreHash::default(),
+            min_bucket_size: 0,
+            small_bucket_policy: SmallBucketPolicy::default(),
+            max_bucket_size: None,
+            overflow_policy: OverflowPolicy::default(),
+            bucket_cache_capacity: DEFAULT_BUCKET_CACHE_CAPACITY,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            progress: None,
+            report: None,
+            checkpoint: None,
+            memory_limit: None,
+            blinder: Blinder(Arc::new(CpuBlinder)),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+impl<S: BuildHasher + Clone> ServerBuilder<S> {
+    /// Use the given hasher for the server's buckets, instead of [`RandomState`].
+    ///
+    /// Buckets are keyed by hashes and points which clients can't choose freely, so a faster,
+    /// non-DoS-resistant hasher is a reasonable choice for large directories.
+    pub fn with_hasher<T: BuildHasher + Clone>(self, hasher: T) -> ServerBuilder<T> {
+        ServerBuilder {
+            conflict_policy: self.conflict_policy,
+            hash: self.hash,
+            pre_hash: self.pre_hash,
+            min_bucket_size: self.min_bucket_size,
+            small_bucket_policy: self.small_bucket_policy,
+            max_bucket_size: self.max_bucket_size,
+            overflow_policy: self.overflow_policy,
+            bucket_cache_capacity: self.bucket_cache_capacity,
+            chunk_size: self.chunk_size,
+            progress: self.progress,
+            report: self.report,
+            checkpoint: self.checkpoint,
+            memory_limit: self.memory_limit,
+            blinder: self.blinder,
+            hasher,
+        }
+    }
+
+    /// Set the policy for phone numbers which appear more than once in the address book.
+    pub fn conflict_policy(mut self, conflict_policy: ConflictPolicy) -> ServerBuilder<S> {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    /// Use the given hash function to derive phone numbers' hash prefixes, instead of SHA-256.
+    pub fn hash_function(mut self, hash: HashFunction) -> ServerBuilder<S> {
+        self.hash = hash;
+        self
+    }
+
+    /// Pre-hash phone numbers with the given function.
+    pub fn pre_hash(mut self, pre_hash: PreHash) -> ServerBuilder<S> {
+        self.pre_hash = pre_hash;
+        self
+    }
+
+    /// Require every non-empty bucket to have at least `k` entries, handling smaller buckets
+    /// according to `policy`.
+    ///
+    /// This guarantees every client which finds a bucket an anonymity set of at least `k` entries.
+    pub fn min_bucket_size(mut self, k: usize, policy: SmallBucketPolicy) -> ServerBuilder<S> {
+        self.min_bucket_size = k;
+        self.small_bucket_policy = policy;
+        self
+    }
+
+    /// Allow no bucket more than `n` real entries, handling larger buckets according to `policy`.
+    ///
+    /// This bounds the size of every response, however the address book's phone numbers were
+    /// chosen.
+    pub fn max_bucket_size(mut self, n: usize, policy: OverflowPolicy) -> ServerBuilder<S> {
+        self.max_bucket_size = Some(n);
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Cache up to `capacity` encoded buckets in the built server, instead of
+    /// [`DEFAULT_BUCKET_CACHE_CAPACITY`], as [`Server::with_bucket_cache_capacity`] does.
+    pub fn bucket_cache_capacity(mut self, capacity: usize) -> ServerBuilder<S> {
+        self.bucket_cache_capacity = capacity;
+        self
+    }
+
+    /// Call `progress` with the number of entries blinded so far and the total number of entries
+    /// after each chunk of entries is blinded, and once the build is complete.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> ServerBuilder<S> {
+        self.progress = Some(Progress(Arc::new(progress)));
+        self
+    }
+
+    /// Call `report` with a [`BuildReport`] once the build is complete.
+    pub fn with_report(
+        mut self,
+        report: impl Fn(&BuildReport) + Send + Sync + 'static,
+    ) -> ServerBuilder<S> {
+        self.report = Some(Report(Arc::new(report)));
+        self
+    }
+
+    /// Blind entries in chunks of `chunk_size`, instead of the default of [`DEFAULT_CHUNK_SIZE`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn chunk_size(mut self, chunk_size: usize) -> ServerBuilder<S> {
+        assert!(chunk_size > 0, "chunk_size should be positive");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Checkpoint the build to the file at `path` after each chunk of entries is blinded, resuming
+    /// from the checkpoint already there, if any. The file is removed once the build is complete.
+    ///
+    /// A build can only be resumed with the same configuration and address book, otherwise it
+    /// fails with [`BuildError::CheckpointMismatch`].
+    ///
+    /// **N.B.:** The checkpoint contains the server secret and must be protected accordingly.
+    pub fn resume(mut self, path: impl Into<PathBuf>) -> ServerBuilder<S> {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Estimate the memory a build needs before blinding anything, and keep it under `bytes`.
+    ///
+    /// Builds which don't fit in memory all at once, but whose finished server does, spill their
+    /// blinded rows to disk as they go: to the [checkpoint](ServerBuilder::resume), if any, or to a
+    /// temporary file which is removed once the build completes or fails. Builds which can't fit
+    /// even then fail with [`BuildError::HeapLimitExceeded`] before blinding anything.
+    ///
+    /// **N.B.:** Like checkpoints, spilled rows are stored alongside the server secret.
+    pub fn memory_limit(mut self, bytes: usize) -> ServerBuilder<S> {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Delegate the scalar multiplications which blind entries to the given [`BulkBlinder`],
+    /// instead of [`CpuBlinder`].
+    ///
+    /// One randomly chosen entry of every chunk is also blinded on the CPU, and the build fails
+    /// with [`BuildError::BlinderMismatch`] if the results differ.
+    pub fn bulk_blinder(mut self, blinder: impl BulkBlinder + 'static) -> ServerBuilder<S> {
+        self.blinder = Blinder(Arc::new(blinder));
+        self
+    }
+
+    /// Without blinding anything, return the hash prefixes of buckets which would have fewer
+    /// entries than the minimum bucket size, and their number of entries, in order of prefix.
+    pub fn small_buckets(
+        &self,
+        users: impl IntoIterator<Item = (u64, Uuid)>,
+    ) -> Result<Vec<(Prefix, usize)>, BuildError> {
+        let book =
+            self.group(users, BuildError::DuplicatePhoneNumber, &mut BuildStats::default())?;
+        let mut sizes = HashMap::<Prefix, usize>::new();
+        for (p, us) in book {
+            *sizes.entry(prefix(&self.hash.hash(&self.pre_hash.apply(p)))).or_default() += us.len();
+        }
+        Ok(self.filter_small(sizes))
+    }
+
+    /// Build a server with a random secret and the given phone numbers and user IDs, returning it
+    /// along with statistics about the build.
+    pub fn build(
+        self,
+        rng: impl CryptoRng + RngCore,
+        users: impl IntoIterator<Item = (u64, Uuid)>,
+    ) -> Result<(Server<S>, BuildStats), BuildError> {
+        let start = Instant::now();
+        let mut stats = BuildStats::default();
+        let book = self.group(users, BuildError::DuplicatePhoneNumber, &mut stats)?;
+        let book = book.into_iter().map(|(p, us)| (self.pre_hash.apply(p), us));
+        self.blind(rng, book, stats, start)
+    }
+
+    /// Like [`ServerBuilder::build`], but for identifiers which the caller has already hashed, for
+    /// clients using [`Client::request_hashed`](crate::Client::request_hashed).
+    ///
+    /// Hashed identifiers aren't [pre-hashed](PreHash).
+    pub fn build_hashed(
+        self,
+        rng: impl CryptoRng + RngCore,
+        users: impl IntoIterator<Item = ([u8; 32], Uuid)>,
+    ) -> Result<(Server<S>, BuildStats), BuildError> {
+        let start = Instant::now();
+        let mut stats = BuildStats::default();
+        let book = self.group(users, BuildError::DuplicateHashedId, &mut stats)?;
+        let book = book.into_iter().map(|(id_hash, us)| (hashed_identity(&id_hash), us));
+        self.blind(rng, book, stats, start)
+    }
+
+    /// Blind the given identities and their user IDs, and build a server from them.
+    fn blind(
+        &self,
+        mut rng: impl CryptoRng + RngCore,
+        book: impl IntoIterator<Item = (Vec<u8>, Vec<Uuid>)>,
+        mut stats: BuildStats,
+        start: Instant,
+    ) -> Result<(Server<S>, BuildStats), BuildError> {
+        // Generate a random secret.
+        let mut d_s = Scalar::random(&mut rng);
+
+        // Flatten the address book into entries, in a deterministic order so a build can be
+        // resumed from a checkpoint.
+        let mut entries = book
+            .into_iter()
+            .flat_map(|(id, us)| (0..).zip(us).map(move |(i, u)| (id.clone(), i, u)))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+
+        // Count the entries in each bucket and handle any which are too large or too small, before
+        // blinding.
+        let mut sizes = HashMap::with_hasher(self.hasher.clone());
+        for (id, _, _) in &entries {
+            *sizes.entry(prefix(&self.hash.hash(id))).or_insert(0) += 1;
+        }
+        let (routes, split) = self.route_overflow(&entries, &mut sizes, &mut stats)?;
+        stats.buckets = sizes.len();
+        stats.small_buckets = self.filter_small(sizes.iter().map(|(&prefix, &n)| (prefix, n)));
+        if !stats.small_buckets.is_empty() && self.small_bucket_policy == SmallBucketPolicy::Reject
+        {
+            return Err(BuildError::BucketsTooSmall(stats.small_buckets.len()));
+        }
+        stats.dummies = stats.small_buckets.iter().map(|&(_, n)| self.min_bucket_size - n).sum();
+
+        // Check the build fits in the memory limit, if any, spilling blinded rows to disk if only
+        // the finished server does.
+        let spill = match self.memory_limit {
+            Some(limit) => self.estimate(&entries, stats.buckets, stats.dummies).check(limit)?,
+            None => false,
+        };
+        let spill_file = (spill && self.checkpoint.is_none()).then(|| SpillFile::new(&mut rng));
+        let path = self.checkpoint.as_deref().or(spill_file.as_ref().map(|f| f.0.as_path()));
+
+        // Keep the real entries of small buckets, so their dummy entries can't collide with them.
+        let mut small = stats
+            .small_buckets
+            .iter()
+            .map(|&(prefix, _)| (prefix, Vec::new()))
+            .collect::<HashMap<_, Vec<EncodedPoint>>>();
+        let mut keep_small = |(prefix, s_p, _): &(Prefix, EncodedPoint, EncodedPoint)| {
+            if let Some(s_ps) = small.get_mut(prefix) {
+                s_ps.push(*s_p);
+            }
+        };
+
+        // Open the checkpoint, if any, and restore the secret and any blinded rows from it, reading
+        // them back one at a time so spilled rows aren't all held in memory.
+        let mut rows = Vec::with_capacity(if spill { 0 } else { entries.len() + stats.dummies });
+        let mut done = 0;
+        let mut checkpoint = None;
+        if let Some(path) = path {
+            let mut c = Checkpoint::open(path, &self.digest(&entries), d_s)?;
+            if c.len > entries.len() {
+                return Err(BuildError::CheckpointMismatch);
+            }
+            d_s = c.d_s;
+            done = c.len;
+            for row in c.read_rows()? {
+                let row = row?;
+                keep_small(&row);
+                if !spill {
+                    rows.push(row);
+                }
+            }
+            checkpoint = Some(c);
+        }
+
+        // Blind the remaining entries in chunks.
+        for chunk in entries[done..].chunks(self.chunk_size) {
+            let mut blinded = self.blind_chunk(&mut rng, &d_s, chunk)?;
+
+            // Move the entries of large buckets to their sub-buckets or the overflow bucket.
+            for (i, row) in (done..).zip(blinded.iter_mut()) {
+                if let Some(&prefix) = routes.get(&i) {
+                    row.0 = prefix;
+                }
+            }
+            done += blinded.len();
+            blinded.iter().for_each(&mut keep_small);
+
+            // Checkpoint the chunk, keep it in memory unless it's spilled, and report progress.
+            if let Some(checkpoint) = &mut checkpoint {
+                checkpoint.append(&blinded)?;
+            }
+            if !spill {
+                rows.extend(blinded);
+            }
+            if let Some(Progress(progress)) = &self.progress {
+                progress(done, entries.len());
+            }
+        }
+
+        // Pad any small buckets with dummy entries.
+        let mut dummies = Vec::with_capacity(stats.dummies);
+        for &(prefix, n) in &stats.small_buckets {
+            let real = &small[&prefix];
+            let entries =
+                dummy_entries(&mut rng, self.min_bucket_size - n, |s_p| real.contains(s_p));
+            dummies.extend(entries.into_iter().map(|(s_p, hs_u)| (prefix, s_p, hs_u)));
+        }
+        drop(entries);
+
+        // Group the rows into buckets by hash prefix, reading them back from disk if they were
+        // spilled.
+        let buckets = match &mut checkpoint {
+            Some(checkpoint) if spill => {
+                for &(prefix, n) in &stats.small_buckets {
+                    sizes.insert(prefix, self.min_bucket_size.max(n));
+                }
+                let rows = checkpoint.read_rows()?.chain(dummies.into_iter().map(Ok));
+                Arena::from_sized_rows(sizes, rows, self.hasher.clone())?
+            }
+            _ => {
+                rows.append(&mut dummies);
+                Arena::from_rows(rows, self.hasher.clone())
+            }
+        };
+        let buckets = buckets.with_split(split);
+
+        // The build is complete, so the checkpoint is no longer needed.
+        if let (Some(path), Some(checkpoint)) = (path, checkpoint) {
+            drop(checkpoint);
+            fs::remove_file(path).map_err(|err| BuildError::Checkpoint(err.kind()))?;
+        }
+
+        let server = Server {
+            d_s,
+            epoch: 0,
+            hash: self.hash,
+            pre_hash: self.pre_hash,
+            buckets,
+            min_bucket_size: self.min_bucket_size,
+            max_bucket_size: self.max_bucket_size,
+            cache: BucketCache::new(self.bucket_cache_capacity),
+            changes: ChangeLog::new(0),
+        };
+        if let Some(Report(report)) = &self.report {
+            report(&BuildReport::new(&server, stats.clone(), start.elapsed()));
+        }
+        Ok((server, stats))
+    }
+
+    /// Blind a chunk of entries with the bulk blinder, cross-checking a random entry on the CPU.
+    fn blind_chunk(
+        &self,
+        rng: &mut (impl CryptoRng + RngCore),
+        d_s: &Scalar,
+        chunk: &[(Vec<u8>, u64, Uuid)],
+    ) -> Result<Vec<(Prefix, EncodedPoint, EncodedPoint)>, BuildError> {
+        // Hash and encode every entry, pairing its points with the scalars which blind them.
+        let mut prefixes = Vec::with_capacity(chunk.len());
+        let mut pairs = Vec::with_capacity(2 * chunk.len());
+        for (id, i, u) in chunk {
+            let (prefix, h_p, h, u) = unblinded_row(self.hash, id, u, *i)?;
+            prefixes.push(prefix);
+            pairs.push((*d_s, h_p));
+            pairs.push((d_s * &h, u));
+        }
+
+        // Multiply them in bulk.
+        let Blinder(blinder) = &self.blinder;
+        let products = blinder.multiply(&pairs);
+        if products.len() != pairs.len() {
+            return Err(BuildError::BlinderMismatch);
+        }
+        let rows = prefixes
+            .into_iter()
+            .zip(products.chunks_exact(2))
+            .map(|(prefix, p)| {
+                (
+                    prefix,
+                    p[0].to_affine().to_encoded_point(true),
+                    p[1].to_affine().to_encoded_point(true),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Check a random entry against the CPU.
+        let j = rng.gen_range(0..chunk.len());
+        let (id, i, u) = &chunk[j];
+        if blind_row(d_s, self.hash, id, u, *i)? != rows[j] {
+            return Err(BuildError::BlinderMismatch);
+        }
+        Ok(rows)
+    }
+
+    /// Return a digest of the build's configuration and entries, to check a checkpoint is for the
+    /// same build.
+    fn digest(&self, entries: &[(Vec<u8>, u64, Uuid)]) -> [u8; 32] {
+        let mut h = Sha256::new().chain_update(format!("{:?}", self.hash));
+        if let Some(n) = self.max_bucket_size {
+            h.update(format!("{n} {:?}", self.overflow_policy));
+        }
+        for (id, i, u) in entries {
+            h.update((id.len() as u64).to_be_bytes());
+            h.update(id);
+            h.update(i.to_be_bytes());
+            h.update(u.as_bytes());
+        }
+        h.finalize().into()
+    }
+
+    /// Estimate the memory needed to build a server from the given entries, with the given numbers
+    /// of buckets and dummy entries.
+    fn estimate(
+        &self,
+        entries: &[(Vec<u8>, u64, Uuid)],
+        buckets: usize,
+        dummies: usize,
+    ) -> MemoryEstimate {
+        // The entries to be blinded, the finished server, and the blinded rows in between.
+        let input = entries
+            .iter()
+            .map(|(id, _, _)| mem::size_of::<(Vec<u8>, u64, Uuid)>() + id.len())
+            .sum::<usize>();
+        let n = entries.len() + dummies;
+        let server = table_size::<Prefix, Range<usize>>(buckets) + n * mem::size_of::<ArenaEntry>();
+        let row = mem::size_of::<(Prefix, EncodedPoint, EncodedPoint)>();
+
+        // In memory, every row is held until the server is built. Streamed through disk, only one
+        // chunk of rows and either the entries or the server are.
+        MemoryEstimate {
+            in_memory: input + n * row + server,
+            streaming: input.max(server) + self.chunk_size.min(n) * row + dummies * row,
+        }
+    }
+
+    /// Group the user IDs by phone number (or hashed identifier), resolving conflicts.
+    fn group<K: Copy + Eq + Hash>(
+        &self,
+        users: impl IntoIterator<Item = (K, Uuid)>,
+        duplicate: impl Fn(K) -> BuildError,
+        stats: &mut BuildStats,
+    ) -> Result<HashMap<K, Vec<Uuid>>, BuildError> {
+        let mut book = HashMap::<K, Vec<Uuid>>::new();
+        for (p, u) in users {
+            stats.rows += 1;
+            match book.entry(p) {
+                Entry::Vacant(e) => {
+                    e.insert(vec![u]);
+                }
+                Entry::Occupied(mut e) => {
+                    stats.conflicts += 1;
+                    match self.conflict_policy {
+                        ConflictPolicy::Reject => return Err(duplicate(p)),
+                        ConflictPolicy::LastWriteWins => {
+                            stats.dropped += 1;
+                            *e.get_mut() = vec![u];
+                        }
+                        ConflictPolicy::KeepAll if e.get().contains(&u) => stats.dropped += 1,
+                        ConflictPolicy::KeepAll => e.get_mut().push(u),
+                    }
+                }
+            }
+        }
+
+        if book.is_empty() {
+            return Err(BuildError::Empty);
+        }
+        stats.phone_numbers = book.len();
+        Ok(book)
+    }
+
+    /// Find the buckets with more entries than the maximum, if any, and apply the overflow policy,
+    /// updating the bucket sizes. Returns the new prefixes of the moved entries, by index, and the
+    /// prefixes of the split buckets.
+    fn route_overflow(
+        &self,
+        entries: &[(Vec<u8>, u64, Uuid)],
+        sizes: &mut HashMap<Prefix, usize, S>,
+        stats: &mut BuildStats,
+    ) -> Result<(HashMap<usize, Prefix>, Vec<Prefix>), BuildError> {
+        let Some(max) = self.max_bucket_size else {
+            return Ok((HashMap::new(), Vec::new()));
+        };
+        stats.large_buckets =
+            sizes.iter().filter(|&(_, &n)| n > max).map(|(&p, &n)| (p, n)).collect();
+        stats.large_buckets.sort_unstable();
+        if stats.large_buckets.is_empty() {
+            return Ok((HashMap::new(), Vec::new()));
+        }
+        if self.overflow_policy == OverflowPolicy::Reject {
+            return Err(BuildError::BucketsTooLarge(stats.large_buckets.len()));
+        }
+
+        // Route every entry of a large bucket to its sub-bucket, or every entry past the maximum to
+        // the overflow bucket, resizing the buckets it moves from and to.
+        let mut routes = HashMap::new();
+        let mut kept = HashMap::<Prefix, usize>::new();
+        for (i, (id, _, _)) in entries.iter().enumerate() {
+            let h = self.hash.hash(id);
+            let prefix = prefix(&h);
+            if stats.large_buckets.binary_search_by_key(&prefix, |&(p, _)| p).is_err() {
+                continue;
+            }
+            let route = match self.overflow_policy {
+                OverflowPolicy::Split | OverflowPolicy::Reject => sub_prefix(&h),
+                OverflowPolicy::Spill => {
+                    let kept = kept.entry(prefix).or_insert(0);
+                    if *kept < max {
+                        *kept += 1;
+                        continue;
+                    }
+                    OVERFLOW_PREFIX
+                }
+            };
+            routes.insert(i, route);
+            *sizes.get_mut(&prefix).expect("should exist") -= 1;
+            *sizes.entry(route).or_insert(0) += 1;
+        }
+        sizes.retain(|_, n| *n > 0);
+
+        // Check no sub-bucket or overflow bucket is still too large.
+        let split = match self.overflow_policy {
+            OverflowPolicy::Split => {
+                let mut large =
+                    routes.values().filter(|prefix| sizes[*prefix] > max).collect::<Vec<_>>();
+                large.sort_unstable();
+                large.dedup();
+                if !large.is_empty() {
+                    return Err(BuildError::BucketsTooLarge(large.len()));
+                }
+                stats.large_buckets.iter().map(|&(prefix, _)| prefix).collect()
+            }
+            OverflowPolicy::Spill if sizes.get(&OVERFLOW_PREFIX).is_some_and(|&n| n > max) => {
+                return Err(BuildError::BucketsTooLarge(1));
+            }
+            _ => Vec::new(),
+        };
+        Ok((routes, split))
+    }
+
+    /// Return the buckets with fewer entries than the minimum, in order of prefix.
+    fn filter_small(
+        &self,
+        sizes: impl IntoIterator<Item = (Prefix, usize)>,
+    ) -> Vec<(Prefix, usize)> {
+        let mut small =
+            sizes.into_iter().filter(|&(_, n)| n < self.min_bucket_size).collect::<Vec<_>>();
+        small.sort_unstable();
+        small
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{
+        rngs::{OsRng, StdRng},
+        SeedableRng,
+    };
+
+    use super::*;
+    use crate::{Client, SeededState};
+
+    #[test]
+    fn conflict_policies() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let users = [(22, a), (23, a), (22, b), (22, b)];
+
+        assert!(matches!(
+            ServerBuilder::new().build(OsRng, users),
+            Err(BuildError::DuplicatePhoneNumber(22))
+        ));
+
+        let (server, stats) = ServerBuilder::new()
+            .conflict_policy(ConflictPolicy::LastWriteWins)
+            .build(OsRng, users)
+            .expect("should build");
+        assert_eq!(stats.conflicts, 2);
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(server.verify([(&22, &b), (&23, &a)].into_iter()), vec![]);
+
+        let (server, stats) = ServerBuilder::new()
+            .conflict_policy(ConflictPolicy::KeepAll)
+            .build(OsRng, users)
+            .expect("should build");
+        assert_eq!(stats.conflicts, 2);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(server.verify([(&22, &a), (&22, &b), (&23, &a)].into_iter()), vec![]);
+
+        // Clients can find all user IDs for a phone number.
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let user_ids = client
+            .find_user_ids(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+                &server.public_key(),
+            )
+            .expect("should be a valid response")
+            .iter()
+            .map(|s_u| server.unblind_user_id(s_u).expect("should be a valid user ID"))
+            .collect::<Vec<_>>();
+        assert_eq!(user_ids, vec![a, b]);
+    }
+
+    #[test]
+    fn progress() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let r = reports.clone();
+        ServerBuilder::new()
+            .chunk_size(30)
+            .with_progress(move |done, total| {
+                r.lock().expect("should not be poisoned").push((done, total))
+            })
+            .build(OsRng, users)
+            .expect("should build");
+        assert_eq!(
+            *reports.lock().expect("should not be poisoned"),
+            vec![(30, 100), (60, 100), (90, 100), (100, 100)]
+        );
+    }
+
+    #[test]
+    fn resume() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("zk-cds-checkpoint-{}", Uuid::new_v4()));
+
+        // Interrupt a build after its second chunk.
+        let interrupted = std::panic::catch_unwind(|| {
+            ServerBuilder::new()
+                .chunk_size(30)
+                .resume(&path)
+                .with_progress(|done, _| assert!(done < 60, "interrupted"))
+                .build(OsRng, users.clone())
+        });
+        assert!(interrupted.is_err());
+        assert!(path.exists());
+
+        // A build of a different address book can't resume from it.
+        assert!(matches!(
+            ServerBuilder::new().resume(&path).build(OsRng, users[1..].to_vec()),
+            Err(BuildError::CheckpointMismatch)
+        ));
+
+        // The same one resumes from the third chunk.
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let r = reports.clone();
+        let (server, _) = ServerBuilder::new()
+            .chunk_size(30)
+            .resume(&path)
+            .with_progress(move |done, _| r.lock().expect("should not be poisoned").push(done))
+            .build(OsRng, users.clone())
+            .expect("should build");
+        assert_eq!(*reports.lock().expect("should not be poisoned"), vec![90, 100]);
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn memory_limit() {
+        let users = (0..1000).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let builder = ServerBuilder::new().chunk_size(100);
+        let entries =
+            users.iter().map(|&(p, u)| (builder.pre_hash.apply(p), 0, u)).collect::<Vec<_>>();
+        let estimate = builder.estimate(&entries, users.len(), 0);
+        assert!(estimate.streaming < estimate.in_memory);
+
+        // A build which only fits by streaming spills to disk, and still builds correctly.
+        let (server, _) = builder
+            .clone()
+            .memory_limit(estimate.streaming)
+            .build(OsRng, users.clone())
+            .expect("should build");
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+
+        // A blinder which fails, after checking the spill file was created.
+        struct Failing(PathBuf);
+        impl BulkBlinder for Failing {
+            fn multiply(
+                &self,
+                _: &[(Scalar, p256::ProjectivePoint)],
+            ) -> Vec<p256::ProjectivePoint> {
+                assert!(self.0.exists(), "should have created the spill file");
+                Vec::new()
+            }
+        }
+
+        // One which fails removes its spill file, whose path is drawn right after the secret.
+        let mut rng = StdRng::seed_from_u64(1);
+        Scalar::random(&mut rng);
+        let spill_file = SpillFile::new(&mut rng).0.clone();
+        assert!(matches!(
+            builder
+                .clone()
+                .memory_limit(estimate.streaming)
+                .bulk_blinder(Failing(spill_file.clone()))
+                .build(StdRng::seed_from_u64(1), users.clone()),
+            Err(BuildError::BlinderMismatch)
+        ));
+        assert!(!spill_file.exists());
+
+        // One which can't fit at all fails before blinding anything.
+        assert!(matches!(
+            builder.memory_limit(1024).build(OsRng, users),
+            Err(BuildError::HeapLimitExceeded { limit: 1024, .. })
+        ));
+    }
+
+    #[test]
+    fn hashed_identifiers() {
+        let u = Uuid::new_v4();
+        let id_hash = [22; 32];
+        let (server, _) =
+            ServerBuilder::new().build_hashed(OsRng, [(id_hash, u)]).expect("should build");
+        assert!(matches!(
+            ServerBuilder::new().build_hashed(OsRng, [(id_hash, u), (id_hash, u)]),
+            Err(BuildError::DuplicateHashedId(_))
+        ));
+
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_hashed(&id_hash);
+        let s_u = client
+            .find_user_id_hashed(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                &id_hash,
+            )
+            .expect("should be a valid response")
+            .expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+    }
+
+    #[test]
+    fn min_bucket_size() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+
+        // With 100 users, every bucket is tiny.
+        let builder = ServerBuilder::new().min_bucket_size(2, SmallBucketPolicy::Reject);
+        let small = builder.small_buckets(users.clone()).expect("should be valid");
+        assert_eq!(small.len(), 100);
+        assert!(matches!(
+            builder.build(OsRng, users.clone()),
+            Err(BuildError::BucketsTooSmall(100))
+        ));
+
+        // Padding them hides the real users among dummies.
+        let (server, stats) = ServerBuilder::new()
+            .min_bucket_size(2, SmallBucketPolicy::Pad)
+            .build(OsRng, users.clone())
+            .expect("should build");
+        assert_eq!(stats.small_buckets, small);
+        assert_eq!(stats.dummies, 100);
+        assert_eq!(server.find_bucket(small[0].0).len(), 2);
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+    }
+
+    #[test]
+    fn max_bucket_size() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let users = [(22, a), (22, b), (22, c), (23, a)];
+        let builder = || ServerBuilder::new().conflict_policy(ConflictPolicy::KeepAll);
+
+        // A bucket with three entries is too large, and can't be split since they share a hash.
+        for policy in [OverflowPolicy::Reject, OverflowPolicy::Split] {
+            assert!(matches!(
+                builder().max_bucket_size(2, policy).build(OsRng, users),
+                Err(BuildError::BucketsTooLarge(1))
+            ));
+        }
+
+        // Spilling moves its last entry to the overflow bucket.
+        let (server, stats) = builder()
+            .max_bucket_size(2, OverflowPolicy::Spill)
+            .build(OsRng, users)
+            .expect("should build");
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        assert_eq!(stats.large_buckets, vec![(prefix, 3)]);
+        assert!(!server.is_split(prefix));
+        let mut bucket = server.find_bucket(prefix);
+        assert_eq!(bucket.len(), 2);
+        bucket.extend(server.overflow_bucket());
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let s_us = client
+            .find_user_ids(&sc_p, &bucket, 22, &server.public_key())
+            .expect("should be a valid response");
+        assert_eq!(s_us.len(), 3);
+
+        // Removing the phone number removes its spilled entry too.
+        let server = server.update(OsRng, [], [22]).expect("should update");
+        assert!(server.overflow_bucket().is_empty());
+
+        // The overflow bucket is capped, too.
+        let users = [(22, a), (22, b), (22, c), (22, Uuid::new_v4()), (22, Uuid::new_v4())];
+        assert!(matches!(
+            builder().max_bucket_size(2, OverflowPolicy::Spill).build(OsRng, users),
+            Err(BuildError::BucketsTooLarge(1))
+        ));
+    }
+
+    #[test]
+    fn build_report() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        let (server, stats) = ServerBuilder::new()
+            .conflict_policy(ConflictPolicy::LastWriteWins)
+            .min_bucket_size(2, SmallBucketPolicy::Pad)
+            .with_report(move |report| {
+                sink.lock().expect("should not be poisoned").push(report.clone())
+            })
+            .build(OsRng, users.iter().copied().chain([(7, Uuid::new_v4())]))
+            .expect("should build");
+
+        // The report describes the server and the build.
+        let reports = reports.lock().expect("should not be poisoned");
+        let [report] = reports.as_slice() else { panic!("should report once") };
+        assert_eq!(report.digest, server.digest());
+        assert_eq!(report.stats, stats);
+        assert_eq!((report.stats.rows, report.stats.dropped), (101, 1));
+        assert_eq!(report.bucket_sizes, BTreeMap::from([(2, 100)]));
+        let json = report.to_json();
+        assert!(json.contains("\n  \"dropped\": 1,\n"));
+        assert!(json.contains("\n  \"bucket_sizes\": {\"2\": 100},\n"));
+
+        // Servers built without a builder can report too.
+        let (server, report) = Server::new_with_report(OsRng, &users.into_iter().collect());
+        assert_eq!((report.stats.rows, report.epoch), (100, server.epoch()));
+        assert_eq!(report.bucket_sizes.values().sum::<usize>(), report.stats.buckets);
+    }
+
+    #[test]
+    fn custom_hasher() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let (server, stats) = ServerBuilder::new()
+            .with_hasher(SeededState::new(22))
+            .build(OsRng, users.clone())
+            .expect("should build");
+        assert_eq!(stats.buckets, 100);
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Asynchronous bulk blinding for clients with very large address books.
+//!
+//! Blinding a phone number is a round trip, and a client onboarding millions of contacts (e.g. an
+//! enterprise CRM) can't hold a connection open while the server performs millions of scalar
+//! multiplications. Instead, it submits its client-blinded points out-of-band as a
+//! [`BulkRequest`], and a [`BulkBlindingService`] blinds them on a background thread, returning a
+//! [`JobId`] the client polls (or [waits](BulkBlindingService::wait) on) until the job is
+//! complete. The client then fetches the [`BulkResults`], which are all blinded by the secret of
+//! the epoch the job was submitted in, even if the server was rotated while the job ran.
+//!
+//! Jobs are tracked by a [`JobQueue`], which a service can share with the [`admin`](crate::admin)
+//! API so operators can watch and cancel them.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use p256::EncodedPoint;
+
+use crate::{
+    decode_point,
+    encoding::POINT_LEN,
+    jobs::{InMemoryJobQueue, JobId, JobKind, JobQueue, JobState, JobStatus},
+    Epoch, Error, Server,
+};
+
+/// The default number of points blinded between progress updates.
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+/// A batch of client-blinded phone number points to blind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkRequest {
+    /// The client-blinded phone number points.
+    pub c_ps: Vec<EncodedPoint>,
+}
+
+impl BulkRequest {
+    /// Encode the request as the concatenation of its points.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_points(&self.c_ps)
+    }
+
+    /// Decode a request, checking that every point is valid.
+    pub fn decode(b: &[u8]) -> Result<BulkRequest, Error> {
+        Ok(BulkRequest { c_ps: decode_points(b)? })
+    }
+}
+
+/// The results of a bulk blinding job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkResults {
+    /// The epoch of the secret which blinded the points.
+    pub epoch: Epoch,
+    /// The double-blinded phone number points, in request order.
+    pub sc_ps: Vec<EncodedPoint>,
+}
+
+impl BulkResults {
+    /// Encode the results as the epoch followed by the concatenation of the points.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = self.epoch.to_be_bytes().to_vec();
+        b.extend_from_slice(&encode_points(&self.sc_ps));
+        b
+    }
+
+    /// Decode results, checking that every point is valid.
+    pub fn decode(b: &[u8]) -> Result<BulkResults, Error> {
+        if b.len() < 8 {
+            return Err(Error::InvalidMessage);
+        }
+        let (epoch, points) = b.split_at(8);
+        Ok(BulkResults {
+            epoch: Epoch::from_be_bytes(epoch.try_into().expect("should be 8 bytes")),
+            sc_ps: decode_points(points)?,
+        })
+    }
+}
+
+/// A service which blinds batches of points on background threads.
+pub struct BulkBlindingService<S = RandomState> {
+    server: Mutex<Arc<Server<S>>>,
+    jobs: Arc<dyn JobQueue>,
+    results: Arc<Mutex<HashMap<JobId, BulkResults>>>,
+    chunk_size: usize,
+}
+
+impl<S: BuildHasher + Clone + Send + Sync + 'static> BulkBlindingService<S> {
+    /// Create a service which blinds points with the given server.
+    pub fn new(server: Arc<Server<S>>) -> BulkBlindingService<S> {
+        BulkBlindingService {
+            server: Mutex::new(server),
+            jobs: Arc::new(InMemoryJobQueue::new()),
+            results: Arc::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Update jobs' progress every `chunk_size` points, instead of every [`DEFAULT_CHUNK_SIZE`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn with_chunk_size(self, chunk_size: usize) -> BulkBlindingService<S> {
+        assert!(chunk_size > 0, "chunk size should be positive");
+        BulkBlindingService { chunk_size, ..self }
+    }
+
+    /// Track jobs with the given queue, e.g. one shared with the [`admin`](crate::admin) API,
+    /// instead of a private [`InMemoryJobQueue`].
+    pub fn with_jobs(self, jobs: Arc<dyn JobQueue>) -> BulkBlindingService<S> {
+        BulkBlindingService { jobs, ..self }
+    }
+
+    /// Blind jobs submitted from now on with the given server, e.g. after a rotation. Running jobs
+    /// finish with the server they were submitted to.
+    pub fn load(&self, server: Arc<Server<S>>) {
+        *self.server.lock().expect("should not be poisoned") = server;
+    }
+
+    /// Submit a request, returning the ID of the job which blinds it.
+    pub fn submit(&self, req: BulkRequest) -> JobId {
+        let server = self.server.lock().expect("should not be poisoned").clone();
+        let id = self.jobs.create(JobKind::BulkBlinding, req.c_ps.len() as u64);
+
+        // Blind the points a chunk at a time, reporting progress after each and stopping if the
+        // job was cancelled.
+        let (jobs, results, chunk_size) =
+            (self.jobs.clone(), self.results.clone(), self.chunk_size);
+        thread::spawn(move || {
+            let mut sc_ps = Vec::with_capacity(req.c_ps.len());
+            for chunk in req.c_ps.chunks(chunk_size) {
+                for c_p in chunk {
+                    match server.blind_phone_number(c_p) {
+                        Ok(sc_p) => sc_ps.push(sc_p),
+                        Err(e) => return jobs.finish(id, Err(e.to_string())),
+                    }
+                }
+                if jobs.progress(id, sc_ps.len() as u64).is_err() {
+                    return;
+                }
+            }
+            let sc_ps = BulkResults { epoch: server.epoch(), sc_ps };
+            results.lock().expect("should not be poisoned").insert(id, sc_ps);
+            jobs.finish(id, Ok(()));
+        });
+        id
+    }
+
+    /// The status of the given job, or `None` if it doesn't exist.
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.jobs.status(id)
+    }
+
+    /// Block until the given job isn't running, returning `false` if it doesn't exist.
+    pub fn wait(&self, id: JobId) -> bool {
+        self.jobs.wait(id).is_some()
+    }
+
+    /// Remove a complete job and return its results, or `None` if it doesn't exist or didn't
+    /// complete.
+    pub fn take_results(&self, id: JobId) -> Option<BulkResults> {
+        if self.jobs.status(id)?.state != JobState::Complete {
+            return None;
+        }
+        self.jobs.remove(id)?;
+        self.results.lock().expect("should not be poisoned").remove(&id)
+    }
+}
+
+impl<S> fmt::Debug for BulkBlindingService<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BulkBlindingService")
+            .field("chunk_size", &self.chunk_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encode the given points as their concatenation.
+fn encode_points(points: &[EncodedPoint]) -> Vec<u8> {
+    points.iter().flat_map(|p| p.as_bytes().iter().copied()).collect()
+}
+
+/// Decode a concatenation of compressed points, checking that each is valid.
+fn decode_points(b: &[u8]) -> Result<Vec<EncodedPoint>, Error> {
+    if !b.len().is_multiple_of(POINT_LEN) {
+        return Err(Error::InvalidMessage);
+    }
+    b.chunks_exact(POINT_LEN)
+        .map(|p| {
+            let p = EncodedPoint::from_bytes(p).map_err(|_| Error::InvalidPoint)?;
+            decode_point(&p)?;
+            Ok(p)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::Client;
+
+    #[test]
+    fn bulk_blinding() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let jobs = Arc::new(InMemoryJobQueue::new());
+        let svc =
+            BulkBlindingService::new(server.clone()).with_chunk_size(2).with_jobs(jobs.clone());
+        let client = Client::new(OsRng);
+        let phone_numbers = [21, 22, 23, 24, 25];
+        let (prefixes, c_ps): (Vec<_>, Vec<_>) =
+            phone_numbers.iter().map(|&p| client.request_phone_number(p)).unzip();
+
+        // Submit the request out-of-band, then wait for the job.
+        let req = BulkRequest::decode(&BulkRequest { c_ps }.encode()).expect("should be valid");
+        let id = svc.submit(req);
+        assert!(svc.wait(id));
+        assert_eq!(svc.status(id).map(|s| (s.state, s.done)), Some((JobState::Complete, 5)));
+        assert_eq!(jobs.list().len(), 1);
+
+        // The results are the same as blinding each point online.
+        let results = svc.take_results(id).expect("should be complete");
+        let results = BulkResults::decode(&results.encode()).expect("should be valid");
+        assert_eq!(results.epoch, server.epoch());
+        let found = client
+            .find_user_id(&results.sc_ps[1], &server.find_bucket(prefixes[1]), 22)
+            .expect("should be a valid response");
+        assert!(found.is_some());
+
+        // Results can only be fetched once.
+        assert_eq!(svc.status(id), None);
+        assert!(!svc.wait(id));
+        assert!(svc.take_results(id).is_none());
+    }
+}
@@ -0,0 +1,562 @@
+//! Pre-serialized, content-addressed bundles of a server's buckets for CDN distribution.
+//!
+//! A [`Bundle`] is a single file containing everything clients need to look up phone numbers in an
+//! epoch without asking the server for buckets, laid out so clients can fetch just the parts they
+//! need with HTTP range requests:
+//!
+//! 1. A fixed-length [`BundleHeader`] with the epoch, the server's public key, the number of
+//!    buckets, the server's padding policy, the Merkle root of the index, and an optional signature.
+//! 2. An index of fixed-length [`IndexEntry`]s, sorted by prefix, giving the byte range and hash of
+//!    each bucket.
+//! 3. The buckets, in order of prefix, each [legacy-encoded](crate::BucketEncoding::Legacy).
+//!
+//! Nothing depends on hash map iteration order, so identical servers export byte-identical bundles.
+//!
+//! The header commits to the index, which commits to every bucket, so a bundle's [ID](Bundle::id),
+//! the hash of its header, addresses its entire contents. A client which trusts the ID (or, with
+//! the `replication` feature, the primary's signature) can check every part it downloads.
+//!
+//! The index's entries are the leaves of a Merkle tree, and each commits to its bucket's size as
+//! well as its hash. The server can [prove](Bundle::prove) that a bucket it served is in the bundle
+//! with a [`SizeProof`], which lets auditors check that served buckets conform to the bundle's
+//! padding policy, and so hide each user among at least the minimum number of entries, without
+//! downloading the index or seeing any other bucket.
+
+use std::{collections::HashMap, hash::BuildHasher, ops::Range};
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, EncodedPoint, ProjectivePoint};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    decode_bucket, decode_point,
+    encoding::{ENTRY_LEN, POINT_LEN},
+    Epoch, Error, Prefix, Server, PREFIX_LEN,
+};
+
+/// The length of a [`BundleHeader`], in bytes.
+pub const HEADER_LEN: usize = UNSIGNED_LEN + SIGNATURE_LEN;
+
+/// The length of an [`IndexEntry`], in bytes.
+pub const INDEX_ENTRY_LEN: usize = PREFIX_LEN + 8 + 4 + 32;
+
+const MAGIC: &[u8; 8] = b"ZKCDSBN2";
+const UNSIGNED_LEN: usize = MAGIC.len() + 8 + POINT_LEN + 8 + 8 + 8 + 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// A serialized bundle of a server's buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle(Vec<u8>);
+
+impl Bundle {
+    /// Decode and check an entire bundle: its index must match its header, its buckets must be
+    /// non-empty, laid out contiguously after the index in order of prefix, and match their hashes,
+    /// and every point must be on the curve.
+    pub fn decode(b: Vec<u8>) -> Result<Bundle, Error> {
+        let header = BundleHeader::decode(b.get(..HEADER_LEN).ok_or(Error::InvalidMessage)?)?;
+        decode_point(&header.public_key)?;
+        let index = b.get(range_of(header.index_range())?).ok_or(Error::InvalidMessage)?;
+        let index = header.decode_index(index)?;
+
+        // Check each bucket starts where the last one ended, and the last one ends the bundle.
+        let mut offset = header.index_range().end;
+        for entry in index.entries() {
+            if entry.offset != offset || entry.len == 0 {
+                return Err(Error::InvalidMessage);
+            }
+            let bucket = b.get(range_of(entry.range())?).ok_or(Error::InvalidMessage)?;
+            for (s_p, hs_u) in entry.decode_bucket(bucket)? {
+                decode_point(&s_p)?;
+                decode_point(&hs_u)?;
+            }
+            offset = entry.range().end;
+        }
+        if offset != b.len() as u64 {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(Bundle(b))
+    }
+
+    /// The bundle's index.
+    pub fn index(&self) -> BundleIndex {
+        let header = self.header();
+        let index = &self.0[range_of(header.index_range()).expect("should have a valid index")];
+        header.decode_index(index).expect("should have a valid index")
+    }
+
+    /// Prove that the bucket with the given prefix is in the bundle, with the size committed to by
+    /// its index entry, or return `None` if it's empty.
+    pub fn prove(&self, prefix: &Prefix) -> Option<SizeProof> {
+        let index = self.index();
+        let position = index.0.binary_search_by_key(prefix, |e| e.prefix).ok()?;
+        let leaves = index.0.iter().map(IndexEntry::leaf).collect();
+        Some(SizeProof { entry: index.0[position], position, path: merkle_path(leaves, position) })
+    }
+
+    /// The encoded bundle.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The bundle's content address, which is the hash of its header without its signature.
+    pub fn id(&self) -> [u8; 32] {
+        self.header().id()
+    }
+
+    /// The bundle's header.
+    pub fn header(&self) -> BundleHeader {
+        BundleHeader::decode(&self.0[..HEADER_LEN]).expect("should have a valid header")
+    }
+
+    /// Set the bundle's signature.
+    #[cfg(feature = "replication")]
+    pub(crate) fn set_signature(&mut self, signature: &[u8; SIGNATURE_LEN]) {
+        self.0[UNSIGNED_LEN..HEADER_LEN].copy_from_slice(signature);
+    }
+}
+
+/// The header of a [`Bundle`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleHeader {
+    /// The epoch of the server's secret.
+    pub epoch: Epoch,
+    /// The server's public key.
+    pub public_key: EncodedPoint,
+    /// The number of non-empty buckets.
+    pub buckets: u64,
+    /// The minimum number of entries in a bucket.
+    pub min_bucket_size: u64,
+    /// The maximum number of entries in a bucket, including the overflow bucket, or `None` if bucket
+    /// sizes aren't capped.
+    pub max_bucket_size: Option<u64>,
+    /// The Merkle root of the index entries.
+    pub root: [u8; 32],
+    /// The primary's signature of the bundle's ID, or zeros if it's unsigned.
+    pub signature: [u8; SIGNATURE_LEN],
+}
+
+impl BundleHeader {
+    /// Decode a header from the first [`HEADER_LEN`] bytes of a bundle.
+    pub fn decode(b: &[u8]) -> Result<BundleHeader, Error> {
+        let b: &[u8; HEADER_LEN] = b.try_into().map_err(|_| Error::InvalidMessage)?;
+        let (magic, b) = b.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(Error::InvalidMessage);
+        }
+        let (epoch, b) = b.split_at(8);
+        let (public_key, b) = b.split_at(POINT_LEN);
+        let (buckets, b) = b.split_at(8);
+        let (min_bucket_size, b) = b.split_at(8);
+        let (max_bucket_size, b) = b.split_at(8);
+        let (root, signature) = b.split_at(32);
+        let be_u64 = |b: &[u8]| u64::from_be_bytes(b.try_into().expect("should be 8 bytes"));
+        let max_bucket_size = be_u64(max_bucket_size);
+        Ok(BundleHeader {
+            epoch: Epoch::from_be_bytes(epoch.try_into().expect("should be 8 bytes")),
+            public_key: EncodedPoint::from_bytes(public_key).map_err(|_| Error::InvalidPoint)?,
+            buckets: be_u64(buckets),
+            min_bucket_size: be_u64(min_bucket_size),
+            max_bucket_size: (max_bucket_size != u64::MAX).then_some(max_bucket_size),
+            root: root.try_into().expect("should be 32 bytes"),
+            signature: signature.try_into().expect("should be 64 bytes"),
+        })
+    }
+
+    /// The bundle's content address.
+    pub fn id(&self) -> [u8; 32] {
+        Sha256::digest(&self.encode()[..UNSIGNED_LEN]).into()
+    }
+
+    /// The byte range of the index in the bundle.
+    pub fn index_range(&self) -> Range<u64> {
+        let start = HEADER_LEN as u64;
+        start..start.saturating_add(self.buckets.saturating_mul(INDEX_ENTRY_LEN as u64))
+    }
+
+    /// Decode and check the index, fetched from [`BundleHeader::index_range`].
+    pub fn decode_index(&self, b: &[u8]) -> Result<BundleIndex, Error> {
+        if b.len() as u64 != self.index_range().end - self.index_range().start
+            || merkle_root(b.chunks_exact(INDEX_ENTRY_LEN).map(leaf_hash).collect()) != self.root
+        {
+            return Err(Error::InvalidMessage);
+        }
+        let entries = b.chunks_exact(INDEX_ENTRY_LEN).map(IndexEntry::decode).collect::<Vec<_>>();
+        if !entries.windows(2).all(|w| w[0].prefix < w[1].prefix) {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(BundleIndex(entries))
+    }
+
+    /// Return `true` if the bucket for the given index entry conforms to the padding policy.
+    pub fn permits(&self, entry: &IndexEntry) -> bool {
+        let n = entry.entries() as u64;
+        n >= self.min_bucket_size && self.max_bucket_size.is_none_or(|max| n <= max)
+    }
+
+    /// Verify the header's signature with the primary's verifying key.
+    #[cfg(feature = "replication")]
+    pub fn verify(&self, verifying_key: &EncodedPoint) -> Result<(), Error> {
+        use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+
+        let key =
+            VerifyingKey::from_encoded_point(verifying_key).map_err(|_| Error::InvalidPoint)?;
+        let signature = Signature::from_slice(&self.signature).map_err(|_| Error::Unauthorized)?;
+        key.verify(&signed_message(&self.id()), &signature).map_err(|_| Error::Unauthorized)
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut b = [0u8; HEADER_LEN];
+        let mut w = &mut b[..];
+        for part in [
+            MAGIC.as_slice(),
+            &self.epoch.to_be_bytes(),
+            self.public_key.as_bytes(),
+            &self.buckets.to_be_bytes(),
+            &self.min_bucket_size.to_be_bytes(),
+            &self.max_bucket_size.unwrap_or(u64::MAX).to_be_bytes(),
+            &self.root,
+            &self.signature,
+        ] {
+            let (head, rest) = w.split_at_mut(part.len());
+            head.copy_from_slice(part);
+            w = rest;
+        }
+        b
+    }
+}
+
+/// The index of a [`Bundle`]'s buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleIndex(Vec<IndexEntry>);
+
+impl BundleIndex {
+    /// Return the entry for the bucket with the given prefix, or `None` if it's empty.
+    pub fn get(&self, prefix: &Prefix) -> Option<&IndexEntry> {
+        self.0.binary_search_by_key(prefix, |e| e.prefix).ok().map(|i| &self.0[i])
+    }
+
+    /// The entries, sorted by prefix.
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.0
+    }
+}
+
+/// The location and hash of a bucket in a [`Bundle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The bucket's hash prefix.
+    pub prefix: Prefix,
+    /// The byte offset of the bucket in the bundle.
+    pub offset: u64,
+    /// The length of the bucket, in bytes.
+    pub len: u32,
+    /// The hash of the encoded bucket.
+    pub hash: [u8; 32],
+}
+
+impl IndexEntry {
+    /// The byte range of the bucket in the bundle.
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset.saturating_add(u64::from(self.len))
+    }
+
+    /// The number of entries in the bucket.
+    pub fn entries(&self) -> usize {
+        self.len as usize / ENTRY_LEN
+    }
+
+    /// Check and decode the bucket, fetched from [`IndexEntry::range`].
+    pub fn decode_bucket(&self, b: &[u8]) -> Result<HashMap<EncodedPoint, EncodedPoint>, Error> {
+        if <[u8; 32]>::from(Sha256::digest(b)) != self.hash {
+            return Err(Error::InvalidMessage);
+        }
+        decode_bucket(b)
+    }
+
+    /// The entry's leaf in the index's Merkle tree.
+    fn leaf(&self) -> [u8; 32] {
+        let mut b = Vec::with_capacity(INDEX_ENTRY_LEN);
+        self.encode(&mut b);
+        leaf_hash(&b)
+    }
+
+    fn encode(&self, b: &mut Vec<u8>) {
+        b.extend_from_slice(&self.prefix);
+        b.extend_from_slice(&self.offset.to_be_bytes());
+        b.extend_from_slice(&self.len.to_be_bytes());
+        b.extend_from_slice(&self.hash);
+    }
+
+    fn decode(b: &[u8]) -> IndexEntry {
+        let (prefix, b) = b.split_at(PREFIX_LEN);
+        let (offset, b) = b.split_at(8);
+        let (len, hash) = b.split_at(4);
+        IndexEntry {
+            prefix: prefix.try_into().expect("should be a prefix"),
+            offset: u64::from_be_bytes(offset.try_into().expect("should be 8 bytes")),
+            len: u32::from_be_bytes(len.try_into().expect("should be 4 bytes")),
+            hash: hash.try_into().expect("should be 32 bytes"),
+        }
+    }
+}
+
+impl<S: BuildHasher> Server<S> {
+    /// Export the server's buckets as an unsigned [`Bundle`].
+    pub fn export_bundle(&self) -> Bundle {
+        // Lay out the buckets after the header and index.
+        let buckets = self.buckets.sorted();
+        let mut offset = (HEADER_LEN + buckets.len() * INDEX_ENTRY_LEN) as u64;
+        let mut index = Vec::with_capacity(buckets.len() * INDEX_ENTRY_LEN);
+        let mut blobs = Vec::new();
+        for (prefix, bucket) in &buckets {
+            // Entries are already sorted by sP, so their concatenation is the legacy encoding.
+            let start = blobs.len();
+            for (s_p, hs_u) in *bucket {
+                blobs.extend_from_slice(s_p);
+                blobs.extend_from_slice(hs_u);
+            }
+            let blob = &blobs[start..];
+            let len = u32::try_from(blob.len()).expect("should be smaller than 4 GiB");
+            IndexEntry { prefix: *prefix, offset, len, hash: Sha256::digest(blob).into() }
+                .encode(&mut index);
+            offset += u64::from(len);
+        }
+
+        // Write the header, which commits to the index, followed by the index and the buckets.
+        let header = BundleHeader {
+            epoch: self.epoch,
+            public_key: (ProjectivePoint::GENERATOR * self.d_s).to_affine().to_encoded_point(true),
+            buckets: buckets.len() as u64,
+            min_bucket_size: self.min_bucket_size as u64,
+            max_bucket_size: self.max_bucket_size.map(|n| n as u64),
+            root: merkle_root(index.chunks_exact(INDEX_ENTRY_LEN).map(leaf_hash).collect()),
+            signature: [0; SIGNATURE_LEN],
+        };
+        let mut b = Vec::with_capacity(HEADER_LEN + index.len() + blobs.len());
+        b.extend_from_slice(&header.encode());
+        b.extend_from_slice(&index);
+        b.extend_from_slice(&blobs);
+        Bundle(b)
+    }
+}
+
+/// A proof that a bucket is in a [`Bundle`], with the size committed to by its index entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeProof {
+    /// The bucket's index entry.
+    pub entry: IndexEntry,
+    position: usize,
+    path: Vec<[u8; 32]>,
+}
+
+impl SizeProof {
+    /// Check the proof against a bundle's header and decode the bucket it proves, returning
+    /// [`Error::InvalidMessage`] if the bucket isn't in the bundle or doesn't conform to the
+    /// bundle's padding policy.
+    pub fn verify(
+        &self,
+        header: &BundleHeader,
+        b: &[u8],
+    ) -> Result<HashMap<EncodedPoint, EncodedPoint>, Error> {
+        let n = usize::try_from(header.buckets).map_err(|_| Error::InvalidMessage)?;
+        if self.position >= n
+            || root_from_path(self.entry.leaf(), self.position, n, &self.path) != Some(header.root)
+            || !header.permits(&self.entry)
+        {
+            return Err(Error::InvalidMessage);
+        }
+        self.entry.decode_bucket(b)
+    }
+
+    /// Encode the proof as its index entry, its position in the index, and its Merkle path.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(INDEX_ENTRY_LEN + 8 + self.path.len() * 32);
+        self.entry.encode(&mut b);
+        b.extend_from_slice(&(self.position as u64).to_be_bytes());
+        self.path.iter().for_each(|h| b.extend_from_slice(h));
+        b
+    }
+
+    /// Decode a proof.
+    pub fn decode(b: &[u8]) -> Result<SizeProof, Error> {
+        if b.len() < INDEX_ENTRY_LEN + 8 || !(b.len() - INDEX_ENTRY_LEN - 8).is_multiple_of(32) {
+            return Err(Error::InvalidMessage);
+        }
+        let (entry, b) = b.split_at(INDEX_ENTRY_LEN);
+        let (position, path) = b.split_at(8);
+        let position = u64::from_be_bytes(position.try_into().expect("should be 8 bytes"));
+        Ok(SizeProof {
+            entry: IndexEntry::decode(entry),
+            position: usize::try_from(position).map_err(|_| Error::InvalidMessage)?,
+            path: path
+                .chunks_exact(32)
+                .map(|h| h.try_into().expect("should be 32 bytes"))
+                .collect(),
+        })
+    }
+}
+
+/// Hash an encoded index entry as a leaf of the index's Merkle tree.
+fn leaf_hash(entry: &[u8]) -> [u8; 32] {
+    Sha256::new().chain_update([0x00]).chain_update(entry).finalize().into()
+}
+
+/// Hash two child nodes of the index's Merkle tree.
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new().chain_update([0x01]).chain_update(left).chain_update(right).finalize().into()
+}
+
+/// Hash one level of a Merkle tree into the next, promoting an odd node at the end unchanged.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level.chunks(2).map(|pair| if let [l, r] = pair { node_hash(l, r) } else { pair[0] }).collect()
+}
+
+/// Return the root of the Merkle tree with the given leaves.
+fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Return the sibling hashes on the path from the `i`-th leaf to the root, from the bottom up.
+fn merkle_path(mut level: Vec<[u8; 32]>, mut i: usize) -> Vec<[u8; 32]> {
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(i ^ 1) {
+            path.push(*sibling);
+        }
+        level = next_level(&level);
+        i /= 2;
+    }
+    path
+}
+
+/// Return the root of the Merkle tree with `n` leaves given the `i`-th leaf and its path, or `None`
+/// if the path is the wrong length.
+fn root_from_path(
+    leaf: [u8; 32],
+    mut i: usize,
+    mut n: usize,
+    path: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    let (mut h, mut path) = (leaf, path.iter());
+    while n > 1 {
+        if i % 2 == 1 {
+            h = node_hash(path.next()?, &h);
+        } else if i + 1 < n {
+            h = node_hash(&h, path.next()?);
+        }
+        i /= 2;
+        n = n.div_ceil(2);
+    }
+    path.next().is_none().then_some(h)
+}
+
+/// Convert a byte range of a bundle to a slice range.
+fn range_of(r: Range<u64>) -> Result<Range<usize>, Error> {
+    let start = usize::try_from(r.start).map_err(|_| Error::InvalidMessage)?;
+    let end = usize::try_from(r.end).map_err(|_| Error::InvalidMessage)?;
+    Ok(start..end)
+}
+
+/// Return the message signed for the bundle with the given ID.
+#[cfg(feature = "replication")]
+pub(crate) fn signed_message(id: &[u8; 32]) -> Vec<u8> {
+    [b"zk-cds-bundle".as_slice(), id].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, ServerBuilder, SmallBucketPolicy};
+
+    #[test]
+    fn range_requests() {
+        let users = (0..50).map(|p| (p, Uuid::new_v4())).collect::<HashMap<_, _>>();
+        let server = Server::new(OsRng, &users);
+        let bundle = server.export_bundle();
+        let b = bundle.as_bytes();
+        let range = |r: Range<u64>| &b[r.start as usize..r.end as usize];
+
+        // Fetch the header and index, then the one bucket needed for a lookup.
+        let header = BundleHeader::decode(&b[..HEADER_LEN]).expect("should be a valid header");
+        assert_eq!(header.id(), bundle.id());
+        assert_eq!(header.public_key, server.public_key());
+        let index = header.decode_index(range(header.index_range())).expect("should be valid");
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let entry = index.get(&prefix).expect("should have a bucket");
+        let bucket = entry.decode_bucket(range(entry.range())).expect("should be valid");
+        assert_eq!(bucket, server.find_bucket(prefix).into_iter().collect());
+        let s_u = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &bucket,
+                22,
+            )
+            .expect("should be a valid response")
+            .expect("should be a valid phone number");
+        assert_eq!(server.unblind_user_id(&s_u), users.get(&22).cloned());
+
+        // Tampered buckets are detected.
+        let mut blob = range(entry.range()).to_vec();
+        blob[40] ^= 1;
+        assert_eq!(entry.decode_bucket(&blob), Err(Error::InvalidMessage));
+    }
+
+    #[test]
+    fn decode() {
+        let users = (0..50).map(|p| (p, Uuid::new_v4())).collect::<HashMap<_, _>>();
+        let server = Server::new(OsRng, &users);
+        let bundle = server.export_bundle();
+        let b = bundle.as_bytes().to_vec();
+
+        // An exported bundle decodes, and its index accounts for every user.
+        let decoded = Bundle::decode(b.clone()).expect("should be a valid bundle");
+        assert_eq!(decoded, bundle);
+        assert_eq!(decoded.index().entries().iter().map(IndexEntry::entries).sum::<usize>(), 50);
+
+        // Truncated, extended, or tampered bundles don't.
+        assert_eq!(Bundle::decode(b[..b.len() - 1].to_vec()), Err(Error::InvalidMessage));
+        assert_eq!(Bundle::decode([b.as_slice(), &[0]].concat()), Err(Error::InvalidMessage));
+        let mut tampered = b.clone();
+        tampered[HEADER_LEN + 1] ^= 1;
+        assert_eq!(Bundle::decode(tampered), Err(Error::InvalidMessage));
+    }
+
+    #[test]
+    fn size_proofs() {
+        let (server, _) = ServerBuilder::new()
+            .min_bucket_size(2, SmallBucketPolicy::Pad)
+            .build(OsRng, (0..21).map(|p| (p, Uuid::new_v4())))
+            .expect("should build");
+        let bundle = server.export_bundle();
+        let header = bundle.header();
+        assert_eq!((header.min_bucket_size, header.max_bucket_size), (2, None));
+
+        // Every bucket can be proven to be in the bundle and padded, without the index.
+        let b = bundle.as_bytes();
+        for entry in bundle.index().entries() {
+            let proof = bundle.prove(&entry.prefix).expect("should have a proof");
+            let proof = SizeProof::decode(&proof.encode()).expect("should be a valid proof");
+            let bucket = &b[entry.range().start as usize..entry.range().end as usize];
+            let bucket = proof.verify(&header, bucket).expect("should be a valid proof");
+            assert_eq!(bucket.len(), 2);
+        }
+
+        // Proofs of buckets with different sizes, or which don't meet the policy, don't verify.
+        let entry = bundle.index().entries()[0];
+        let bucket = &b[entry.range().start as usize..entry.range().end as usize];
+        let mut proof = bundle.prove(&entry.prefix).expect("should have a proof");
+        let strict = BundleHeader { min_bucket_size: 3, ..header.clone() };
+        assert_eq!(proof.verify(&strict, bucket), Err(Error::InvalidMessage));
+        proof.entry.len += ENTRY_LEN as u32;
+        assert_eq!(proof.verify(&header, bucket), Err(Error::InvalidMessage));
+    }
+}
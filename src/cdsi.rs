@@ -0,0 +1,321 @@
+//! Compatibility with Signal's CDSi request and response messages.
+//!
+//! CDSi clients send a `ClientRequest` listing the E.164 phone numbers they looked up before, the
+//! ones they're adding, and the ones they're discarding, along with a token for the previous set,
+//! and receive a `ClientResponse` of `(E.164, PNI, ACI)` triples. This module encodes and decodes
+//! those messages in their protobuf wire format, so a bridge can translate them into lookups
+//! against a zk-cds [`Server`], either as a test double for CDSi clients or while migrating them.
+//!
+//! zk-cds maps each phone number to a single user ID, which is returned as the ACI; PNIs are always
+//! zero. Tokens are a hash of the set of phone numbers they cover, so a client which presents one
+//! with its previous phone numbers proves it looked them up before. ACI/UAK pairs are decoded but
+//! ignored, since the server returns every matched ACI.
+
+use std::{collections::BTreeSet, hash::BuildHasher};
+
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{Client, Error, Server};
+
+/// The length of an encoded E.164 phone number, in bytes.
+const E164_LEN: usize = 8;
+
+/// The length of an encoded ACI/UAK pair, in bytes.
+const ACI_UAK_LEN: usize = 16 + 16;
+
+/// The length of an encoded `(E.164, PNI, ACI)` triple, in bytes.
+const TRIPLE_LEN: usize = E164_LEN + 16 + 16;
+
+/// A CDSi `ClientRequest`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientRequest {
+    /// ACIs and their unidentified access keys, which are ignored.
+    pub aci_uak_pairs: Vec<(Uuid, [u8; 16])>,
+    /// The phone numbers covered by the token.
+    pub prev_e164s: Vec<u64>,
+    /// The phone numbers being added.
+    pub new_e164s: Vec<u64>,
+    /// The phone numbers being removed from the previous set.
+    pub discard_e164s: Vec<u64>,
+    /// The token from the response to the previous request, if any.
+    pub token: Vec<u8>,
+    /// Whether the client is acknowledging a token.
+    pub token_ack: bool,
+}
+
+impl ClientRequest {
+    /// Encode the request as a protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        let pairs = self
+            .aci_uak_pairs
+            .iter()
+            .flat_map(|(aci, uak)| aci.as_bytes().iter().chain(uak).copied())
+            .collect::<Vec<_>>();
+        put_bytes(&mut b, 1, &pairs);
+        put_bytes(&mut b, 2, &encode_e164s(&self.prev_e164s));
+        put_bytes(&mut b, 3, &encode_e164s(&self.new_e164s));
+        put_bytes(&mut b, 4, &encode_e164s(&self.discard_e164s));
+        put_bytes(&mut b, 6, &self.token);
+        if self.token_ack {
+            put_varint(&mut b, 7 << 3);
+            put_varint(&mut b, 1);
+        }
+        b
+    }
+
+    /// Decode a request, skipping unknown fields.
+    pub fn decode(mut b: &[u8]) -> Result<ClientRequest, Error> {
+        let mut req = ClientRequest::default();
+        while !b.is_empty() {
+            match read_field(&mut b)? {
+                (1, Value::Bytes(v)) => {
+                    if !v.len().is_multiple_of(ACI_UAK_LEN) {
+                        return Err(Error::InvalidMessage);
+                    }
+                    req.aci_uak_pairs = v
+                        .chunks_exact(ACI_UAK_LEN)
+                        .map(|p| {
+                            let (aci, uak) = p.split_at(16);
+                            let aci = Uuid::from_slice(aci).expect("should be 16 bytes");
+                            (aci, uak.try_into().expect("should be 16 bytes"))
+                        })
+                        .collect();
+                }
+                (2, Value::Bytes(v)) => req.prev_e164s = decode_e164s(v)?,
+                (3, Value::Bytes(v)) => req.new_e164s = decode_e164s(v)?,
+                (4, Value::Bytes(v)) => req.discard_e164s = decode_e164s(v)?,
+                (6, Value::Bytes(v)) => req.token = v.to_vec(),
+                (7, Value::Varint(v)) => req.token_ack = v != 0,
+                _ => {}
+            }
+        }
+        Ok(req)
+    }
+
+    /// Return the phone numbers to look up: the previous ones which weren't discarded, and the new
+    /// ones, without duplicates.
+    ///
+    /// Returns [`Error::InvalidMessage`] if the request lists previous phone numbers without a
+    /// token for them.
+    pub fn phone_numbers(&self) -> Result<Vec<u64>, Error> {
+        if !self.prev_e164s.is_empty() && self.token != token_for(&self.prev_e164s) {
+            return Err(Error::InvalidMessage);
+        }
+        let mut ps = self.prev_e164s.iter().copied().collect::<BTreeSet<_>>();
+        for p in &self.discard_e164s {
+            ps.remove(p);
+        }
+        ps.extend(&self.new_e164s);
+        Ok(ps.into_iter().collect())
+    }
+}
+
+/// A CDSi `ClientResponse`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientResponse {
+    /// The `(E.164, PNI, ACI)` triples of the matched phone numbers.
+    pub triples: Vec<(u64, Uuid, Uuid)>,
+    /// The token covering the phone numbers which were looked up.
+    pub token: Vec<u8>,
+}
+
+impl ClientResponse {
+    /// Create a response for the given phone numbers and the user IDs found for them, if any.
+    pub fn from_lookups(results: impl IntoIterator<Item = (u64, Option<Uuid>)>) -> ClientResponse {
+        let (ps, triples): (Vec<_>, Vec<_>) =
+            results.into_iter().map(|(p, aci)| (p, aci.map(|aci| (p, Uuid::nil(), aci)))).unzip();
+        ClientResponse { triples: triples.into_iter().flatten().collect(), token: token_for(&ps) }
+    }
+
+    /// Encode the response as a protobuf message.
+    pub fn encode(&self) -> Vec<u8> {
+        let triples = self
+            .triples
+            .iter()
+            .flat_map(|(p, pni, aci)| {
+                p.to_be_bytes().into_iter().chain(*pni.as_bytes()).chain(*aci.as_bytes())
+            })
+            .collect::<Vec<_>>();
+        let mut b = Vec::new();
+        put_bytes(&mut b, 1, &triples);
+        put_bytes(&mut b, 3, &self.token);
+        b
+    }
+
+    /// Decode a response, skipping unknown fields.
+    pub fn decode(mut b: &[u8]) -> Result<ClientResponse, Error> {
+        let mut resp = ClientResponse::default();
+        while !b.is_empty() {
+            match read_field(&mut b)? {
+                (1, Value::Bytes(v)) => {
+                    if !v.len().is_multiple_of(TRIPLE_LEN) {
+                        return Err(Error::InvalidMessage);
+                    }
+                    resp.triples = v
+                        .chunks_exact(TRIPLE_LEN)
+                        .map(|t| {
+                            let (p, ids) = t.split_at(E164_LEN);
+                            let (pni, aci) = ids.split_at(16);
+                            (
+                                u64::from_be_bytes(p.try_into().expect("should be 8 bytes")),
+                                Uuid::from_slice(pni).expect("should be 16 bytes"),
+                                Uuid::from_slice(aci).expect("should be 16 bytes"),
+                            )
+                        })
+                        .collect();
+                }
+                (3, Value::Bytes(v)) => resp.token = v.to_vec(),
+                _ => {}
+            }
+        }
+        Ok(resp)
+    }
+}
+
+/// Answer a CDSi request by looking up its phone numbers in the given server, acting as both the
+/// client and the server.
+pub fn serve<S: BuildHasher + Clone>(
+    server: &Server<S>,
+    req: &ClientRequest,
+) -> Result<ClientResponse, Error> {
+    let client = Client::new(OsRng).with_hash_function(server.hash).with_pre_hash(server.pre_hash);
+    let results = req
+        .phone_numbers()?
+        .into_iter()
+        .map(|p| {
+            let (prefix, c_p) = client.request_phone_number(p);
+            let sc_p = server.blind_phone_number(&c_p)?;
+            let s_u = client.find_user_id(&sc_p, &server.find_bucket(prefix), p)?;
+            Ok((p, s_u.and_then(|s_u| server.unblind_user_id(&s_u))))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(ClientResponse::from_lookups(results))
+}
+
+/// Return the token covering the given phone numbers.
+fn token_for(ps: &[u64]) -> Vec<u8> {
+    let ps = ps.iter().collect::<BTreeSet<_>>();
+    let mut h = Sha256::new().chain_update(b"zk-cds-cdsi-token");
+    for p in ps {
+        h.update(p.to_be_bytes());
+    }
+    h.finalize().to_vec()
+}
+
+fn encode_e164s(ps: &[u64]) -> Vec<u8> {
+    ps.iter().flat_map(|p| p.to_be_bytes()).collect()
+}
+
+fn decode_e164s(b: &[u8]) -> Result<Vec<u64>, Error> {
+    if !b.len().is_multiple_of(E164_LEN) {
+        return Err(Error::InvalidMessage);
+    }
+    Ok(b.chunks_exact(E164_LEN)
+        .map(|p| u64::from_be_bytes(p.try_into().expect("should be 8 bytes")))
+        .collect())
+}
+
+/// A protobuf field's value.
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Other,
+}
+
+/// Append a length-delimited field, unless it's empty.
+fn put_bytes(b: &mut Vec<u8>, field: u64, v: &[u8]) {
+    if !v.is_empty() {
+        put_varint(b, field << 3 | 2);
+        put_varint(b, v.len() as u64);
+        b.extend_from_slice(v);
+    }
+}
+
+fn put_varint(b: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        b.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    b.push(v as u8);
+}
+
+fn read_varint(b: &mut &[u8]) -> Result<u64, Error> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = b.split_first().ok_or(Error::InvalidMessage)?;
+        *b = rest;
+        v |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(v);
+        }
+    }
+    Err(Error::InvalidMessage)
+}
+
+/// Read a field's number and value.
+fn read_field<'a>(b: &mut &'a [u8]) -> Result<(u64, Value<'a>), Error> {
+    let key = read_varint(b)?;
+    let value = match key & 7 {
+        0 => Value::Varint(read_varint(b)?),
+        1 | 5 => {
+            let len = if key & 7 == 1 { 8 } else { 4 };
+            *b = b.get(len..).ok_or(Error::InvalidMessage)?;
+            Value::Other
+        }
+        2 => {
+            let len = usize::try_from(read_varint(b)?).map_err(|_| Error::InvalidMessage)?;
+            if len > b.len() {
+                return Err(Error::InvalidMessage);
+            }
+            let (v, rest) = b.split_at(len);
+            *b = rest;
+            Value::Bytes(v)
+        }
+        _ => return Err(Error::InvalidMessage),
+    };
+    Ok((key >> 3, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn cdsi_round_trip() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let server = Server::new(OsRng, &HashMap::from([(15550100, alice), (15550101, bob)]));
+
+        // A first request looks up new phone numbers and gets a token for them.
+        let req = ClientRequest {
+            aci_uak_pairs: vec![(alice, [7; 16])],
+            new_e164s: vec![15550100, 15550199],
+            ..Default::default()
+        };
+        let req = ClientRequest::decode(&req.encode()).expect("should be a valid request");
+        let resp = serve(&server, &req).expect("should be a valid request");
+        let resp = ClientResponse::decode(&resp.encode()).expect("should be a valid response");
+        assert_eq!(resp.triples, vec![(15550100, Uuid::nil(), alice)]);
+
+        // A later request adds and discards phone numbers from the previous set.
+        let req = ClientRequest {
+            prev_e164s: vec![15550100, 15550199],
+            new_e164s: vec![15550101],
+            discard_e164s: vec![15550100],
+            token: resp.token.clone(),
+            ..Default::default()
+        };
+        assert_eq!(req.phone_numbers(), Ok(vec![15550101, 15550199]));
+        let resp = serve(&server, &req).expect("should be a valid request");
+        assert_eq!(resp.triples, vec![(15550101, Uuid::nil(), bob)]);
+
+        // Previous phone numbers require a matching token.
+        let forged = ClientRequest { prev_e164s: vec![15550101], ..req };
+        assert_eq!(serve(&server, &forged), Err(Error::InvalidMessage));
+        assert_eq!(ClientRequest::decode(&[0x12, 0x03, 1, 2, 3]), Err(Error::InvalidMessage));
+    }
+}
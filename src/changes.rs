@@ -0,0 +1,529 @@
+//! Incremental updates to a server's directory, and a feed of the buckets they change.
+//!
+//! Rebuilding a server from the full address book for every signup re-blinds every row, and
+//! forces clients and replicas which keep a full copy of the directory to download every bucket
+//! again. Instead, [`Server::update`] adds and removes phone numbers, producing a server for the
+//! next epoch with the same secret in which only the affected buckets differ. Each server
+//! remembers the prefixes changed by its most recent [`CHANGE_LOG_LEN`] updates, so
+//! [`Server::changes_since`] can tell a subscriber at an earlier epoch which buckets to refetch.
+//!
+//! Subscribers can also fetch [`BucketDelta`]s from [`Server::deltas_since`] instead of whole
+//! buckets. A delta lists the entries added to a bucket and tombstones for the entries removed
+//! from it. Tombstones carry only the removed entry's `sP` key, which is blinded by the server's
+//! secret, so they don't reveal which phone number left.
+//!
+//! Rotating the secret changes every bucket, so the log is reset by [`Server::rotate`], and
+//! subscribers from before a rotation must refetch everything.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    hash::BuildHasher,
+};
+
+use p256::{
+    elliptic_curve::{ops::ReduceNonZero, sec1::ToEncodedPoint},
+    EncodedPoint, ProjectivePoint, Scalar,
+};
+use rand::{CryptoRng, RngCore};
+use uuid::Uuid;
+
+use crate::{
+    arena::{encoded_point, point_bytes, Entry, PointBytes},
+    blind_row, decode_point,
+    dummy::dummy_entries,
+    encode_to_point,
+    encoding::{BucketCache, ENTRY_LEN, POINT_LEN},
+    hash_to_curve,
+    user_id::{tagged_point_id, Capabilities},
+    BuildError, Epoch, Error, Prefix, Server, OVERFLOW_PREFIX, PREFIX_LEN,
+};
+
+/// The number of updates whose changed prefixes a server remembers.
+pub const CHANGE_LOG_LEN: usize = 64;
+
+/// The changes made to a server's buckets by its most recent updates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChangeLog {
+    /// The earliest epoch the log can report changes since.
+    since: Epoch,
+    /// The epoch produced by each update after `since`, and the buckets it changed.
+    updates: VecDeque<(Epoch, Vec<Change>)>,
+}
+
+/// The changes made to a bucket by an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Change {
+    prefix: Prefix,
+    /// The `sP` keys of the entries added.
+    added: Vec<PointBytes>,
+    /// The `sP` keys of the entries removed.
+    removed: Vec<PointBytes>,
+}
+
+impl ChangeLog {
+    /// Create an empty log for a server at the given epoch.
+    pub(crate) fn new(epoch: Epoch) -> ChangeLog {
+        ChangeLog { since: epoch, updates: VecDeque::new() }
+    }
+
+    /// Return a copy of the log with an update recorded, forgetting the oldest one if it's full.
+    fn record(&self, epoch: Epoch, changes: Vec<Change>) -> ChangeLog {
+        let mut log = self.clone();
+        log.updates.push_back((epoch, changes));
+        if log.updates.len() > CHANGE_LOG_LEN {
+            let (oldest, _) = log.updates.pop_front().expect("should not be empty");
+            log.since = oldest;
+        }
+        log
+    }
+}
+
+impl<S: BuildHasher + Clone> Server<S> {
+    /// Create a server for the next epoch with the given users added and phone numbers removed.
+    ///
+    /// The secret is unchanged, so only the buckets of the added and removed phone numbers differ
+    /// from this server's. A phone number may be both removed and added to change its user ID;
+    /// otherwise, adding one which is already present fails with
+    /// [`BuildError::DuplicatePhoneNumber`]. Removing a phone number removes all of its entries,
+    /// and removing an absent one does nothing. Changed buckets are padded with dummy entries from
+    /// `rng` to the server's minimum bucket size. If the server has a maximum bucket size, adding
+    /// a phone number to a bucket which is already full fails with [`BuildError::BucketsTooLarge`],
+    /// and the server must be rebuilt to split or spill the bucket.
+    pub fn update(
+        &self,
+        mut rng: impl CryptoRng + RngCore,
+        added: impl IntoIterator<Item = (u64, Uuid)>,
+        removed: impl IntoIterator<Item = u64>,
+    ) -> Result<Server<S>, BuildError> {
+        let mut changed = HashMap::<Prefix, Vec<Entry>>::new();
+
+        // Remove every entry of each removed phone number from a copy of its bucket.
+        for p in removed {
+            let id = self.pre_hash.apply(p);
+            let h_p = hash_to_curve(&id);
+
+            // Entries which didn't fit in their bucket may be in the overflow bucket instead.
+            let prefixes = [self.bucket_prefix(&self.hash.hash(&id)), OVERFLOW_PREFIX];
+            for i in 0u64.. {
+                let s_p = (h_p + ProjectivePoint::GENERATOR * Scalar::from(i)) * self.d_s;
+                let s_p = point_bytes(&s_p.to_affine().to_encoded_point(true));
+                let mut found = false;
+                for prefix in prefixes {
+                    let bucket =
+                        changed.entry(prefix).or_insert_with(|| self.buckets.get(&prefix).to_vec());
+                    if let Ok(j) = bucket.binary_search_by_key(&s_p, |&(k, _)| k) {
+                        bucket.remove(j);
+                        found = true;
+                    }
+                }
+                if !found {
+                    break;
+                }
+            }
+        }
+
+        // Add each new phone number to a copy of its bucket, unless it's already there.
+        for (p, u) in added {
+            let id = self.pre_hash.apply(p);
+            let (_, s_p, hs_u) = blind_row(&self.d_s, self.hash, &id, &u, 0)?;
+            let prefix = self.bucket_prefix(&self.hash.hash(&id));
+            let bucket =
+                changed.entry(prefix).or_insert_with(|| self.buckets.get(&prefix).to_vec());
+            let s_p = point_bytes(&s_p);
+            match bucket.binary_search_by_key(&s_p, |&(k, _)| k) {
+                Ok(_) => return Err(BuildError::DuplicatePhoneNumber(p)),
+                Err(j) => bucket.insert(j, (s_p, point_bytes(&hs_u))),
+            }
+        }
+
+        // Pad any changed buckets which are now too small with dummy entries.
+        for bucket in changed.values_mut().filter(|bucket| !bucket.is_empty()) {
+            let n = self.min_bucket_size.saturating_sub(bucket.len());
+            let exists = |s_p: &EncodedPoint| {
+                bucket.binary_search_by_key(&point_bytes(s_p), |&(k, _)| k).is_ok()
+            };
+            for (s_p, hs_u) in dummy_entries(&mut rng, n, exists) {
+                let s_p = point_bytes(&s_p);
+                let j = bucket.binary_search_by_key(&s_p, |&(k, _)| k).unwrap_or_else(|j| j);
+                bucket.insert(j, (s_p, point_bytes(&hs_u)));
+            }
+        }
+
+        // Reject the update if it grew any bucket past the maximum.
+        if let Some(max) = self.max_bucket_size {
+            let large = changed.values().filter(|bucket| bucket.len() > max).count();
+            if large > 0 {
+                return Err(BuildError::BucketsTooLarge(large));
+            }
+        }
+
+        Ok(self.with_buckets(changed))
+    }
+
+    /// Create a server for the next epoch with the given phone numbers' capabilities changed.
+    ///
+    /// Every user ID of each phone number is re-encoded with its new capabilities, so only their
+    /// buckets differ from this server's. Absent phone numbers are ignored. Returns
+    /// [`BuildError::CorruptEntry`] if a phone number's entry doesn't decode to a user ID with a
+    /// valid integrity tag.
+    pub fn set_capabilities(
+        &self,
+        updated: impl IntoIterator<Item = (u64, Capabilities)>,
+    ) -> Result<Server<S>, BuildError> {
+        let mut changed = HashMap::<Prefix, Vec<Entry>>::new();
+        for (p, caps) in updated {
+            let id = self.pre_hash.apply(p);
+            let h = self.hash.hash(&id);
+            let hd_s = self.d_s * Scalar::reduce_nonzero_bytes(&h.into());
+            let h_p = hash_to_curve(&id);
+
+            // Entries which didn't fit in their bucket may be in the overflow bucket instead.
+            let prefixes = [self.bucket_prefix(&h), OVERFLOW_PREFIX];
+            for i in 0u64.. {
+                let s_p = (h_p + ProjectivePoint::GENERATOR * Scalar::from(i)) * self.d_s;
+                let s_p = point_bytes(&s_p.to_affine().to_encoded_point(true));
+                let found = prefixes.iter().find_map(|prefix| {
+                    let bucket =
+                        changed.entry(*prefix).or_insert_with(|| self.buckets.get(prefix).to_vec());
+                    Some((*prefix, bucket.binary_search_by_key(&s_p, |&(k, _)| k).ok()?))
+                });
+                let Some((prefix, j)) = found else {
+                    break;
+                };
+                let entry = &mut changed.get_mut(&prefix).expect("should have a copy")[j];
+
+                // Unblind the user ID point, then re-encode and re-blind it with the capabilities.
+                let hs_u = decode_point(&encoded_point(&entry.1))
+                    .map_err(|_| BuildError::CorruptEntry(p))?;
+                let u = hs_u * hd_s.invert().expect("should be invertible");
+                let (u, _) = tagged_point_id(u, &h).map_err(|_| BuildError::CorruptEntry(p))?;
+                let u = Uuid::from(u);
+                let u = encode_to_point(&u, caps, &h).ok_or(BuildError::UnencodableUserId(u))?;
+                entry.1 = point_bytes(&(u * hd_s).to_affine().to_encoded_point(true));
+            }
+        }
+        Ok(self.with_buckets(changed))
+    }
+
+    /// Return a server for the next epoch with the given buckets replaced, recording the entries
+    /// added to and removed from them.
+    fn with_buckets(&self, mut changed: HashMap<Prefix, Vec<Entry>>) -> Server<S> {
+        // Record the entries added to and removed from the buckets which actually changed.
+        changed.retain(|prefix, bucket| self.buckets.get(prefix) != bucket.as_slice());
+        let mut changes = changed
+            .iter()
+            .map(|(&prefix, bucket)| {
+                let old = self.buckets.get(&prefix);
+                let keys = |a: &[Entry], b: &[Entry]| {
+                    a.iter().filter(|e| b.binary_search(e).is_err()).map(|&(k, _)| k).collect()
+                };
+                Change { prefix, added: keys(bucket, old), removed: keys(old, bucket) }
+            })
+            .collect::<Vec<_>>();
+        changes.sort_unstable_by_key(|change| change.prefix);
+        let epoch = self.epoch + 1;
+
+        Server {
+            d_s: self.d_s,
+            epoch,
+            hash: self.hash,
+            pre_hash: self.pre_hash,
+            buckets: self.buckets.replace(changed),
+            min_bucket_size: self.min_bucket_size,
+            max_bucket_size: self.max_bucket_size,
+            cache: BucketCache::new(self.cache.capacity()),
+            changes: self.changes.record(epoch, changes),
+        }
+    }
+
+    /// Return the prefixes of the buckets which changed after the given epoch, in order, or `None`
+    /// if the server doesn't know, e.g. because the secret was rotated since then, and every
+    /// bucket must be refetched.
+    pub fn changes_since(&self, epoch: Epoch) -> Option<Vec<Prefix>> {
+        let prefixes =
+            self.changes_after(epoch)?.map(|change| change.prefix).collect::<BTreeSet<_>>();
+        Some(prefixes.into_iter().collect())
+    }
+
+    /// Return a delta for each bucket which changed after the given epoch, in order of prefix, or
+    /// `None` if every bucket must be refetched.
+    ///
+    /// Applying the deltas to a copy of the buckets from the given epoch makes them identical to
+    /// this server's.
+    pub fn deltas_since(&self, epoch: Epoch) -> Option<Vec<BucketDelta>> {
+        // Collect every key added to or removed from each bucket since the epoch.
+        let mut keys = BTreeMap::<Prefix, (BTreeSet<PointBytes>, BTreeSet<PointBytes>)>::new();
+        for change in self.changes_after(epoch)? {
+            let (added, removed) = keys.entry(change.prefix).or_default();
+            added.extend(&change.added);
+            removed.extend(&change.removed);
+        }
+
+        // Tombstone every removed key, and add every added key which is still present, so entries
+        // which were removed and re-added are replaced.
+        let deltas = keys
+            .into_iter()
+            .map(|(prefix, (added, removed))| {
+                let bucket = self.buckets.get(&prefix);
+                let added = added
+                    .iter()
+                    .filter_map(|k| bucket.binary_search_by_key(k, |&(k, _)| k).ok())
+                    .map(|i| (encoded_point(&bucket[i].0), encoded_point(&bucket[i].1)))
+                    .collect();
+                let removed = removed.iter().map(encoded_point).collect();
+                BucketDelta { prefix, added, removed }
+            })
+            .collect();
+        Some(deltas)
+    }
+
+    /// Return the changes made by updates after the given epoch, if the log reaches back that far.
+    fn changes_after(&self, epoch: Epoch) -> Option<impl Iterator<Item = &Change>> {
+        if epoch < self.changes.since || epoch > self.epoch {
+            return None;
+        }
+        Some(
+            self.changes
+                .updates
+                .iter()
+                .filter(move |&&(e, _)| e > epoch)
+                .flat_map(|(_, changes)| changes.iter()),
+        )
+    }
+}
+
+/// The changes made to a bucket between two epochs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketDelta {
+    /// The prefix of the bucket.
+    pub prefix: Prefix,
+    /// The `(sP, hsU)` entries added to the bucket, in order of `sP`.
+    pub added: Vec<(EncodedPoint, EncodedPoint)>,
+    /// Tombstones for the entries removed from the bucket: their `sP` keys, in order.
+    pub removed: Vec<EncodedPoint>,
+}
+
+impl BucketDelta {
+    /// Apply the delta to a cached copy of the bucket, pruning entries with tombstones before
+    /// adding the new ones.
+    pub fn apply<S: BuildHasher>(&self, bucket: &mut HashMap<EncodedPoint, EncodedPoint, S>) {
+        for s_p in &self.removed {
+            bucket.remove(s_p);
+        }
+        bucket.extend(self.added.iter().cloned());
+    }
+
+    /// Encode the delta as its prefix, the number of tombstones and the tombstones, then the
+    /// number of added entries and the entries.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(
+            PREFIX_LEN + 8 + self.removed.len() * POINT_LEN + self.added.len() * ENTRY_LEN,
+        );
+        b.extend_from_slice(&self.prefix);
+        b.extend_from_slice(&(self.removed.len() as u32).to_be_bytes());
+        for s_p in &self.removed {
+            b.extend_from_slice(s_p.as_bytes());
+        }
+        b.extend_from_slice(&(self.added.len() as u32).to_be_bytes());
+        for (s_p, hs_u) in &self.added {
+            b.extend_from_slice(s_p.as_bytes());
+            b.extend_from_slice(hs_u.as_bytes());
+        }
+        b
+    }
+
+    /// Decode a delta.
+    pub fn decode(b: &[u8]) -> Result<BucketDelta, Error> {
+        let (prefix, b) = b.split_at_checked(PREFIX_LEN).ok_or(Error::InvalidMessage)?;
+        let (removed, b) = counted(b, POINT_LEN)?;
+        let (added, b) = counted(b, ENTRY_LEN)?;
+        if !b.is_empty() {
+            return Err(Error::InvalidMessage);
+        }
+        let point = |b: &[u8]| EncodedPoint::from_bytes(b).map_err(|_| Error::InvalidPoint);
+        Ok(BucketDelta {
+            prefix: prefix.try_into().expect("should be a prefix"),
+            added: added
+                .chunks_exact(ENTRY_LEN)
+                .map(|e| Ok((point(&e[..POINT_LEN])?, point(&e[POINT_LEN..])?)))
+                .collect::<Result<_, Error>>()?,
+            removed: removed.chunks_exact(POINT_LEN).map(point).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Split a count-prefixed run of fixed-length records off the front of `b`.
+fn counted(b: &[u8], len: usize) -> Result<(&[u8], &[u8]), Error> {
+    let (n, b) = b.split_at_checked(4).ok_or(Error::InvalidMessage)?;
+    let n = u32::from_be_bytes(n.try_into().expect("should be 4 bytes")) as usize;
+    b.split_at_checked(n.checked_mul(len).ok_or(Error::InvalidMessage)?)
+        .ok_or(Error::InvalidMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{Client, ServerBuilder, SmallBucketPolicy};
+
+    #[test]
+    fn updates() {
+        let (u1, u2, u3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let server = Server::new(OsRng, &HashMap::from([(22, u1), (23, u2)]));
+        let client = Client::new(OsRng);
+        let lookup = |server: &Server, p| {
+            let (prefix, c_p) = client.request_phone_number(p);
+            client
+                .find_user_id(
+                    &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                    &server.find_bucket(prefix),
+                    p,
+                )
+                .expect("should be a valid response")
+                .and_then(|s_u| server.unblind_user_id(&s_u))
+        };
+
+        // Add one phone number, remove another, and change a third.
+        let next = server.update(OsRng, [(24, u3), (22, u2)], [23, 22]).expect("should update");
+        assert_eq!(next.epoch(), 1);
+        assert_eq!(lookup(&next, 22), Some(u2));
+        assert_eq!(lookup(&next, 23), None);
+        assert_eq!(lookup(&next, 24), Some(u3));
+        assert_eq!(
+            next.update(OsRng, [(24, u1)], []).err(),
+            Some(BuildError::DuplicatePhoneNumber(24))
+        );
+
+        // Only the affected buckets are reported as changed.
+        let prefixes = [22, 23, 24].map(|p| client.request_phone_number(p).0);
+        let mut expected = prefixes.to_vec();
+        expected.sort_unstable();
+        assert_eq!(next.changes_since(0), Some(expected));
+        assert_eq!(next.changes_since(1), Some(vec![]));
+        let last = next.update(OsRng, [], [24]).expect("should update");
+        assert_eq!(last.changes_since(1), Some(vec![prefixes[2]]));
+
+        // Rotations change every bucket.
+        assert_eq!(last.rotate(OsRng).changes_since(2), None);
+    }
+
+    #[test]
+    fn capabilities() {
+        let u = Uuid::new_v4();
+        let server = Server::new(OsRng, &HashMap::from([(22, u), (23, Uuid::new_v4())]));
+        let video = Capabilities(1 << 3);
+        let next = server.set_capabilities([(22, video), (24, video)]).expect("should update");
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        assert_eq!(next.changes_since(0), Some(vec![prefix]));
+
+        // A client which matches the user learns its capabilities along with its user ID.
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let sc_p = next.blind_phone_number(&c_p).expect("should be a valid point");
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &next.find_bucket(prefix))
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let h_u = next.unblind_proven(&req).expect("should be authorized").expect("should exist");
+        let (id, caps) =
+            client.finish_unblind_with_capabilities(22, &h_u).expect("should be a valid entry");
+        assert_eq!((Uuid::from(id), caps), (u, video));
+        assert!(caps.contains(video) && !caps.contains(Capabilities(1)));
+        assert_eq!(client.finish_unblind(22, &h_u), Ok(Some(u)));
+    }
+
+    #[test]
+    fn moved_capabilities() {
+        let video = Capabilities(1 << 7);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let users = [(22, Uuid::new_v4()), (22, Uuid::new_v4()), (22, Uuid::new_v4())];
+        let split = Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())]));
+        for server in [crate::tests::spilled_server(&users), crate::tests::split_bucket(split, 22)]
+        {
+            // Every entry for the phone number is updated, wherever it was moved to.
+            let next = server.set_capabilities([(22, video)]).expect("should update");
+            let mut bucket = next.find_bucket(prefix);
+            bucket.extend(next.find_bucket(client.sub_prefix(22)));
+            bucket.extend(next.overflow_bucket());
+            let sc_p = next.blind_phone_number(&c_p).expect("should be a valid point");
+            let n = bucket.len() as u32;
+            let req = client
+                .request_unblind(OsRng, 22, &sc_p, &bucket)
+                .expect("should be a valid response")
+                .expect("should be in the bucket");
+            for index in 0..n {
+                let mut req = req.clone();
+                req.index = index;
+                let h_u =
+                    next.unblind_proven(&req).expect("should be authorized").expect("should exist");
+                let (_, caps) = client
+                    .finish_unblind_with_capabilities(22, &h_u)
+                    .expect("should be a valid entry");
+                assert_eq!(caps, video);
+            }
+        }
+    }
+
+    #[test]
+    fn padded_updates() {
+        let (server, _) = ServerBuilder::new()
+            .min_bucket_size(4, SmallBucketPolicy::Pad)
+            .build(OsRng, [(22, Uuid::new_v4())])
+            .expect("should build");
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        let next = server.update(OsRng, [], [22]).expect("should update");
+        assert_eq!(next.find_bucket(prefix).len(), 4);
+        assert_ne!(next.find_bucket(prefix), server.find_bucket(prefix));
+    }
+
+    #[test]
+    fn full_buckets() {
+        let users = HashMap::from([(23, Uuid::new_v4()), (24, Uuid::new_v4())]);
+        let server = Server::new(OsRng, &users);
+
+        // Simulate a phone number whose hash prefix collides with that of 22.
+        let client = Client::new(OsRng);
+        let [p22, p23] = [22, 23].map(|p| client.request_phone_number(p).0);
+        let server = crate::tests::move_bucket(server, p23, p22, vec![]);
+        let server = Server { max_bucket_size: Some(1), ..server };
+
+        // Adding 22 would put two entries in a bucket with room for one.
+        assert_eq!(
+            server.update(OsRng, [(22, Uuid::new_v4())], []).err(),
+            Some(BuildError::BucketsTooLarge(1))
+        );
+        assert!(server.update(OsRng, [(25, Uuid::new_v4())], []).is_ok());
+    }
+
+    #[test]
+    fn tombstones() {
+        let (u1, u2) = (Uuid::new_v4(), Uuid::new_v4());
+        let server = Server::new(OsRng, &HashMap::from([(22, u1), (23, u1), (24, u2)]));
+        let client = Client::new(OsRng);
+        let prefixes = [22, 23, 24, 25].map(|p| client.request_phone_number(p).0);
+
+        // Remove one phone number, change another, and add a third, over two updates.
+        let next = server
+            .update(OsRng, [(23, u2)], [22, 23])
+            .and_then(|next| next.update(OsRng, [(25, u2)], []))
+            .expect("should update");
+        let deltas = next.deltas_since(0).expect("should have deltas");
+        assert_eq!(deltas.len(), 3);
+        assert!(deltas.iter().all(|delta| delta.removed.len() + delta.added.len() > 0));
+
+        // Applying the deltas to cached buckets brings them up to date.
+        for prefix in prefixes {
+            let mut bucket = server.find_bucket(prefix);
+            for delta in deltas.iter().filter(|delta| delta.prefix == prefix) {
+                let delta = BucketDelta::decode(&delta.encode()).expect("should be a valid delta");
+                delta.apply(&mut bucket);
+            }
+            assert_eq!(bucket, next.find_bucket(prefix));
+        }
+        assert_eq!(BucketDelta::decode(&deltas[0].encode()[1..]), Err(Error::InvalidMessage));
+        assert_eq!(next.deltas_since(2), Some(vec![]));
+        assert_eq!(next.rotate(OsRng).deltas_since(2), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Checkpoints of partially built servers, so interrupted builds can be resumed.
+//!
+//! A checkpoint file starts with a header of a magic number, a digest of the build's inputs, and the
+//! server secret, followed by a `(prefix, sP, hsU)` row for every entry blinded so far. Rows are
+//! appended and synced to disk after each chunk, and a partially written row left by a crash is
+//! discarded on resume.
+//!
+//! **N.B.:** Checkpoints contain the server secret and must be protected accordingly.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    iter,
+    path::Path,
+};
+
+use p256::{elliptic_curve::PrimeField, EncodedPoint, FieldBytes, Scalar};
+
+use crate::{encoding::POINT_LEN, BuildError, Prefix, PREFIX_LEN};
+
+/// The magic number at the start of every checkpoint file.
+const MAGIC: &[u8; 8] = b"ZKCDSCK1";
+
+/// The length of a checkpoint file's header, in bytes.
+const HEADER_LEN: usize = MAGIC.len() + 32 + 32;
+
+/// The length of a checkpointed row, in bytes.
+const ROW_LEN: usize = PREFIX_LEN + 2 * POINT_LEN;
+
+/// A blinded `(prefix, sP, hsU)` row.
+type Row = (Prefix, EncodedPoint, EncodedPoint);
+
+/// A checkpoint file being appended to.
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    file: File,
+    /// The server secret.
+    pub(crate) d_s: Scalar,
+    /// The number of rows blinded before the build was interrupted.
+    pub(crate) len: usize,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint at `path` for a build with the given input digest, creating it with the
+    /// given secret if it doesn't exist.
+    pub(crate) fn open(
+        path: &Path,
+        digest: &[u8; 32],
+        d_s: Scalar,
+    ) -> Result<Checkpoint, BuildError> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(false);
+
+        // Don't let other users read the secret.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path).map_err(io_error)?;
+        let file_len = file.metadata().map_err(io_error)?.len();
+
+        // Start a new checkpoint if the file is new or was interrupted while writing the header.
+        if file_len < HEADER_LEN as u64 {
+            file.set_len(0).map_err(io_error)?;
+            file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+            file.write_all(MAGIC).map_err(io_error)?;
+            file.write_all(digest).map_err(io_error)?;
+            file.write_all(&d_s.to_repr()).map_err(io_error)?;
+            file.sync_all().map_err(io_error)?;
+            return Ok(Checkpoint { file, d_s, len: 0 });
+        }
+
+        // Check the checkpoint is for this build.
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header).map_err(io_error)?;
+        if &header[..MAGIC.len()] != MAGIC || &header[MAGIC.len()..MAGIC.len() + 32] != digest {
+            return Err(BuildError::CheckpointMismatch);
+        }
+        let d_s: [u8; 32] = header[MAGIC.len() + 32..].try_into().expect("should be 32 bytes");
+        let d_s = Option::from(Scalar::from_repr(FieldBytes::from(d_s)))
+            .ok_or(BuildError::CheckpointMismatch)?;
+
+        // Count the complete rows, which are decoded as they're read back, and discard any
+        // partial one.
+        let len = (file_len - HEADER_LEN as u64) / ROW_LEN as u64;
+        file.set_len(HEADER_LEN as u64 + len * ROW_LEN as u64).map_err(io_error)?;
+        let len = usize::try_from(len).map_err(|_| BuildError::CheckpointMismatch)?;
+
+        Ok(Checkpoint { file, d_s, len })
+    }
+
+    /// Append the given rows to the checkpoint and sync it to disk.
+    pub(crate) fn append(
+        &mut self,
+        rows: &[(Prefix, EncodedPoint, EncodedPoint)],
+    ) -> Result<(), BuildError> {
+        let mut b = Vec::with_capacity(rows.len() * ROW_LEN);
+        for (prefix, s_p, hs_u) in rows {
+            b.extend_from_slice(prefix);
+            b.extend_from_slice(s_p.as_bytes());
+            b.extend_from_slice(hs_u.as_bytes());
+        }
+        self.file.seek(SeekFrom::End(0)).map_err(io_error)?;
+        self.file.write_all(&b).map_err(io_error)?;
+        self.file.sync_data().map_err(io_error)?;
+        self.len += rows.len();
+        Ok(())
+    }
+
+    /// Read back every row appended to the checkpoint, in order, without holding them all in
+    /// memory.
+    pub(crate) fn read_rows(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<Row, BuildError>> + '_, BuildError> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN as u64)).map_err(io_error)?;
+        let mut reader = BufReader::new(&self.file);
+        let mut row = [0u8; ROW_LEN];
+        Ok(iter::from_fn(move || match reader.read_exact(&mut row) {
+            Ok(()) => Some(decode_row(&row)),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(io_error(err))),
+        }))
+    }
+}
+
+/// Decode a checkpointed row.
+fn decode_row(row: &[u8]) -> Result<Row, BuildError> {
+    let (prefix, points) = row.split_at(PREFIX_LEN);
+    let (s_p, hs_u) = points.split_at(POINT_LEN);
+    Ok((
+        prefix.try_into().expect("should be a prefix"),
+        EncodedPoint::from_bytes(s_p).map_err(|_| BuildError::CheckpointMismatch)?,
+        EncodedPoint::from_bytes(hs_u).map_err(|_| BuildError::CheckpointMismatch)?,
+    ))
+}
+
+fn io_error(err: io::Error) -> BuildError {
+    BuildError::Checkpoint(err.kind())
+}
@@ -0,0 +1,483 @@
+//! Configuration of server deployments, loaded from TOML or YAML.
+//!
+//! A [`Config`] collects every parameter an operator chooses (how the directory is built, how
+//! often its secret is rotated, rate limits, and transport settings) in one place, so embedders
+//! and tools share a single, validated configuration surface. Every field has a default, so a
+//! config file need only list what it changes, and unknown fields are rejected rather than
+//! silently ignored:
+//!
+//! ```toml
+//! hash_function = "sha512-256"
+//! min_bucket_size = 8
+//! pad_small_buckets = true
+//! max_bucket_size = 64
+//! overflow_policy = "split"
+//!
+//! [pre_hash]
+//! kind = "peppered"
+//! pepper = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+//!
+//! [rate_limit]
+//! requests = 1000
+//! period_secs = 86400
+//! ```
+
+use std::{fmt, time::Duration};
+
+use serde::{
+    de::{self, Unexpected},
+    Deserialize, Deserializer,
+};
+
+use crate::{
+    builder::{ConflictPolicy, OverflowPolicy, ServerBuilder, SmallBucketPolicy},
+    metrics::MAX_PREFIX_BITS,
+    simulate::RateLimit,
+    HashFunction, PreHash, DEFAULT_BUCKET_CACHE_CAPACITY,
+};
+
+/// The configuration of a server deployment.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The hash function used to derive hash prefixes: `sha256`, `sha512-256`, or `blake3`.
+    pub hash_function: HashFunctionName,
+    /// The function phone numbers are pre-hashed with, if any.
+    pub pre_hash: Option<PreHashConfig>,
+    /// How to handle phone numbers listed more than once: `reject`, `last-write-wins`, or
+    /// `keep-all`.
+    pub conflict_policy: ConflictPolicyName,
+    /// The minimum number of entries in a non-empty bucket.
+    pub min_bucket_size: usize,
+    /// Whether to pad buckets smaller than the minimum with dummy entries, instead of failing.
+    pub pad_small_buckets: bool,
+    /// The maximum number of real entries in a bucket, if any.
+    pub max_bucket_size: Option<usize>,
+    /// How to handle buckets larger than the maximum: `reject`, `split`, or `spill`.
+    pub overflow_policy: OverflowPolicyName,
+    /// The per-client rate limit, if any.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// How often the server secret is rotated.
+    pub epochs: EpochConfig,
+    /// Settings for serving requests.
+    pub transport: TransportConfig,
+    /// Settings for differentially private metrics, if they're collected.
+    pub metrics: Option<MetricsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            hash_function: HashFunctionName::Sha256,
+            pre_hash: None,
+            conflict_policy: ConflictPolicyName::Reject,
+            min_bucket_size: 0,
+            pad_small_buckets: false,
+            max_bucket_size: None,
+            overflow_policy: OverflowPolicyName::Reject,
+            rate_limit: None,
+            epochs: EpochConfig::default(),
+            transport: TransportConfig::default(),
+            metrics: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse and validate a config from TOML.
+    pub fn from_toml(s: &str) -> Result<Config, ConfigError> {
+        let config: Config =
+            toml::from_str(s).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse and validate a config from YAML, with the same fields as TOML.
+    pub fn from_yaml(s: &str) -> Result<Config, ConfigError> {
+        let config: Config =
+            serde_yaml_ng::from_str(s).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the config's values are consistent and in range.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        #[cfg(not(feature = "blake3"))]
+        if self.hash_function == HashFunctionName::Blake3 {
+            return Err(ConfigError::Invalid("hash_function blake3 requires the blake3 feature"));
+        }
+        #[cfg(not(feature = "argon2"))]
+        if matches!(self.pre_hash, Some(PreHashConfig::Argon2id { .. })) {
+            return Err(ConfigError::Invalid("pre_hash argon2id requires the argon2 feature"));
+        }
+        #[cfg(feature = "argon2")]
+        if let Some(PreHashConfig::Argon2id { memory_kib, iterations, parallelism, .. }) =
+            self.pre_hash
+        {
+            if argon2::Params::new(memory_kib, iterations, parallelism, Some(32)).is_err() {
+                return Err(ConfigError::Invalid("pre_hash has invalid Argon2id parameters"));
+            }
+        }
+        if self.pad_small_buckets && self.min_bucket_size == 0 {
+            return Err(ConfigError::Invalid("pad_small_buckets requires a min_bucket_size"));
+        }
+        match self.max_bucket_size {
+            None if self.overflow_policy != OverflowPolicyName::Reject => {
+                return Err(ConfigError::Invalid("overflow_policy requires a max_bucket_size"));
+            }
+            Some(max) if max == 0 || max < self.min_bucket_size => {
+                return Err(ConfigError::Invalid("max_bucket_size should be at least the minimum"));
+            }
+            _ => {}
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.requests == 0 || rate_limit.period_secs == 0 {
+                return Err(ConfigError::Invalid("rate_limit should allow requests"));
+            }
+        }
+        if self.epochs.rotation_period_secs == 0 {
+            return Err(ConfigError::Invalid("epochs.rotation_period_secs should be positive"));
+        }
+        if self.epochs.overlap_secs >= self.epochs.rotation_period_secs {
+            return Err(ConfigError::Invalid("epochs.overlap_secs should be less than the period"));
+        }
+        if self.transport.max_in_flight == 0 {
+            return Err(ConfigError::Invalid("transport.max_in_flight should be positive"));
+        }
+        if self.transport.bucket_cache_capacity == 0 {
+            return Err(ConfigError::Invalid("transport.bucket_cache_capacity should be positive"));
+        }
+        if let Some(metrics) = &self.metrics {
+            if !(metrics.epsilon.is_finite() && metrics.epsilon > 0.0) {
+                return Err(ConfigError::Invalid("metrics.epsilon should be positive"));
+            }
+            if metrics.prefix_bits > MAX_PREFIX_BITS {
+                return Err(ConfigError::Invalid("metrics.prefix_bits is too large"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a [`ServerBuilder`] configured by this config.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the config names a hash function or pre-hash which isn't enabled, which
+    /// [`Config::validate`] checks.
+    pub fn server_builder(&self) -> ServerBuilder {
+        let policy =
+            if self.pad_small_buckets { SmallBucketPolicy::Pad } else { SmallBucketPolicy::Reject };
+        let builder = ServerBuilder::new()
+            .hash_function(self.hash_function.into())
+            .pre_hash(self.pre_hash.map_or(PreHash::None, PreHash::from))
+            .conflict_policy(self.conflict_policy.into())
+            .min_bucket_size(self.min_bucket_size, policy)
+            .bucket_cache_capacity(self.transport.bucket_cache_capacity);
+        match self.max_bucket_size {
+            Some(max) => builder.max_bucket_size(max, self.overflow_policy.into()),
+            None => builder,
+        }
+    }
+}
+
+/// The name of a [`HashFunction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HashFunctionName {
+    /// [`HashFunction::Sha256`].
+    #[serde(rename = "sha256")]
+    Sha256,
+    /// [`HashFunction::Sha512_256`].
+    #[serde(rename = "sha512-256")]
+    Sha512_256,
+    /// `HashFunction::Blake3`, which requires the `blake3` feature.
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl From<HashFunctionName> for HashFunction {
+    fn from(value: HashFunctionName) -> Self {
+        match value {
+            HashFunctionName::Sha256 => HashFunction::Sha256,
+            HashFunctionName::Sha512_256 => HashFunction::Sha512_256,
+            #[cfg(feature = "blake3")]
+            HashFunctionName::Blake3 => HashFunction::Blake3,
+            #[cfg(not(feature = "blake3"))]
+            HashFunctionName::Blake3 => panic!("blake3 feature should be enabled"),
+        }
+    }
+}
+
+/// The name of a [`ConflictPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicyName {
+    /// [`ConflictPolicy::Reject`].
+    Reject,
+    /// [`ConflictPolicy::LastWriteWins`].
+    LastWriteWins,
+    /// [`ConflictPolicy::KeepAll`].
+    KeepAll,
+}
+
+impl From<ConflictPolicyName> for ConflictPolicy {
+    fn from(value: ConflictPolicyName) -> Self {
+        match value {
+            ConflictPolicyName::Reject => ConflictPolicy::Reject,
+            ConflictPolicyName::LastWriteWins => ConflictPolicy::LastWriteWins,
+            ConflictPolicyName::KeepAll => ConflictPolicy::KeepAll,
+        }
+    }
+}
+
+/// A [`PreHash`], selected by its `kind`: `argon2id` or `peppered`.
+///
+/// Salts and peppers are hex-encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PreHashConfig {
+    /// `PreHash::Argon2id`, which requires the `argon2` feature.
+    Argon2id {
+        /// The memory cost, in KiB.
+        memory_kib: u32,
+        /// The number of iterations.
+        iterations: u32,
+        /// The degree of parallelism.
+        parallelism: u32,
+        /// The per-deployment salt, as 16 hex-encoded bytes.
+        #[serde(deserialize_with = "hex")]
+        salt: [u8; 16],
+    },
+    /// [`PreHash::Peppered`].
+    Peppered {
+        /// The deployment's pepper, as 32 hex-encoded bytes.
+        #[serde(deserialize_with = "hex")]
+        pepper: [u8; 32],
+    },
+}
+
+impl From<PreHashConfig> for PreHash {
+    fn from(value: PreHashConfig) -> Self {
+        match value {
+            #[cfg(feature = "argon2")]
+            PreHashConfig::Argon2id { memory_kib, iterations, parallelism, salt } => {
+                PreHash::Argon2id { memory_kib, iterations, parallelism, salt }
+            }
+            #[cfg(not(feature = "argon2"))]
+            PreHashConfig::Argon2id { .. } => panic!("argon2 feature should be enabled"),
+            PreHashConfig::Peppered { pepper } => PreHash::Peppered { pepper },
+        }
+    }
+}
+
+/// Deserialize a fixed-length array of bytes from a hex string.
+fn hex<'de, D: Deserializer<'de>, const N: usize>(d: D) -> Result<[u8; N], D::Error> {
+    let s = String::deserialize(d)?;
+    let invalid = || de::Error::invalid_value(Unexpected::Str(&s), &"hex-encoded bytes");
+    if s.len() != 2 * N || !s.is_ascii() {
+        return Err(invalid());
+    }
+    let mut b = [0u8; N];
+    for (b, i) in b.iter_mut().zip((0..s.len()).step_by(2)) {
+        *b = u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(b)
+}
+
+/// The name of an [`OverflowPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicyName {
+    /// [`OverflowPolicy::Reject`].
+    Reject,
+    /// [`OverflowPolicy::Split`].
+    Split,
+    /// [`OverflowPolicy::Spill`].
+    Spill,
+}
+
+impl From<OverflowPolicyName> for OverflowPolicy {
+    fn from(value: OverflowPolicyName) -> Self {
+        match value {
+            OverflowPolicyName::Reject => OverflowPolicy::Reject,
+            OverflowPolicyName::Split => OverflowPolicy::Split,
+            OverflowPolicyName::Spill => OverflowPolicy::Spill,
+        }
+    }
+}
+
+/// A per-client rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// The number of requests allowed per period.
+    pub requests: u64,
+    /// The length of the period, in seconds.
+    pub period_secs: u64,
+}
+
+impl From<RateLimitConfig> for RateLimit {
+    fn from(value: RateLimitConfig) -> Self {
+        RateLimit { requests: value.requests, period: Duration::from_secs(value.period_secs) }
+    }
+}
+
+/// Settings for rotating the server secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EpochConfig {
+    /// How often the server secret is rotated, in seconds.
+    pub rotation_period_secs: u64,
+    /// How long the previous epoch is still served after a rotation, in seconds.
+    pub overlap_secs: u64,
+    /// The number of past epochs kept resident after the overlap window.
+    pub retained: usize,
+}
+
+impl Default for EpochConfig {
+    fn default() -> Self {
+        EpochConfig { rotation_period_secs: 7 * 24 * 60 * 60, overlap_secs: 10 * 60, retained: 0 }
+    }
+}
+
+impl EpochConfig {
+    /// How often the server secret is rotated.
+    pub fn rotation_period(&self) -> Duration {
+        Duration::from_secs(self.rotation_period_secs)
+    }
+
+    /// How long the previous epoch is still served after a rotation.
+    pub fn overlap(&self) -> Duration {
+        Duration::from_secs(self.overlap_secs)
+    }
+}
+
+/// Settings for serving requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    /// The maximum number of requests processed concurrently.
+    pub max_in_flight: usize,
+    /// The maximum number of encoded buckets cached.
+    pub bucket_cache_capacity: usize,
+    /// How long clients should wait before retrying rejected requests, in seconds.
+    pub retry_after_secs: u64,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            max_in_flight: 64,
+            bucket_cache_capacity: DEFAULT_BUCKET_CACHE_CAPACITY,
+            retry_after_secs: 5,
+        }
+    }
+}
+
+/// Settings for differentially private metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The privacy budget of each report.
+    pub epsilon: f64,
+    /// The number of bits of each hash prefix which bucket accesses are counted by.
+    pub prefix_bits: u8,
+}
+
+/// An error returned when loading a [`Config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The config couldn't be parsed.
+    Parse(String),
+    /// The config had an invalid value.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(err) => write!(f, "invalid config: {err}"),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn loading() {
+        let config = Config::from_toml(
+            r#"
+            hash_function = "sha512-256"
+            conflict_policy = "keep-all"
+            min_bucket_size = 8
+            pad_small_buckets = true
+
+            [rate_limit]
+            requests = 1000
+            period_secs = 86400
+
+            [transport]
+            max_in_flight = 16
+            "#,
+        )
+        .expect("should be a valid config");
+        assert_eq!(config.hash_function, HashFunctionName::Sha512_256);
+        assert_eq!(config.transport.max_in_flight, 16);
+        assert_eq!(config.transport.bucket_cache_capacity, DEFAULT_BUCKET_CACHE_CAPACITY);
+        assert_eq!(
+            config.rate_limit.map(RateLimit::from),
+            Some(RateLimit { requests: 1000, period: Duration::from_secs(86400) })
+        );
+        assert_eq!(Config::from_toml(""), Ok(Config::default()));
+
+        // Unknown fields and inconsistent values are rejected.
+        assert!(matches!(Config::from_toml("prefix_len = 8"), Err(ConfigError::Parse(_))));
+        assert_eq!(
+            Config::from_toml("pad_small_buckets = true"),
+            Err(ConfigError::Invalid("pad_small_buckets requires a min_bucket_size"))
+        );
+        assert_eq!(
+            Config::from_toml("overflow_policy = \"split\""),
+            Err(ConfigError::Invalid("overflow_policy requires a max_bucket_size"))
+        );
+        assert!(matches!(
+            Config::from_toml("pre_hash = { kind = \"peppered\", pepper = \"00\" }"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn server_builder() {
+        let yaml = format!(
+            "
+            pre_hash:
+              kind: peppered
+              pepper: \"{}\"
+            max_bucket_size: 2
+            overflow_policy: spill
+            transport:
+              bucket_cache_capacity: 16
+            ",
+            "2a".repeat(32)
+        );
+        let config = Config::from_yaml(&yaml).expect("should be a valid config");
+        assert_eq!(config.pre_hash, Some(PreHashConfig::Peppered { pepper: [0x2a; 32] }));
+        assert_eq!(config.max_bucket_size, Some(2));
+
+        // Every field is passed on to the builder.
+        let users = [(1, Uuid::new_v4()), (1, Uuid::new_v4()), (1, Uuid::new_v4())];
+        let builder = config.server_builder().conflict_policy(ConflictPolicy::KeepAll);
+        let (server, stats) = builder.build(OsRng, users).expect("should build");
+        assert_eq!(server.pre_hash(), PreHash::Peppered { pepper: [0x2a; 32] });
+        assert_eq!(server.overflow_bucket().len(), 1);
+        assert_eq!(stats.large_buckets.len(), 1);
+        assert_eq!(server.cache.capacity(), 16);
+    }
+}
@@ -0,0 +1,120 @@
+//! Deadlines and cooperative cancellation for batch operations.
+//!
+//! Blinding a batch of phone numbers costs one scalar multiplication each, so a large batch whose
+//! client has given up can keep a core busy long after anyone is waiting for the answer. Batch
+//! operations which take a [`Deadline`] check it every [`CHECK_INTERVAL`] items, and stop with
+//! [`Error::DeadlineExceeded`] once it has passed or it has been [cancelled](Deadline::cancel),
+//! e.g. by another thread when the client disconnects.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::Error;
+
+/// Batch operations check their deadline before each run of this many items.
+pub const CHECK_INTERVAL: usize = 64;
+
+/// A point in time after which a request's remaining work should be abandoned, which can also be
+/// cancelled early.
+///
+/// Clones of a deadline share its cancellation.
+#[derive(Debug, Clone, Default)]
+pub struct Deadline {
+    at: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    /// A deadline which never passes, unless it's cancelled.
+    pub fn never() -> Deadline {
+        Deadline::default()
+    }
+
+    /// A deadline which passes at the given instant.
+    pub fn at(at: Instant) -> Deadline {
+        Deadline { at: Some(at), ..Deadline::default() }
+    }
+
+    /// A deadline which passes after the given duration.
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline::at(Instant::now() + timeout)
+    }
+
+    /// The instant the deadline passes, if any.
+    pub fn instant(&self) -> Option<Instant> {
+        self.at
+    }
+
+    /// Cancel the deadline, and every clone of it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if the deadline has passed or been cancelled.
+    pub fn is_expired(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Return [`Error::DeadlineExceeded`] if the deadline has passed or been cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_expired() {
+            return Err(Error::DeadlineExceeded);
+        }
+        Ok(())
+    }
+
+    /// Map `f` over the given items, checking the deadline before each run of [`CHECK_INTERVAL`]
+    /// items.
+    pub(crate) fn map<T, U>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        mut f: impl FnMut(T) -> U,
+    ) -> Result<Vec<U>, Error> {
+        let items = items.into_iter();
+        let mut out = Vec::with_capacity(items.size_hint().0);
+        for (i, item) in items.enumerate() {
+            if i.is_multiple_of(CHECK_INTERVAL) {
+                self.check()?;
+            }
+            out.push(f(item));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let deadline = Deadline::never();
+        assert_eq!(deadline.check(), Ok(()));
+        let clone = deadline.clone();
+        clone.cancel();
+        assert_eq!(deadline.check(), Err(Error::DeadlineExceeded));
+
+        assert!(Deadline::at(Instant::now()).is_expired());
+        assert!(!Deadline::after(Duration::from_secs(3600)).is_expired());
+    }
+
+    #[test]
+    fn cooperative_cancellation() {
+        // Work stops at the first check after the deadline is cancelled.
+        let deadline = Deadline::never();
+        let mut done = 0;
+        let res = deadline.map(0..1000, |i| {
+            if i == 100 {
+                deadline.cancel();
+            }
+            done += 1;
+        });
+        assert_eq!(res, Err(Error::DeadlineExceeded));
+        assert_eq!(done, 2 * CHECK_INTERVAL);
+    }
+}
@@ -0,0 +1,94 @@
+//! Decoy lookups which hide when a client is really looking up phone numbers.
+//!
+//! A client which only talks to the server when syncing its address book reveals when it syncs and
+//! how many contacts it has. A [`DecoyScheduler`] tells a client when to send dummy lookups, at
+//! exponentially distributed intervals, and
+//! [`ClientSession::send_decoy`](crate::session::ClientSession::send_decoy) sends one: a request for
+//! the bucket with a uniformly random prefix and for the blinding of a uniformly random point. Real
+//! prefixes are truncated SHA-256 hashes and real blinded points are uniformly random without the
+//! client's secret, so neither the server nor the network can tell decoys from real lookups.
+
+use std::time::{Duration, Instant};
+
+use p256::{
+    elliptic_curve::{sec1::ToEncodedPoint, Group},
+    EncodedPoint, ProjectivePoint,
+};
+use rand::{CryptoRng, Rng, RngCore};
+
+use crate::Prefix;
+
+/// A schedule of decoy lookups.
+#[derive(Debug)]
+pub struct DecoyScheduler<R> {
+    rng: R,
+    mean_interval: Duration,
+    next_at: Instant,
+}
+
+impl<R: CryptoRng + RngCore> DecoyScheduler<R> {
+    /// Create a scheduler which sends decoys at random intervals averaging `mean_interval`,
+    /// starting at `now`.
+    pub fn new(mut rng: R, mean_interval: Duration, now: Instant) -> DecoyScheduler<R> {
+        let next_at = now + next_delay(&mut rng, mean_interval);
+        DecoyScheduler { rng, mean_interval, next_at }
+    }
+
+    /// When the next decoy is due.
+    pub fn next_at(&self) -> Instant {
+        self.next_at
+    }
+
+    /// Return the number of decoys due as of `now`, scheduling the ones after them.
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let mut due = 0;
+        while self.next_at <= now {
+            due += 1;
+            self.next_at += next_delay(&mut self.rng, self.mean_interval);
+        }
+        due
+    }
+}
+
+/// Return an exponentially distributed delay with the given mean, so decoys form a Poisson process.
+fn next_delay(rng: &mut impl RngCore, mean: Duration) -> Duration {
+    // Never schedule a zero delay, which would make `poll` loop forever.
+    mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()).max(Duration::from_nanos(1))
+}
+
+/// Return a random prefix and a random point, indistinguishable from a real lookup request.
+pub(crate) fn decoy_request(rng: &mut impl RngCore) -> (Prefix, EncodedPoint) {
+    let mut prefix = Prefix::default();
+    rng.fill_bytes(&mut prefix);
+    (prefix, ProjectivePoint::random(rng).to_affine().to_encoded_point(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{session::ClientSession, testing::MockServer};
+
+    #[test]
+    fn decoys() {
+        let start = Instant::now();
+        let mut scheduler = DecoyScheduler::new(OsRng, Duration::from_secs(60), start);
+        assert!(scheduler.next_at() > start);
+
+        // About one decoy per minute is due.
+        let due = scheduler.poll(start + Duration::from_secs(60 * 1000));
+        assert!((800..1200).contains(&due), "{due} decoys due");
+        assert!(scheduler.next_at() > start + Duration::from_secs(60 * 1000));
+
+        // Decoys are valid requests.
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let mut server = MockServer::new(OsRng, &users);
+        let mut session = ClientSession::new(&mut server, OsRng);
+        assert_eq!(session.send_decoy(), Ok(()));
+    }
+}
@@ -0,0 +1,355 @@
+//! Separation of the trusted build, which sees plaintext, from serving, which doesn't.
+//!
+//! A [`Server`] can both ingest plaintext address books (e.g. with [`Server::update`] or
+//! [`Server::verify`]) and serve lookups, so every binary which serves lookups has code paths which
+//! handle raw phone numbers. Instead, a [`DirectoryBuilder`] runs in a trusted build environment and
+//! produces a [`SealedDirectory`] of blinded rows and a [`KeyHandle`] for the server secret, which
+//! are shipped separately (e.g. the key via a KMS). A [`DirectoryServer`] opens them and only serves
+//! lookups: none of its methods take phone numbers, so the serving binary's audit scope excludes
+//! them.
+
+use std::{fmt, hash::BuildHasher};
+
+use p256::{
+    elliptic_curve::{Field, PrimeField},
+    EncodedPoint, FieldBytes, Scalar,
+};
+use rand::{CryptoRng, RngCore};
+use uuid::Uuid;
+
+use crate::{
+    arena::encoded_point,
+    bundle::Bundle,
+    changes::{BucketDelta, ChangeLog},
+    encoding::POINT_LEN,
+    protocol::ServerInfo,
+    unblind::UnblindRequest,
+    BuildError, BuildStats, Conditional, EncodedBucket, Epoch, Error, HashFunction, Prefix, Server,
+    ServerBuilder, PREFIX_LEN,
+};
+
+/// The magic bytes which start an encoded [`SealedDirectory`].
+const MAGIC: &[u8; 8] = b"ZKCDSDR2";
+
+/// The length of an encoded [`SealedDirectory`]'s header, without its split buckets' prefixes, in
+/// bytes.
+const HEADER_LEN: usize = MAGIC.len() + 8 + 1 + 8 + 8 + 8;
+
+/// The length of a row in an encoded [`SealedDirectory`], in bytes.
+const ROW_LEN: usize = PREFIX_LEN + 2 * POINT_LEN;
+
+/// Builds sealed directories from plaintext address books in a trusted environment.
+#[derive(Debug, Clone)]
+pub struct DirectoryBuilder<S> {
+    builder: ServerBuilder<S>,
+}
+
+impl<S: BuildHasher + Clone> DirectoryBuilder<S> {
+    /// Create a directory builder which blinds address books as the given server builder does.
+    pub fn new(builder: ServerBuilder<S>) -> DirectoryBuilder<S> {
+        DirectoryBuilder { builder }
+    }
+
+    /// Build a sealed directory with a random secret from the given phone numbers and user IDs,
+    /// returning it along with the secret's key handle and statistics about the build.
+    pub fn build(
+        self,
+        rng: impl CryptoRng + RngCore,
+        users: impl IntoIterator<Item = (u64, Uuid)>,
+    ) -> Result<(SealedDirectory, KeyHandle, BuildStats), BuildError> {
+        let (server, stats) = self.builder.build(rng, users)?;
+        Ok((SealedDirectory::seal(&server), KeyHandle(server.d_s), stats))
+    }
+}
+
+/// A handle to a server secret, kept apart from the directory it blinded.
+#[derive(Clone)]
+pub struct KeyHandle(Scalar);
+
+impl KeyHandle {
+    /// Encode the secret, e.g. for storage in a KMS.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes().into()
+    }
+
+    /// Decode a secret, returning [`BuildError::InvalidSecret`] if it isn't a non-zero scalar.
+    pub fn from_bytes(b: &[u8; 32]) -> Result<KeyHandle, BuildError> {
+        Option::from(Scalar::from_repr(FieldBytes::from(*b)))
+            .filter(|d_s: &Scalar| !bool::from(d_s.is_zero()))
+            .map(KeyHandle)
+            .ok_or(BuildError::InvalidSecret)
+    }
+}
+
+impl fmt::Debug for KeyHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyHandle").finish_non_exhaustive()
+    }
+}
+
+/// A directory of blinded rows, without its secret or any plaintext.
+///
+/// Rows are sorted by prefix and then by `sP`, so identical directories have byte-identical
+/// encodings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedDirectory {
+    epoch: Epoch,
+    hash: HashFunction,
+    min_bucket_size: usize,
+    max_bucket_size: Option<usize>,
+    split: Vec<Prefix>,
+    rows: Vec<(Prefix, EncodedPoint, EncodedPoint)>,
+}
+
+impl SealedDirectory {
+    fn seal<S: BuildHasher>(server: &Server<S>) -> SealedDirectory {
+        let rows = server
+            .buckets
+            .sorted()
+            .into_iter()
+            .flat_map(|(prefix, bucket)| {
+                bucket
+                    .iter()
+                    .map(move |(s_p, hs_u)| (prefix, encoded_point(s_p), encoded_point(hs_u)))
+            })
+            .collect();
+        SealedDirectory {
+            epoch: server.epoch,
+            hash: server.hash,
+            min_bucket_size: server.min_bucket_size,
+            max_bucket_size: server.max_bucket_size,
+            split: server.buckets.split().to_vec(),
+            rows,
+        }
+    }
+
+    /// The epoch of the directory's secret.
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    /// Encode the directory as a header of magic bytes, epoch, hash function ID, minimum and maximum
+    /// bucket sizes (all ones if uncapped), and number of split buckets, followed by the split
+    /// buckets' prefixes and its rows.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(
+            HEADER_LEN + self.split.len() * PREFIX_LEN + self.rows.len() * ROW_LEN,
+        );
+        b.extend_from_slice(MAGIC);
+        b.extend_from_slice(&self.epoch.to_be_bytes());
+        b.push(self.hash.id());
+        b.extend_from_slice(&(self.min_bucket_size as u64).to_be_bytes());
+        b.extend_from_slice(&self.max_bucket_size.map_or(u64::MAX, |n| n as u64).to_be_bytes());
+        b.extend_from_slice(&(self.split.len() as u64).to_be_bytes());
+        self.split.iter().for_each(|prefix| b.extend_from_slice(prefix));
+        for (prefix, s_p, hs_u) in &self.rows {
+            b.extend_from_slice(prefix);
+            b.extend_from_slice(s_p.as_bytes());
+            b.extend_from_slice(hs_u.as_bytes());
+        }
+        b
+    }
+
+    /// Decode a directory. Its points are only checked when it's [opened](DirectoryServer::open).
+    pub fn decode(b: &[u8]) -> Result<SealedDirectory, Error> {
+        if b.len() < HEADER_LEN || &b[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidMessage);
+        }
+        let (header, b) = b.split_at(HEADER_LEN);
+        let epoch = Epoch::from_be_bytes(header[8..16].try_into().expect("should be 8 bytes"));
+        let hash = HashFunction::from_id(header[16]).ok_or(Error::InvalidMessage)?;
+        let min_bucket_size =
+            u64::from_be_bytes(header[17..25].try_into().expect("should be 8 bytes"));
+        let max_bucket_size =
+            u64::from_be_bytes(header[25..33].try_into().expect("should be 8 bytes"));
+        let split = u64::from_be_bytes(header[33..].try_into().expect("should be 8 bytes"));
+        let split_len = usize::try_from(split)
+            .ok()
+            .and_then(|n| n.checked_mul(PREFIX_LEN))
+            .filter(|&n| n <= b.len())
+            .ok_or(Error::InvalidMessage)?;
+        let (split, rows) = b.split_at(split_len);
+        if !rows.len().is_multiple_of(ROW_LEN) {
+            return Err(Error::InvalidMessage);
+        }
+        let split = split
+            .chunks_exact(PREFIX_LEN)
+            .map(|prefix| prefix.try_into().expect("should be a prefix"))
+            .collect();
+        let point = |b: &[u8]| EncodedPoint::from_bytes(b).map_err(|_| Error::InvalidPoint);
+        let rows = rows
+            .chunks_exact(ROW_LEN)
+            .map(|row| {
+                let (prefix, points) = row.split_at(PREFIX_LEN);
+                let (s_p, hs_u) = points.split_at(POINT_LEN);
+                Ok((prefix.try_into().expect("should be a prefix"), point(s_p)?, point(hs_u)?))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(SealedDirectory {
+            epoch,
+            hash,
+            min_bucket_size: usize::try_from(min_bucket_size).map_err(|_| Error::InvalidMessage)?,
+            max_bucket_size: (max_bucket_size != u64::MAX)
+                .then(|| usize::try_from(max_bucket_size).map_err(|_| Error::InvalidMessage))
+                .transpose()?,
+            split,
+            rows,
+        })
+    }
+}
+
+/// A server which only serves lookups from a sealed directory.
+#[derive(Debug)]
+pub struct DirectoryServer(Server);
+
+impl DirectoryServer {
+    /// Open the given sealed directory with its key handle, checking its rows as
+    /// [`Server::from_blinded_rows`] does.
+    pub fn open(key: &KeyHandle, sealed: SealedDirectory) -> Result<DirectoryServer, BuildError> {
+        let server = Server::from_blinded_rows(key.0, sealed.rows)?;
+        Ok(DirectoryServer(Server {
+            epoch: sealed.epoch,
+            hash: sealed.hash,
+            min_bucket_size: sealed.min_bucket_size,
+            max_bucket_size: sealed.max_bucket_size,
+            buckets: server.buckets.with_split(sealed.split),
+            changes: ChangeLog::new(sealed.epoch),
+            ..server
+        }))
+    }
+
+    /// The epoch of the server's secret.
+    pub fn epoch(&self) -> Epoch {
+        self.0.epoch()
+    }
+
+    /// Describe the server's deployment for clients.
+    pub fn info(&self) -> ServerInfo {
+        self.0.info()
+    }
+
+    /// Return a digest of the server's epoch, public key, and buckets.
+    pub fn digest(&self) -> [u8; 32] {
+        self.0.digest()
+    }
+
+    /// The server's public key, `d_s·G`.
+    pub fn public_key(&self) -> EncodedPoint {
+        self.0.public_key()
+    }
+
+    /// Return an estimate of the number of bytes of heap memory used by the server's buckets.
+    pub fn heap_size(&self) -> usize {
+        self.0.heap_size()
+    }
+
+    /// Create a server for the next epoch with a new random secret, returning it along with its
+    /// re-sealed directory and the new secret's key handle, which must be stored in place of the
+    /// old ones for the rotated server to be opened again.
+    pub fn rotate(
+        &self,
+        rng: impl CryptoRng + RngCore,
+    ) -> (DirectoryServer, SealedDirectory, KeyHandle) {
+        let server = self.0.rotate(rng);
+        let (sealed, key) = (SealedDirectory::seal(&server), KeyHandle(server.d_s));
+        (DirectoryServer(server), sealed, key)
+    }
+
+    /// Given a hash prefix, return the encoded bucket of users.
+    pub fn encoded_bucket(&self, prefix: Prefix) -> EncodedBucket {
+        self.0.encoded_bucket(prefix)
+    }
+
+    /// Given a hash prefix and the value of an HTTP `If-None-Match` header, if any, return the
+    /// encoded bucket of users only if the client doesn't already have it.
+    pub fn encoded_bucket_if_none_match(
+        &self,
+        prefix: Prefix,
+        if_none_match: Option<&str>,
+    ) -> Conditional {
+        self.0.encoded_bucket_if_none_match(prefix, if_none_match)
+    }
+
+    /// Return every bucket in a single range-addressable bundle.
+    pub fn export_bundle(&self) -> Bundle {
+        self.0.export_bundle()
+    }
+
+    /// Given a client-blinded phone number point, return a double-blinded phone number point.
+    pub fn blind_phone_number(&self, c_p: &EncodedPoint) -> Result<EncodedPoint, Error> {
+        self.0.blind_phone_number(c_p)
+    }
+
+    /// Given a strict unblind request, check its proof and return the requested user ID point,
+    /// still blinded by the phone number's hash.
+    pub fn unblind_proven(&self, req: &UnblindRequest) -> Result<Option<EncodedPoint>, Error> {
+        self.0.unblind_proven(req)
+    }
+
+    /// Return a delta for each bucket which changed after the given epoch, or `None` if every
+    /// bucket must be refetched.
+    pub fn deltas_since(&self, epoch: Epoch) -> Option<Vec<BucketDelta>> {
+        self.0.deltas_since(epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::Client;
+
+    #[test]
+    fn sealing() {
+        let users = [(22, Uuid::new_v4()), (23, Uuid::new_v4())];
+        let (sealed, key, stats) =
+            DirectoryBuilder::new(ServerBuilder::new()).build(OsRng, users).expect("should build");
+        assert_eq!(stats.phone_numbers, 2);
+
+        // The directory and key survive transport separately.
+        let sealed =
+            SealedDirectory::decode(&sealed.encode()).expect("should be a valid directory");
+        let key = KeyHandle::from_bytes(&key.to_bytes()).expect("should be a valid key");
+        assert_eq!(format!("{key:?}"), "KeyHandle { .. }");
+        let server = DirectoryServer::open(&key, sealed.clone()).expect("should open");
+
+        // The server answers strict lookups.
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let bucket = crate::decode_bucket(&server.encoded_bucket(prefix).bytes)
+            .expect("should be a valid bucket");
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &bucket)
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let h_u = server.unblind_proven(&req).expect("should be authorized").expect("should exist");
+        assert_eq!(client.finish_unblind(22, &h_u), Ok(Some(users[0].1)));
+
+        // A different key doesn't open the directory to the same server.
+        let other = KeyHandle::from_bytes(&[7; 32]).expect("should be a valid key");
+        let other = DirectoryServer::open(&other, sealed).expect("should open");
+        assert_ne!(other.digest(), server.digest());
+        assert_eq!(KeyHandle::from_bytes(&[0; 32]).err(), Some(BuildError::InvalidSecret));
+
+        // A rotated server can be opened again from its new directory and key.
+        let (rotated, sealed, key) = server.rotate(OsRng);
+        assert_eq!(rotated.epoch(), server.epoch() + 1);
+        assert_eq!(sealed.epoch(), rotated.epoch());
+        let reopened = DirectoryServer::open(&key, sealed).expect("should open");
+        assert_eq!(reopened.digest(), rotated.digest());
+    }
+
+    #[test]
+    fn sealed_split_buckets() {
+        let server = Server::new(OsRng, &crate::HashMap::from([(22, Uuid::new_v4())]));
+        let server = crate::tests::split_bucket(server, 22);
+        let sealed = SealedDirectory::decode(&SealedDirectory::seal(&server).encode())
+            .expect("should be a valid directory");
+        let opened = DirectoryServer::open(&KeyHandle(server.d_s), sealed).expect("should open");
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        assert!(opened.0.is_split(prefix));
+        assert_eq!(opened.digest(), server.digest());
+    }
+}
@@ -0,0 +1,111 @@
+//! Dummy bucket entries for padding.
+//!
+//! Padding only hides how many real users share a bucket if a dummy entry can't be told apart from
+//! a real one. A real entry is a pair of compressed points, `sP = d_s·H(p)` and `hsU`, each a
+//! uniformly distributed non-identity point of P-256. A dummy entry is a pair of points `r·G` for
+//! independent, uniformly random non-zero scalars `r`, which have exactly the same distribution, so
+//! their encodings are equally likely to have either tag byte and have uniformly distributed
+//! x-coordinates.
+//!
+//! Dummy entries are generated so their `sP` points never collide with a real entry's (or each
+//! other's), so padding can never shadow a real user's entry in a bucket.
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, EncodedPoint, NonZeroScalar, ProjectivePoint};
+use rand::{CryptoRng, RngCore};
+
+/// Return a random point `r·G` for a random non-zero scalar `r`.
+fn random_point(rng: &mut (impl CryptoRng + RngCore)) -> EncodedPoint {
+    let r = NonZeroScalar::random(rng);
+    (ProjectivePoint::GENERATOR * *r).to_affine().to_encoded_point(true)
+}
+
+/// Return a random dummy `(sP, hsU)` entry.
+pub fn dummy_entry(mut rng: impl CryptoRng + RngCore) -> (EncodedPoint, EncodedPoint) {
+    (random_point(&mut rng), random_point(&mut rng))
+}
+
+/// Return `n` random dummy `(sP, hsU)` entries with distinct `sP` points, none of which `exists`.
+pub fn dummy_entries(
+    mut rng: impl CryptoRng + RngCore,
+    n: usize,
+    exists: impl Fn(&EncodedPoint) -> bool,
+) -> Vec<(EncodedPoint, EncodedPoint)> {
+    let mut entries: Vec<(EncodedPoint, EncodedPoint)> = Vec::with_capacity(n);
+    while entries.len() < n {
+        let (s_p, hs_u) = dummy_entry(&mut rng);
+        if !exists(&s_p) && entries.iter().all(|(other, _)| *other != s_p) {
+            entries.push((s_p, hs_u));
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{arena::encoded_point, Server};
+
+    /// The fraction of points with an odd y-coordinate, the mean of their x-coordinate bytes, and
+    /// the chi-squared statistic of their x-coordinate byte frequencies.
+    fn stats<'a>(points: impl Iterator<Item = &'a EncodedPoint>) -> (f64, f64, f64) {
+        let (mut n, mut odd, mut counts) = (0usize, 0usize, [0usize; 256]);
+        for p in points {
+            n += 1;
+            odd += usize::from(p.as_bytes()[0] == 0x03);
+            p.as_bytes()[1..].iter().for_each(|&b| counts[usize::from(b)] += 1);
+        }
+        let bytes = (n * 32) as f64;
+        let mean = counts.iter().enumerate().map(|(b, &c)| (b * c) as f64).sum::<f64>() / bytes;
+        let expected = bytes / 256.0;
+        let chi2 = counts.iter().map(|&c| (c as f64 - expected).powi(2) / expected).sum();
+        (odd as f64 / n as f64, mean, chi2)
+    }
+
+    #[test]
+    fn indistinguishable_from_real_entries() {
+        let mut rng = ChaChaRng::seed_from_u64(22);
+        let users = (0..2000u64).map(|p| (p, Uuid::from_u128(p.into()))).collect::<HashMap<_, _>>();
+        let server = Server::new(&mut rng, &users);
+        let real = server
+            .buckets
+            .sorted()
+            .into_iter()
+            .flat_map(|(_, bucket)| bucket.iter())
+            .map(|(s_p, hs_u)| (encoded_point(s_p), encoded_point(hs_u)))
+            .collect::<Vec<_>>();
+        let dummies = dummy_entries(&mut rng, real.len(), |_| false);
+
+        // Both kinds of entry have the same distribution of tags and x-coordinate bytes: half odd,
+        // with a mean byte of 127.5, and byte frequencies consistent with uniformity (the 99.9th
+        // percentile of the chi-squared distribution with 255 degrees of freedom is about 330).
+        for entries in [&real, &dummies] {
+            for points in [
+                stats(entries.iter().map(|(s_p, _)| s_p)),
+                stats(entries.iter().map(|(_, hs_u)| hs_u)),
+            ] {
+                let (odd, mean, chi2) = points;
+                assert!((odd - 0.5).abs() < 0.05, "tag bias: {odd}");
+                assert!((mean - 127.5).abs() < 2.0, "byte bias: {mean}");
+                assert!(chi2 < 330.0, "non-uniform bytes: {chi2}");
+            }
+        }
+    }
+
+    #[test]
+    fn never_collides() {
+        let rng = ChaChaRng::seed_from_u64(22);
+        let real = dummy_entry(ChaChaRng::seed_from_u64(22)).0;
+
+        // A generator seeded like the one which produced a real point skips it.
+        let dummies = dummy_entries(rng, 4, |s_p| *s_p == real);
+        assert_eq!(dummies.len(), 4);
+        assert!(dummies.iter().all(|(s_p, _)| *s_p != real));
+        assert!(dummies.iter().all(|(s_p, hs_u)| s_p != hs_u));
+    }
+}
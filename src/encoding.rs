@@ -0,0 +1,440 @@
+//! The wire encodings of buckets.
+//!
+//! A bucket is a set of `(sP, hsU)` entries, each a pair of 33-byte compressed SEC1 points, sorted
+//! by `sP` so that identical buckets always have identical encodings. There are three encodings:
+//!
+//! * [`BucketEncoding::Legacy`]: the concatenation of the entries.
+//! * [`BucketEncoding::Tagged`]: a tag byte followed by the concatenation of the entries.
+//! * [`BucketEncoding::Truncated`]: a tag byte, a key length byte, and the concatenation of the
+//!   entries with each `sP` truncated to the first `key_len` bytes of its x-coordinate.
+//!
+//! Because a legacy-encoded bucket is either empty or starts with a compressed point's `0x02` or
+//! `0x03` tag, [`decode_any_bucket`] can tell the encodings apart, allowing servers to migrate from
+//! one to another without coordinating with every client.
+//!
+//! Decoders don't depend on the order of entries, so servers which don't want responses to carry
+//! any ordering signal at all (e.g. which entries of a padded bucket were added together) can
+//! [shuffle](encode_bucket_shuffled) each response's entries instead. Shuffled encodings aren't
+//! canonical, so they can't be cached or compared by content hash.
+
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+};
+
+use p256::EncodedPoint;
+use rand::{seq::SliceRandom, CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+
+use crate::{Error, Prefix};
+
+/// The length of a compressed point, in bytes.
+pub const POINT_LEN: usize = 33;
+
+/// The length of an encoded bucket entry, in bytes.
+pub const ENTRY_LEN: usize = 2 * POINT_LEN;
+
+/// The tag byte of a [`BucketEncoding::Tagged`] bucket.
+const TAG_FULL: u8 = 0x10;
+
+/// The tag byte of a [`BucketEncoding::Truncated`] bucket.
+const TAG_TRUNCATED: u8 = 0x11;
+
+/// The minimum length of a truncated `sP` key, in bytes.
+pub const MIN_KEY_LEN: u8 = 8;
+
+/// The compressed encoding of a point, which is compared in constant time.
+///
+/// Comparing a received encoding with a stored one using `==` on bytes returns as soon as they
+/// differ, so timing could reveal how much of a stored `sP` a guess matched. `CtPointBytes`
+/// implements [`ConstantTimeEq`], and its [`PartialEq`] uses it.
+#[derive(Debug, Clone, Copy, Eq)]
+pub struct CtPointBytes(pub [u8; POINT_LEN]);
+
+impl CtPointBytes {
+    /// Wrap the given point's encoding, or return `None` if it isn't compressed.
+    pub fn new(p: &EncodedPoint) -> Option<CtPointBytes> {
+        p.as_bytes().try_into().ok().map(CtPointBytes)
+    }
+}
+
+impl ConstantTimeEq for CtPointBytes {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.0.ct_eq(&other.0)
+    }
+}
+
+impl PartialEq for CtPointBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+/// A bucket of `(sP, hsU)` entries which can be searched by `sP`.
+pub trait BucketLookup {
+    /// Return the `hsU` point for the given `sP` point, if any.
+    fn get_entry(&self, s_p: &EncodedPoint) -> Option<&EncodedPoint>;
+}
+
+impl<S: BuildHasher> BucketLookup for HashMap<EncodedPoint, EncodedPoint, S> {
+    fn get_entry(&self, s_p: &EncodedPoint) -> Option<&EncodedPoint> {
+        self.get(s_p)
+    }
+}
+
+/// An encoding of a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketEncoding {
+    /// The concatenation of the bucket's entries.
+    Legacy,
+    /// A tag byte followed by the concatenation of the bucket's entries.
+    Tagged,
+    /// A tag byte and key length followed by the bucket's entries, with each `sP` truncated.
+    Truncated {
+        /// The number of bytes of each `sP` point's x-coordinate to keep, from [`MIN_KEY_LEN`] to
+        /// 32.
+        key_len: u8,
+    },
+}
+
+/// A bucket decoded from any of the [`BucketEncoding`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedBucket {
+    /// A bucket with full `sP` points.
+    Full(HashMap<EncodedPoint, EncodedPoint>),
+    /// A bucket with truncated `sP` points.
+    Truncated {
+        /// The number of bytes of each `sP` point's x-coordinate which were kept.
+        key_len: usize,
+        /// The bucket's entries, keyed by truncated x-coordinate.
+        entries: HashMap<Vec<u8>, EncodedPoint>,
+    },
+}
+
+impl BucketLookup for DecodedBucket {
+    fn get_entry(&self, s_p: &EncodedPoint) -> Option<&EncodedPoint> {
+        match self {
+            DecodedBucket::Full(entries) => entries.get(s_p),
+            DecodedBucket::Truncated { key_len, entries } => {
+                // The identity point's encoding has no x-coordinate to truncate.
+                s_p.as_bytes().get(1..1 + key_len).and_then(|k| entries.get(k))
+            }
+        }
+    }
+}
+
+/// Encode the given bucket using the [`BucketEncoding::Legacy`] encoding.
+pub fn encode_bucket<S>(bucket: &HashMap<EncodedPoint, EncodedPoint, S>) -> Vec<u8> {
+    encode_bucket_as(bucket, BucketEncoding::Legacy)
+}
+
+/// Encode the given bucket using the given encoding.
+///
+/// # Panics
+///
+/// Panics if a [`BucketEncoding::Truncated`] key length is less than [`MIN_KEY_LEN`] or more than
+/// 32.
+pub fn encode_bucket_as<S>(
+    bucket: &HashMap<EncodedPoint, EncodedPoint, S>,
+    encoding: BucketEncoding,
+) -> Vec<u8> {
+    let mut entries = bucket.iter().collect::<Vec<_>>();
+    entries.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+    encode_entries(&entries, encoding)
+}
+
+/// Like [`encode_bucket_as`], but with the entries in a random order drawn from `rng`, rather than
+/// sorted.
+///
+/// # Panics
+///
+/// Panics if a [`BucketEncoding::Truncated`] key length is less than [`MIN_KEY_LEN`] or more than
+/// 32.
+pub fn encode_bucket_shuffled<S>(
+    bucket: &HashMap<EncodedPoint, EncodedPoint, S>,
+    encoding: BucketEncoding,
+    mut rng: impl CryptoRng + RngCore,
+) -> Vec<u8> {
+    // Sort the entries first, so the shuffle depends only on the RNG and not on the map's order.
+    let mut entries = bucket.iter().collect::<Vec<_>>();
+    entries.sort_unstable_by(|(a, _), (b, _)| a.as_bytes().cmp(b.as_bytes()));
+    entries.shuffle(&mut rng);
+    encode_entries(&entries, encoding)
+}
+
+/// Encode the given entries, in order, using the given encoding.
+fn encode_entries(entries: &[(&EncodedPoint, &EncodedPoint)], encoding: BucketEncoding) -> Vec<u8> {
+    let mut b = Vec::with_capacity(2 + entries.len() * ENTRY_LEN);
+    let key = match encoding {
+        BucketEncoding::Legacy => 0..POINT_LEN,
+        BucketEncoding::Tagged => {
+            b.push(TAG_FULL);
+            0..POINT_LEN
+        }
+        BucketEncoding::Truncated { key_len } => {
+            assert!((MIN_KEY_LEN..=32).contains(&key_len), "invalid key length");
+            b.extend([TAG_TRUNCATED, key_len]);
+            1..1 + usize::from(key_len)
+        }
+    };
+
+    for (s_p, hs_u) in entries {
+        b.extend_from_slice(&s_p.as_bytes()[key.clone()]);
+        b.extend_from_slice(hs_u.as_bytes());
+    }
+    b
+}
+
+/// Decode a [`BucketEncoding::Legacy`] bucket from the concatenation of its `(sP, hsU)` entries.
+pub fn decode_bucket(b: &[u8]) -> Result<HashMap<EncodedPoint, EncodedPoint>, Error> {
+    if !b.len().is_multiple_of(ENTRY_LEN) {
+        return Err(Error::InvalidMessage);
+    }
+
+    b.chunks_exact(ENTRY_LEN)
+        .map(|entry| {
+            let s_p =
+                EncodedPoint::from_bytes(&entry[..POINT_LEN]).map_err(|_| Error::InvalidPoint)?;
+            let hs_u =
+                EncodedPoint::from_bytes(&entry[POINT_LEN..]).map_err(|_| Error::InvalidPoint)?;
+            Ok((s_p, hs_u))
+        })
+        .collect()
+}
+
+/// Decode a bucket in any of the [`BucketEncoding`]s.
+pub fn decode_any_bucket(b: &[u8]) -> Result<DecodedBucket, Error> {
+    match b.first() {
+        None | Some(0x02 | 0x03) => decode_bucket(b).map(DecodedBucket::Full),
+        Some(&TAG_FULL) => decode_bucket(&b[1..]).map(DecodedBucket::Full),
+        Some(&TAG_TRUNCATED) => {
+            let key_len = usize::from(*b.get(1).ok_or(Error::InvalidMessage)?);
+            let entries = &b[2..];
+            if !(usize::from(MIN_KEY_LEN)..=32).contains(&key_len)
+                || !entries.len().is_multiple_of(key_len + POINT_LEN)
+            {
+                return Err(Error::InvalidMessage);
+            }
+
+            let entries = entries
+                .chunks_exact(key_len + POINT_LEN)
+                .map(|entry| {
+                    let hs_u = EncodedPoint::from_bytes(&entry[key_len..])
+                        .map_err(|_| Error::InvalidPoint)?;
+                    Ok((entry[..key_len].to_vec(), hs_u))
+                })
+                .collect::<Result<_, Error>>()?;
+            Ok(DecodedBucket::Truncated { key_len, entries })
+        }
+        Some(_) => Err(Error::InvalidMessage),
+    }
+}
+
+/// An encoded bucket and its content hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedBucket {
+    /// The encoded bucket.
+    pub bytes: Arc<[u8]>,
+    /// The SHA-256 hash of the encoded bucket.
+    pub digest: [u8; 32],
+}
+
+impl EncodedBucket {
+    /// Encode the given bucket and hash its encoding.
+    pub fn new<S>(bucket: &HashMap<EncodedPoint, EncodedPoint, S>) -> EncodedBucket {
+        let bytes = encode_bucket(bucket);
+        let digest = Sha256::digest(&bytes).into();
+        EncodedBucket { bytes: bytes.into(), digest }
+    }
+
+    /// The bucket's content hash as a strong HTTP entity tag (i.e. a quoted hex string).
+    pub fn etag(&self) -> String {
+        let mut etag = String::with_capacity(2 * self.digest.len() + 2);
+        etag.push('"');
+        for b in self.digest {
+            etag.push_str(&format!("{b:02x}"));
+        }
+        etag.push('"');
+        etag
+    }
+
+    /// Returns `true` if the value of an HTTP `If-None-Match` header matches this bucket's entity
+    /// tag, using the weak comparison function from RFC 9110.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        let etag = self.etag();
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    }
+}
+
+/// The response to a conditional request for a bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conditional {
+    /// The client's copy of the bucket is current.
+    NotModified,
+    /// The client's copy of the bucket, if any, is out of date.
+    Modified(EncodedBucket),
+}
+
+/// A bounded cache of encoded buckets, keyed by prefix.
+#[derive(Debug)]
+pub(crate) struct BucketCache {
+    capacity: usize,
+    entries: Mutex<HashMap<Prefix, EncodedBucket>>,
+}
+
+impl BucketCache {
+    pub(crate) fn new(capacity: usize) -> BucketCache {
+        BucketCache { capacity, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return the cached encoding of the bucket with the given prefix, encoding and caching it if
+    /// necessary.
+    pub(crate) fn get_or_encode<S: BuildHasher>(
+        &self,
+        prefix: Prefix,
+        bucket: impl FnOnce() -> HashMap<EncodedPoint, EncodedPoint, S>,
+    ) -> EncodedBucket {
+        if let Some(encoded) = self.entries.lock().expect("should not be poisoned").get(&prefix) {
+            return encoded.clone();
+        }
+
+        // Encode the bucket without holding the lock.
+        let encoded = EncodedBucket::new(&bucket());
+
+        // Make room for the bucket by evicting an arbitrary entry, if needed, then cache it.
+        let mut entries = self.entries.lock().expect("should not be poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&prefix) {
+            if let Some(&evicted) = entries.keys().next() {
+                entries.remove(&evicted);
+            }
+        }
+        if self.capacity > 0 {
+            entries.insert(prefix, encoded.clone());
+        }
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::OsRng, SeedableRng};
+    use rand_chacha::ChaChaRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, HashFunction, Server, ServerBuilder, SmallBucketPolicy};
+
+    #[test]
+    fn constant_time_points() {
+        let (_, a) = Client::new(OsRng).request_phone_number(22);
+        let (_, b) = Client::new(OsRng).request_phone_number(22);
+        let ct = |p| CtPointBytes::new(p).expect("should be compressed");
+        assert_eq!(ct(&a), ct(&a));
+        assert_ne!(ct(&a), ct(&b));
+        assert!(bool::from(ct(&a).ct_eq(&ct(&a))));
+        assert_eq!(CtPointBytes::new(&EncodedPoint::identity()), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+        let server = Server::new(OsRng, &users).with_bucket_cache_capacity(1);
+        let prefix = crate::prefix(&HashFunction::default().hash(&22u64.to_be_bytes()));
+
+        let encoded = server.encoded_bucket(prefix);
+        assert_eq!(decode_bucket(&encoded.bytes), Ok(server.find_bucket(prefix)));
+        assert_eq!(encoded.etag().len(), 66);
+        assert_eq!(
+            decode_any_bucket(&encoded.bytes),
+            Ok(DecodedBucket::Full(server.find_bucket(prefix)))
+        );
+
+        // Cached buckets are shared.
+        assert!(Arc::ptr_eq(&encoded.bytes, &server.encoded_bucket(prefix).bytes));
+
+        // Conditional requests with a matching entity tag aren't sent the bucket again.
+        let etag = encoded.etag();
+        assert_eq!(
+            server.encoded_bucket_if_none_match(prefix, None),
+            Conditional::Modified(encoded)
+        );
+        assert_eq!(
+            server.encoded_bucket_if_none_match(prefix, Some(&format!("\"abc\", W/{etag}"))),
+            Conditional::NotModified
+        );
+        assert!(matches!(
+            server.encoded_bucket_if_none_match(prefix, Some("\"abc\"")),
+            Conditional::Modified(_)
+        ));
+    }
+
+    #[test]
+    fn encodings() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let server = Server::new(OsRng, &users);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(1234567890);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let bucket = server.find_bucket(prefix);
+
+        for encoding in [
+            BucketEncoding::Legacy,
+            BucketEncoding::Tagged,
+            BucketEncoding::Truncated { key_len: 8 },
+            BucketEncoding::Truncated { key_len: 32 },
+        ] {
+            let decoded = decode_any_bucket(&encode_bucket_as(&bucket, encoding))
+                .expect("should be a valid bucket");
+            let s_u = client
+                .find_user_id(&sc_p, &decoded, 1234567890)
+                .expect("should be a valid response")
+                .expect("should be a valid phone number");
+            assert_eq!(server.unblind_user_id(&s_u), users.get(&1234567890).cloned());
+        }
+
+        // A server which double-blinds the phone number to the identity point doesn't crash the
+        // client.
+        let truncated = encode_bucket_as(&bucket, BucketEncoding::Truncated { key_len: 8 });
+        let truncated = decode_any_bucket(&truncated).expect("should be a valid bucket");
+        assert_eq!(
+            client.find_user_id(&EncodedPoint::identity(), &truncated, 1234567890),
+            Ok(None)
+        );
+
+        assert_eq!(decode_any_bucket(&[0x11, 4]), Err(Error::InvalidMessage));
+        assert_eq!(decode_any_bucket(&[0xFF]), Err(Error::InvalidMessage));
+    }
+
+    #[test]
+    fn shuffled() {
+        let users = [(22, Uuid::new_v4())];
+        let (server, _) = ServerBuilder::new()
+            .min_bucket_size(8, SmallBucketPolicy::Pad)
+            .build(OsRng, users)
+            .expect("should build");
+        let prefix = crate::prefix(&HashFunction::default().hash(&22u64.to_be_bytes()));
+        let bucket = server.find_bucket(prefix);
+        let sorted = encode_bucket_as(&bucket, BucketEncoding::Tagged);
+
+        // Shuffled buckets decode to the same entries, in an order which depends only on the seed.
+        let shuffle = |seed| {
+            encode_bucket_shuffled(&bucket, BucketEncoding::Tagged, ChaChaRng::seed_from_u64(seed))
+        };
+        assert_eq!(shuffle(1), shuffle(1));
+        assert_eq!(decode_any_bucket(&shuffle(1)), decode_any_bucket(&sorted));
+
+        // The first entry of a response is any of the bucket's entries, not always the same one.
+        let firsts = (0..200).map(|seed| shuffle(seed)[1..1 + ENTRY_LEN].to_vec());
+        assert_eq!(firsts.collect::<std::collections::HashSet<_>>().len(), 8);
+    }
+}
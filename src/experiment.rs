@@ -0,0 +1,301 @@
+//! A/B experiments with deployment parameters.
+//!
+//! Padding and rate limits trade bandwidth against privacy, and it's hard to predict what a change
+//! costs clients before making it globally. An [`Experiment`] serves each labeled [`Cohort`] of
+//! clients from its own [`Arm`]: a [`LookupService`] (e.g. for a server built with different
+//! padding) and an optional per-client [`RateLimit`]. The embedder assigns clients to cohorts (e.g.
+//! by hashing their account IDs), and requests from clients outside every cohort are served by the
+//! control arm. Each arm keeps its own [`CohortMetrics`], so operators can compare the bandwidth
+//! and error rates of the cohorts before changing everyone's configuration.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use tower::Service;
+
+use crate::{
+    budget::AccountId,
+    encoding::ENTRY_LEN,
+    service::{LookupFuture, LookupService, Request, Response},
+    simulate::RateLimit,
+    Error,
+};
+
+/// The label of a cohort of clients.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cohort(pub String);
+
+impl From<&str> for Cohort {
+    fn from(value: &str) -> Self {
+        Cohort(value.to_string())
+    }
+}
+
+/// A request from a client in a cohort.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CohortRequest {
+    /// The client's cohort, or `None` for the control cohort.
+    pub cohort: Option<Cohort>,
+    /// The client's account, which rate limits are applied to.
+    pub account: AccountId,
+    /// The request.
+    pub request: Request,
+}
+
+/// The metrics of a cohort.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CohortMetrics {
+    /// The number of lookup requests made.
+    pub requests: u64,
+    /// The number of lookup requests refused by the arm's rate limit.
+    pub rate_limited: u64,
+    /// The number of lookup requests which failed for any other reason.
+    pub errors: u64,
+    /// The number of phone numbers blinded.
+    pub phone_numbers: u64,
+    /// The number of bucket entries returned, including padding.
+    pub bucket_entries: u64,
+}
+
+impl CohortMetrics {
+    /// The number of bytes of bucket entries returned.
+    pub fn bucket_bytes(&self) -> u64 {
+        self.bucket_entries * ENTRY_LEN as u64
+    }
+}
+
+/// The configuration a cohort is served with, and its metrics.
+#[derive(Debug)]
+pub struct Arm<S = RandomState> {
+    service: LookupService<S>,
+    rate_limit: Option<RateLimit>,
+    windows: Mutex<HashMap<AccountId, (Instant, u64)>>,
+    metrics: Mutex<CohortMetrics>,
+}
+
+impl<S> Arm<S> {
+    /// Create an arm which serves requests from the given service, without a rate limit.
+    pub fn new(service: LookupService<S>) -> Arm<S> {
+        Arm {
+            service,
+            rate_limit: None,
+            windows: Mutex::new(HashMap::new()),
+            metrics: Mutex::default(),
+        }
+    }
+
+    /// Refuse each client's lookup requests beyond the given limit with [`Error::QuotaExhausted`].
+    pub fn with_rate_limit(self, rate_limit: RateLimit) -> Arm<S> {
+        Arm { rate_limit: Some(rate_limit), ..self }
+    }
+
+    /// Count a lookup request from the given account, returning whether it's within the rate limit.
+    fn admit(&self, account: AccountId) -> bool {
+        let mut metrics = self.metrics.lock().expect("should not be poisoned");
+        metrics.requests += 1;
+        let Some(limit) = self.rate_limit else {
+            return true;
+        };
+
+        // Start a new window for clients whose last one has ended, forgetting idle clients.
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("should not be poisoned");
+        windows.retain(|_, (start, _)| now.duration_since(*start) < limit.period);
+        let (_, n) = windows.entry(account).or_insert((now, 0));
+        if *n >= limit.requests {
+            metrics.rate_limited += 1;
+            return false;
+        }
+        *n += 1;
+        true
+    }
+
+    /// Record the response to a lookup request.
+    fn record(&self, res: &Result<Response<S>, Error>) {
+        let mut metrics = self.metrics.lock().expect("should not be poisoned");
+        let resp = match res {
+            Ok(Response::Tagged(_, resp)) => resp,
+            Ok(resp) => resp,
+            Err(_) => {
+                metrics.errors += 1;
+                return;
+            }
+        };
+        match resp {
+            Response::Buckets(buckets) => {
+                metrics.bucket_entries += buckets.iter().map(|b| b.len() as u64).sum::<u64>();
+            }
+            Response::BlindedPhoneNumbers(sc_ps, _) => metrics.phone_numbers += sc_ps.len() as u64,
+            _ => {}
+        }
+    }
+}
+
+/// A [`Service`] which serves each cohort of clients from its own [`Arm`].
+#[derive(Debug)]
+pub struct Experiment<S = RandomState> {
+    control: Cohort,
+    arms: Arc<HashMap<Cohort, Arc<Arm<S>>>>,
+}
+
+impl<S> Experiment<S> {
+    /// Create an experiment which serves the control cohort, and clients outside every other
+    /// cohort, from the given arm.
+    pub fn new(control: Cohort, arm: Arm<S>) -> Experiment<S> {
+        let arms = HashMap::from([(control.clone(), Arc::new(arm))]);
+        Experiment { control, arms: Arc::new(arms) }
+    }
+
+    /// Serve the given cohort from the given arm, replacing any previous arm for it.
+    pub fn with_cohort(mut self, cohort: Cohort, arm: Arm<S>) -> Experiment<S> {
+        Arc::make_mut(&mut self.arms).insert(cohort, Arc::new(arm));
+        self
+    }
+
+    /// The metrics of the given cohort, if the experiment has it.
+    pub fn metrics(&self, cohort: &Cohort) -> Option<CohortMetrics> {
+        let arm = self.arms.get(cohort)?;
+        Some(*arm.metrics.lock().expect("should not be poisoned"))
+    }
+
+    /// The metrics of every cohort.
+    pub fn report(&self) -> HashMap<Cohort, CohortMetrics> {
+        self.arms
+            .iter()
+            .map(|(cohort, arm)| {
+                (cohort.clone(), *arm.metrics.lock().expect("should not be poisoned"))
+            })
+            .collect()
+    }
+
+    /// The arm for the given cohort, falling back to the control arm.
+    fn arm(&self, cohort: Option<&Cohort>) -> Arc<Arm<S>> {
+        cohort
+            .and_then(|cohort| self.arms.get(cohort))
+            .unwrap_or_else(|| &self.arms[&self.control])
+            .clone()
+    }
+}
+
+impl<S> Clone for Experiment<S> {
+    fn clone(&self) -> Self {
+        Experiment { control: self.control.clone(), arms: self.arms.clone() }
+    }
+}
+
+impl<S: BuildHasher + Clone> Service<CohortRequest> for Experiment<S> {
+    type Response = Response<S>;
+    type Error = Error;
+    type Future = ExperimentFuture<S>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: CohortRequest) -> ExperimentFuture<S> {
+        let arm = self.arm(req.cohort.as_ref());
+
+        // Only lookups are counted and rate limited.
+        let lookup = is_lookup(&req.request);
+        if lookup && !arm.admit(req.account) {
+            return ExperimentFuture { inner: None, arm: None };
+        }
+        let inner = arm.service.clone().call(req.request);
+        ExperimentFuture { inner: Some(inner), arm: lookup.then_some(arm) }
+    }
+}
+
+/// The response future of an [`Experiment`].
+#[derive(Debug)]
+pub struct ExperimentFuture<S = RandomState> {
+    inner: Option<LookupFuture<S>>,
+    arm: Option<Arc<Arm<S>>>,
+}
+
+impl<S: BuildHasher + Clone> Future for ExperimentFuture<S> {
+    type Output = Result<Response<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Err(Error::QuotaExhausted));
+        };
+        let res = Pin::new(inner).poll(cx);
+        if let (Poll::Ready(res), Some(arm)) = (&res, &self.arm) {
+            arm.record(res);
+        }
+        res
+    }
+}
+
+/// Whether the request is a lookup, rather than a health check or metadata request.
+fn is_lookup(req: &Request) -> bool {
+    match req {
+        Request::Tagged(_, req) => is_lookup(req),
+        Request::FindBuckets(_)
+        | Request::BlindPhoneNumbers(_)
+        | Request::BlindWithTokens(..)
+        | Request::UnblindUserId(..)
+        | Request::UnblindProven(..) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, Server, ServerBuilder, SmallBucketPolicy};
+
+    #[tokio::test]
+    async fn cohorts() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let control = LookupService::new(Arc::new(Server::new(OsRng, &users)));
+        let (padded, _) = ServerBuilder::new()
+            .min_bucket_size(4, SmallBucketPolicy::Pad)
+            .build(OsRng, users.clone())
+            .expect("should build");
+        let padded = Arm::new(LookupService::new(Arc::new(padded)))
+            .with_rate_limit(RateLimit { requests: 1, period: Duration::from_secs(3600) });
+        let mut experiment = Experiment::new(Cohort::from("control"), Arm::new(control))
+            .with_cohort(Cohort::from("padded"), padded);
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        let request = |cohort: Option<&str>| CohortRequest {
+            cohort: cohort.map(Cohort::from),
+            account: AccountId(1),
+            request: Request::FindBuckets(vec![prefix]),
+        };
+
+        // Unknown cohorts get the control arm, and each arm serves its own padding.
+        experiment.call(request(None)).await.expect("should find buckets");
+        experiment.call(request(Some("other"))).await.expect("should find buckets");
+        experiment.call(request(Some("padded"))).await.expect("should find buckets");
+        let control = experiment.metrics(&Cohort::from("control")).expect("should have metrics");
+        assert_eq!((control.requests, control.bucket_entries), (2, 2));
+        let padded = experiment.metrics(&Cohort::from("padded")).expect("should have metrics");
+        assert_eq!((padded.requests, padded.bucket_entries), (1, 4));
+        assert_eq!(padded.bucket_bytes(), 4 * ENTRY_LEN as u64);
+
+        // Rate limits only apply to their own cohort, and not to health checks.
+        let health = CohortRequest { request: Request::Health, ..request(Some("padded")) };
+        experiment.call(health).await.expect("should be healthy");
+        assert!(matches!(
+            experiment.call(request(Some("padded"))).await,
+            Err(Error::QuotaExhausted)
+        ));
+        experiment.call(request(None)).await.expect("should find buckets");
+        let report = experiment.report();
+        assert_eq!(report[&Cohort::from("padded")].rate_limited, 1);
+        assert_eq!(report[&Cohort::from("control")].rate_limited, 0);
+    }
+}
@@ -0,0 +1,267 @@
+//! C bindings for the client side of the protocol.
+//!
+//! Points are passed as 33-byte compressed SEC1 encodings, prefixes as 8-byte arrays, and buckets
+//! as the concatenation of their `(sP, hsU)` entries, each 66 bytes long. Clients are allocated by
+//! [`zk_cds_client_new`] and must be released with [`zk_cds_client_free`]. All other buffers are
+//! owned by the caller.
+//!
+//! The C header is in `include/zk_cds.h`. It's generated from this module by
+//! [cbindgen](https://github.com/mozilla/cbindgen) with the repository's `cbindgen.toml`, and CI
+//! checks it's up to date:
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --crate zk-cds --output include/zk_cds.h
+//! ```
+
+use std::slice;
+
+use p256::EncodedPoint;
+use rand::rngs::OsRng;
+
+use crate::{decode_bucket, Client, PREFIX_LEN};
+
+/// The length of a compressed point, in bytes.
+pub const ZK_CDS_POINT_LEN: usize = 33;
+
+/// The length of a hash prefix, in bytes.
+pub const ZK_CDS_PREFIX_LEN: usize = 8;
+
+const _: () = assert!(ZK_CDS_PREFIX_LEN == PREFIX_LEN);
+
+/// The result of a client operation.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZkCdsStatus {
+    /// The operation succeeded.
+    Ok = 0,
+    /// The phone number was not found in the bucket.
+    NotFound = 1,
+    /// A required pointer argument was null.
+    NullPointer = -1,
+    /// A point or bucket was not validly encoded.
+    InvalidPoint = -2,
+}
+
+/// An opaque handle to a client.
+#[derive(Debug)]
+pub struct ZkCdsClient {
+    client: Client,
+}
+
+/// Create a new client using a random secret. The returned client must be released with
+/// [`zk_cds_client_free`].
+#[no_mangle]
+pub extern "C" fn zk_cds_client_new() -> *mut ZkCdsClient {
+    Box::into_raw(Box::new(ZkCdsClient { client: Client::new(OsRng) }))
+}
+
+/// Release a client created by [`zk_cds_client_new`]. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `client` must be null or a pointer returned by [`zk_cds_client_new`] which has not already been
+/// released.
+#[no_mangle]
+pub unsafe extern "C" fn zk_cds_client_free(client: *mut ZkCdsClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Initiate a client request for the given phone number, writing the hash prefix of the phone
+/// number to `prefix_out` and the blinded phone number point to `c_p_out`.
+///
+/// # Safety
+///
+/// `client` must be a live client, `prefix_out` must be valid for writes of
+/// [`ZK_CDS_PREFIX_LEN`] bytes, and `c_p_out` must be valid for writes of [`ZK_CDS_POINT_LEN`]
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zk_cds_client_request(
+    client: *const ZkCdsClient,
+    p: u64,
+    prefix_out: *mut u8,
+    c_p_out: *mut u8,
+) -> ZkCdsStatus {
+    if client.is_null() || prefix_out.is_null() || c_p_out.is_null() {
+        return ZkCdsStatus::NullPointer;
+    }
+
+    let (prefix, c_p) = (*client).client.request_phone_number(p);
+    slice::from_raw_parts_mut(prefix_out, ZK_CDS_PREFIX_LEN).copy_from_slice(&prefix);
+    slice::from_raw_parts_mut(c_p_out, ZK_CDS_POINT_LEN).copy_from_slice(c_p.as_bytes());
+    ZkCdsStatus::Ok
+}
+
+/// Given a double-blinded phone number point and bucket of users from the server, write the
+/// unblinded user ID point to `s_u_out` if one can be found.
+///
+/// Returns [`ZkCdsStatus::NotFound`] and leaves `s_u_out` untouched if the phone number is not in
+/// the bucket.
+///
+/// # Safety
+///
+/// `client` must be a live client, `sc_p` must be valid for reads of [`ZK_CDS_POINT_LEN`] bytes,
+/// `bucket` must be valid for reads of `bucket_len` bytes, and `s_u_out` must be valid for writes
+/// of [`ZK_CDS_POINT_LEN`] bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zk_cds_client_find(
+    client: *const ZkCdsClient,
+    sc_p: *const u8,
+    bucket: *const u8,
+    bucket_len: usize,
+    p: u64,
+    s_u_out: *mut u8,
+) -> ZkCdsStatus {
+    if client.is_null() || sc_p.is_null() || bucket.is_null() || s_u_out.is_null() {
+        return ZkCdsStatus::NullPointer;
+    }
+
+    let Ok(sc_p) = EncodedPoint::from_bytes(slice::from_raw_parts(sc_p, ZK_CDS_POINT_LEN)) else {
+        return ZkCdsStatus::InvalidPoint;
+    };
+    let Ok(bucket) = decode_bucket(slice::from_raw_parts(bucket, bucket_len)) else {
+        return ZkCdsStatus::InvalidPoint;
+    };
+
+    match (*client).client.find_user_id(&sc_p, &bucket, p) {
+        Ok(Some(s_u)) => {
+            slice::from_raw_parts_mut(s_u_out, ZK_CDS_POINT_LEN).copy_from_slice(s_u.as_bytes());
+            ZkCdsStatus::Ok
+        }
+        Ok(None) => ZkCdsStatus::NotFound,
+        Err(_) => ZkCdsStatus::InvalidPoint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::Server;
+
+    #[test]
+    fn round_trip() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let server = Server::new(OsRng, &users);
+
+        let client = zk_cds_client_new();
+        let mut prefix = [0u8; ZK_CDS_PREFIX_LEN];
+        let mut c_p = [0u8; ZK_CDS_POINT_LEN];
+        let status = unsafe {
+            zk_cds_client_request(client, 1234567890, prefix.as_mut_ptr(), c_p.as_mut_ptr())
+        };
+        assert_eq!(status, ZkCdsStatus::Ok);
+
+        let bucket = server
+            .find_bucket(prefix)
+            .iter()
+            .flat_map(|(s_p, hs_u)| [s_p.as_bytes(), hs_u.as_bytes()].concat())
+            .collect::<Vec<u8>>();
+        let sc_p = server
+            .blind_phone_number(&EncodedPoint::from_bytes(c_p).expect("should be valid"))
+            .expect("should be a valid point");
+
+        let mut s_u = [0u8; ZK_CDS_POINT_LEN];
+        let status = unsafe {
+            zk_cds_client_find(
+                client,
+                sc_p.as_bytes().as_ptr(),
+                bucket.as_ptr(),
+                bucket.len(),
+                1234567890,
+                s_u.as_mut_ptr(),
+            )
+        };
+        assert_eq!(status, ZkCdsStatus::Ok);
+
+        let s_u = EncodedPoint::from_bytes(s_u).expect("should be valid");
+        assert_eq!(server.unblind_user_id(&s_u), users.get(&1234567890).cloned());
+
+        unsafe { zk_cds_client_free(client) };
+    }
+
+    #[test]
+    fn errors() {
+        let server = Server::new(OsRng, &HashMap::from([(1234567890, Uuid::new_v4())]));
+        let client = zk_cds_client_new();
+        let mut prefix = [0u8; ZK_CDS_PREFIX_LEN];
+        let mut c_p = [0u8; ZK_CDS_POINT_LEN];
+        let mut s_u = [0u8; ZK_CDS_POINT_LEN];
+        let null = std::ptr::null_mut();
+
+        // Null arguments are rejected.
+        unsafe {
+            assert_eq!(
+                zk_cds_client_request(null, 22, prefix.as_mut_ptr(), c_p.as_mut_ptr()),
+                ZkCdsStatus::NullPointer
+            );
+            assert_eq!(
+                zk_cds_client_request(client, 22, null.cast(), c_p.as_mut_ptr()),
+                ZkCdsStatus::NullPointer
+            );
+            assert_eq!(
+                zk_cds_client_request(client, 22, prefix.as_mut_ptr(), null.cast()),
+                ZkCdsStatus::NullPointer
+            );
+            assert_eq!(
+                zk_cds_client_find(client, c_p.as_ptr(), null.cast(), 0, 22, s_u.as_mut_ptr()),
+                ZkCdsStatus::NullPointer
+            );
+            zk_cds_client_free(null);
+        }
+
+        let status = unsafe {
+            zk_cds_client_request(client, 1234567890, prefix.as_mut_ptr(), c_p.as_mut_ptr())
+        };
+        assert_eq!(status, ZkCdsStatus::Ok);
+        let bucket = server
+            .find_bucket(prefix)
+            .iter()
+            .flat_map(|(s_p, hs_u)| [s_p.as_bytes(), hs_u.as_bytes()].concat())
+            .collect::<Vec<u8>>();
+        let sc_p = server
+            .blind_phone_number(&EncodedPoint::from_bytes(c_p).expect("should be valid"))
+            .expect("should be a valid point");
+        let find = |sc_p: &[u8], bucket: &[u8], p: u64, s_u: &mut [u8; ZK_CDS_POINT_LEN]| unsafe {
+            zk_cds_client_find(
+                client,
+                sc_p.as_ptr(),
+                bucket.as_ptr(),
+                bucket.len(),
+                p,
+                s_u.as_mut_ptr(),
+            )
+        };
+
+        // Invalid points and malformed buckets are rejected.
+        assert_eq!(
+            find(&[0u8; ZK_CDS_POINT_LEN], &bucket, 1234567890, &mut s_u),
+            ZkCdsStatus::InvalidPoint
+        );
+        let mut off_curve = sc_p.as_bytes().to_vec();
+        off_curve[1..].fill(0xff);
+        assert_eq!(find(&off_curve, &bucket, 1234567890, &mut s_u), ZkCdsStatus::InvalidPoint);
+        assert_eq!(
+            find(sc_p.as_bytes(), &bucket[..bucket.len() - 1], 1234567890, &mut s_u),
+            ZkCdsStatus::InvalidPoint
+        );
+
+        // Other phone numbers aren't found, leaving the output untouched.
+        let status =
+            unsafe { zk_cds_client_request(client, 22, prefix.as_mut_ptr(), c_p.as_mut_ptr()) };
+        assert_eq!(status, ZkCdsStatus::Ok);
+        let sc_p = server
+            .blind_phone_number(&EncodedPoint::from_bytes(c_p).expect("should be valid"))
+            .expect("should be a valid point");
+        let mut s_u = [0xaa; ZK_CDS_POINT_LEN];
+        assert_eq!(find(sc_p.as_bytes(), &bucket, 22, &mut s_u), ZkCdsStatus::NotFound);
+        assert_eq!(s_u, [0xaa; ZK_CDS_POINT_LEN]);
+
+        unsafe { zk_cds_client_free(client) };
+    }
+}
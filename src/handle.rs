@@ -0,0 +1,270 @@
+//! Graceful shutdown of a [`LookupService`].
+//!
+//! A lookup takes several round trips, so a server which exits as soon as it's told to stop breaks
+//! every client which is mid-protocol during a rolling deploy. A [`ServerHandle`] hands out
+//! [`HandledService`]s which count their in-flight requests. When the handle is
+//! [shut down](ServerHandle::shutdown), its services report themselves as not ready, so load
+//! balancers stop routing to them, and refuse new lookups with [`Error::NotReady`], which clients
+//! retry elsewhere. The handle then waits for in-flight requests to drain, up to its
+//! [shutdown timeout](ServerHandle::with_shutdown_timeout), and finally runs its shutdown hooks,
+//! e.g. to flush [metrics](crate::metrics) and [audit](crate::audit) counters.
+
+use std::{
+    fmt,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tower::Service;
+
+use crate::{
+    service::{LookupFuture, LookupService, Request, Response},
+    Error,
+};
+
+/// The default time to wait for in-flight requests to drain when shutting down.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A hook run once a server has shut down.
+type ShutdownHook = Box<dyn FnOnce() + Send>;
+
+/// Whether a handle is accepting requests, and how many are in flight.
+#[derive(Debug, Default)]
+struct State {
+    stopping: AtomicBool,
+    in_flight: Mutex<usize>,
+    drained: Condvar,
+}
+
+/// The outcome of shutting down a [`ServerHandle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The number of requests still in flight when the shutdown timeout expired.
+    pub abandoned: usize,
+    /// How long the shutdown took.
+    pub elapsed: Duration,
+}
+
+/// A handle for shutting down a [`LookupService`] gracefully.
+pub struct ServerHandle<S = RandomState> {
+    service: LookupService<S>,
+    state: Arc<State>,
+    shutdown_timeout: Duration,
+    hooks: Vec<ShutdownHook>,
+}
+
+impl<S: BuildHasher + Clone> ServerHandle<S> {
+    /// Create a handle for the given service, with a shutdown timeout of
+    /// [`DEFAULT_SHUTDOWN_TIMEOUT`].
+    pub fn new(service: LookupService<S>) -> ServerHandle<S> {
+        ServerHandle {
+            service,
+            state: Arc::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Wait at most `shutdown_timeout` for in-flight requests to drain, instead of
+    /// [`DEFAULT_SHUTDOWN_TIMEOUT`].
+    pub fn with_shutdown_timeout(self, shutdown_timeout: Duration) -> ServerHandle<S> {
+        ServerHandle { shutdown_timeout, ..self }
+    }
+
+    /// Call `f` once the server has shut down, e.g. to flush metrics or audit counters. Hooks are
+    /// called in the order they were added.
+    pub fn on_shutdown(mut self, f: impl FnOnce() + Send + 'static) -> ServerHandle<S> {
+        self.hooks.push(Box::new(f));
+        self
+    }
+
+    /// Return a service which serves requests until the handle is shut down.
+    pub fn service(&self) -> HandledService<S> {
+        HandledService { inner: self.service.clone(), state: self.state.clone() }
+    }
+
+    /// The number of requests in flight.
+    pub fn in_flight(&self) -> usize {
+        *self.state.in_flight.lock().expect("should not be poisoned")
+    }
+
+    /// Stop accepting new requests, wait for in-flight ones to drain or for the shutdown timeout to
+    /// expire, then run the shutdown hooks.
+    ///
+    /// This blocks the calling thread, so async callers should run it on a blocking thread.
+    pub fn shutdown(self) -> ShutdownReport {
+        let start = Instant::now();
+        self.state.stopping.store(true, Ordering::SeqCst);
+
+        // Wait for in-flight requests to finish.
+        let in_flight = self.state.in_flight.lock().expect("should not be poisoned");
+        let (in_flight, _) = self
+            .state
+            .drained
+            .wait_timeout_while(in_flight, self.shutdown_timeout, |n| *n > 0)
+            .expect("should not be poisoned");
+        let abandoned = *in_flight;
+        drop(in_flight);
+
+        // Then flush whatever needs flushing.
+        for hook in self.hooks {
+            hook();
+        }
+        ShutdownReport { abandoned, elapsed: start.elapsed() }
+    }
+}
+
+impl<S> fmt::Debug for ServerHandle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerHandle")
+            .field("state", &self.state)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("hooks", &self.hooks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [`LookupService`] which refuses new requests once its [`ServerHandle`] is shut down.
+#[derive(Debug)]
+pub struct HandledService<S = RandomState> {
+    inner: LookupService<S>,
+    state: Arc<State>,
+}
+
+impl<S> Clone for HandledService<S> {
+    fn clone(&self) -> Self {
+        HandledService { inner: self.inner.clone(), state: self.state.clone() }
+    }
+}
+
+impl<S: BuildHasher + Clone> Service<Request> for HandledService<S> {
+    type Response = Response<S>;
+    type Error = Error;
+    type Future = HandledFuture<S>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> HandledFuture<S> {
+        // Once stopping, stay healthy but unready, and refuse everything else.
+        if self.state.stopping.load(Ordering::SeqCst) {
+            let res = match req {
+                Request::Health => Ok(Response::Healthy),
+                Request::Ready => Ok(Response::Ready(false)),
+                _ => Err(Error::NotReady),
+            };
+            return HandledFuture { inner: None, guard: None, refused: Some(res) };
+        }
+
+        *self.state.in_flight.lock().expect("should not be poisoned") += 1;
+        let guard = InFlight(self.state.clone());
+        HandledFuture { inner: Some(self.inner.call(req)), guard: Some(guard), refused: None }
+    }
+}
+
+/// Counts a request as in flight until it's dropped.
+#[derive(Debug)]
+struct InFlight(Arc<State>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.0.in_flight.lock().expect("should not be poisoned");
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.0.drained.notify_all();
+        }
+    }
+}
+
+/// The response future of a [`HandledService`].
+#[derive(Debug)]
+pub struct HandledFuture<S = RandomState> {
+    inner: Option<LookupFuture<S>>,
+    guard: Option<InFlight>,
+    refused: Option<Result<Response<S>, Error>>,
+}
+
+impl<S: BuildHasher + Clone> Future for HandledFuture<S> {
+    type Output = Result<Response<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(res) = self.refused.take() {
+            return Poll::Ready(res);
+        }
+        let inner = self.inner.as_mut().expect("should not be polled after completion");
+        let res = Pin::new(inner).poll(cx);
+        if res.is_ready() {
+            // The request is no longer in flight.
+            self.inner = None;
+            self.guard = None;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::atomic::AtomicUsize, thread};
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, Server};
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let flushed = Arc::new(AtomicUsize::new(0));
+        let hook = flushed.clone();
+        let handle = ServerHandle::new(LookupService::new(server))
+            .with_shutdown_timeout(Duration::from_secs(5))
+            .on_shutdown(move || {
+                hook.fetch_add(1, Ordering::SeqCst);
+            });
+        let mut svc = handle.service();
+        let (_, c_p) = Client::new(OsRng).request_phone_number(22);
+
+        // A request which started before the shutdown finishes after it.
+        let in_flight = svc.call(Request::BlindPhoneNumbers(vec![c_p]));
+        assert_eq!(handle.in_flight(), 1);
+        let shutdown = thread::spawn(move || handle.shutdown());
+        while !svc.state.stopping.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        assert!(matches!(in_flight.await, Ok(Response::BlindedPhoneNumbers(..))));
+        let report = shutdown.join().expect("should shut down");
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(flushed.load(Ordering::SeqCst), 1);
+
+        // New requests are refused, and the service reports itself as unready.
+        assert!(matches!(
+            svc.call(Request::BlindPhoneNumbers(vec![c_p])).await,
+            Err(Error::NotReady)
+        ));
+        assert!(matches!(svc.call(Request::Ready).await, Ok(Response::Ready(false))));
+        assert!(matches!(svc.call(Request::Health).await, Ok(Response::Healthy)));
+    }
+
+    #[test]
+    fn shutdown_timeout() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let handle = ServerHandle::new(LookupService::new(server))
+            .with_shutdown_timeout(Duration::from_millis(10));
+        let mut svc = handle.service();
+
+        // Requests which never finish are abandoned once the timeout expires.
+        let _stuck = svc.call(Request::Info);
+        let report = handle.shutdown();
+        assert_eq!(report.abandoned, 1);
+        assert!(report.elapsed >= Duration::from_millis(10));
+    }
+}
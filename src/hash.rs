@@ -0,0 +1,383 @@
+//! Hash functions for deriving phone numbers' hash prefixes and blinding scalars.
+//!
+//! A phone number is first optionally [pre-hashed](PreHash) with a memory-hard function or a
+//! per-deployment pepper, giving its identity. The identity is then hashed to a curve point, and
+//! separately with a [`HashFunction`] to derive its hash prefix and blinding scalar.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use hmac::{Hmac, Mac};
+use p256::ProjectivePoint;
+use sha2::{Digest, Sha256, Sha512_256};
+
+use crate::hash_to_curve;
+
+/// A hash function used to derive a phone number's hash prefix and the scalar which blinds its user
+/// ID point.
+///
+/// Clients and servers must be configured with the same hash function, or clients won't find any
+/// users. The hash-to-curve map is part of the curve's suite and always uses SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum HashFunction {
+    /// SHA-256.
+    #[default]
+    Sha256,
+    /// SHA-512/256.
+    Sha512_256,
+    /// BLAKE3.
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashFunction {
+    /// Hash the given identity.
+    pub fn hash(self, id: &[u8]) -> [u8; 32] {
+        match self {
+            HashFunction::Sha256 => Sha256::new().chain_update(id).finalize().into(),
+            HashFunction::Sha512_256 => Sha512_256::new().chain_update(id).finalize().into(),
+            #[cfg(feature = "blake3")]
+            HashFunction::Blake3 => blake3::hash(id).into(),
+        }
+    }
+
+    /// The hash function's identifier in [`ServerInfo`](crate::protocol::ServerInfo) messages.
+    pub fn id(self) -> u8 {
+        match self {
+            HashFunction::Sha256 => 1,
+            HashFunction::Sha512_256 => 2,
+            #[cfg(feature = "blake3")]
+            HashFunction::Blake3 => 3,
+        }
+    }
+
+    /// Return the hash function with the given identifier, if it's supported.
+    pub fn from_id(id: u8) -> Option<HashFunction> {
+        match id {
+            1 => Some(HashFunction::Sha256),
+            2 => Some(HashFunction::Sha512_256),
+            #[cfg(feature = "blake3")]
+            3 => Some(HashFunction::Blake3),
+            _ => None,
+        }
+    }
+}
+
+/// A memory-hard function applied to phone numbers before they're hashed.
+///
+/// The phone number space is small enough that anyone who learns a server-blinded phone number
+/// point and its server secret, or harvests prefixes, can brute-force it. Pre-hashing with a
+/// memory-hard function multiplies the cost of each guess. Clients pay the cost twice per lookup,
+/// once in [`Client::request_phone_number`](crate::Client::request_phone_number) and again in
+/// [`Client::find_user_id`](crate::Client::find_user_id), and servers once per user when building.
+/// Clients with an [identity cache](crate::Client::with_identity_cache) pay it once per phone
+/// number for as long as it stays cached.
+///
+/// A deployment can instead mix a [pepper](PreHash::Peppered) into every phone number's identity.
+/// Peppers are distributed with clients, so they aren't secret, but blinded databases and
+/// prefixes from deployments with different peppers can't be correlated with each other.
+///
+/// Clients and servers must be configured with the same pre-hash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum PreHash {
+    /// Phone numbers aren't pre-hashed.
+    #[default]
+    None,
+    /// Phone numbers are pre-hashed with Argon2id.
+    #[cfg(feature = "argon2")]
+    Argon2id {
+        /// The memory cost, in KiB.
+        memory_kib: u32,
+        /// The number of iterations.
+        iterations: u32,
+        /// The degree of parallelism.
+        parallelism: u32,
+        /// A per-deployment salt, which prevents precomputation across deployments.
+        salt: [u8; 16],
+    },
+    /// Phone numbers are keyed with a per-deployment pepper, using HMAC-SHA-256. Changing the
+    /// pepper changes every identity, so it requires rebuilding the server and updating clients.
+    Peppered {
+        /// The deployment's pepper.
+        pepper: [u8; 32],
+    },
+}
+
+impl PreHash {
+    /// Return the identity of the given phone number.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the Argon2id parameters are invalid.
+    pub fn apply(self, p: u64) -> Vec<u8> {
+        match self {
+            PreHash::None => p.to_be_bytes().to_vec(),
+            #[cfg(feature = "argon2")]
+            PreHash::Argon2id { memory_kib, iterations, parallelism, salt } => {
+                let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(32))
+                    .expect("should be valid Argon2id parameters");
+                let argon2 = argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params,
+                );
+                let mut id = vec![0u8; 32];
+                argon2
+                    .hash_password_into(&p.to_be_bytes(), &salt, &mut id)
+                    .expect("should be valid Argon2id inputs");
+                id
+            }
+            PreHash::Peppered { pepper } => Hmac::<Sha256>::new_from_slice(&pepper)
+                .expect("should accept any key length")
+                .chain_update(b"zk-cds-pepper")
+                .chain_update(p.to_be_bytes())
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        }
+    }
+}
+
+/// Return the identity of an identifier which the caller has already hashed.
+///
+/// Phone number identities are 8 or 32 bytes long, so the tag makes hashed identities distinct from
+/// them as well as domain-separated.
+pub(crate) fn hashed_identity(id_hash: &[u8; 32]) -> Vec<u8> {
+    [b"zk-cds-hashed-id".as_slice(), id_hash].concat()
+}
+
+/// An identity's hash and its hash-to-curve point.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HashedIdentity {
+    pub(crate) h: [u8; 32],
+    pub(crate) h_p: ProjectivePoint,
+}
+
+impl HashedIdentity {
+    pub(crate) fn new(hash: HashFunction, id: &[u8]) -> HashedIdentity {
+        HashedIdentity { h: hash.hash(id), h_p: hash_to_curve(id) }
+    }
+}
+
+/// A least-recently-used cache of phone numbers' hashed identities.
+///
+/// Clients look up mostly the same phone numbers every sync, and pre-hashing and hashing to the
+/// curve dominate the cost of requesting them, so caching the results makes repeat syncs much
+/// cheaper. Its entries reveal which phone numbers were looked up, so it deliberately doesn't
+/// implement `Debug`.
+pub(crate) struct IdentityCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    tick: u64,
+    entries: HashMap<u64, (HashedIdentity, u64)>,
+    recency: BTreeMap<u64, u64>,
+}
+
+impl IdentityCache {
+    pub(crate) fn new(capacity: usize) -> IdentityCache {
+        IdentityCache { capacity, state: Mutex::new(CacheState::default()) }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return an empty cache with the same capacity, e.g. after the hash function changes.
+    pub(crate) fn cleared(&self) -> IdentityCache {
+        IdentityCache::new(self.capacity)
+    }
+
+    /// Return the cached hashed identity of the given phone number, hashing and caching it if
+    /// necessary.
+    pub(crate) fn get_or_hash(
+        &self,
+        p: u64,
+        hash: impl FnOnce() -> HashedIdentity,
+    ) -> HashedIdentity {
+        if let Some(id) = self.state.lock().expect("should not be poisoned").touch(p) {
+            return id;
+        }
+
+        // Hash the phone number without holding the lock.
+        let id = hash();
+
+        // Make room for the identity by evicting the least recently used entry, if needed, then
+        // cache it.
+        let mut state = self.state.lock().expect("should not be poisoned");
+        if self.capacity > 0 && state.touch(p).is_none() {
+            if state.entries.len() >= self.capacity {
+                if let Some((_, evicted)) = state.recency.pop_first() {
+                    state.entries.remove(&evicted);
+                }
+            }
+            state.tick += 1;
+            let tick = state.tick;
+            state.entries.insert(p, (id, tick));
+            state.recency.insert(tick, p);
+        }
+        id
+    }
+}
+
+impl CacheState {
+    /// Return the cached identity of the given phone number, if any, marking it as most recently
+    /// used.
+    fn touch(&mut self, p: u64) -> Option<HashedIdentity> {
+        self.tick += 1;
+        let (id, tick) = self.entries.get_mut(&p)?;
+        self.recency.remove(tick);
+        self.recency.insert(self.tick, p);
+        *tick = self.tick;
+        Some(*id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, ServerBuilder};
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn pre_hash() {
+        let pre_hash =
+            PreHash::Argon2id { memory_kib: 64, iterations: 1, parallelism: 1, salt: [7; 16] };
+        assert_eq!(pre_hash.apply(22), pre_hash.apply(22));
+        assert_ne!(pre_hash.apply(22), PreHash::None.apply(22));
+
+        let u = Uuid::new_v4();
+        let (server, _) =
+            ServerBuilder::new().pre_hash(pre_hash).build(OsRng, [(22, u)]).expect("should build");
+        let client = Client::new(OsRng).with_pre_hash(pre_hash);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let s_u = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+            )
+            .expect("should be a valid response")
+            .expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+    }
+
+    #[test]
+    fn pepper() {
+        let peppered = |pepper| PreHash::Peppered { pepper };
+        assert_ne!(peppered([1; 32]).apply(22), PreHash::None.apply(22));
+        assert_ne!(peppered([1; 32]).apply(22), peppered([2; 32]).apply(22));
+
+        // Only clients with the deployment's pepper find its users.
+        let u = Uuid::new_v4();
+        let (server, _) = ServerBuilder::new()
+            .pre_hash(peppered([1; 32]))
+            .build(OsRng, [(22, u)])
+            .expect("should build");
+        for (pepper, found) in [([1; 32], Some(u)), ([2; 32], None)] {
+            let client = Client::new(OsRng).with_pre_hash(peppered(pepper));
+            let (prefix, c_p) = client.request_phone_number(22);
+            let s_u = client
+                .find_user_id(
+                    &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                    &server.find_bucket(prefix),
+                    22,
+                )
+                .expect("should be a valid response");
+            assert_eq!(s_u.and_then(|s_u| server.unblind_user_id(&s_u)), found);
+        }
+    }
+
+    #[test]
+    fn hash_functions() {
+        let u = Uuid::new_v4();
+        let (server, _) = ServerBuilder::new()
+            .hash_function(HashFunction::Sha512_256)
+            .build(OsRng, [(22, u)])
+            .expect("should build");
+        assert_eq!(server.hash_function(), HashFunction::Sha512_256);
+
+        // A client using the same hash function finds the user.
+        let client = Client::new(OsRng).with_hash_function(HashFunction::Sha512_256);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let s_u = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+            )
+            .expect("should be a valid response")
+            .expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+
+        // A client using a different one doesn't.
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        assert_eq!(
+            client.find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22
+            ),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn identity_cache() {
+        let cache = IdentityCache::new(2);
+        let hashed = |p: u64| HashedIdentity::new(HashFunction::Sha256, &p.to_be_bytes());
+        let misses = Cell::new(0);
+        let get = |p: u64| {
+            cache.get_or_hash(p, || {
+                misses.set(misses.get() + 1);
+                hashed(p)
+            })
+        };
+
+        // Cached identities match freshly hashed ones.
+        assert_eq!(get(22).h, hashed(22).h);
+        assert_eq!(get(22).h_p, hashed(22).h_p);
+        assert_eq!(misses.get(), 1);
+
+        // The least recently used phone number is evicted when the cache is full.
+        get(23);
+        get(22);
+        get(24);
+        assert_eq!(misses.get(), 3);
+        get(22);
+        assert_eq!(misses.get(), 3);
+        get(23);
+        assert_eq!(misses.get(), 4);
+
+        // A caching client still finds users after switching to the server's hash function.
+        let u = Uuid::new_v4();
+        let (server, _) = ServerBuilder::new()
+            .hash_function(HashFunction::Sha512_256)
+            .build(OsRng, [(22, u)])
+            .expect("should build");
+        let client = Client::new(OsRng).with_identity_cache(16);
+        client.request_phone_number(22);
+        let client = client.with_hash_function(HashFunction::Sha512_256);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let s_u = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+            )
+            .expect("should be a valid response")
+            .expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+    }
+}
@@ -0,0 +1,316 @@
+//! Tracking of long-running server-side work, such as bulk blinding, rotations, and rebuilds.
+//!
+//! Work which takes too long for a request is run as a job: the code doing the work registers it
+//! with a [`JobQueue`], reports its progress, and checks whether it has been cancelled between
+//! units of work. Operators inspect and cancel jobs through the [`admin`](crate::admin) API.
+//!
+//! [`InMemoryJobQueue`] keeps jobs in memory, and can call a persistence hook on every change and
+//! [restore](InMemoryJobQueue::restore) jobs after a restart, at which point jobs which were still
+//! running are marked as failed.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use rand::{rngs::OsRng, RngCore};
+
+use crate::rng::RngProvider;
+
+/// An unguessable identifier for a job, since some jobs' results are fetched by ID alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct JobId(pub u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// The kind of work a job does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    /// Blinding a batch of points for a [`BulkBlindingService`](crate::bulk::BulkBlindingService).
+    BulkBlinding,
+    /// Rotating the server secret.
+    Rotation,
+    /// Rebuilding a server from an address book.
+    Rebuild,
+}
+
+/// The state of a job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobState {
+    /// The job is running.
+    Running,
+    /// The job finished successfully.
+    Complete,
+    /// The job was cancelled before it finished.
+    Cancelled,
+    /// The job failed for the given reason.
+    Failed(String),
+}
+
+/// The status of a job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobStatus {
+    /// The kind of work the job does.
+    pub kind: JobKind,
+    /// The job's state.
+    pub state: JobState,
+    /// The number of units of work done so far.
+    pub done: u64,
+    /// The number of units of work in the job.
+    pub total: u64,
+}
+
+/// The error returned when reporting progress on a job which was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A registry of jobs.
+pub trait JobQueue: Send + Sync {
+    /// Register a running job of the given kind with `total` units of work, returning its ID.
+    fn create(&self, kind: JobKind, total: u64) -> JobId;
+
+    /// Report that `done` units of a job's work are done, returning [`Cancelled`] if the job was
+    /// cancelled and its work should stop.
+    fn progress(&self, id: JobId, done: u64) -> Result<(), Cancelled>;
+
+    /// Mark a running job as finished, successfully or with the given failure.
+    fn finish(&self, id: JobId, result: Result<(), String>);
+
+    /// Cancel a running job, returning `false` if it doesn't exist or isn't running.
+    fn cancel(&self, id: JobId) -> bool;
+
+    /// The status of the given job, if it exists.
+    fn status(&self, id: JobId) -> Option<JobStatus>;
+
+    /// Block until the given job isn't running, returning its status, if it exists.
+    fn wait(&self, id: JobId) -> Option<JobStatus>;
+
+    /// Remove a job which isn't running, returning its status.
+    fn remove(&self, id: JobId) -> Option<JobStatus>;
+
+    /// Every job and its status, in order of ID.
+    fn list(&self) -> Vec<(JobId, JobStatus)>;
+}
+
+/// A hook called with a job's status whenever it changes, or `None` when it's removed.
+type PersistHook = Box<dyn Fn(JobId, Option<&JobStatus>) + Send + Sync>;
+
+/// A [`JobQueue`] which keeps jobs in memory.
+#[derive(Default)]
+pub struct InMemoryJobQueue {
+    jobs: Mutex<HashMap<JobId, JobStatus>>,
+    updated: Condvar,
+    persist: Option<PersistHook>,
+    rng: Option<Arc<dyn RngProvider>>,
+}
+
+impl InMemoryJobQueue {
+    /// Create an empty queue.
+    pub fn new() -> InMemoryJobQueue {
+        InMemoryJobQueue::default()
+    }
+
+    /// Call `f` with a job's status whenever it changes, or with `None` when it's removed, e.g. to
+    /// persist it.
+    pub fn with_persistence(
+        self,
+        f: impl Fn(JobId, Option<&JobStatus>) + Send + Sync + 'static,
+    ) -> InMemoryJobQueue {
+        InMemoryJobQueue { persist: Some(Box::new(f)), ..self }
+    }
+
+    /// Draw job IDs from the given provider, instead of [`OsRng`].
+    pub fn with_rng(self, rng: Arc<dyn RngProvider>) -> InMemoryJobQueue {
+        InMemoryJobQueue { rng: Some(rng), ..self }
+    }
+
+    /// Restore persisted jobs, marking any which were running as failed, since their work was
+    /// lost.
+    pub fn restore(&self, jobs: impl IntoIterator<Item = (JobId, JobStatus)>) {
+        let mut all = self.jobs.lock().expect("should not be poisoned");
+        for (id, mut status) in jobs {
+            if status.state == JobState::Running {
+                status.state = JobState::Failed("interrupted".into());
+                self.persist(id, Some(&status));
+            }
+            all.insert(id, status);
+        }
+    }
+
+    fn persist(&self, id: JobId, status: Option<&JobStatus>) {
+        if let Some(f) = &self.persist {
+            f(id, status);
+        }
+    }
+
+    /// Apply `f` to a running job's status, persisting and announcing the change, and returning
+    /// `false` if it doesn't exist or isn't running.
+    fn update_running(&self, id: JobId, f: impl FnOnce(&mut JobStatus)) -> bool {
+        let mut jobs = self.jobs.lock().expect("should not be poisoned");
+        let Some(status) = jobs.get_mut(&id).filter(|status| status.state == JobState::Running)
+        else {
+            return false;
+        };
+        f(status);
+        self.persist(id, Some(status));
+        self.updated.notify_all();
+        true
+    }
+}
+
+impl JobQueue for InMemoryJobQueue {
+    fn create(&self, kind: JobKind, total: u64) -> JobId {
+        let mut jobs = self.jobs.lock().expect("should not be poisoned");
+        let id = loop {
+            let id = JobId(match &self.rng {
+                Some(rng) => rng.rng().next_u64(),
+                None => OsRng.next_u64(),
+            });
+            if !jobs.contains_key(&id) {
+                break id;
+            }
+        };
+        let status = JobStatus { kind, state: JobState::Running, done: 0, total };
+        self.persist(id, Some(&status));
+        jobs.insert(id, status);
+        id
+    }
+
+    fn progress(&self, id: JobId, done: u64) -> Result<(), Cancelled> {
+        if self.update_running(id, |status| status.done = done) {
+            Ok(())
+        } else {
+            Err(Cancelled)
+        }
+    }
+
+    fn finish(&self, id: JobId, result: Result<(), String>) {
+        self.update_running(id, |status| {
+            status.state = match result {
+                Ok(()) => {
+                    status.done = status.total;
+                    JobState::Complete
+                }
+                Err(reason) => JobState::Failed(reason),
+            };
+        });
+    }
+
+    fn cancel(&self, id: JobId) -> bool {
+        self.update_running(id, |status| status.state = JobState::Cancelled)
+    }
+
+    fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.jobs.lock().expect("should not be poisoned").get(&id).cloned()
+    }
+
+    fn wait(&self, id: JobId) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().expect("should not be poisoned");
+        let jobs = self
+            .updated
+            .wait_while(jobs, |jobs| jobs.get(&id).is_some_and(|s| s.state == JobState::Running))
+            .expect("should not be poisoned");
+        jobs.get(&id).cloned()
+    }
+
+    fn remove(&self, id: JobId) -> Option<JobStatus> {
+        let mut jobs = self.jobs.lock().expect("should not be poisoned");
+        if jobs.get(&id)?.state == JobState::Running {
+            return None;
+        }
+        self.persist(id, None);
+        jobs.remove(&id)
+    }
+
+    fn list(&self) -> Vec<(JobId, JobStatus)> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .expect("should not be poisoned")
+            .iter()
+            .map(|(&id, status)| (id, status.clone()))
+            .collect::<Vec<_>>();
+        jobs.sort_unstable_by_key(|&(id, _)| id);
+        jobs
+    }
+}
+
+impl fmt::Debug for InMemoryJobQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryJobQueue")
+            .field("jobs", &self.jobs.lock().expect("should not be poisoned").len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let persisted = Arc::new(Mutex::new(HashMap::new()));
+        let log = persisted.clone();
+        let queue = InMemoryJobQueue::new().with_persistence(move |id, status| {
+            let mut log = log.lock().expect("should not be poisoned");
+            match status {
+                Some(status) => log.insert(id, status.clone()),
+                None => log.remove(&id),
+            };
+        });
+
+        // Jobs report progress until they finish.
+        let id = queue.create(JobKind::Rebuild, 10);
+        assert_eq!(queue.progress(id, 4), Ok(()));
+        assert_eq!(queue.status(id).map(|s| (s.state, s.done)), Some((JobState::Running, 4)));
+        queue.finish(id, Ok(()));
+        assert_eq!(queue.wait(id).map(|s| (s.state, s.done)), Some((JobState::Complete, 10)));
+
+        // Cancelled jobs are told to stop.
+        let cancelled = queue.create(JobKind::BulkBlinding, 10);
+        assert!(queue.cancel(cancelled));
+        assert_eq!(queue.progress(cancelled, 5), Err(Cancelled));
+        assert!(!queue.cancel(cancelled));
+        assert_eq!(queue.remove(cancelled).map(|s| s.state), Some(JobState::Cancelled));
+
+        // Persisted jobs are restored, and interrupted ones are marked as failed.
+        let running = queue.create(JobKind::Rotation, 1);
+        let restored = InMemoryJobQueue::new();
+        restored.restore(persisted.lock().expect("should not be poisoned").clone());
+        assert_eq!(restored.list().len(), 2);
+        assert_eq!(restored.status(id).map(|s| s.state), Some(JobState::Complete));
+        assert_eq!(
+            restored.status(running).map(|s| s.state),
+            Some(JobState::Failed("interrupted".into()))
+        );
+    }
+
+    #[test]
+    fn injected_rng() {
+        // Queues with identically seeded RNGs generate identical job IDs.
+        let queue = || {
+            let rng = Mutex::new(ChaChaRng::seed_from_u64(22));
+            InMemoryJobQueue::new().with_rng(Arc::new(rng))
+        };
+        let (a, b) = (queue(), queue());
+        for kind in [JobKind::Rebuild, JobKind::Rotation] {
+            assert_eq!(a.create(kind, 1), b.create(kind, 1));
+        }
+    }
+}
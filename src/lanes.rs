@@ -0,0 +1,280 @@
+//! Priority lanes for interactive and batch lookups.
+//!
+//! A client syncing a large address book sends batches of thousands of prefixes and phone numbers,
+//! while a client checking whether a single new contact is registered is waiting on the result to
+//! compose a message. [`Lanes`] serves each [`Lane`] with its own concurrency limit, so a backlog of
+//! batch syncs can only ever occupy the batch lane's slots, and interactive lookups queue behind
+//! each other but never behind a batch.
+//!
+//! Requests for at most [`DEFAULT_BATCH_THRESHOLD`] prefixes or phone numbers are interactive, as
+//! are unblinding requests and metadata requests. Larger batches and long polls for
+//! [changes](Request::WaitForChanges) are batch requests, so the batch lane's limit also bounds the
+//! number of subscribers. Each lane keeps its own [`LaneMetrics`], including the time requests spent
+//! queued for a slot.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tower::Service;
+
+use crate::{
+    service::{LookupFuture, LookupService, Request, Response},
+    Error,
+};
+
+/// By default, requests for more than this many prefixes or phone numbers are batch requests.
+pub const DEFAULT_BATCH_THRESHOLD: usize = 16;
+
+/// A scheduling class of requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lane {
+    /// Small lookups which a user is waiting on.
+    Interactive,
+    /// Large batch lookups and long polls.
+    Batch,
+}
+
+/// The metrics of a lane.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LaneMetrics {
+    /// The number of requests answered.
+    pub requests: u64,
+    /// The number of requests which had to wait for a slot.
+    pub queued: u64,
+    /// The total time from requests being made to being answered, including time spent queued.
+    pub total_latency: Duration,
+    /// The longest time from a request being made to being answered.
+    pub max_latency: Duration,
+}
+
+impl LaneMetrics {
+    /// The mean time from requests being made to being answered.
+    pub fn mean_latency(&self) -> Duration {
+        if self.requests == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.total_latency.as_secs_f64() / self.requests as f64)
+    }
+}
+
+/// The slots of a lane, and the requests waiting for one.
+#[derive(Debug)]
+struct LaneState {
+    limit: usize,
+    in_flight: usize,
+    waiters: VecDeque<Waker>,
+    metrics: LaneMetrics,
+}
+
+impl LaneState {
+    fn new(limit: usize) -> LaneState {
+        LaneState {
+            limit: limit.max(1),
+            in_flight: 0,
+            waiters: VecDeque::new(),
+            metrics: LaneMetrics::default(),
+        }
+    }
+}
+
+/// A [`Service`] which serves interactive and batch requests with separate concurrency limits.
+#[derive(Debug)]
+pub struct Lanes<S = RandomState> {
+    service: LookupService<S>,
+    threshold: usize,
+    interactive: Arc<Mutex<LaneState>>,
+    batch: Arc<Mutex<LaneState>>,
+}
+
+impl<S> Lanes<S> {
+    /// Create lanes for the given service which allow at most `interactive` interactive requests and
+    /// `batch` batch requests to be processed concurrently.
+    pub fn new(service: LookupService<S>, interactive: usize, batch: usize) -> Lanes<S> {
+        Lanes {
+            service,
+            threshold: DEFAULT_BATCH_THRESHOLD,
+            interactive: Arc::new(Mutex::new(LaneState::new(interactive))),
+            batch: Arc::new(Mutex::new(LaneState::new(batch))),
+        }
+    }
+
+    /// Treat requests for more than `n` prefixes or phone numbers as batch requests, instead of
+    /// more than [`DEFAULT_BATCH_THRESHOLD`].
+    pub fn with_batch_threshold(self, n: usize) -> Lanes<S> {
+        Lanes { threshold: n, ..self }
+    }
+
+    /// Return the lane the given request is served in.
+    pub fn lane(&self, req: &Request) -> Lane {
+        match req {
+            Request::Tagged(_, req) => self.lane(req),
+            Request::FindBuckets(prefixes) if prefixes.len() > self.threshold => Lane::Batch,
+            Request::BlindPhoneNumbers(c_ps) | Request::BlindWithTokens(c_ps, _)
+                if c_ps.len() > self.threshold =>
+            {
+                Lane::Batch
+            }
+            Request::WaitForChanges(_) => Lane::Batch,
+            _ => Lane::Interactive,
+        }
+    }
+
+    /// The metrics of the given lane.
+    pub fn metrics(&self, lane: Lane) -> LaneMetrics {
+        self.state(lane).lock().expect("should not be poisoned").metrics
+    }
+
+    fn state(&self, lane: Lane) -> &Arc<Mutex<LaneState>> {
+        match lane {
+            Lane::Interactive => &self.interactive,
+            Lane::Batch => &self.batch,
+        }
+    }
+}
+
+impl<S> Clone for Lanes<S> {
+    fn clone(&self) -> Self {
+        Lanes {
+            service: self.service.clone(),
+            threshold: self.threshold,
+            interactive: self.interactive.clone(),
+            batch: self.batch.clone(),
+        }
+    }
+}
+
+impl<S: BuildHasher + Clone> Service<Request> for Lanes<S> {
+    type Response = Response<S>;
+    type Error = Error;
+    type Future = LaneFuture<S>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // Requests wait for a slot in their lane when polled, since the lane depends on the request.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> LaneFuture<S> {
+        let state = self.state(self.lane(&req)).clone();
+        LaneFuture {
+            inner: self.service.clone().call(req),
+            state,
+            start: Instant::now(),
+            admitted: false,
+            queued: false,
+        }
+    }
+}
+
+/// The response future of [`Lanes`].
+#[derive(Debug)]
+pub struct LaneFuture<S = RandomState> {
+    inner: LookupFuture<S>,
+    state: Arc<Mutex<LaneState>>,
+    start: Instant,
+    admitted: bool,
+    queued: bool,
+}
+
+impl<S> LaneFuture<S> {
+    /// Release the request's slot, and wake the requests waiting for one.
+    fn release(&mut self) {
+        if !self.admitted {
+            return;
+        }
+        self.admitted = false;
+        let mut state = self.state.lock().expect("should not be poisoned");
+        state.in_flight -= 1;
+        let waiters = mem::take(&mut state.waiters);
+        drop(state);
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl<S: BuildHasher + Clone> Future for LaneFuture<S> {
+    type Output = Result<Response<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Wait for a slot in the lane.
+        if !self.admitted {
+            let mut state = self.state.lock().expect("should not be poisoned");
+            if state.in_flight >= state.limit {
+                state.waiters.push_back(cx.waker().clone());
+                drop(state);
+                self.queued = true;
+                return Poll::Pending;
+            }
+            state.in_flight += 1;
+            drop(state);
+            self.admitted = true;
+        }
+
+        let res = Pin::new(&mut self.inner).poll(cx);
+        if res.is_ready() {
+            // Free the slot and record the request's latency.
+            self.release();
+            let latency = self.start.elapsed();
+            let mut state = self.state.lock().expect("should not be poisoned");
+            state.metrics.requests += 1;
+            state.metrics.queued += u64::from(self.queued);
+            state.metrics.total_latency += latency;
+            state.metrics.max_latency = state.metrics.max_latency.max(latency);
+        }
+        res
+    }
+}
+
+impl<S> Drop for LaneFuture<S> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, Server};
+
+    #[tokio::test]
+    async fn batches_dont_starve_interactive_lookups() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let svc = LookupService::new(server.clone());
+        let mut lanes = Lanes::new(svc.clone(), 1, 1).with_batch_threshold(2);
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // A subscriber occupies the only batch slot, so a batch lookup has to wait for it.
+        let mut subscription = lanes.call(Request::WaitForChanges(server.epoch()));
+        assert!(Pin::new(&mut subscription).poll(&mut cx).is_pending());
+        let mut batch = lanes.call(Request::FindBuckets(vec![prefix; 3]));
+        assert_eq!(lanes.lane(&Request::FindBuckets(vec![prefix; 3])), Lane::Batch);
+        assert!(Pin::new(&mut batch).poll(&mut cx).is_pending());
+
+        // Interactive lookups are served regardless.
+        lanes.call(Request::FindBuckets(vec![prefix])).await.expect("should find buckets");
+        lanes.call(Request::FindBuckets(vec![prefix; 2])).await.expect("should find buckets");
+
+        // Once the subscriber is answered, the batch lookup gets its slot.
+        svc.load(Arc::new(server.rotate(OsRng)));
+        subscription.await.expect("should return changes");
+        batch.await.expect("should find buckets");
+        let interactive = lanes.metrics(Lane::Interactive);
+        assert_eq!((interactive.requests, interactive.queued), (2, 0));
+        let batch = lanes.metrics(Lane::Batch);
+        assert_eq!((batch.requests, batch.queued), (2, 1));
+        assert!(batch.max_latency >= batch.mean_latency());
+    }
+}
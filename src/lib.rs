@@ -1,6 +1,14 @@
 #![doc = include_str!("../README.md")]
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, DefaultHasher, Hasher, RandomState},
+    io, mem,
+    ops::Range,
+    thread,
+    time::Instant,
+};
 
 use p256::{
     elliptic_curve::{
@@ -15,11 +23,166 @@ use rand::{CryptoRng, RngCore};
 use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::{
+    arena::{encoded_point, point_bytes, Arena},
+    changes::ChangeLog,
+    deadline::Deadline,
+    encoding::BucketCache,
+    point::decode_client_point,
+    protocol::{ClientHello, ServerHello, ServerInfo, SUPPORTED_VERSIONS},
+    suite::Ciphersuite,
+    user_id::{
+        check_uuid, encode_payload, point_id, Capabilities, OpaqueId, UserIdError, PAYLOAD_LEN,
+    },
+};
+
+pub use builder::{
+    BuildReport, BuildStats, ConflictPolicy, OverflowPolicy, ServerBuilder, SmallBucketPolicy,
+};
+pub use encoding::{
+    decode_any_bucket, decode_bucket, encode_bucket, encode_bucket_as, encode_bucket_shuffled,
+    BucketEncoding, BucketLookup, Conditional, CtPointBytes, DecodedBucket, EncodedBucket,
+};
+use hash::{hashed_identity, HashedIdentity, IdentityCache};
+pub use hash::{HashFunction, PreHash};
+
+pub mod abuse;
+
+pub mod admin;
+
+mod arena;
+
+pub mod audit;
+
+pub mod blinder;
+
+pub mod builder;
+
+pub mod bundle;
+
+pub mod budget;
+
+pub mod bulk;
+
+#[cfg(feature = "cdsi")]
+pub mod cdsi;
+
+pub mod changes;
+
+mod checkpoint;
+
+#[cfg(feature = "config")]
+pub mod config;
+
+pub mod deadline;
+
+pub mod decoy;
+
+pub mod directory;
+
+pub mod dummy;
+
+pub mod encoding;
+
+#[cfg(feature = "tower")]
+pub mod experiment;
+
+pub mod hash;
+
+#[cfg(feature = "tower")]
+pub mod handle;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+pub mod jobs;
+
+#[cfg(feature = "tower")]
+pub mod lanes;
+
+pub mod mac;
+
+pub mod metrics;
+
+#[cfg(feature = "uniffi")]
+pub mod mobile;
+
+pub mod namespace;
+
+pub mod planner;
+
+#[cfg(feature = "replication")]
+pub mod replication;
+
+#[cfg(feature = "tower")]
+pub mod service;
+
+pub mod point;
+
+pub mod protocol;
+
+#[cfg(feature = "quic")]
+pub mod quic;
+
+pub mod rng;
+
+#[cfg(feature = "rotation")]
+pub mod rotation;
+
+pub mod router;
+
+pub mod session;
+
+#[cfg(feature = "tower")]
+pub mod shadow;
+
+pub mod shard;
+
+pub mod signature;
+
+pub mod simulate;
+
+#[cfg(feature = "simulation")]
+pub mod simulation;
+
+#[cfg(feature = "stream")]
+pub mod stream;
+
+pub mod suite;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod transcript;
+
+pub mod token;
+
+pub mod transport;
+
+pub mod unblind;
+
+pub mod user_id;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub mod wire;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
 /// A server in a hypothetical CDS.
-#[derive(Debug)]
-pub struct Server {
+pub struct Server<S = RandomState> {
     d_s: Scalar,
-    buckets: HashMap<Prefix, HashMap<EncodedPoint, EncodedPoint>>,
+    epoch: Epoch,
+    hash: HashFunction,
+    pre_hash: PreHash,
+    buckets: Arena<S>,
+    min_bucket_size: usize,
+    max_bucket_size: Option<usize>,
+    cache: BucketCache,
+    changes: ChangeLog,
 }
 
 impl Server {
@@ -30,73 +193,628 @@ impl Server {
         let d_s = Scalar::random(rng);
 
         // Blind the address book and group it into buckets by hash prefix.
-        let mut buckets = HashMap::new();
-        for (&p, u) in users {
-            // Hash the phone number and truncate it to 8 bytes.
-            let h = sha256(p);
+        let rows = users
+            .iter()
+            .map(|(&p, u)| {
+                blind_row(&d_s, HashFunction::default(), &p.to_be_bytes(), u, 0)
+                    .expect("should be encodable")
+            })
+            .collect();
+        let buckets = Arena::from_rows(rows, RandomState::new());
+
+        Server {
+            d_s,
+            epoch: 0,
+            hash: HashFunction::default(),
+            pre_hash: PreHash::None,
+            buckets,
+            min_bucket_size: 0,
+            max_bucket_size: None,
+            cache: BucketCache::new(DEFAULT_BUCKET_CACHE_CAPACITY),
+            changes: ChangeLog::new(0),
+        }
+    }
+
+    /// Like [`Server::new`], but also return a [`BuildReport`] of the build.
+    pub fn new_with_report(
+        rng: impl CryptoRng + RngCore,
+        users: &HashMap<u64, Uuid>,
+    ) -> (Server, BuildReport) {
+        let start = Instant::now();
+        let server = Server::new(rng, users);
+        let stats = BuildStats {
+            rows: users.len(),
+            phone_numbers: users.len(),
+            buckets: server.buckets.bucket_count(),
+            ..BuildStats::default()
+        };
+        let report = BuildReport::new(&server, stats, start.elapsed());
+        (server, report)
+    }
+
+    /// Create a new server with a random secret and the given phone numbers and user IDs.
+    ///
+    /// Unlike [`Server::new`], this rejects address books which are empty, list a phone number
+    /// more than once, or contain user IDs which can't be encoded as points. Use [`ServerBuilder`]
+    /// to handle repeated phone numbers differently.
+    pub fn try_new(
+        rng: impl CryptoRng + RngCore,
+        users: impl IntoIterator<Item = (u64, Uuid)>,
+    ) -> Result<Server, BuildError> {
+        ServerBuilder::new().build(rng, users).map(|(server, _)| server)
+    }
+
+    /// Create a server from an existing table of `(prefix, sP, hsU)` rows blinded with the secret
+    /// `d_s`, e.g. one produced by another system's OPRF evaluation, without the plaintext address
+    /// book.
+    ///
+    /// Every point is checked to be a valid, non-identity point and every `sP` to be unique, and
+    /// points are re-encoded in compressed form. Whether the rows were actually blinded with `d_s`
+    /// can only be checked against plaintext, so operators should [verify](Server::verify) a sample
+    /// of the address book against the result.
+    pub fn from_blinded_rows(
+        d_s: Scalar,
+        rows: impl IntoIterator<Item = (Prefix, EncodedPoint, EncodedPoint)>,
+    ) -> Result<Server, BuildError> {
+        if bool::from(d_s.is_zero()) {
+            return Err(BuildError::InvalidSecret);
+        }
+
+        // Check and normalize every point.
+        let normalize = |p: &EncodedPoint| {
+            let p = decode_point(p).ok().filter(|p| !bool::from(p.is_identity()))?;
+            Some(p.to_encoded_point(true))
+        };
+        let rows = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, (prefix, s_p, hs_u))| {
+                let (s_p, hs_u) = normalize(&s_p)
+                    .zip(normalize(&hs_u))
+                    .ok_or(BuildError::InvalidBlindedRow(i))?;
+                Ok((prefix, s_p, hs_u))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if rows.is_empty() {
+            return Err(BuildError::Empty);
+        }
+
+        // Check that no two rows share an sP, which would make lookups ambiguous.
+        let mut seen = HashMap::with_capacity(rows.len());
+        for (i, (_, s_p, _)) in rows.iter().enumerate() {
+            if seen.insert(point_bytes(s_p), i).is_some() {
+                return Err(BuildError::InvalidBlindedRow(i));
+            }
+        }
+
+        Ok(Server {
+            d_s,
+            epoch: 0,
+            hash: HashFunction::default(),
+            pre_hash: PreHash::None,
+            buckets: Arena::from_rows(rows, RandomState::new()),
+            min_bucket_size: 0,
+            max_bucket_size: None,
+            cache: BucketCache::new(DEFAULT_BUCKET_CACHE_CAPACITY),
+            changes: ChangeLog::new(0),
+        })
+    }
+}
+
+impl Server<SeededState> {
+    /// Create a new server with a random secret and the given address book of phone numbers and
+    /// user IDs, using a hasher seeded from `rng` and allocating no more than `heap_limit` bytes.
+    ///
+    /// Unlike [`Server::new`], this requires no entropy beyond `rng` and sizes every map exactly
+    /// before blinding any rows, failing with [`BuildError::HeapLimitExceeded`] before allocating
+    /// if the directory won't fit. This makes it suitable for environments with small, fixed heaps
+    /// like secure enclaves.
+    pub fn new_bounded(
+        mut rng: impl CryptoRng + RngCore,
+        users: &HashMap<u64, Uuid>,
+        heap_limit: usize,
+    ) -> Result<Server<SeededState>, BuildError> {
+        // Generate a hasher seed and a random secret.
+        let hasher = SeededState::new(rng.next_u64());
+        let d_s = Scalar::random(rng);
+
+        // Check that the prefix index alone fits before allocating it.
+        let index_size = users.len() * mem::size_of::<(Prefix, u64, &Uuid)>();
+        if index_size > heap_limit {
+            return Err(BuildError::HeapLimitExceeded { required: index_size, limit: heap_limit });
+        }
+
+        // Index the address book by hash prefix.
+        let mut index = Vec::with_capacity(users.len());
+        let hash = HashFunction::default();
+        index.extend(users.iter().map(|(&p, u)| (prefix(&hash.hash(&p.to_be_bytes())), p, u)));
+        index.sort_unstable_by_key(|&(prefix, p, _)| (prefix, p));
+
+        // Calculate the exact size of the buckets.
+        let n = index.chunk_by(|a, b| a.0 == b.0).count();
+        let required = index_size
+            + table_size::<Prefix, Range<usize>>(n)
+            + users.len() * mem::size_of::<arena::Entry>();
+        if required > heap_limit {
+            return Err(BuildError::HeapLimitExceeded { required, limit: heap_limit });
+        }
+
+        // Allocate the buckets up front and blind the address book into them.
+        let mut buckets = Arena::with_capacity_and_hasher(n, users.len(), hasher);
+        for rows in index.chunk_by(|a, b| a.0 == b.0) {
+            let bucket = rows
+                .iter()
+                .map(|&(_, p, u)| {
+                    blind_row(&d_s, hash, &p.to_be_bytes(), u, 0)
+                        .map(|(_, s_p, hs_u)| (point_bytes(&s_p), point_bytes(&hs_u)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            buckets.push(rows[0].0, bucket);
+        }
+
+        Ok(Server {
+            d_s,
+            epoch: 0,
+            hash,
+            pre_hash: PreHash::None,
+            buckets,
+            min_bucket_size: 0,
+            max_bucket_size: None,
+            cache: BucketCache::new(DEFAULT_BUCKET_CACHE_CAPACITY),
+            changes: ChangeLog::new(0),
+        })
+    }
+}
+
+impl<S: BuildHasher + Clone> Server<S> {
+    /// Cache the encodings of at most `capacity` buckets, instead of the default of
+    /// [`DEFAULT_BUCKET_CACHE_CAPACITY`].
+    pub fn with_bucket_cache_capacity(self, capacity: usize) -> Server<S> {
+        Server { cache: BucketCache::new(capacity), ..self }
+    }
+
+    /// The hash function used to derive phone numbers' hash prefixes.
+    pub fn hash_function(&self) -> HashFunction {
+        self.hash
+    }
+
+    /// The function used to pre-hash phone numbers.
+    pub fn pre_hash(&self) -> PreHash {
+        self.pre_hash
+    }
+
+    /// The server's ciphersuite.
+    pub fn ciphersuite(&self) -> Ciphersuite {
+        self.hash.into()
+    }
 
-            // Hash the phone number to a point on the curve and blind it with the server secret.
-            let s_p = hash_to_curve(p) * d_s;
+    /// The epoch of the server's secret. Servers start at epoch zero and each call to
+    /// [`Server::rotate`] increments it.
+    pub fn epoch(&self) -> Epoch {
+        self.epoch
+    }
 
-            // Encode the user ID as a point and blind it with both the server's secret and the hash
-            // of the phone number.
-            let hs_u = encode_to_point(u) * d_s * Scalar::reduce_nonzero_bytes(&h.into());
+    /// The minimum number of entries in a non-empty bucket, or zero if bucket sizes aren't
+    /// enforced.
+    pub fn min_bucket_size(&self) -> usize {
+        self.min_bucket_size
+    }
 
-            // Record the (prefix, sP, hsU) row.
-            buckets.entry(prefix(&h)).or_insert_with(HashMap::new).insert(
-                s_p.to_affine().to_encoded_point(true),
-                hs_u.to_affine().to_encoded_point(true),
-            );
+    /// The maximum number of entries in a bucket, including the [overflow
+    /// bucket](Server::overflow_bucket), or `None` if bucket sizes aren't capped.
+    pub fn max_bucket_size(&self) -> Option<usize> {
+        self.max_bucket_size
+    }
+
+    /// Return a digest of the server's epoch, public key, bucket size limits, split buckets, and
+    /// buckets, which identifies its contents. This hashes every bucket, so callers should compute
+    /// it once per server.
+    ///
+    /// Buckets are hashed in order of prefix and entries in order of `sP`, so identical directories
+    /// have identical digests regardless of the order they were built in.
+    pub fn digest(&self) -> [u8; 32] {
+        let public_key = (ProjectivePoint::GENERATOR * self.d_s).to_affine().to_encoded_point(true);
+        let mut h = Sha256::new()
+            .chain_update(b"zk-cds-digest")
+            .chain_update(self.epoch.to_be_bytes())
+            .chain_update(public_key.as_bytes())
+            .chain_update((self.min_bucket_size as u64).to_be_bytes())
+            .chain_update(self.max_bucket_size.map_or(u64::MAX, |n| n as u64).to_be_bytes())
+            .chain_update((self.buckets.split().len() as u64).to_be_bytes());
+        for prefix in self.buckets.split() {
+            h.update(prefix);
+        }
+        for (prefix, bucket) in self.buckets.sorted() {
+            h.update(prefix);
+            h.update((bucket.len() as u64).to_be_bytes());
+            for (s_p, hs_u) in bucket {
+                h.update(s_p);
+                h.update(hs_u);
+            }
         }
+        h.finalize().into()
+    }
 
-        Server { d_s, buckets }
+    /// Describe the server's deployment for clients, with its contents' [digest](Server::digest).
+    pub fn info(&self) -> ServerInfo {
+        ServerInfo {
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            epoch: self.epoch,
+            digest: self.digest(),
+            suite: self.ciphersuite().id(),
+            prefix_len: PREFIX_LEN as u8,
+            min_bucket_size: self.min_bucket_size as u64,
+        }
+    }
+
+    /// Create a server for the next epoch with a new random secret.
+    ///
+    /// The existing buckets are re-blinded with the new secret, so the plaintext address book isn't
+    /// needed. Clients must not mix responses from different epochs.
+    pub fn rotate(&self, rng: impl CryptoRng + RngCore) -> Server<S> {
+        self.rotate_to(Scalar::random(rng))
+    }
+
+    /// Create a server for the next epoch with the given secret.
+    fn rotate_to(&self, d_s: Scalar) -> Server<S> {
+        // Calculate the ratio of the new secret to the old one.
+        let r = d_s * self.d_s.invert().expect("should be invertible");
+
+        // Re-blind every (sP, hsU) pair with the ratio.
+        let buckets = self.buckets.map(|(s_p, hs_u)| {
+            let s_p = decode_point(&encoded_point(s_p)).expect("should be a valid point") * r;
+            let hs_u = decode_point(&encoded_point(hs_u)).expect("should be a valid point") * r;
+            (
+                point_bytes(&s_p.to_affine().to_encoded_point(true)),
+                point_bytes(&hs_u.to_affine().to_encoded_point(true)),
+            )
+        });
+
+        Server {
+            d_s,
+            epoch: self.epoch + 1,
+            hash: self.hash,
+            pre_hash: self.pre_hash,
+            buckets,
+            min_bucket_size: self.min_bucket_size,
+            max_bucket_size: self.max_bucket_size,
+            cache: BucketCache::new(self.cache.capacity()),
+            changes: ChangeLog::new(self.epoch + 1),
+        }
+    }
+
+    /// Re-derive the blinded rows for the given phone numbers and user IDs (e.g. the plaintext
+    /// address book or a sample of it) and check them against the stored buckets, returning any
+    /// discrepancies found.
+    ///
+    /// This catches corruption from storage backends and botched key rotations before clients see
+    /// wrong answers.
+    pub fn verify<'a>(
+        &self,
+        users: impl IntoIterator<Item = (&'a u64, &'a Uuid)>,
+    ) -> Vec<Discrepancy> {
+        users
+            .into_iter()
+            .filter_map(|(&p, u)| {
+                // Check each of the phone number's entries for the user ID, in order.
+                let id = self.pre_hash.apply(p);
+                let h = self.hash.hash(&id);
+                for i in 0.. {
+                    let Ok((_, s_p, hs_u)) = blind_row(&self.d_s, self.hash, &id, u, i) else {
+                        return Some(Discrepancy::UnencodableUserId(p));
+                    };
+                    match self.find_entry(&h, &s_p) {
+                        None if i == 0 => return Some(Discrepancy::Missing(p)),
+                        None => return Some(Discrepancy::WrongUserId(p)),
+                        Some(&stored)
+                            if CtPointBytes(stored) == CtPointBytes(point_bytes(&hs_u)) =>
+                        {
+                            return None
+                        }
+                        Some(_) => {}
+                    }
+                }
+                unreachable!("should find a missing entry")
+            })
+            .collect()
+    }
+
+    /// Return an estimate of the number of bytes of heap memory used by the server's buckets.
+    pub fn heap_size(&self) -> usize {
+        self.buckets.heap_size()
     }
 
     /// Given a hash prefix and a blinded phone number point, return the double-blinded phone number
     /// point and the bucket of users.
-    pub fn find_bucket(&self, prefix: Prefix) -> HashMap<EncodedPoint, EncodedPoint> {
+    pub fn find_bucket(&self, prefix: Prefix) -> HashMap<EncodedPoint, EncodedPoint, S> {
         // Find the bucket of blinded phone number and user ID points.
-        self.buckets.get(&prefix).cloned().unwrap_or_default()
+        let entries = self.buckets.get(&prefix);
+        let mut bucket =
+            HashMap::with_capacity_and_hasher(entries.len(), self.buckets.hasher().clone());
+        bucket.extend(entries.iter().map(|(s_p, hs_u)| (encoded_point(s_p), encoded_point(hs_u))));
+        bucket
+    }
+
+    /// Whether the bucket with the given hash prefix was split into sub-buckets, because it had more
+    /// entries than the [maximum bucket size](ServerBuilder::max_bucket_size).
+    ///
+    /// A split bucket's entries are served by [`Server::find_bucket`] under their
+    /// [sub-prefixes](Client::sub_prefix), not its prefix.
+    pub fn is_split(&self, prefix: Prefix) -> bool {
+        self.buckets.is_split(&prefix)
+    }
+
+    /// Whether the given prefix is that of a sub-bucket of a [split](Server::is_split) bucket.
+    pub fn is_sub_bucket(&self, prefix: Prefix) -> bool {
+        self.buckets.is_sub_bucket(&prefix)
+    }
+
+    /// Return the overflow bucket of entries which didn't fit in their buckets, if any, which
+    /// clients search for entries they don't find in their own bucket.
+    pub fn overflow_bucket(&self) -> HashMap<EncodedPoint, EncodedPoint, S> {
+        self.find_bucket(OVERFLOW_PREFIX)
+    }
+
+    /// Given a hash prefix, return the encoded bucket of users and its content hash.
+    ///
+    /// Encodings are cached, so frequently requested buckets are only encoded once for the lifetime
+    /// of the server (and thus its secret).
+    pub fn encoded_bucket(&self, prefix: Prefix) -> EncodedBucket {
+        self.cache.get_or_encode(prefix, || self.find_bucket(prefix))
+    }
+
+    /// Given a hash prefix and the value of an HTTP `If-None-Match` header, if any, return the
+    /// encoded bucket of users only if the client doesn't already have it.
+    pub fn encoded_bucket_if_none_match(
+        &self,
+        prefix: Prefix,
+        if_none_match: Option<&str>,
+    ) -> Conditional {
+        let encoded = self.encoded_bucket(prefix);
+        match if_none_match {
+            Some(tags) if encoded.matches(tags) => Conditional::NotModified,
+            _ => Conditional::Modified(encoded),
+        }
+    }
+
+    /// The server's public key, `d_s·G`, which clients need to find every user ID for a phone number
+    /// with [`Client::find_user_ids`].
+    pub fn public_key(&self) -> EncodedPoint {
+        (ProjectivePoint::GENERATOR * self.d_s).to_affine().to_encoded_point(true)
+    }
+
+    /// Given a client's hello message, negotiate a protocol version, failing with
+    /// [`Error::SuiteMismatch`] if the client names a different ciphersuite.
+    pub fn handshake(&self, hello: &ClientHello) -> Result<ServerHello, Error> {
+        if let Some(suite) = hello.suite {
+            self.ciphersuite().check(suite)?;
+        }
+        hello.negotiate(SUPPORTED_VERSIONS)
     }
 
-    /// Given a blinded user ID point, unblind it and recover the encoded UUID.
+    /// Given a blinded user ID point, unblind it and recover the encoded UUID, or `None` if it
+    /// doesn't encode a valid one.
+    ///
+    /// This unblinds any point, so deployments which need to bind unblinding to lookups should use
+    /// [`Server::unblind_proven`] instead.
     pub fn unblind_user_id(&self, s_u: &EncodedPoint) -> Option<Uuid> {
+        self.try_unblind_user_id(s_u).ok()
+    }
+
+    /// Like [`Server::unblind_user_id`], but return why the point doesn't encode a valid UUID.
+    pub fn try_unblind_user_id(&self, s_u: &EncodedPoint) -> Result<Uuid, UserIdError> {
+        check_uuid(self.unblind_opaque_id(s_u)?.0)
+    }
+
+    /// Given a blinded user ID point, unblind it and recover the encoded user ID, without checking
+    /// it's a valid UUID.
+    pub fn unblind_opaque_id(&self, s_u: &EncodedPoint) -> Result<OpaqueId, UserIdError> {
+        Ok(self.unblind_entry(s_u)?.0)
+    }
+
+    /// Given a blinded user ID point, unblind it and recover the user's capabilities.
+    pub fn unblind_capabilities(&self, s_u: &EncodedPoint) -> Result<Capabilities, UserIdError> {
+        Ok(self.unblind_entry(s_u)?.1)
+    }
+
+    fn unblind_entry(&self, s_u: &EncodedPoint) -> Result<(OpaqueId, Capabilities), Error> {
         // Unblind the double blinded point, giving us the server's point for this phone number.
-        let s_u = AffinePoint::from_encoded_point(s_u).expect("should be a valid point");
-        let u = (s_u * self.d_s.invert().expect("should be invertible")).to_encoded_point(true);
-        Uuid::from_slice(&u.as_bytes()[1..17]).ok()
+        let s_u = decode_point(s_u)?;
+        point_id(s_u * self.d_s.invert().expect("should be invertible"))
     }
 
     /// Given a client-blinded phone number point, return a double-blinded phone number point.
-    pub fn blind_phone_number(&self, c_p: &EncodedPoint) -> EncodedPoint {
-        let c_p = AffinePoint::from_encoded_point(c_p).expect("should be a valid point");
-        (c_p * self.d_s).to_affine().to_encoded_point(true)
+    ///
+    /// The point may be compressed or uncompressed, and is always returned compressed. Returns
+    /// [`Error::InvalidPoint`] if it's invalid, the identity, or in any other encoding.
+    pub fn blind_phone_number(&self, c_p: &EncodedPoint) -> Result<EncodedPoint, Error> {
+        let c_p = decode_client_point(c_p.as_bytes())?;
+        Ok((c_p * self.d_s).to_affine().to_encoded_point(true))
+    }
+
+    /// Given a batch of client-blinded phone number points, return the double-blinded points,
+    /// abandoning the batch with [`Error::DeadlineExceeded`] once the deadline passes.
+    ///
+    /// Points may be compressed or uncompressed, and are always returned compressed. Returns
+    /// [`Error::InvalidPoint`] if any point is invalid or in any other encoding, before blinding any
+    /// of them.
+    pub fn blind_phone_numbers(
+        &self,
+        c_ps: &[EncodedPoint],
+        deadline: &Deadline,
+    ) -> Result<Vec<EncodedPoint>, Error> {
+        let c_ps = c_ps
+            .iter()
+            .map(|c_p| decode_client_point(c_p.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        deadline.map(c_ps, |c_p| (c_p * self.d_s).to_affine().to_encoded_point(true))
+    }
+
+    /// Return the buckets for a batch of hash prefixes, abandoning the batch with
+    /// [`Error::DeadlineExceeded`] once the deadline passes.
+    pub fn find_buckets(
+        &self,
+        prefixes: &[Prefix],
+        deadline: &Deadline,
+    ) -> Result<Vec<HashMap<EncodedPoint, EncodedPoint, S>>, Error> {
+        deadline.map(prefixes, |&prefix| self.find_bucket(prefix))
+    }
+}
+
+impl<S: BuildHasher> Server<S> {
+    /// Return the prefix of the bucket an identity with the given hash is in: its hash prefix, or
+    /// its sub-prefix if that bucket was split.
+    fn bucket_prefix(&self, h: &[u8; 32]) -> Prefix {
+        let prefix = prefix(h);
+        if self.buckets.is_split(&prefix) {
+            sub_prefix(h)
+        } else {
+            prefix
+        }
+    }
+
+    /// Return the `hsU` point stored for the given `sP` point of an identity with the given hash,
+    /// searching its bucket and then the overflow bucket.
+    fn find_entry(&self, h: &[u8; 32], s_p: &EncodedPoint) -> Option<&arena::PointBytes> {
+        [self.bucket_prefix(h), OVERFLOW_PREFIX]
+            .iter()
+            .find_map(|prefix| self.buckets.find(prefix, s_p))
+    }
+
+    /// Like [`Server::find_entry`], but given only an identity's hash prefix, so every sub-bucket of
+    /// a split bucket is searched.
+    fn find_prefix_entry(&self, prefix: &Prefix, s_p: &EncodedPoint) -> Option<&arena::PointBytes> {
+        let prefixes = if self.buckets.is_split(prefix) {
+            (0..=u8::MAX).map(|b| sub_bucket_prefix(prefix, &[b])).collect()
+        } else {
+            vec![*prefix]
+        };
+        prefixes
+            .into_iter()
+            .chain([OVERFLOW_PREFIX])
+            .find_map(|prefix| self.buckets.find(&prefix, s_p))
+    }
+}
+
+impl<S> fmt::Debug for Server<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the server secret into logs.
+        f.debug_struct("Server")
+            .field("epoch", &self.epoch)
+            .field("hash", &self.hash)
+            .field("pre_hash", &self.pre_hash)
+            .field("buckets", &self.buckets.bucket_count())
+            .field("entries", &self.buckets.entry_count())
+            .field("min_bucket_size", &self.min_bucket_size)
+            .field("max_bucket_size", &self.max_bucket_size)
+            .finish_non_exhaustive()
     }
 }
 
 /// A client in a hypothetical CDS.
-#[derive(Debug)]
 pub struct Client {
     d_c: Scalar,
+    hash: HashFunction,
+    pre_hash: PreHash,
+    cache: Option<IdentityCache>,
+    threads: usize,
 }
 
 impl Client {
     /// Create a new [`Client`] using a random secret.
     pub fn new(rng: impl CryptoRng + RngCore) -> Client {
-        Client { d_c: Scalar::random(rng) }
+        Client {
+            d_c: Scalar::random(rng),
+            hash: HashFunction::default(),
+            pre_hash: PreHash::None,
+            cache: None,
+            threads: 1,
+        }
+    }
+
+    /// Use the given hash function, which must match the server's, instead of SHA-256.
+    pub fn with_hash_function(self, hash: HashFunction) -> Client {
+        Client { hash, cache: self.cache.as_ref().map(IdentityCache::cleared), ..self }
+    }
+
+    /// Pre-hash phone numbers with the given function, which must match the server's.
+    pub fn with_pre_hash(self, pre_hash: PreHash) -> Client {
+        Client { pre_hash, cache: self.cache.as_ref().map(IdentityCache::cleared), ..self }
+    }
+
+    /// Cache the hashes and hash-to-curve points of up to `capacity` recently used phone numbers,
+    /// evicting the least recently used ones, so repeat syncs of a stable address book skip
+    /// [pre-hashing](PreHash) and hashing to the curve.
+    pub fn with_identity_cache(self, capacity: usize) -> Client {
+        Client { cache: Some(IdentityCache::new(capacity)), ..self }
+    }
+
+    /// Spread batch operations across up to `threads` threads, instead of running them on the
+    /// calling thread. Mobile apps should keep the budget small, to leave cores for the UI.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn with_threads(self, threads: usize) -> Client {
+        assert!(threads > 0, "thread budget should be positive");
+        Client { threads, ..self }
+    }
+
+    /// Configure the client for the deployment described by the given [`ServerInfo`], using the
+    /// server's hash function.
+    ///
+    /// Returns an [`InfoMismatch`] if the server's suite, prefix length, or protocol versions
+    /// aren't supported, since lookups against it would never find any users.
+    pub fn configure_from_info(self, info: &ServerInfo) -> Result<Client, InfoMismatch> {
+        let suite =
+            Ciphersuite::from_id(info.suite).map_err(|_| InfoMismatch::Suite(info.suite))?;
+        if usize::from(info.prefix_len) != PREFIX_LEN {
+            return Err(InfoMismatch::PrefixLen(info.prefix_len));
+        }
+        if !info.versions.iter().any(|v| SUPPORTED_VERSIONS.contains(v)) {
+            return Err(InfoMismatch::Version);
+        }
+        Ok(self.with_hash_function(suite.hash_function()))
+    }
+
+    /// The client's ciphersuite.
+    pub fn ciphersuite(&self) -> Ciphersuite {
+        self.hash.into()
+    }
+
+    /// Return a hello message offering every supported version with the client's ciphersuite.
+    pub fn hello(&self) -> ClientHello {
+        ClientHello::with_suite(self.ciphersuite())
     }
 
     /// Initiate a client request for the given phone number. Returns the hash prefix of the phone
     /// number and a blinded phone number point.
     pub fn request_phone_number(&self, p: u64) -> (Prefix, EncodedPoint) {
-        // Hash the phone number and truncate it to 8 bytes.
-        let h = sha256(p);
+        self.blind_identity(&self.hash_phone_number(p))
+    }
 
-        // Hash the phone number to a point on the curve and blind it with the client secret.
-        let c_p = hash_to_curve(p) * self.d_c;
+    /// Return the prefix of the sub-bucket the given phone number is in, if its bucket was
+    /// [split](Server::is_split).
+    ///
+    /// Requesting a sub-bucket reveals [`SUB_PREFIX_LEN`] more bytes of the phone number's hash to
+    /// the server.
+    pub fn sub_prefix(&self, p: u64) -> Prefix {
+        sub_prefix(&self.phone_number_hash(p))
+    }
 
-        // Return the hash prefix and the blinded phone number point.
-        (prefix(&h), c_p.to_affine().to_encoded_point(true))
+    /// Like [`Client::request_phone_number`], but for a batch of phone numbers, spread across the
+    /// client's [thread budget](Client::with_threads). Returns the requests in order.
+    pub fn request_phone_numbers(&self, ps: &[u64]) -> Vec<(Prefix, EncodedPoint)> {
+        self.par_map(ps, |&p| self.request_phone_number(p))
+    }
+
+    /// Like [`Client::request_phone_number`], but for an identifier which the caller has already
+    /// hashed, for servers built with [`ServerBuilder::build_hashed`].
+    ///
+    /// Hashed identifiers are domain-separated from phone numbers, so they never match a phone
+    /// number's entry, and aren't [pre-hashed](PreHash).
+    pub fn request_hashed(&self, id_hash: &[u8; 32]) -> (Prefix, EncodedPoint) {
+        self.blind_identity(&HashedIdentity::new(self.hash, &hashed_identity(id_hash)))
     }
 
     /// Given a double-blinded phone number point and bucket of users from the server, unblind the
@@ -105,60 +823,447 @@ impl Client {
     pub fn find_user_id(
         &self,
         sc_p: &EncodedPoint,
-        bucket: &HashMap<EncodedPoint, EncodedPoint>,
+        bucket: &impl BucketLookup,
         p: u64,
-    ) -> Option<EncodedPoint> {
+    ) -> Result<Option<EncodedPoint>, Error> {
+        self.find_identity(sc_p, bucket, &self.phone_number_hash(p))
+    }
+
+    /// Like [`Client::find_user_id`], but for a batch of phone numbers, each with its
+    /// double-blinded point and bucket, spread across the client's
+    /// [thread budget](Client::with_threads). Returns the results in order.
+    pub fn find_user_id_batch<B: BucketLookup + Sync>(
+        &self,
+        responses: &[(u64, EncodedPoint, B)],
+    ) -> Vec<LookupResult> {
+        self.par_map(responses, |(p, sc_p, bucket)| LookupResult {
+            phone_number: *p,
+            blinded_user_id: self.find_user_id(sc_p, bucket, *p),
+        })
+    }
+
+    /// Like [`Client::find_user_id`], but for an identifier requested with
+    /// [`Client::request_hashed`].
+    pub fn find_user_id_hashed(
+        &self,
+        sc_p: &EncodedPoint,
+        bucket: &impl BucketLookup,
+        id_hash: &[u8; 32],
+    ) -> Result<Option<EncodedPoint>, Error> {
+        self.find_identity(sc_p, bucket, &self.hash.hash(&hashed_identity(id_hash)))
+    }
+
+    /// Return the hashed identity of the given phone number, from the identity cache if possible.
+    fn hash_phone_number(&self, p: u64) -> HashedIdentity {
+        let hash = || HashedIdentity::new(self.hash, &self.pre_hash.apply(p));
+        match &self.cache {
+            Some(cache) => cache.get_or_hash(p, hash),
+            None => hash(),
+        }
+    }
+
+    /// Return the hash of the given phone number's identity, without hashing it to the curve
+    /// unless it's being cached.
+    fn phone_number_hash(&self, p: u64) -> [u8; 32] {
+        match &self.cache {
+            Some(_) => self.hash_phone_number(p).h,
+            None => self.hash.hash(&self.pre_hash.apply(p)),
+        }
+    }
+
+    /// Map `f` over the given items, in contiguous chunks on up to the thread budget's threads.
+    fn par_map<T: Sync, U: Send>(&self, items: &[T], f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+        if self.threads == 1 || items.len() < 2 {
+            return items.iter().map(f).collect();
+        }
+        let chunk_size = items.len().div_ceil(self.threads);
+        thread::scope(|s| {
+            let handles = items
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(|| chunk.iter().map(&f).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("batch operation should not panic"))
+                .collect()
+        })
+    }
+
+    fn blind_identity(&self, id: &HashedIdentity) -> (Prefix, EncodedPoint) {
+        // Blind the phone number point with the client secret.
+        let c_p = id.h_p * self.d_c;
+
+        // Return the hash prefix and the blinded phone number point.
+        (prefix(&id.h), c_p.to_affine().to_encoded_point(true))
+    }
+
+    fn find_identity(
+        &self,
+        sc_p: &EncodedPoint,
+        bucket: &impl BucketLookup,
+        h: &[u8; 32],
+    ) -> Result<Option<EncodedPoint>, Error> {
         // Unblind the double blinded point, giving us the server's point for this phone number.
-        let sc_p = AffinePoint::from_encoded_point(sc_p).expect("should be a valid point");
+        let sc_p = decode_point(sc_p)?;
         let s_p = (sc_p * self.d_c.invert().expect("should be invertible")).to_encoded_point(true);
 
         // Use it to find the user ID point, if any.
-        if let Some(hs_u) = bucket.get(&s_p).cloned() {
+        if let Some(hs_u) = bucket.get_entry(&s_p) {
             // Hash the phone number and reduce it to a scalar.
-            let h = Scalar::reduce_nonzero_bytes(&sha256(p).into());
+            let h = Scalar::reduce_nonzero_bytes(&(*h).into());
 
             // Unblind the user ID point.
-            let hs_u = AffinePoint::from_encoded_point(&hs_u).expect("should be a valid point");
+            let hs_u = decode_point(hs_u)?;
             let s_u = hs_u * h.invert().expect("should be invertible");
 
             // Return it.
-            Some(s_u.to_affine().to_encoded_point(true))
+            Ok(Some(s_u.to_affine().to_encoded_point(true)))
         } else {
-            None
+            Ok(None)
+        }
+    }
+
+    /// Given a phone number and its double-blinded point from the server, derive a 32-byte secret
+    /// for the match.
+    ///
+    /// The secret is a hash of the phone number and its server-blinded point, `sP`, so the owner of
+    /// the phone number can derive the same secret by looking up their own number, and use it to
+    /// bootstrap an end-to-end encrypted handshake with the client. Any party which knows the phone
+    /// number and can query the server can also derive it, so it must not be used as an
+    /// authenticated key. The secret changes when the server's secret is rotated, so both parties
+    /// must derive it in the same [`Epoch`].
+    pub fn derive_match_secret(&self, p: u64, sc_p: &EncodedPoint) -> Result<[u8; 32], Error> {
+        // Unblind the double blinded point, giving us the server's point for this phone number.
+        let sc_p = decode_point(sc_p)?;
+        let s_p = (sc_p * self.d_c.invert().expect("should be invertible")).to_encoded_point(true);
+
+        // Hash it along with the phone number.
+        Ok(Sha256::new()
+            .chain_update(b"zk-cds-match-secret")
+            .chain_update(p.to_be_bytes())
+            .chain_update(s_p.as_bytes())
+            .finalize()
+            .into())
+    }
+
+    /// Like [`Client::find_user_id`], but return every user ID point for the phone number, in the
+    /// order they were listed in the server's address book.
+    ///
+    /// Servers built with [`ConflictPolicy::KeepAll`] store the `i`-th user ID for a phone number
+    /// under `d_s·(H(p) + i·G)`, so the client walks those entries using the server's public key
+    /// until one is missing.
+    pub fn find_user_ids(
+        &self,
+        sc_p: &EncodedPoint,
+        bucket: &impl BucketLookup,
+        p: u64,
+        public_key: &EncodedPoint,
+    ) -> Result<Vec<EncodedPoint>, Error> {
+        // Unblind the double blinded point, giving us the server's point for this phone number.
+        let sc_p = decode_point(sc_p)?;
+        let mut s_p = sc_p * self.d_c.invert().expect("should be invertible");
+        let public_key = ProjectivePoint::from(decode_point(public_key)?);
+
+        // Hash the phone number and reduce it to a scalar.
+        let h_inv = Scalar::reduce_nonzero_bytes(&self.phone_number_hash(p).into())
+            .invert()
+            .expect("should be invertible");
+
+        // Unblind each user ID point until an entry is missing.
+        let mut user_ids = Vec::new();
+        while let Some(hs_u) = bucket.get_entry(&s_p.to_affine().to_encoded_point(true)) {
+            let s_u = decode_point(hs_u)? * h_inv;
+            user_ids.push(s_u.to_affine().to_encoded_point(true));
+            s_p += public_key;
+        }
+        Ok(user_ids)
+    }
+}
+
+/// A discrepancy between a server's buckets and its plaintext address book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The phone number is missing from its bucket.
+    Missing(u64),
+    /// The phone number's entry in its bucket maps to the wrong user ID.
+    WrongUserId(u64),
+    /// The phone number's user ID can't be encoded as a point, so its entries can't be checked.
+    UnencodableUserId(u64),
+}
+
+/// The result of looking up a phone number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LookupResult {
+    /// The phone number which was looked up.
+    pub phone_number: u64,
+    /// The server-blinded user ID point for the phone number, if it was found.
+    pub blinded_user_id: Result<Option<EncodedPoint>, Error>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the client secret into logs.
+        f.debug_struct("Client")
+            .field("hash", &self.hash)
+            .field("pre_hash", &self.pre_hash)
+            .field("identity_cache", &self.cache.as_ref().map(IdentityCache::capacity))
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An error returned when building a [`Server`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// Building the server would require more heap memory than the given limit.
+    HeapLimitExceeded {
+        /// The estimated number of bytes required.
+        required: usize,
+        /// The maximum number of bytes allowed.
+        limit: usize,
+    },
+    /// The address book was empty.
+    Empty,
+    /// The address book listed the phone number more than once.
+    DuplicatePhoneNumber(u64),
+    /// The address book listed the hashed identifier more than once.
+    DuplicateHashedId([u8; 32]),
+    /// The user ID could not be encoded as a point.
+    UnencodableUserId(Uuid),
+    /// The given number of buckets had fewer entries than the minimum bucket size.
+    BucketsTooSmall(usize),
+    /// The given number of buckets (or sub-buckets) had more entries than the maximum bucket size.
+    BucketsTooLarge(usize),
+    /// Reading or writing a checkpoint failed.
+    Checkpoint(io::ErrorKind),
+    /// A checkpoint was for a different build or was corrupt.
+    CheckpointMismatch,
+    /// A [`BulkBlinder`](blinder::BulkBlinder) returned an incorrect result.
+    BlinderMismatch,
+    /// The server secret was zero.
+    InvalidSecret,
+    /// The blinded row at the given index had an invalid point or repeated an earlier row's `sP`.
+    InvalidBlindedRow(usize),
+    /// The given phone number's entry didn't decode to a user ID with a valid integrity tag.
+    CorruptEntry(u64),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::HeapLimitExceeded { required, limit } => {
+                write!(f, "heap limit exceeded: {required} bytes required, {limit} allowed")
+            }
+            BuildError::Empty => write!(f, "empty address book"),
+            BuildError::DuplicatePhoneNumber(p) => write!(f, "duplicate phone number: {p}"),
+            BuildError::DuplicateHashedId(id) => {
+                write!(f, "duplicate hashed identifier: ")?;
+                id.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+            BuildError::UnencodableUserId(u) => write!(f, "unencodable user ID: {u}"),
+            BuildError::BucketsTooSmall(n) => write!(f, "{n} buckets below minimum size"),
+            BuildError::BucketsTooLarge(n) => write!(f, "{n} buckets above maximum size"),
+            BuildError::Checkpoint(kind) => write!(f, "checkpoint I/O error: {kind}"),
+            BuildError::CheckpointMismatch => write!(f, "checkpoint is for a different build"),
+            BuildError::BlinderMismatch => write!(f, "bulk blinder returned an incorrect result"),
+            BuildError::InvalidSecret => write!(f, "invalid server secret"),
+            BuildError::InvalidBlindedRow(i) => write!(f, "invalid blinded row: {i}"),
+            BuildError::CorruptEntry(p) => write!(f, "corrupt entry for phone number: {p}"),
         }
     }
 }
 
-/// Use a try-and-increment algorithm to encode the given user ID as a point on the P-256 curve.
+impl std::error::Error for BuildError {}
+
+/// An error returned when processing a message from the other party.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Error))]
+pub enum Error {
+    /// A point was not a valid encoding of a point on the P-256 curve.
+    InvalidPoint,
+    /// A message was not validly encoded.
+    InvalidMessage,
+    /// The client and server have no protocol version in common.
+    VersionMismatch,
+    /// The server hasn't loaded an epoch yet.
+    NotReady,
+    /// A request lacked a valid session token.
+    Unauthorized,
+    /// The client and server use different ciphersuites.
+    SuiteMismatch,
+    /// A user ID's integrity tag didn't match, so its bucket entry was corrupted or substituted.
+    CorruptEntry,
+    /// A bucket response didn't match the request it was returned for.
+    BucketMismatch,
+    /// The client has made as many requests as it's allowed to for now.
+    QuotaExhausted,
+    /// The request's deadline passed, or it was cancelled, before it was answered.
+    DeadlineExceeded,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidPoint => write!(f, "invalid point encoding"),
+            Error::InvalidMessage => write!(f, "invalid message encoding"),
+            Error::VersionMismatch => write!(f, "no mutually supported protocol version"),
+            Error::NotReady => write!(f, "server not ready"),
+            Error::Unauthorized => write!(f, "unauthorized"),
+            Error::SuiteMismatch => write!(f, "mismatched ciphersuites"),
+            Error::CorruptEntry => write!(f, "corrupt bucket entry"),
+            Error::BucketMismatch => write!(f, "bucket does not match request"),
+            Error::QuotaExhausted => write!(f, "request quota exhausted"),
+            Error::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// An incompatibility between a client and the deployment described by a [`ServerInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoMismatch {
+    /// The server uses a suite the client doesn't support.
+    Suite(u8),
+    /// The server uses a different hash prefix length.
+    PrefixLen(u8),
+    /// The client and server have no protocol version in common.
+    Version,
+}
+
+impl fmt::Display for InfoMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfoMismatch::Suite(suite) => write!(f, "unsupported suite {suite}"),
+            InfoMismatch::PrefixLen(n) => write!(f, "unsupported prefix length {n}"),
+            InfoMismatch::Version => write!(f, "no mutually supported protocol version"),
+        }
+    }
+}
+
+impl std::error::Error for InfoMismatch {}
+
+/// A [`BuildHasher`] which seeds its hashers with a fixed value instead of OS-provided randomness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeededState {
+    seed: u64,
+}
+
+impl SeededState {
+    /// Create a new [`SeededState`] with the given seed.
+    pub fn new(seed: u64) -> SeededState {
+        SeededState { seed }
+    }
+}
+
+impl BuildHasher for SeededState {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+
+/// Estimate the number of bytes allocated by a hash table with the given capacity.
+fn table_size<K, V>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+
+    // Tables are allocated with a power-of-two number of slots, kept at most 7/8ths full, plus a
+    // control byte per slot and a trailing group of control bytes.
+    let slots = if capacity < 8 { capacity + 1 } else { capacity * 8 / 7 }.next_power_of_two();
+    slots * (mem::size_of::<(K, V)>() + 1) + 16
+}
+
+/// Decode the given point, returning [`Error::InvalidPoint`] if it's not on the curve.
+fn decode_point(p: &EncodedPoint) -> Result<AffinePoint, Error> {
+    Option::from(AffinePoint::from_encoded_point(p)).ok_or(Error::InvalidPoint)
+}
+
+/// Hash the phone number's identity to its prefix, then blind it and its user ID with the server
+/// secret.
+///
+/// The `i`-th user ID for a phone number is stored under `d_s·(H(p) + i·G)`.
+fn blind_row(
+    d_s: &Scalar,
+    hash: HashFunction,
+    id: &[u8],
+    u: &Uuid,
+    i: u64,
+) -> Result<(Prefix, EncodedPoint, EncodedPoint), BuildError> {
+    let (prefix, h_p, h, u) = unblinded_row(hash, id, u, i)?;
+
+    // Blind the phone number point with the server secret, and the user ID point with both the
+    // server secret and the hash of the phone number.
+    let s_p = h_p * d_s;
+    let hs_u = u * (d_s * &h);
+
+    Ok((prefix, s_p.to_affine().to_encoded_point(true), hs_u.to_affine().to_encoded_point(true)))
+}
+
+/// Return the hash prefix, phone number point, phone number hash scalar, and user ID point for
+/// the `i`-th user ID of the given identity, before blinding.
+fn unblinded_row(
+    hash: HashFunction,
+    id: &[u8],
+    u: &Uuid,
+    i: u64,
+) -> Result<(Prefix, ProjectivePoint, Scalar, ProjectivePoint), BuildError> {
+    // Hash the phone number.
+    let h = hash.hash(id);
+
+    // Hash the phone number to a point on the curve and offset it by the index.
+    let mut h_p = hash_to_curve(id);
+    if i > 0 {
+        h_p += ProjectivePoint::GENERATOR * Scalar::from(i);
+    }
+
+    // Encode the user ID and its integrity tag as a point.
+    let u = encode_to_point(u, Capabilities::NONE, &h).ok_or(BuildError::UnencodableUserId(*u))?;
+
+    Ok((prefix(&h), h_p, Scalar::reduce_nonzero_bytes(&h.into()), u.into()))
+}
+
+/// Use a try-and-increment algorithm to encode the given user ID, followed by its integrity tag
+/// for the phone number with the given hash and its capabilities, as a point on the P-256 curve.
+///
+/// Gives up after [`MAX_ENCODING_ATTEMPTS`] candidates, which for a random UUID happens with
+/// probability of roughly 2^-256.
 ///
 /// **N.B.:** This is a variable time encoding, but it isn't used online.
-fn encode_to_point(user_id: &Uuid) -> AffinePoint {
+fn encode_to_point(user_id: &Uuid, caps: Capabilities, h: &[u8; 32]) -> Option<AffinePoint> {
     let mut buf = [0u8; 33];
     buf[0] = sec1::Tag::Compact.into();
-    buf[1..17].copy_from_slice(user_id.as_bytes());
+    buf[1..1 + PAYLOAD_LEN].copy_from_slice(&encode_payload(user_id.as_bytes(), caps, h));
 
-    let mut i = 0u128;
-    loop {
-        buf[17..].copy_from_slice(&i.to_le_bytes());
+    for i in 0..MAX_ENCODING_ATTEMPTS {
+        buf[1 + PAYLOAD_LEN..].copy_from_slice(&i.to_le_bytes());
         if let Ok(encoded) = EncodedPoint::from_bytes(buf) {
             if let Some(p) = AffinePoint::from_encoded_point(&encoded).into() {
-                return p;
+                return Some(p);
             }
         }
-        i += 1;
     }
+    None
 }
 
+/// The maximum number of candidate points tried when encoding a user ID.
+const MAX_ENCODING_ATTEMPTS: u32 = 256;
+
 /// Hash `b` to a point on the P-256 curve using the method in RFC 9380 using SHA-256.
-fn hash_to_curve(b: u64) -> ProjectivePoint {
-    NistP256::hash_from_bytes::<ExpandMsgXmd<Sha256>>(&[&b.to_be_bytes()], &[b"zk-cds-prototype"])
+fn hash_to_curve(b: &[u8]) -> ProjectivePoint {
+    NistP256::hash_from_bytes::<ExpandMsgXmd<Sha256>>(&[b], &[b"zk-cds-prototype"])
         .expect("should produce a valid point")
 }
 
-/// Hash `b` with SHA-256.
-fn sha256(b: u64) -> [u8; 32] {
-    sha2::Sha256::new().chain_update(b.to_be_bytes()).finalize().into()
-}
+/// A counter identifying a server secret.
+pub type Epoch = u64;
+
+/// The default maximum number of encoded buckets cached by a [`Server`].
+pub const DEFAULT_BUCKET_CACHE_CAPACITY: usize = 4096;
 
 /// A fixed-size prefix of an SHA-256 hash.
 pub type Prefix = [u8; PREFIX_LEN];
@@ -170,11 +1275,42 @@ fn prefix(b: &[u8]) -> Prefix {
     b[..PREFIX_LEN].try_into().expect("should be at least 8 bytes long")
 }
 
+/// The number of additional hash bytes which select a sub-bucket of a split bucket.
+pub const SUB_PREFIX_LEN: usize = 1;
+
+/// The prefix of the overflow bucket, which holds the entries which didn't fit in their own buckets.
+///
+/// Hash prefixes are uniformly distributed, so a real bucket shares it with negligible probability.
+pub const OVERFLOW_PREFIX: Prefix = *b"overflow";
+
+/// Return the prefix of the sub-bucket an identity with the given hash is in, if its bucket was
+/// split.
+///
+/// Sub-buckets are stored alongside buckets, so their prefixes are derived from the longer prefix
+/// with a domain-separated hash.
+fn sub_prefix(h: &[u8; 32]) -> Prefix {
+    let extra: &[u8; SUB_PREFIX_LEN] =
+        h[PREFIX_LEN..][..SUB_PREFIX_LEN].try_into().expect("should be long enough");
+    sub_bucket_prefix(&prefix(h), extra)
+}
+
+/// Return the prefix of the sub-bucket of the given bucket selected by the given extra hash bytes.
+fn sub_bucket_prefix(prefix: &Prefix, extra: &[u8; SUB_PREFIX_LEN]) -> Prefix {
+    let h = Sha256::new()
+        .chain_update(b"zk-cds sub-bucket")
+        .chain_update(prefix)
+        .chain_update(extra)
+        .finalize();
+    self::prefix(&h)
+}
+
 #[cfg(test)]
 mod tests {
-    use rand::rngs::OsRng;
+    use rand::{rngs::OsRng, SeedableRng};
+    use rand_chacha::ChaChaRng;
 
     use super::*;
+    use crate::protocol::ProtocolVersion;
 
     #[test]
     fn round_trip() {
@@ -196,11 +1332,12 @@ mod tests {
         let bucket = server.find_bucket(prefix);
 
         // Send the blinded phone number point to the server to be double-blinded.
-        let sc_p = server.blind_phone_number(&c_p);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
 
         // Look through the bucket for the phone number and get the blinded user ID.
         let blinded_user_id = client
             .find_user_id(&sc_p, &bucket, 1234567890)
+            .expect("should be a valid response")
             .expect("should be a valid phone number");
 
         // Send the blinded user ID to the server, which unblinds it.
@@ -208,4 +1345,296 @@ mod tests {
 
         assert_eq!(user_id, users.get(&1234567890).cloned());
     }
+
+    #[test]
+    fn match_secret() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let server = Server::new(OsRng, &users);
+
+        // Two clients with different secrets derive the same secret for a phone number.
+        let secrets = (0..2)
+            .map(|_| {
+                let client = Client::new(OsRng);
+                let (_, c_p) = client.request_phone_number(1234567890);
+                client
+                    .derive_match_secret(
+                        1234567890,
+                        &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                    )
+                    .expect("should be a valid response")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(secrets[0], secrets[1]);
+
+        // But not after the server's secret is rotated.
+        let server = server.rotate(OsRng);
+        let client = Client::new(OsRng);
+        let (_, c_p) = client.request_phone_number(1234567890);
+        let rotated = client
+            .derive_match_secret(
+                1234567890,
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+            )
+            .expect("should be a valid response");
+        assert_ne!(secrets[0], rotated);
+    }
+
+    #[test]
+    fn bounded() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+
+        // A server with too little heap fails before allocating its buckets.
+        assert!(matches!(
+            Server::new_bounded(OsRng, &users, 1024),
+            Err(BuildError::HeapLimitExceeded { limit: 1024, .. })
+        ));
+
+        // A server with enough heap stays within it.
+        let server = Server::new_bounded(OsRng, &users, 64 * 1024).expect("should fit");
+        assert!(server.heap_size() <= 64 * 1024);
+
+        // And works as usual.
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let bucket = server.find_bucket(prefix);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let blinded_user_id = client
+            .find_user_id(&sc_p, &bucket, 22)
+            .expect("should be a valid response")
+            .expect("should be a valid phone number");
+        assert_eq!(server.unblind_user_id(&blinded_user_id), users.get(&22).cloned());
+    }
+
+    #[test]
+    fn verify() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+        let server = Server::new(OsRng, &users);
+
+        // A server is consistent with its address book, before and after rotation.
+        assert_eq!(server.verify(&users), vec![]);
+        assert_eq!(server.rotate(OsRng).verify(&users), vec![]);
+
+        // But not with a different one.
+        let mut other = users.clone();
+        other.insert(22, Uuid::new_v4());
+        other.insert(1234567890, Uuid::new_v4());
+        let mut discrepancies = server.verify(&other);
+        discrepancies.sort_by_key(|d| match d {
+            Discrepancy::Missing(p)
+            | Discrepancy::WrongUserId(p)
+            | Discrepancy::UnencodableUserId(p) => *p,
+        });
+        assert_eq!(
+            discrepancies,
+            vec![Discrepancy::WrongUserId(22), Discrepancy::Missing(1234567890)]
+        );
+    }
+
+    #[test]
+    fn verify_moved_entries() {
+        // Entries spilled to the overflow bucket are found there.
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let users = [(22, a), (22, b), (22, c), (23, a)];
+        let server = spilled_server(&users);
+        assert_eq!(server.verify(users.iter().map(|(p, u)| (p, u))), vec![]);
+
+        // And entries moved to sub-buckets are found in them.
+        let users = HashMap::from([(22, a), (23, b)]);
+        let server = split_bucket(Server::new(OsRng, &users), 22);
+        assert_eq!(server.verify(&users), vec![]);
+        assert_eq!(server.verify([(&22, &c)]), vec![Discrepancy::WrongUserId(22)]);
+    }
+
+    /// Build a server which keeps every user ID for a phone number and spills entries past the
+    /// second in a bucket to the overflow bucket.
+    pub(crate) fn spilled_server(users: &[(u64, Uuid)]) -> Server {
+        ServerBuilder::new()
+            .conflict_policy(ConflictPolicy::KeepAll)
+            .max_bucket_size(2, OverflowPolicy::Spill)
+            .build(OsRng, users.iter().copied())
+            .expect("should build")
+            .0
+    }
+
+    /// Move the entries of the given phone number's bucket to its sub-bucket, as if the bucket had
+    /// been too large and split.
+    pub(crate) fn split_bucket(server: Server, p: u64) -> Server {
+        let h = server.hash.hash(&server.pre_hash.apply(p));
+        move_bucket(server, prefix(&h), sub_prefix(&h), vec![prefix(&h)])
+    }
+
+    /// Move the entries of the given phone number's bucket to the overflow bucket, as if the bucket
+    /// had been too large and spilled.
+    pub(crate) fn spill_bucket(server: Server, p: u64) -> Server {
+        let h = server.hash.hash(&server.pre_hash.apply(p));
+        move_bucket(server, prefix(&h), OVERFLOW_PREFIX, vec![])
+    }
+
+    /// Move the entries of one bucket to another, marking the given prefixes as split.
+    pub(crate) fn move_bucket(
+        server: Server,
+        from: Prefix,
+        to: Prefix,
+        split: Vec<Prefix>,
+    ) -> Server {
+        let rows = server
+            .buckets
+            .sorted()
+            .into_iter()
+            .flat_map(|(prefix, bucket)| {
+                let prefix = if prefix == from { to } else { prefix };
+                bucket
+                    .iter()
+                    .map(move |(s_p, hs_u)| (prefix, encoded_point(s_p), encoded_point(hs_u)))
+            })
+            .collect();
+        let buckets = Arena::from_rows(rows, RandomState::new()).with_split(split);
+        Server { buckets, ..server }
+    }
+
+    #[test]
+    fn try_new() {
+        let u = Uuid::new_v4();
+        assert!(Server::try_new(OsRng, [(22, u), (23, u)]).is_ok());
+        assert!(matches!(Server::try_new(OsRng, []), Err(BuildError::Empty)));
+        assert!(matches!(
+            Server::try_new(OsRng, [(22, u), (23, u), (22, Uuid::new_v4())]),
+            Err(BuildError::DuplicatePhoneNumber(22))
+        ));
+    }
+
+    #[test]
+    fn from_blinded_rows() {
+        let users = HashMap::from([(22, Uuid::new_v4()), (23, Uuid::new_v4())]);
+        let server = Server::new(OsRng, &users);
+        let rows = server
+            .buckets
+            .sorted()
+            .into_iter()
+            .flat_map(|(prefix, bucket)| {
+                bucket
+                    .iter()
+                    .map(move |(s_p, hs_u)| (prefix, encoded_point(s_p), encoded_point(hs_u)))
+            })
+            .collect::<Vec<_>>();
+
+        // An imported table serves the same lookups as the original.
+        let imported =
+            Server::from_blinded_rows(server.d_s, rows.clone()).expect("should be valid rows");
+        assert_eq!(imported.digest(), server.digest());
+        assert!(imported.verify(&users).is_empty());
+
+        // Invalid secrets, invalid points, and repeated sP keys are rejected.
+        assert_eq!(
+            Server::from_blinded_rows(Scalar::ZERO, rows.clone()).err(),
+            Some(BuildError::InvalidSecret)
+        );
+        let identity = (rows[0].0, EncodedPoint::identity(), rows[0].2);
+        assert_eq!(
+            Server::from_blinded_rows(server.d_s, [rows[0], identity]).err(),
+            Some(BuildError::InvalidBlindedRow(1))
+        );
+        assert_eq!(
+            Server::from_blinded_rows(server.d_s, [rows[0], rows[1], rows[0]]).err(),
+            Some(BuildError::InvalidBlindedRow(2))
+        );
+    }
+
+    #[test]
+    fn redact() {
+        let server = Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())]));
+        let client = Client::new(OsRng);
+
+        // Neither secret appears in debug output, in any format.
+        let secrets = [server.d_s, client.d_c].map(|d| format!("{:x}", d.to_bytes()));
+        for debug in [format!("{server:?}"), format!("{server:#?}"), format!("{client:?}")] {
+            assert!(secrets.iter().all(|secret| !debug.contains(secret)), "{debug}");
+            assert!(!debug.contains("Scalar"), "{debug}");
+        }
+        assert!(format!("{server:?}").contains("buckets: 1, entries: 1"));
+    }
+
+    #[test]
+    fn configure_from_info() {
+        let (server, _) = ServerBuilder::new()
+            .hash_function(HashFunction::Sha512_256)
+            .build(OsRng, [(22, Uuid::new_v4())])
+            .expect("should build");
+        let info = server.info();
+
+        // A client adopts the server's hash function and finds its users.
+        let client = Client::new(OsRng).configure_from_info(&info).expect("should be compatible");
+        let (prefix, c_p) = client.request_phone_number(22);
+        let found = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+            )
+            .expect("should be a valid response");
+        assert!(found.is_some());
+
+        // Incompatible deployments are rejected.
+        let check = |info| Client::new(OsRng).configure_from_info(&info).map(|_| ());
+        assert_eq!(check(ServerInfo { suite: 99, ..info.clone() }), Err(InfoMismatch::Suite(99)));
+        assert_eq!(
+            check(ServerInfo { prefix_len: 4, ..info.clone() }),
+            Err(InfoMismatch::PrefixLen(4))
+        );
+        assert_eq!(
+            check(ServerInfo { versions: vec![ProtocolVersion::V2], ..info }),
+            Err(InfoMismatch::Version)
+        );
+    }
+
+    #[test]
+    fn parallel_batches() {
+        let users = (0..10).map(|p| (p, Uuid::new_v4())).collect::<HashMap<_, _>>();
+        let server = Server::new(OsRng, &users);
+        let client = Client::new(OsRng).with_threads(3);
+        let ps = (5..15).collect::<Vec<u64>>();
+
+        // Batches are blinded and unblinded across threads, in order.
+        let requests = client.request_phone_numbers(&ps);
+        assert_eq!(requests.len(), ps.len());
+        let responses = ps
+            .iter()
+            .zip(&requests)
+            .map(|(&p, (prefix, c_p))| {
+                (
+                    p,
+                    server.blind_phone_number(c_p).expect("should be a valid point"),
+                    server.find_bucket(*prefix),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (&p, found) in ps.iter().zip(client.find_user_id_batch(&responses)) {
+            assert_eq!(found.phone_number, p);
+            let s_u = found.blinded_user_id.expect("should be a valid response");
+            assert_eq!(s_u.and_then(|s_u| server.unblind_user_id(&s_u)), users.get(&p).copied());
+        }
+    }
+
+    #[test]
+    fn canonical_order() {
+        // Build the same directory twice, from differently ordered inputs with different hashers.
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let build = |users: Vec<(u64, Uuid)>| {
+            ServerBuilder::new()
+                .min_bucket_size(2, SmallBucketPolicy::Pad)
+                .build(ChaChaRng::seed_from_u64(22), users)
+                .expect("should build")
+                .0
+        };
+        let a = build(users.clone());
+        let b = build(users.into_iter().rev().collect());
+
+        // Every serialized output is byte-identical.
+        assert_eq!(a.digest(), b.digest());
+        assert_eq!(a.export_bundle(), b.export_bundle());
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        assert_eq!(a.encoded_bucket(prefix).etag(), b.encoded_bucket(prefix).etag());
+        assert_eq!(encode_bucket(&a.find_bucket(prefix)), encode_bucket(&b.find_bucket(prefix)));
+    }
 }
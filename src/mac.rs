@@ -0,0 +1,158 @@
+//! Authentication of bucket responses.
+//!
+//! Buckets are public and often served through a CDN or cache, which a client has to trust to
+//! return the bucket it asked for: a misbehaving cache which serves another prefix's bucket, or
+//! one from another epoch, causes silent non-matches. A client and server which share a
+//! [`BucketMacKey`] for a session or channel (e.g. one exported from its TLS session) can bind each
+//! bucket response to its request instead. The client sends a random nonce with the request, the
+//! server [tags](Server::authenticated_bucket) the bucket over its prefix, its epoch, and the
+//! nonce, and the client [verifies](BucketMacKey::verify) the tag, failing with
+//! [`Error::BucketMismatch`] if it doesn't match.
+
+use std::{fmt, hash::BuildHasher};
+
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{EncodedBucket, Epoch, Error, Prefix, Server};
+
+/// The length of a bucket request's nonce, in bytes.
+pub const NONCE_LEN: usize = 16;
+
+/// The length of a bucket response's tag, in bytes.
+pub const MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A key shared by a client and server for authenticating bucket responses.
+#[derive(Clone)]
+pub struct BucketMacKey([u8; 32]);
+
+impl BucketMacKey {
+    /// Use the given key material, e.g. exported from the channel's TLS session.
+    pub fn new(key: [u8; 32]) -> BucketMacKey {
+        BucketMacKey(key)
+    }
+
+    /// Generate a random key, e.g. for a session.
+    pub fn generate(mut rng: impl CryptoRng + RngCore) -> BucketMacKey {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        BucketMacKey(key)
+    }
+
+    /// Return the tag of the bucket with the given digest, for the given prefix, epoch, and nonce.
+    pub fn tag(
+        &self,
+        prefix: Prefix,
+        epoch: Epoch,
+        nonce: &[u8; NONCE_LEN],
+        digest: &[u8; 32],
+    ) -> [u8; MAC_LEN] {
+        self.mac(prefix, epoch, nonce, digest).finalize().into_bytes().into()
+    }
+
+    /// Check the tag of an encoded bucket returned for a request with the given prefix, epoch, and
+    /// nonce, returning [`Error::BucketMismatch`] if it doesn't match.
+    pub fn verify(
+        &self,
+        prefix: Prefix,
+        epoch: Epoch,
+        nonce: &[u8; NONCE_LEN],
+        bucket: &[u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        let digest = Sha256::digest(bucket).into();
+        self.mac(prefix, epoch, nonce, &digest).verify_slice(tag).map_err(|_| Error::BucketMismatch)
+    }
+
+    fn mac(
+        &self,
+        prefix: Prefix,
+        epoch: Epoch,
+        nonce: &[u8; NONCE_LEN],
+        digest: &[u8; 32],
+    ) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0)
+            .expect("should accept any key length")
+            .chain_update(b"zk-cds-bucket-mac")
+            .chain_update(prefix)
+            .chain_update(epoch.to_be_bytes())
+            .chain_update(nonce)
+            .chain_update(digest)
+    }
+}
+
+impl fmt::Debug for BucketMacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key into logs.
+        f.debug_struct("BucketMacKey").finish_non_exhaustive()
+    }
+}
+
+impl<S: BuildHasher + Clone> Server<S> {
+    /// Given a hash prefix, a client's bucket MAC key, and its request nonce, return the encoded
+    /// bucket of users and its tag.
+    pub fn authenticated_bucket(
+        &self,
+        prefix: Prefix,
+        key: &BucketMacKey,
+        nonce: &[u8; NONCE_LEN],
+    ) -> (EncodedBucket, [u8; MAC_LEN]) {
+        let bucket = self.encoded_bucket(prefix);
+        let tag = key.tag(prefix, self.epoch, nonce, &bucket.digest);
+        (bucket, tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{decode_bucket, Client, ServerBuilder};
+
+    #[test]
+    fn bucket_mac() {
+        let (server, _) = ServerBuilder::new()
+            .build(OsRng, [(22, Uuid::new_v4()), (23, Uuid::new_v4())])
+            .expect("should build");
+        let key = BucketMacKey::generate(OsRng);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let nonce = [7; NONCE_LEN];
+
+        // An authentic bucket verifies and finds the user.
+        let (bucket, tag) = server.authenticated_bucket(prefix, &key, &nonce);
+        assert_eq!(key.verify(prefix, server.epoch(), &nonce, &bucket.bytes, &tag), Ok(()));
+        let bucket = decode_bucket(&bucket.bytes).expect("should be a valid bucket");
+        let found = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &bucket,
+                22,
+            )
+            .expect("should be a valid response");
+        assert!(found.is_some());
+
+        // A bucket for another prefix, epoch, or nonce doesn't.
+        let (other, _) = client.request_phone_number(23);
+        let (other_bucket, other_tag) = server.authenticated_bucket(other, &key, &nonce);
+        let mismatch = Err(Error::BucketMismatch);
+        let (bucket, tag) = server.authenticated_bucket(prefix, &key, &nonce);
+        if other != prefix {
+            let verified =
+                key.verify(prefix, server.epoch(), &nonce, &other_bucket.bytes, &other_tag);
+            assert_eq!(verified, mismatch);
+        }
+        assert_eq!(key.verify(prefix, server.epoch() + 1, &nonce, &bucket.bytes, &tag), mismatch);
+        assert_eq!(
+            key.verify(prefix, server.epoch(), &[8; NONCE_LEN], &bucket.bytes, &tag),
+            mismatch
+        );
+        let stranger = BucketMacKey::generate(OsRng);
+        assert_eq!(stranger.verify(prefix, server.epoch(), &nonce, &bucket.bytes, &tag), mismatch);
+    }
+}
@@ -0,0 +1,523 @@
+//! Differentially private operational metrics.
+//!
+//! Exact logs of which buckets were requested, and when, let an operator reconstruct who looked up
+//! whom. [`Metrics`] instead keeps per-epoch counters which are only ever released with noise
+//! added, so operators can monitor the service without building such a log.
+//!
+//! Each counter is released with discrete Laplace noise of scale `1/ε`, so the presence or absence
+//! of any single lookup or bucket request changes the probability of any counter's value by a
+//! factor of at most `e^ε`. A lookup may touch all three kinds of counter, so a report as a whole
+//! is `3ε`-private per lookup. A request which batches `k` phone numbers or prefixes changes each
+//! kind of counter by up to `k` in total, so by group privacy a report is only `3kε`-private per
+//! request, and servers should bound batch sizes accordingly. Bucket accesses are counted by a
+//! coarse prefix of at most [`MAX_PREFIX_BITS`] bits, and every coarse prefix is reported (with
+//! noise) whether or not it was accessed, so the set of reported prefixes leaks nothing either.
+//!
+//! [`DirectoryStats`] releases public statistics about the directory itself, e.g. for integrators
+//! to display "N users discoverable": an approximate count of registered users per epoch, with the
+//! same kind of noise. Each epoch's count is noised once and the release is cached, so repeated
+//! requests can't average the noise away.
+//!
+//! [`HourlyMetrics`] keeps hourly totals of lookups, matches, and bucket bandwidth for capacity
+//! planning, for a fixed number of recent hours. Exact hourly totals would undo the noise above,
+//! since an operator who knows when a lookup was made could tell from its hour's exact match count
+//! whether it found a user, so each hour's totals are released only once the hour is over, with the
+//! same kind of noise drawn once. Byte counts are clamped per bucket and noised in proportion, and
+//! batches cost more privacy in the same way as above.
+
+use std::{
+    collections::BTreeMap,
+    fmt, mem,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{CryptoRng, Rng, RngCore};
+
+use crate::{Epoch, Error, Prefix};
+
+/// The maximum number of bits of the hash prefix to count bucket accesses by.
+pub const MAX_PREFIX_BITS: u8 = 16;
+
+/// Counters of server activity which are released with differentially private noise.
+#[derive(Debug)]
+pub struct Metrics {
+    epsilon: f64,
+    prefix_bits: u8,
+    lookups: AtomicU64,
+    matches: AtomicU64,
+    accesses: Mutex<Vec<u64>>,
+}
+
+impl Metrics {
+    /// Create a new set of counters with the given privacy parameter, counting bucket accesses by
+    /// the first `prefix_bits` bits of their hash prefixes.
+    ///
+    /// Smaller values of `epsilon` give more privacy and noisier reports.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `epsilon` isn't positive or `prefix_bits` is greater than [`MAX_PREFIX_BITS`].
+    pub fn new(epsilon: f64, prefix_bits: u8) -> Metrics {
+        assert!(epsilon > 0.0, "epsilon should be positive");
+        assert!(prefix_bits <= MAX_PREFIX_BITS, "prefix_bits should be at most {MAX_PREFIX_BITS}");
+        Metrics {
+            epsilon,
+            prefix_bits,
+            lookups: AtomicU64::new(0),
+            matches: AtomicU64::new(0),
+            accesses: Mutex::new(vec![0; 1 << prefix_bits]),
+        }
+    }
+
+    /// Record a lookup and whether it found a user.
+    pub fn record_lookup(&self, matched: bool) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            self.matches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a request for the bucket with the given hash prefix.
+    pub fn record_bucket_access(&self, prefix: &Prefix) {
+        let i = coarse_prefix(prefix, self.prefix_bits);
+        self.accesses.lock().expect("should not be poisoned")[i] += 1;
+    }
+
+    /// Release a noisy report of the counters for the given epoch and reset them.
+    ///
+    /// Each report spends the privacy budget afresh, so reports should be taken once per epoch.
+    pub fn report(&self, epoch: Epoch, mut rng: impl CryptoRng + RngCore) -> MetricsReport {
+        let accesses = {
+            let mut accesses = self.accesses.lock().expect("should not be poisoned");
+            let n = accesses.len();
+            mem::replace(&mut *accesses, vec![0; n])
+        };
+
+        MetricsReport {
+            epoch,
+            lookups: noisy(self.lookups.swap(0, Ordering::Relaxed), self.epsilon, &mut rng),
+            matches: noisy(self.matches.swap(0, Ordering::Relaxed), self.epsilon, &mut rng),
+            bucket_accesses: (0u32..)
+                .zip(accesses)
+                .map(|(i, n)| (i, noisy(n, self.epsilon, &mut rng)))
+                .collect(),
+        }
+    }
+}
+
+/// A noisy report of server activity during an epoch.
+///
+/// Noisy counts may be negative.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsReport {
+    /// The epoch the report covers.
+    pub epoch: Epoch,
+    /// The number of lookups.
+    pub lookups: i64,
+    /// The number of lookups which found a user.
+    pub matches: i64,
+    /// The number of bucket requests, by coarse prefix.
+    pub bucket_accesses: BTreeMap<u32, i64>,
+}
+
+/// Public statistics about the directory, released with differentially private noise.
+pub struct DirectoryStats {
+    epsilon: f64,
+    releases: Mutex<BTreeMap<Epoch, PublicStats>>,
+}
+
+impl DirectoryStats {
+    /// Create a new set of statistics with the given privacy parameter, spent once per epoch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `epsilon` isn't positive.
+    pub fn new(epsilon: f64) -> DirectoryStats {
+        assert!(epsilon > 0.0, "epsilon should be positive");
+        DirectoryStats { epsilon, releases: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Release the statistics for the given epoch, which has `users` registered users, e.g. from
+    /// its [build stats](crate::builder::BuildStats). If the epoch's statistics were already
+    /// released, returns them unchanged.
+    pub fn release(
+        &self,
+        epoch: Epoch,
+        users: u64,
+        mut rng: impl CryptoRng + RngCore,
+    ) -> PublicStats {
+        let mut releases = self.releases.lock().expect("should not be poisoned");
+        if let Some(&stats) = releases.get(&epoch) {
+            return stats;
+        }
+
+        // Growth is derived from the noisy counts, so it costs no extra privacy budget.
+        let users = noisy(users, self.epsilon, &mut rng).max(0);
+        let previous = releases.range(..epoch).next_back().map(|(_, stats)| stats.users);
+        let stats = PublicStats { epoch, users, growth: previous.map_or(0, |n| users - n) };
+        releases.insert(epoch, stats);
+        stats
+    }
+
+    /// The statistics for the most recently released epoch, if any.
+    pub fn latest(&self) -> Option<PublicStats> {
+        let releases = self.releases.lock().expect("should not be poisoned");
+        releases.values().next_back().copied()
+    }
+
+    /// The statistics for every released epoch, in order.
+    pub fn history(&self) -> Vec<PublicStats> {
+        self.releases.lock().expect("should not be poisoned").values().copied().collect()
+    }
+}
+
+impl fmt::Debug for DirectoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirectoryStats").field("epsilon", &self.epsilon).finish_non_exhaustive()
+    }
+}
+
+/// The length of an encoded [`PublicStats`], in bytes.
+pub const PUBLIC_STATS_LEN: usize = 8 + 8 + 8;
+
+/// Noisy public statistics about the directory during an epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicStats {
+    /// The epoch the statistics cover.
+    pub epoch: Epoch,
+    /// The approximate number of registered users, which is never negative.
+    pub users: i64,
+    /// The approximate change in registered users since the previously released epoch.
+    pub growth: i64,
+}
+
+impl PublicStats {
+    /// Encode the statistics as the epoch, the user count, and the growth.
+    pub fn encode(&self) -> [u8; PUBLIC_STATS_LEN] {
+        let mut b = [0u8; PUBLIC_STATS_LEN];
+        b[..8].copy_from_slice(&self.epoch.to_be_bytes());
+        b[8..16].copy_from_slice(&self.users.to_be_bytes());
+        b[16..].copy_from_slice(&self.growth.to_be_bytes());
+        b
+    }
+
+    /// Decode statistics encoded with [`PublicStats::encode`].
+    pub fn decode(b: &[u8]) -> Result<PublicStats, Error> {
+        let b: &[u8; PUBLIC_STATS_LEN] = b.try_into().map_err(|_| Error::InvalidMessage)?;
+        let field = |i: usize| b[i..i + 8].try_into().expect("should be 8 bytes");
+        Ok(PublicStats {
+            epoch: Epoch::from_be_bytes(field(0)),
+            users: i64::from_be_bytes(field(8)),
+            growth: i64::from_be_bytes(field(16)),
+        })
+    }
+}
+
+/// The number of hours of totals a [`HourlyMetrics`] keeps by default: four weeks.
+pub const DEFAULT_RETAINED_HOURS: u64 = 28 * 24;
+
+/// The privacy parameter a [`HourlyMetrics`] uses by default.
+pub const DEFAULT_HOURLY_EPSILON: f64 = 1.0;
+
+/// The number of bytes of a single bucket a [`HourlyMetrics`] counts by default.
+pub const DEFAULT_MAX_BUCKET_BYTES: u64 = 64 * 1024;
+
+/// The kind of bucket a request is for, by how much of the hash its prefix reveals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PrefixClass {
+    /// A bucket, selected by a hash prefix.
+    Bucket,
+    /// A sub-bucket of a [split](crate::Server::is_split) bucket, selected by a hash prefix and
+    /// [`SUB_PREFIX_LEN`](crate::SUB_PREFIX_LEN) more bytes.
+    SubBucket,
+    /// The [overflow bucket](crate::Server::overflow_bucket), which reveals nothing.
+    Overflow,
+}
+
+impl PrefixClass {
+    const ALL: [PrefixClass; 3] =
+        [PrefixClass::Bucket, PrefixClass::SubBucket, PrefixClass::Overflow];
+}
+
+/// Noisy totals of server activity during an hour.
+///
+/// Noisy counts may be negative.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HourlyTotals {
+    /// The hour, in hours since the Unix epoch.
+    pub hour: u64,
+    /// The number of phone numbers blinded.
+    pub lookups: i64,
+    /// The number of user IDs unblinded, i.e. lookups which found a user.
+    pub matches: i64,
+    /// The number of buckets served, by class.
+    pub buckets: BTreeMap<PrefixClass, i64>,
+    /// The number of bytes of bucket entries served, by class.
+    pub bytes: BTreeMap<PrefixClass, i64>,
+}
+
+impl HourlyTotals {
+    /// The fraction of lookups which found a user, if there were any lookups.
+    pub fn match_ratio(&self) -> Option<f64> {
+        (self.lookups > 0).then(|| self.matches.clamp(0, self.lookups) as f64 / self.lookups as f64)
+    }
+}
+
+/// Hourly totals of server activity, kept for a fixed number of recent hours and released with
+/// differentially private noise once each hour is over.
+pub struct HourlyMetrics {
+    retained: u64,
+    epsilon: f64,
+    max_bucket_bytes: u64,
+    hours: Mutex<Hours>,
+}
+
+/// The exact totals of unreleased hours and the noisy totals of released ones.
+#[derive(Default)]
+struct Hours {
+    exact: BTreeMap<u64, ExactTotals>,
+    released: BTreeMap<u64, HourlyTotals>,
+    released_before: u64,
+}
+
+/// Exact totals of server activity during an hour, which are never released.
+#[derive(Default)]
+struct ExactTotals {
+    lookups: u64,
+    matches: u64,
+    buckets: BTreeMap<PrefixClass, u64>,
+    bytes: BTreeMap<PrefixClass, u64>,
+}
+
+impl HourlyMetrics {
+    /// Create an empty set of totals, keeping the given number of most recent hours, with the given
+    /// privacy parameter, and counting at most `max_bucket_bytes` bytes of any one bucket.
+    ///
+    /// Smaller values of `epsilon` give more privacy and noisier totals, and byte counts are noised
+    /// in proportion to `max_bucket_bytes`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `retained`, `epsilon`, or `max_bucket_bytes` isn't positive.
+    pub fn new(retained: u64, epsilon: f64, max_bucket_bytes: u64) -> HourlyMetrics {
+        assert!(retained > 0, "retained should be positive");
+        assert!(epsilon > 0.0, "epsilon should be positive");
+        assert!(max_bucket_bytes > 0, "max_bucket_bytes should be positive");
+        HourlyMetrics { retained, epsilon, max_bucket_bytes, hours: Mutex::new(Hours::default()) }
+    }
+
+    /// Record `n` lookups at the given time.
+    pub fn record_lookups(&self, at: SystemTime, n: u64) {
+        self.update(at, |totals| totals.lookups += n);
+    }
+
+    /// Record a lookup which found a user at the given time.
+    pub fn record_match(&self, at: SystemTime) {
+        self.update(at, |totals| totals.matches += 1);
+    }
+
+    /// Record a bucket of the given class and size served at the given time.
+    pub fn record_bucket(&self, at: SystemTime, class: PrefixClass, bytes: u64) {
+        let bytes = bytes.min(self.max_bucket_bytes);
+        self.update(at, |totals| {
+            *totals.buckets.entry(class).or_insert(0) += 1;
+            *totals.bytes.entry(class).or_insert(0) += bytes;
+        });
+    }
+
+    /// The noisy totals for every retained hour in the given range of hours since the Unix epoch
+    /// which was over at time `now`, in order.
+    ///
+    /// Every hour is released once, whether or not it had any activity, with noise drawn when it's
+    /// first released. Later records for a released hour are ignored.
+    pub fn query(
+        &self,
+        hours: Range<u64>,
+        now: SystemTime,
+        mut rng: impl CryptoRng + RngCore,
+    ) -> Vec<HourlyTotals> {
+        let current = hour_of(now);
+        let mut all = self.hours.lock().expect("should not be poisoned");
+        for hour in all.released_before.max(current.saturating_sub(self.retained))..current {
+            let exact = all.exact.remove(&hour).unwrap_or_default();
+            let totals = self.noisy_totals(hour, exact, &mut rng);
+            all.released.insert(hour, totals);
+        }
+        all.released_before = all.released_before.max(current);
+        self.forget(&mut all, current);
+        all.released.range(hours).map(|(_, totals)| totals.clone()).collect()
+    }
+
+    /// The noisy totals for every retained hour which was over at time `now`, in order.
+    pub fn history(&self, now: SystemTime, rng: impl CryptoRng + RngCore) -> Vec<HourlyTotals> {
+        self.query(0..u64::MAX, now, rng)
+    }
+
+    /// Apply `f` to the exact totals of the hour containing the given time, creating them if
+    /// needed, unless the hour has been released or is no longer retained.
+    fn update(&self, at: SystemTime, f: impl FnOnce(&mut ExactTotals)) {
+        let hour = hour_of(at);
+        let mut all = self.hours.lock().expect("should not be poisoned");
+
+        // Late records for a retained hour still count until it's released.
+        let latest = all.exact.keys().next_back().map_or(hour, |&latest| latest.max(hour));
+        if hour < all.released_before || latest - hour >= self.retained {
+            return;
+        }
+        f(all.exact.entry(hour).or_default());
+        self.forget(&mut all, latest);
+    }
+
+    /// Forget the totals of hours which are no longer retained as of the given hour.
+    fn forget(&self, all: &mut Hours, latest: u64) {
+        let retained = |hour: &u64| latest.saturating_sub(*hour) < self.retained;
+        all.exact.retain(|hour, _| retained(hour));
+        all.released.retain(|hour, _| retained(hour));
+    }
+
+    /// Add noise to the exact totals of an hour, reporting every class of bucket.
+    fn noisy_totals(&self, hour: u64, exact: ExactTotals, rng: &mut impl RngCore) -> HourlyTotals {
+        let bytes_epsilon = self.epsilon / self.max_bucket_bytes as f64;
+        let mut class = |counts: &BTreeMap<PrefixClass, u64>, epsilon: f64| {
+            PrefixClass::ALL
+                .into_iter()
+                .map(|class| {
+                    (class, noisy(counts.get(&class).copied().unwrap_or(0), epsilon, &mut *rng))
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let buckets = class(&exact.buckets, self.epsilon);
+        let bytes = class(&exact.bytes, bytes_epsilon);
+        HourlyTotals {
+            hour,
+            lookups: noisy(exact.lookups, self.epsilon, rng),
+            matches: noisy(exact.matches, self.epsilon, rng),
+            buckets,
+            bytes,
+        }
+    }
+}
+
+impl Default for HourlyMetrics {
+    fn default() -> Self {
+        HourlyMetrics::new(DEFAULT_RETAINED_HOURS, DEFAULT_HOURLY_EPSILON, DEFAULT_MAX_BUCKET_BYTES)
+    }
+}
+
+impl fmt::Debug for HourlyMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HourlyMetrics")
+            .field("retained", &self.retained)
+            .field("epsilon", &self.epsilon)
+            .field("max_bucket_bytes", &self.max_bucket_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Return the hour containing the given time, in hours since the Unix epoch.
+fn hour_of(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 3600)
+}
+
+/// Return the first `bits` bits of the given hash prefix as an index.
+fn coarse_prefix(prefix: &Prefix, bits: u8) -> usize {
+    (u32::from(u16::from_be_bytes([prefix[0], prefix[1]])) >> (MAX_PREFIX_BITS - bits)) as usize
+}
+
+/// Add discrete Laplace noise with scale `1/epsilon` to the given count.
+fn noisy(count: u64, epsilon: f64, rng: &mut impl RngCore) -> i64 {
+    // The difference of two geometric variables with success probability 1 - e^-ε is distributed
+    // as a discrete Laplace variable.
+    let p = 1.0 - (-epsilon).exp();
+    let mut geometric = || ((1.0 - rng.gen::<f64>()).ln() / (1.0 - p).ln()).floor() as i64;
+    i64::try_from(count).unwrap_or(i64::MAX).saturating_add(geometric() - geometric())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn noisy_report() {
+        let metrics = Metrics::new(1.0, 4);
+        for _ in 0..1000 {
+            metrics.record_lookup(true);
+            metrics.record_bucket_access(&[0xff; 8]);
+        }
+        metrics.record_lookup(false);
+
+        // Every coarse prefix is reported, and counts are close to the true values.
+        let report = metrics.report(7, OsRng);
+        assert_eq!(report.epoch, 7);
+        assert_eq!(report.bucket_accesses.len(), 16);
+        assert!((report.lookups - 1001).abs() < 50);
+        assert!((report.matches - 1000).abs() < 50);
+        assert!((report.bucket_accesses[&15] - 1000).abs() < 50);
+
+        // Counters are reset after each report.
+        let report = metrics.report(8, OsRng);
+        assert!(report.lookups.abs() < 50);
+    }
+
+    #[test]
+    fn public_stats() {
+        let stats = DirectoryStats::new(1.0);
+        assert_eq!(stats.latest(), None);
+
+        // Counts are close to the true values, and growth is derived from them.
+        let first = stats.release(1, 10_000, OsRng);
+        let second = stats.release(2, 10_500, OsRng);
+        assert!((first.users - 10_000).abs() < 50);
+        assert_eq!(first.growth, 0);
+        assert_eq!(second.growth, second.users - first.users);
+        assert_eq!(stats.latest(), Some(second));
+
+        // Releasing an epoch again doesn't draw fresh noise.
+        assert_eq!(stats.release(1, 10_000, OsRng), first);
+        assert_eq!(stats.history(), vec![first, second]);
+        assert_eq!(PublicStats::decode(&second.encode()), Ok(second));
+    }
+
+    #[test]
+    fn hourly_totals() {
+        let metrics = HourlyMetrics::new(2, 1.0, 100);
+        let hour = |h: u64| UNIX_EPOCH + Duration::from_secs(h * 3600 + 60);
+        for _ in 0..1000 {
+            metrics.record_lookups(hour(10), 4);
+            metrics.record_match(hour(10));
+            metrics.record_bucket(hour(10), PrefixClass::Bucket, 1000);
+        }
+
+        // Hours aren't released until they're over.
+        assert_eq!(metrics.query(10..11, hour(10), OsRng), vec![]);
+
+        // Totals are aggregated by hour and class, clamped, and noised.
+        let [totals] = metrics.query(10..11, hour(11), OsRng).try_into().expect("should be one");
+        assert!((totals.lookups - 4000).abs() < 50);
+        assert!((totals.matches - 1000).abs() < 50);
+        assert!((totals.buckets[&PrefixClass::Bucket] - 1000).abs() < 50);
+        assert!((totals.bytes[&PrefixClass::Bucket] - 100_000).abs() < 5000);
+        assert!(totals.match_ratio().is_some_and(|r| (r - 0.25).abs() < 0.05));
+
+        // Every class is reported, and the noise is drawn only once.
+        assert_eq!(totals.buckets.len(), 3);
+        assert_eq!(metrics.query(10..11, hour(11), OsRng), vec![totals.clone()]);
+
+        // Late records for released hours are ignored, and older hours are forgotten.
+        metrics.record_match(hour(10));
+        metrics.record_lookups(hour(11), 1);
+        assert_eq!(metrics.query(10..11, hour(11), OsRng), vec![totals]);
+        let history = metrics.history(hour(13), OsRng);
+        assert_eq!(history.iter().map(|t| t.hour).collect::<Vec<_>>(), vec![12]);
+        assert_eq!(HourlyTotals::default().match_ratio(), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! UniFFI bindings for the client side of the protocol.
+//!
+//! Kotlin and Swift bindings can be generated from the compiled library with the bundled
+//! `uniffi-bindgen` binary:
+//!
+//! ```text
+//! cargo build --release --features uniffi
+//! cargo run --features uniffi-cli --bin uniffi-bindgen -- generate \
+//!     --library target/release/libzk_cds.so --language kotlin --out-dir out
+//! ```
+//!
+//! Points are passed as 33-byte compressed SEC1 encodings and buckets as the concatenation of their
+//! `(sP, hsU)` entries, each 66 bytes long.
+
+use std::sync::Arc;
+
+use p256::EncodedPoint;
+use rand::rngs::OsRng;
+
+use crate::{decode_bucket, Client, Error};
+
+/// A client in a hypothetical CDS.
+#[derive(Debug, uniffi::Object)]
+pub struct MobileClient {
+    client: Client,
+}
+
+#[uniffi::export]
+impl MobileClient {
+    /// Create a new client using a random secret.
+    #[uniffi::constructor]
+    pub fn new() -> Arc<MobileClient> {
+        Arc::new(MobileClient { client: Client::new(OsRng) })
+    }
+
+    /// Create a new client using a random secret, which spreads batch operations across up to
+    /// `threads` threads.
+    #[uniffi::constructor]
+    pub fn with_threads(threads: u32) -> Arc<MobileClient> {
+        let threads = (threads as usize).max(1);
+        Arc::new(MobileClient { client: Client::new(OsRng).with_threads(threads) })
+    }
+
+    /// Initiate a client request for the given phone number.
+    pub fn request_phone_number(&self, p: u64) -> PhoneNumberRequest {
+        let (prefix, c_p) = self.client.request_phone_number(p);
+        PhoneNumberRequest { prefix: prefix.to_vec(), blinded_point: c_p.as_bytes().to_vec() }
+    }
+
+    /// Initiate client requests for the given phone numbers, in order.
+    pub fn request_phone_numbers(&self, ps: Vec<u64>) -> Vec<PhoneNumberRequest> {
+        let requests = self.client.request_phone_numbers(&ps);
+        requests
+            .into_iter()
+            .map(|(prefix, c_p)| PhoneNumberRequest {
+                prefix: prefix.to_vec(),
+                blinded_point: c_p.as_bytes().to_vec(),
+            })
+            .collect()
+    }
+
+    /// Given a double-blinded phone number point and bucket of users from the server, return the
+    /// unblinded user ID point, if any can be found.
+    pub fn find_user_id(
+        &self,
+        sc_p: Vec<u8>,
+        bucket: Vec<u8>,
+        p: u64,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let sc_p = EncodedPoint::from_bytes(sc_p).map_err(|_| Error::InvalidPoint)?;
+        let s_u = self.client.find_user_id(&sc_p, &decode_bucket(&bucket)?, p)?;
+        Ok(s_u.map(|s_u| s_u.as_bytes().to_vec()))
+    }
+}
+
+/// A blinded request for a phone number.
+#[derive(Debug, uniffi::Record)]
+pub struct PhoneNumberRequest {
+    /// The hash prefix of the phone number.
+    pub prefix: Vec<u8>,
+    /// The client-blinded phone number point.
+    pub blinded_point: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Prefix, Server};
+
+    #[test]
+    fn round_trip() {
+        let users = HashMap::from([(22, Uuid::new_v4()), (23, Uuid::new_v4())]);
+        let server = Server::new(OsRng, &users);
+        let lookup = |client: &MobileClient, req: &PhoneNumberRequest, p| {
+            let prefix = Prefix::try_from(req.prefix.as_slice()).expect("should be a prefix");
+            let bucket = server
+                .find_bucket(prefix)
+                .iter()
+                .flat_map(|(s_p, hs_u)| [s_p.as_bytes(), hs_u.as_bytes()].concat())
+                .collect::<Vec<u8>>();
+            let c_p = EncodedPoint::from_bytes(&req.blinded_point).expect("should be valid");
+            let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+            let s_u = client
+                .find_user_id(sc_p.as_bytes().to_vec(), bucket, p)
+                .expect("should be a valid response")?;
+            let s_u = EncodedPoint::from_bytes(s_u).expect("should be valid");
+            server.unblind_user_id(&s_u)
+        };
+
+        // Phone numbers can be looked up one at a time.
+        let client = MobileClient::new();
+        let req = client.request_phone_number(22);
+        assert_eq!(lookup(&client, &req, 22), users.get(&22).copied());
+        assert_eq!(lookup(&client, &client.request_phone_number(24), 24), None);
+
+        // Or in batches.
+        let client = MobileClient::with_threads(2);
+        let reqs = client.request_phone_numbers(vec![22, 23, 24]);
+        let found = [22, 23, 24].iter().zip(&reqs).map(|(&p, req)| lookup(&client, req, p));
+        assert_eq!(
+            found.collect::<Vec<_>>(),
+            vec![users.get(&22).copied(), users.get(&23).copied(), None]
+        );
+
+        // Invalid points and malformed buckets are rejected.
+        assert_eq!(client.find_user_id(vec![0; 33], vec![], 22), Err(Error::InvalidPoint));
+        let sc_p = req.blinded_point.clone();
+        assert_eq!(client.find_user_id(sc_p, vec![0; 65], 22), Err(Error::InvalidMessage));
+    }
+}
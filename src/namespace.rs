@@ -0,0 +1,235 @@
+//! Independent secrets for each kind of identifier.
+//!
+//! A deployment which serves phone numbers, emails, and usernames could blind them all with one
+//! server secret, but then a client which learns the server-blinded points of a user's phone
+//! number and email can link them, and rotating one index rotates them all. A
+//! [`NamespacedServer`] instead keeps a separate [`Server`] for each [`Namespace`], each with its
+//! own secret, epoch, and rotation period, and routes requests by namespace.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use p256::EncodedPoint;
+use rand::{CryptoRng, RngCore};
+
+use crate::{Epoch, Error, Prefix, Server};
+
+/// A kind of identifier which users are looked up by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Namespace {
+    /// Phone numbers.
+    PhoneNumber,
+    /// Email addresses, as [hashed identifiers](crate::Client::request_hashed).
+    Email,
+    /// Usernames, as [hashed identifiers](crate::Client::request_hashed).
+    Username,
+}
+
+impl Namespace {
+    /// The namespace's ID on the wire.
+    pub fn id(&self) -> u8 {
+        match self {
+            Namespace::PhoneNumber => 1,
+            Namespace::Email => 2,
+            Namespace::Username => 3,
+        }
+    }
+
+    /// Return the namespace with the given ID, if any.
+    pub fn from_id(id: u8) -> Option<Namespace> {
+        match id {
+            1 => Some(Namespace::PhoneNumber),
+            2 => Some(Namespace::Email),
+            3 => Some(Namespace::Username),
+            _ => None,
+        }
+    }
+}
+
+/// A namespace's server and its rotation schedule.
+struct Index<S> {
+    server: Arc<Server<S>>,
+    period: Option<Duration>,
+    rotated_at: Instant,
+}
+
+/// A set of servers, one per namespace, with independent secrets, epochs, and rotation schedules.
+pub struct NamespacedServer<S = RandomState> {
+    indexes: HashMap<Namespace, Index<S>>,
+}
+
+impl<S: BuildHasher + Clone> NamespacedServer<S> {
+    /// Create a set of servers with no namespaces.
+    pub fn new() -> NamespacedServer<S> {
+        NamespacedServer { indexes: HashMap::new() }
+    }
+
+    /// Serve the given namespace with the given server, rotating its secret every `period` as of
+    /// `now`, if any. The server should have been built with its own random secret.
+    pub fn with_namespace(
+        mut self,
+        namespace: Namespace,
+        server: Arc<Server<S>>,
+        period: Option<Duration>,
+        now: Instant,
+    ) -> NamespacedServer<S> {
+        self.indexes.insert(namespace, Index { server, period, rotated_at: now });
+        self
+    }
+
+    /// The namespaces being served, in order.
+    pub fn namespaces(&self) -> Vec<Namespace> {
+        let mut namespaces = self.indexes.keys().copied().collect::<Vec<_>>();
+        namespaces.sort_unstable();
+        namespaces
+    }
+
+    /// The server for the given namespace, if it's being served.
+    pub fn server(&self, namespace: Namespace) -> Option<Arc<Server<S>>> {
+        self.indexes.get(&namespace).map(|index| index.server.clone())
+    }
+
+    /// The current epoch of the given namespace, if it's being served.
+    pub fn epoch(&self, namespace: Namespace) -> Option<Epoch> {
+        self.indexes.get(&namespace).map(|index| index.server.epoch())
+    }
+
+    /// Given a namespace and a hash prefix, return the bucket of users, or `None` if the namespace
+    /// isn't being served.
+    pub fn find_bucket(
+        &self,
+        namespace: Namespace,
+        prefix: Prefix,
+    ) -> Option<HashMap<EncodedPoint, EncodedPoint, S>> {
+        self.indexes.get(&namespace).map(|index| index.server.find_bucket(prefix))
+    }
+
+    /// Given a namespace and a client-blinded point, return the point double-blinded by the
+    /// namespace's secret, or `None` if the namespace isn't being served. Returns
+    /// [`Error::InvalidPoint`] if the point is invalid.
+    pub fn blind(
+        &self,
+        namespace: Namespace,
+        c_p: &EncodedPoint,
+    ) -> Result<Option<EncodedPoint>, Error> {
+        self.indexes.get(&namespace).map(|index| index.server.blind_phone_number(c_p)).transpose()
+    }
+
+    /// Rotate the given namespace's secret as of `now`, leaving the other namespaces' secrets and
+    /// epochs unchanged. Returns the new epoch, or `None` if the namespace isn't being served.
+    pub fn rotate(
+        &mut self,
+        namespace: Namespace,
+        rng: impl CryptoRng + RngCore,
+        now: Instant,
+    ) -> Option<Epoch> {
+        let index = self.indexes.get_mut(&namespace)?;
+        index.server = Arc::new(index.server.rotate(rng));
+        index.rotated_at = now;
+        Some(index.server.epoch())
+    }
+
+    /// Rotate every namespace whose rotation period has elapsed as of `now`, returning the rotated
+    /// namespaces in order.
+    pub fn rotate_due(
+        &mut self,
+        mut rng: impl CryptoRng + RngCore,
+        now: Instant,
+    ) -> Vec<Namespace> {
+        let due = self
+            .namespaces()
+            .into_iter()
+            .filter(|namespace| {
+                let index = &self.indexes[namespace];
+                index.period.is_some_and(|period| now.duration_since(index.rotated_at) >= period)
+            })
+            .collect::<Vec<_>>();
+        for &namespace in &due {
+            self.rotate(namespace, &mut rng, now);
+        }
+        due
+    }
+}
+
+impl<S: BuildHasher + Clone> Default for NamespacedServer<S> {
+    fn default() -> Self {
+        NamespacedServer::new()
+    }
+}
+
+impl<S> fmt::Debug for NamespacedServer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut epochs = self
+            .indexes
+            .iter()
+            .map(|(namespace, index)| (namespace, index.server.epoch))
+            .collect::<Vec<_>>();
+        epochs.sort_unstable();
+        f.debug_struct("NamespacedServer").field("epochs", &epochs).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, ServerBuilder};
+
+    #[test]
+    fn independent_namespaces() {
+        let u = Uuid::new_v4();
+        let email = Sha256::digest(b"alice@example.com").into();
+        let (phones, _) = ServerBuilder::new().build(OsRng, [(22, u)]).expect("should build");
+        let (emails, _) =
+            ServerBuilder::new().build_hashed(OsRng, [(email, u)]).expect("should build");
+        let start = Instant::now();
+        let mut servers = NamespacedServer::new()
+            .with_namespace(Namespace::PhoneNumber, Arc::new(phones), None, start)
+            .with_namespace(
+                Namespace::Email,
+                Arc::new(emails),
+                Some(Duration::from_secs(60)),
+                start,
+            );
+        assert_eq!(servers.namespaces(), vec![Namespace::PhoneNumber, Namespace::Email]);
+
+        // Each namespace blinds with its own secret.
+        let client = Client::new(OsRng);
+        let (_, c_p) = client.request_phone_number(22);
+        assert_ne!(
+            servers.blind(Namespace::PhoneNumber, &c_p),
+            servers.blind(Namespace::Email, &c_p)
+        );
+        assert_eq!(servers.blind(Namespace::Username, &c_p), Ok(None));
+
+        // And rotates on its own schedule, without changing the others.
+        assert!(servers.rotate_due(OsRng, start + Duration::from_secs(30)).is_empty());
+        let rotated = servers.rotate_due(OsRng, start + Duration::from_secs(60));
+        assert_eq!(rotated, vec![Namespace::Email]);
+        assert_eq!(servers.epoch(Namespace::Email), Some(1));
+        assert_eq!(servers.epoch(Namespace::PhoneNumber), Some(0));
+
+        // Lookups still work in the rotated namespace.
+        let (prefix, c_p) = client.request_hashed(&email);
+        let sc_p = servers
+            .blind(Namespace::Email, &c_p)
+            .expect("should be a valid point")
+            .expect("should be served");
+        let bucket = servers.find_bucket(Namespace::Email, prefix).expect("should be served");
+        let s_u = client
+            .find_user_id_hashed(&sc_p, &bucket, &email)
+            .expect("should be a valid response")
+            .expect("should be found");
+        let server = servers.server(Namespace::Email).expect("should be served");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+        assert_eq!(Namespace::from_id(Namespace::Email.id()), Some(Namespace::Email));
+    }
+}
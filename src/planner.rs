@@ -0,0 +1,253 @@
+//! Planning large syncs to fit within server-advertised quotas.
+//!
+//! Servers limit both how many contacts a client may look up per day and how many requests it may
+//! make per minute, so a client syncing a large address book for the first time must spread its
+//! lookups out. A [`SyncPlan`] splits the phone numbers into chunks which fit within a [`Quota`],
+//! says when the next chunk may be sent, and records which chunks have been looked up. Plans are
+//! [encoded](SyncPlan::encode) after each chunk and stored, so a sync resumes where it left off after
+//! the app restarts, without spending quota on contacts it already looked up.
+//!
+//! Quota windows are tracked in wall-clock time, rather than with [`Instant`](std::time::Instant)s,
+//! since they must survive restarts.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+/// The number of requests a lookup makes: one for the bucket and one for the blinded point.
+pub const REQUESTS_PER_LOOKUP: u32 = 2;
+
+/// The length of an encoded [`Quota`], in bytes.
+const QUOTA_LEN: usize = 4 + 4;
+
+/// The length of an encoded [`SyncPlan`]'s header, in bytes.
+const PLAN_HEADER_LEN: usize = QUOTA_LEN + 2 * (8 + 4);
+
+/// The length of the daily quota window.
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The length of the per-minute quota window.
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// The limits a server places on each client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// The maximum number of phone numbers a client may look up per day.
+    pub contacts_per_day: u32,
+    /// The maximum number of requests a client may make per minute.
+    pub requests_per_minute: u32,
+}
+
+impl Quota {
+    /// Encode the quota as the contacts per day followed by the requests per minute.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(QUOTA_LEN);
+        b.extend_from_slice(&self.contacts_per_day.to_be_bytes());
+        b.extend_from_slice(&self.requests_per_minute.to_be_bytes());
+        b
+    }
+
+    /// Decode a quota, returning [`Error::InvalidMessage`] if it doesn't allow any lookups.
+    pub fn decode(b: &[u8]) -> Result<Quota, Error> {
+        let b: &[u8; QUOTA_LEN] = b.try_into().map_err(|_| Error::InvalidMessage)?;
+        let quota = Quota {
+            contacts_per_day: u32::from_be_bytes(b[..4].try_into().expect("should be 4 bytes")),
+            requests_per_minute: u32::from_be_bytes(b[4..].try_into().expect("should be 4 bytes")),
+        };
+        quota.allows_lookups().then_some(quota).ok_or(Error::InvalidMessage)
+    }
+
+    fn allows_lookups(&self) -> bool {
+        self.contacts_per_day > 0 && self.requests_per_minute >= REQUESTS_PER_LOOKUP
+    }
+}
+
+/// What a client should do next to make progress on a [`SyncPlan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Look up the given phone numbers, then [record](SyncPlan::record) them.
+    Lookup(Vec<u64>),
+    /// Wait for the given duration before asking again.
+    Wait(Duration),
+    /// Every phone number has been looked up.
+    Done,
+}
+
+/// A quota window: when it started, and how much of it has been used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    start: u64,
+    used: u32,
+}
+
+impl Window {
+    /// Return the window as of `now`, starting a new one if it has expired.
+    fn at(self, now: u64, len: Duration) -> Window {
+        if now.saturating_sub(self.start) >= len.as_secs() {
+            Window { start: now, used: 0 }
+        } else {
+            self
+        }
+    }
+
+    /// How long after `now` the window expires.
+    fn remaining(self, now: u64, len: Duration) -> Duration {
+        Duration::from_secs((self.start + len.as_secs()).saturating_sub(now))
+    }
+}
+
+/// A resumable plan for looking up a large set of phone numbers within a [`Quota`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncPlan {
+    quota: Quota,
+    day: Window,
+    minute: Window,
+    pending: Vec<u64>,
+}
+
+impl SyncPlan {
+    /// Create a plan for looking up the given phone numbers, in order, within the given quota.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the quota doesn't allow any lookups.
+    pub fn new(quota: Quota, phone_numbers: impl IntoIterator<Item = u64>) -> SyncPlan {
+        assert!(quota.allows_lookups(), "quota should allow lookups");
+        let window = Window { start: 0, used: 0 };
+        SyncPlan {
+            quota,
+            day: window,
+            minute: window,
+            pending: phone_numbers.into_iter().collect(),
+        }
+    }
+
+    /// The quota the plan was made for.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// The number of phone numbers which have yet to be looked up.
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Return the next step of the plan as of `now`.
+    pub fn next(&self, now: SystemTime) -> Step {
+        if self.pending.is_empty() {
+            return Step::Done;
+        }
+
+        // Size the chunk to whichever of the windows has less room left.
+        let now = unix_secs(now);
+        let (day, minute) = (self.day.at(now, DAY), self.minute.at(now, MINUTE));
+        let by_day = self.quota.contacts_per_day.saturating_sub(day.used);
+        let by_minute =
+            self.quota.requests_per_minute.saturating_sub(minute.used) / REQUESTS_PER_LOOKUP;
+        let n = by_day.min(by_minute) as usize;
+        if n > 0 {
+            return Step::Lookup(self.pending[..n.min(self.pending.len())].to_vec());
+        }
+
+        // Otherwise, wait for the exhausted window to expire.
+        if by_day == 0 {
+            Step::Wait(day.remaining(now, DAY))
+        } else {
+            Step::Wait(minute.remaining(now, MINUTE))
+        }
+    }
+
+    /// Record that the first `n` pending phone numbers were looked up at `now`, charging them
+    /// against the quota.
+    pub fn record(&mut self, n: usize, now: SystemTime) {
+        let n = n.min(self.pending.len());
+        let now = unix_secs(now);
+        let lookups = u32::try_from(n).unwrap_or(u32::MAX);
+        self.day = self.day.at(now, DAY);
+        self.day.used = self.day.used.saturating_add(lookups);
+        self.minute = self.minute.at(now, MINUTE);
+        self.minute.used =
+            self.minute.used.saturating_add(lookups.saturating_mul(REQUESTS_PER_LOOKUP));
+        self.pending.drain(..n);
+    }
+
+    /// Encode the plan as its quota, the start and usage of its daily and per-minute windows, and
+    /// its pending phone numbers.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(PLAN_HEADER_LEN + self.pending.len() * 8);
+        b.extend_from_slice(&self.quota.encode());
+        for window in [self.day, self.minute] {
+            b.extend_from_slice(&window.start.to_be_bytes());
+            b.extend_from_slice(&window.used.to_be_bytes());
+        }
+        for p in &self.pending {
+            b.extend_from_slice(&p.to_be_bytes());
+        }
+        b
+    }
+
+    /// Decode a plan.
+    pub fn decode(b: &[u8]) -> Result<SyncPlan, Error> {
+        if b.len() < PLAN_HEADER_LEN || !(b.len() - PLAN_HEADER_LEN).is_multiple_of(8) {
+            return Err(Error::InvalidMessage);
+        }
+        let (header, pending) = b.split_at(PLAN_HEADER_LEN);
+        let (quota, windows) = header.split_at(QUOTA_LEN);
+        let mut windows = windows.chunks_exact(8 + 4).map(|w| Window {
+            start: u64::from_be_bytes(w[..8].try_into().expect("should be 8 bytes")),
+            used: u32::from_be_bytes(w[8..].try_into().expect("should be 4 bytes")),
+        });
+        Ok(SyncPlan {
+            quota: Quota::decode(quota)?,
+            day: windows.next().expect("should have a daily window"),
+            minute: windows.next().expect("should have a per-minute window"),
+            pending: pending
+                .chunks_exact(8)
+                .map(|p| u64::from_be_bytes(p.try_into().expect("should be 8 bytes")))
+                .collect(),
+        })
+    }
+}
+
+/// Return the given time as seconds since the Unix epoch, or zero if it's before it.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn planning() {
+        let quota = Quota { contacts_per_day: 5, requests_per_minute: 4 };
+        assert_eq!(Quota::decode(&quota.encode()), Ok(quota));
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut plan = SyncPlan::new(quota, 100..107);
+
+        // Chunks are limited by the per-minute quota.
+        assert_eq!(plan.next(start), Step::Lookup(vec![100, 101]));
+        plan.record(2, start);
+        assert_eq!(plan.next(start + Duration::from_secs(15)), Step::Wait(Duration::from_secs(45)));
+        let t = start + MINUTE;
+        assert_eq!(plan.next(t), Step::Lookup(vec![102, 103]));
+        plan.record(2, t);
+
+        // Then by the daily quota, which survives a restart.
+        let mut plan = SyncPlan::decode(&plan.encode()).expect("should be a valid plan");
+        let t = t + MINUTE;
+        assert_eq!(plan.next(t), Step::Lookup(vec![104]));
+        plan.record(1, t);
+        assert_eq!(plan.next(t + MINUTE), Step::Wait(DAY - 3 * MINUTE));
+        let t = start + DAY;
+        assert_eq!(plan.next(t), Step::Lookup(vec![105, 106]));
+        plan.record(2, t);
+        assert_eq!(plan.remaining(), 0);
+        assert_eq!(plan.next(t), Step::Done);
+
+        // Quotas which allow no lookups are invalid.
+        let none = Quota { contacts_per_day: 5, requests_per_minute: 1 };
+        assert_eq!(Quota::decode(&none.encode()), Err(Error::InvalidMessage));
+        assert_eq!(SyncPlan::decode(&[0; 3]), Err(Error::InvalidMessage));
+    }
+}
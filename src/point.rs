@@ -0,0 +1,102 @@
+//! Canonicalization of points received from clients.
+//!
+//! Clients are only required to send valid SEC1 encodings of points on P-256, and third-party
+//! implementations differ in which encoding they default to. [`parse_point`] accepts both the
+//! compressed (`0x02`/`0x03`) and uncompressed (`0x04`) encodings and normalizes them to the
+//! compressed encoding the rest of the crate uses, so equal points always have equal encodings.
+//!
+//! Every other encoding is rejected explicitly with [`Error::InvalidPoint`]: the identity (`0x00`),
+//! which would blind to itself; the hybrid encodings (`0x06`/`0x07`), which are redundant and
+//! rarely validated consistently; and the non-standard compact encoding (`0x05`).
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, AffinePoint, EncodedPoint};
+
+use crate::{decode_point, encoding::POINT_LEN, Error};
+
+/// The length of an uncompressed point, in bytes.
+pub const UNCOMPRESSED_POINT_LEN: usize = 65;
+
+/// Parse a compressed or uncompressed encoding of a point on P-256, returning its compressed
+/// encoding, or [`Error::InvalidPoint`] if it's any other encoding or isn't on the curve.
+pub fn parse_point(b: &[u8]) -> Result<EncodedPoint, Error> {
+    Ok(decode_client_point(b)?.to_encoded_point(true))
+}
+
+/// Return the compressed encoding of the given point, or [`Error::InvalidPoint`] if it's not a
+/// compressed or uncompressed encoding of a point on P-256.
+pub fn normalize_point(p: &EncodedPoint) -> Result<EncodedPoint, Error> {
+    parse_point(p.as_bytes())
+}
+
+/// Decode a compressed or uncompressed encoding of a point on P-256, returning
+/// [`Error::InvalidPoint`] for any other encoding.
+pub(crate) fn decode_client_point(b: &[u8]) -> Result<AffinePoint, Error> {
+    // Check the tag and length before parsing, so only the two standard encodings are accepted.
+    match (b.first(), b.len()) {
+        (Some(0x02 | 0x03), POINT_LEN) | (Some(0x04), UNCOMPRESSED_POINT_LEN) => {}
+        _ => return Err(Error::InvalidPoint),
+    }
+    decode_point(&EncodedPoint::from_bytes(b).map_err(|_| Error::InvalidPoint)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use p256::{elliptic_curve::Field, ProjectivePoint, Scalar};
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{deadline::Deadline, Client, Server};
+
+    #[test]
+    fn encodings() {
+        let p = (ProjectivePoint::GENERATOR * Scalar::random(&mut OsRng)).to_affine();
+        let compressed = p.to_encoded_point(true);
+        let uncompressed = p.to_encoded_point(false);
+
+        // Both standard encodings normalize to the compressed one.
+        assert_eq!(parse_point(compressed.as_bytes()), Ok(compressed));
+        assert_eq!(parse_point(uncompressed.as_bytes()), Ok(compressed));
+        assert_eq!(normalize_point(&uncompressed), Ok(compressed));
+
+        // Hybrid encodings, the identity, truncations, and points off the curve are rejected.
+        let mut hybrid = uncompressed.as_bytes().to_vec();
+        hybrid[0] = 0x06 | (hybrid[64] & 1);
+        assert_eq!(parse_point(&hybrid), Err(Error::InvalidPoint));
+        assert_eq!(parse_point(&[0x00]), Err(Error::InvalidPoint));
+        assert_eq!(parse_point(&uncompressed.as_bytes()[..POINT_LEN]), Err(Error::InvalidPoint));
+        let mut off_curve = uncompressed.as_bytes().to_vec();
+        off_curve[64] ^= 1;
+        assert_eq!(parse_point(&off_curve), Err(Error::InvalidPoint));
+    }
+
+    #[test]
+    fn uncompressed_lookups() {
+        let server = Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())]));
+        let (_, c_p) = Client::new(OsRng).request_phone_number(22);
+        let uncompressed = decode_point(&c_p).expect("should be valid").to_encoded_point(false);
+
+        // Clients which send uncompressed points get the same compressed response.
+        let sc_ps = server
+            .blind_phone_numbers(&[c_p, uncompressed], &Deadline::never())
+            .expect("should blind");
+        assert_eq!(
+            sc_ps,
+            vec![server.blind_phone_number(&c_p).expect("should be a valid point"); 2]
+        );
+        assert_eq!(
+            server.blind_phone_numbers(&[EncodedPoint::identity()], &Deadline::never()),
+            Err(Error::InvalidPoint)
+        );
+
+        // Single points are held to the same standard.
+        assert_eq!(server.blind_phone_number(&uncompressed), Ok(sc_ps[0]));
+        assert_eq!(server.blind_phone_number(&EncodedPoint::identity()), Err(Error::InvalidPoint));
+        let mut compact = c_p.as_bytes().to_vec();
+        compact[0] = 0x05;
+        let compact = EncodedPoint::from_bytes(compact).expect("should be a valid encoding");
+        assert_eq!(server.blind_phone_number(&compact), Err(Error::InvalidPoint));
+    }
+}
@@ -0,0 +1,219 @@
+//! Protocol version negotiation.
+//!
+//! Before its first lookup, a client sends a [`ClientHello`] listing the protocol versions it
+//! supports, in order of preference. The server picks the first of them which it also supports and
+//! replies with a [`ServerHello`]. Versions unknown to the server are ignored, so new versions can be
+//! rolled out to clients while old servers keep working, and vice versa. The hello may also name
+//! the client's [`Ciphersuite`], which the server rejects unless it's the server's own.
+//!
+//! Servers also describe their deployment with a [`ServerInfo`], so clients and load balancers can
+//! check what they're talking to before sending lookups.
+
+use crate::{suite::Ciphersuite, Epoch, Error};
+
+/// A version of the CDS protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+    /// The original protocol: P-256, SHA-256, and unpadded buckets of compressed points.
+    V1 = 1,
+    /// Reserved for the next revision of the protocol. Not yet supported.
+    V2 = 2,
+}
+
+impl ProtocolVersion {
+    /// Parse the given version number, returning `None` if it's unknown.
+    pub fn from_u8(v: u8) -> Option<ProtocolVersion> {
+        match v {
+            1 => Some(ProtocolVersion::V1),
+            2 => Some(ProtocolVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// The protocol versions supported by this implementation, in order of preference.
+pub const SUPPORTED_VERSIONS: &[ProtocolVersion] = &[ProtocolVersion::V1];
+
+/// The first message sent by a client, listing the protocol versions it supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The protocol versions supported by the client, in order of preference.
+    pub versions: Vec<ProtocolVersion>,
+    /// The client's ciphersuite, if it names one.
+    pub suite: Option<Ciphersuite>,
+}
+
+impl ClientHello {
+    /// Create a [`ClientHello`] offering all supported versions, without naming a suite.
+    pub fn new() -> ClientHello {
+        ClientHello { versions: SUPPORTED_VERSIONS.to_vec(), suite: None }
+    }
+
+    /// Create a [`ClientHello`] offering all supported versions with the given suite.
+    pub fn with_suite(suite: Ciphersuite) -> ClientHello {
+        ClientHello { suite: Some(suite), ..ClientHello::new() }
+    }
+
+    /// Encode the message as a count of versions followed by one byte per version and, if the
+    /// client names one, its suite's identifier.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(2 + self.versions.len());
+        b.push(u8::try_from(self.versions.len()).expect("should have fewer than 256 versions"));
+        b.extend(self.versions.iter().map(|&v| v as u8));
+        b.extend(self.suite.map(Ciphersuite::id));
+        b
+    }
+
+    /// Decode the message, skipping any unknown versions. Unknown suites are rejected with
+    /// [`Error::SuiteMismatch`].
+    pub fn decode(b: &[u8]) -> Result<ClientHello, Error> {
+        let (&n, b) = b.split_first().ok_or(Error::InvalidMessage)?;
+        let (versions, suite) = b.split_at_checked(usize::from(n)).ok_or(Error::InvalidMessage)?;
+        let suite = match suite {
+            [] => None,
+            &[id] => Some(Ciphersuite::from_id(id)?),
+            _ => return Err(Error::InvalidMessage),
+        };
+        Ok(ClientHello {
+            versions: versions.iter().copied().filter_map(ProtocolVersion::from_u8).collect(),
+            suite,
+        })
+    }
+
+    /// Choose the first of the client's versions which is also in `supported`.
+    pub fn negotiate(&self, supported: &[ProtocolVersion]) -> Result<ServerHello, Error> {
+        self.versions
+            .iter()
+            .find(|v| supported.contains(v))
+            .map(|&version| ServerHello { version })
+            .ok_or(Error::VersionMismatch)
+    }
+}
+
+impl Default for ClientHello {
+    fn default() -> Self {
+        ClientHello::new()
+    }
+}
+
+/// The server's reply to a [`ClientHello`], with the protocol version to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerHello {
+    /// The negotiated protocol version.
+    pub version: ProtocolVersion,
+}
+
+impl ServerHello {
+    /// Encode the message as a single version byte.
+    pub fn encode(&self) -> Vec<u8> {
+        vec![self.version as u8]
+    }
+
+    /// Decode the message.
+    pub fn decode(b: &[u8]) -> Result<ServerHello, Error> {
+        match b {
+            &[v] => ProtocolVersion::from_u8(v)
+                .map(|version| ServerHello { version })
+                .ok_or(Error::VersionMismatch),
+            _ => Err(Error::InvalidMessage),
+        }
+    }
+}
+
+/// A description of a server's deployment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The protocol versions supported by the server, in order of preference.
+    pub versions: Vec<ProtocolVersion>,
+    /// The epoch of the server's secret.
+    pub epoch: Epoch,
+    /// The [digest](crate::Server::digest) of the server's contents.
+    pub digest: [u8; 32],
+    /// The [identifier](Ciphersuite::id) of the server's ciphersuite.
+    pub suite: u8,
+    /// The length of hash prefixes, in bytes.
+    pub prefix_len: u8,
+    /// The minimum number of entries in a non-empty bucket, or zero if buckets aren't padded.
+    pub min_bucket_size: u64,
+}
+
+impl ServerInfo {
+    /// Encode the message as a count of versions, one byte per version, the epoch, the digest, the
+    /// suite, the prefix length, and the minimum bucket size.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = ClientHello { versions: self.versions.clone(), suite: None }.encode();
+        b.extend_from_slice(&self.epoch.to_be_bytes());
+        b.extend_from_slice(&self.digest);
+        b.push(self.suite);
+        b.push(self.prefix_len);
+        b.extend_from_slice(&self.min_bucket_size.to_be_bytes());
+        b
+    }
+
+    /// Decode the message, skipping any unknown versions.
+    pub fn decode(b: &[u8]) -> Result<ServerInfo, Error> {
+        let n = usize::from(*b.first().ok_or(Error::InvalidMessage)?);
+        if b.len() != 1 + n + 8 + 32 + 2 + 8 {
+            return Err(Error::InvalidMessage);
+        }
+        let (versions, b) = b.split_at(1 + n);
+        let (epoch, b) = b.split_at(8);
+        let (digest, b) = b.split_at(32);
+        Ok(ServerInfo {
+            versions: ClientHello::decode(versions)?.versions,
+            epoch: Epoch::from_be_bytes(epoch.try_into().expect("should be 8 bytes")),
+            digest: digest.try_into().expect("should be 32 bytes"),
+            suite: b[0],
+            prefix_len: b[1],
+            min_bucket_size: u64::from_be_bytes(b[2..].try_into().expect("should be 8 bytes")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        // A newer client falls back to a version an older server supports.
+        let hello =
+            ClientHello { versions: vec![ProtocolVersion::V2, ProtocolVersion::V1], suite: None };
+        let hello = ClientHello::decode(&hello.encode()).expect("should be valid");
+        let reply = hello.negotiate(&[ProtocolVersion::V1]).expect("should negotiate");
+        assert_eq!(ServerHello::decode(&reply.encode()), Ok(reply));
+        assert_eq!(reply.version, ProtocolVersion::V1);
+
+        // Unknown versions are ignored.
+        let hello = ClientHello::decode(&[3, 1, 9, 200]).expect("should be valid");
+        assert_eq!(hello.versions, vec![ProtocolVersion::V1]);
+
+        // Clients and servers without a version in common can't negotiate one.
+        let hello = ClientHello { versions: vec![ProtocolVersion::V2], suite: None };
+        assert_eq!(hello.negotiate(SUPPORTED_VERSIONS), Err(Error::VersionMismatch));
+
+        // Suites are carried, and unknown suites are rejected.
+        let hello = ClientHello::with_suite(Ciphersuite::P256Sha512_256);
+        assert_eq!(ClientHello::decode(&hello.encode()), Ok(hello));
+        assert_eq!(ClientHello::decode(&[1, 1, 200]), Err(Error::SuiteMismatch));
+
+        // Truncated messages are invalid.
+        assert_eq!(ClientHello::decode(&[2, 1]), Err(Error::InvalidMessage));
+        assert_eq!(ServerHello::decode(&[]), Err(Error::InvalidMessage));
+    }
+
+    #[test]
+    fn server_info() {
+        let info = ServerInfo {
+            versions: vec![ProtocolVersion::V1],
+            epoch: 22,
+            digest: [7; 32],
+            suite: 1,
+            prefix_len: 8,
+            min_bucket_size: 4,
+        };
+        assert_eq!(ServerInfo::decode(&info.encode()), Ok(info.clone()));
+        let b = info.encode();
+        assert_eq!(ServerInfo::decode(&b[..b.len() - 1]), Err(Error::InvalidMessage));
+    }
+}
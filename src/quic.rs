@@ -0,0 +1,455 @@
+//! A QUIC transport, for clients on lossy networks.
+//!
+//! Over TCP, a lookup's two requests wait on a TCP handshake, a TLS handshake, and every packet lost
+//! by either. Over QUIC, each request is sent on its own stream of a single connection, so a lost
+//! packet only delays the request it belongs to. A [`QuicClient`] also remembers each server's TLS
+//! session tickets, so its later connections to the same server (e.g. for the next sync) resume
+//! with 0-RTT, sending their first requests as early data without waiting for a handshake.
+//!
+//! Early data can be replayed by anyone who captures it, so only requests which are idempotent and
+//! reveal nothing the directory doesn't already publish are sent early: handshakes, bucket
+//! requests, and epoch pins. Blinding phone numbers waits for the handshake to complete.
+//!
+//! Each request is a kind byte followed by its body, and each response a status byte followed by
+//! the result or an encoded [`WireError`]. Requests the server can't parse have their stream reset.
+//!
+//! | Request            | Body               | Result                                              |
+//! |--------------------|--------------------|-----------------------------------------------------|
+//! | handshake          | a [`ClientHello`]  | a [`ServerHello`]                                   |
+//! | find bucket        | a prefix           | the epoch, then `0` and the bucket, or `1` and `n`  |
+//! | blind phone number | `cP`               | the epoch and `scP`                                 |
+//! | pin epoch          | the epoch          | nothing                                             |
+
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use p256::EncodedPoint;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        RootCertStore,
+    },
+    Connection, ConnectionError, Endpoint, ReadError, ReadToEndError, RecvStream, SendStream,
+    VarInt, WriteError, ZeroRttAccepted,
+};
+use tokio::{runtime::Handle, sync::oneshot};
+
+use crate::{
+    decode_bucket,
+    protocol::{ClientHello, ServerHello},
+    transport::{FoundBucket, Transport, TransportError},
+    wire::WireError,
+    Epoch, Prefix, Server, SUB_PREFIX_LEN,
+};
+
+/// The ALPN protocol ID clients and servers negotiate.
+pub const ALPN: &[u8] = b"zk-cds/1";
+
+/// The maximum length of a request, in bytes.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// The maximum length of a response, in bytes.
+const MAX_RESPONSE_LEN: usize = 64 * 1024 * 1024;
+
+const HANDSHAKE: u8 = 0;
+const FIND_BUCKET: u8 = 1;
+const BLIND_PHONE_NUMBER: u8 = 2;
+const PIN_EPOCH: u8 = 3;
+
+const OK: u8 = 0;
+const ERROR: u8 = 1;
+
+const BUCKET: u8 = 0;
+const SPLIT: u8 = 1;
+
+/// The application error code of a reset stream whose request couldn't be parsed.
+const INVALID_REQUEST: VarInt = VarInt::from_u32(1);
+
+/// Return a QUIC server config with the given certificate chain and private key, which accepts
+/// 0-RTT early data from resumed clients.
+pub fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<quinn::ServerConfig, rustls::Error> {
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    tls.max_early_data_size = u32::MAX;
+    let tls = QuicServerConfig::try_from(tls).expect("should support QUIC's initial cipher suite");
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(tls)))
+}
+
+/// Serve lookups from `server` on `endpoint`, which should be configured with [`server_config`],
+/// until the endpoint is closed.
+pub async fn serve<S>(endpoint: Endpoint, server: Arc<Server<S>>)
+where
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        let server = server.clone();
+        tokio::spawn(async move {
+            let Ok(connecting) = incoming.accept() else {
+                return;
+            };
+
+            // Answer resumed clients' early data without waiting for the handshake.
+            let connection = match connecting.into_0rtt() {
+                Ok((connection, _)) => connection,
+                Err(connecting) => match connecting.await {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                },
+            };
+            while let Ok((send, recv)) = connection.accept_bi().await {
+                tokio::spawn(answer(server.clone(), send, recv));
+            }
+        });
+    }
+}
+
+/// Read a request from a stream and write its response.
+async fn answer<S: BuildHasher + Clone>(
+    server: Arc<Server<S>>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) {
+    let Ok(req) = recv.read_to_end(MAX_REQUEST_LEN).await else {
+        let _ = send.reset(INVALID_REQUEST);
+        return;
+    };
+    let resp = match respond(&server, &req).map_err(WireError::try_from) {
+        Ok(result) => [&[OK][..], &result].concat(),
+        Err(Ok(err)) => [&[ERROR][..], &err.encode()].concat(),
+        Err(Err(_)) => {
+            let _ = send.reset(INVALID_REQUEST);
+            return;
+        }
+    };
+    if send.write_all(&resp).await.is_ok() {
+        let _ = send.finish();
+    }
+}
+
+/// Return the result of the given request.
+fn respond<S: BuildHasher + Clone>(
+    server: &Server<S>,
+    req: &[u8],
+) -> Result<Vec<u8>, TransportError> {
+    let (&kind, body) = req.split_first().ok_or(TransportError::InvalidMessage)?;
+    let epoch = server.epoch().to_be_bytes();
+    match kind {
+        HANDSHAKE => Ok(server.handshake(&ClientHello::decode(body)?)?.encode()),
+        FIND_BUCKET => {
+            let prefix: Prefix = body.try_into().map_err(|_| TransportError::InvalidMessage)?;
+            if server.is_split(prefix) {
+                return Ok([&epoch[..], &[SPLIT, SUB_PREFIX_LEN as u8]].concat());
+            }
+            Ok([&epoch[..], &[BUCKET], &server.encoded_bucket(prefix).bytes].concat())
+        }
+        BLIND_PHONE_NUMBER => {
+            let c_p = EncodedPoint::from_bytes(body).map_err(|_| TransportError::InvalidPoint)?;
+            Ok([&epoch[..], server.blind_phone_number(&c_p)?.as_bytes()].concat())
+        }
+        PIN_EPOCH => {
+            let epoch = body.try_into().map_err(|_| TransportError::InvalidMessage)?;
+            let mut transport = server;
+            transport.pin_epoch(Epoch::from_be_bytes(epoch))?;
+            Ok(Vec::new())
+        }
+        _ => Err(TransportError::InvalidMessage),
+    }
+}
+
+/// A QUIC client endpoint, which remembers servers' session tickets so its later connections to
+/// them resume with 0-RTT.
+pub struct QuicClient {
+    endpoint: Endpoint,
+    driver: Arc<Driver>,
+}
+
+impl QuicClient {
+    /// Create a client endpoint bound to the given local address, trusting servers whose
+    /// certificates chain to the given roots.
+    pub fn new(addr: SocketAddr, roots: RootCertStore) -> io::Result<QuicClient> {
+        let mut tls =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .expect("should support TLS 1.3")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        tls.enable_early_data = true;
+        let tls =
+            QuicClientConfig::try_from(tls).expect("should support QUIC's initial cipher suite");
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let mut endpoint = runtime.block_on(async { Endpoint::client(addr) })?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+        Ok(QuicClient { endpoint, driver: Arc::new(Driver::new(runtime)) })
+    }
+
+    /// Connect to the server at the given address, whose certificate is for `server_name`.
+    ///
+    /// If the client has connected to the server before, the connection is resumed with 0-RTT.
+    pub fn connect(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+    ) -> Result<QuicTransport, TransportError> {
+        let _runtime = self.driver.handle.enter();
+        let connecting =
+            self.endpoint.connect(addr, server_name).map_err(|_| TransportError::Disconnected)?;
+        let (connection, handshake) = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => (connection, Some(accepted)),
+            Err(connecting) => {
+                (self.driver.handle.block_on(connecting).map_err(connection_error)?, None)
+            }
+        };
+        Ok(QuicTransport { driver: self.driver.clone(), connection, handshake, early_data: false })
+    }
+}
+
+/// A thread which drives a client's connections, even between requests.
+struct Driver {
+    handle: Handle,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Driver {
+    fn new(runtime: tokio::runtime::Runtime) -> Driver {
+        let (shutdown, done) = oneshot::channel::<()>();
+        let handle = runtime.handle().clone();
+        let thread = thread::spawn(move || {
+            let _ = runtime.block_on(done);
+        });
+        Driver { handle, shutdown: Some(shutdown), thread: Some(thread) }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A connection to a server over QUIC.
+pub struct QuicTransport {
+    driver: Arc<Driver>,
+    connection: Connection,
+    handshake: Option<ZeroRttAccepted>,
+    early_data: bool,
+}
+
+impl QuicTransport {
+    /// Wait for the connection's handshake to complete, and return whether it was resumed with
+    /// early data the server accepted.
+    pub fn early_data_accepted(&mut self) -> bool {
+        if let Some(handshake) = self.handshake.take() {
+            self.early_data = self.driver.handle.block_on(handshake);
+        }
+        self.early_data
+    }
+
+    /// Send a request and return its result, sending it as early data if it's idempotent and
+    /// resending it if the server rejected the early data.
+    fn request(&mut self, kind: u8, body: &[u8]) -> Result<Vec<u8>, TransportError> {
+        if kind == BLIND_PHONE_NUMBER {
+            self.early_data_accepted();
+        }
+        let resp = match self.driver.handle.block_on(exchange(&self.connection, kind, body)) {
+            Err(Failure::EarlyDataRejected) => {
+                self.early_data_accepted();
+                self.driver.handle.block_on(exchange(&self.connection, kind, body))
+            }
+            resp => resp,
+        };
+        let resp = resp.map_err(|failure| match failure {
+            Failure::EarlyDataRejected => TransportError::Disconnected,
+            Failure::Transport(err) => err,
+        })?;
+        match resp.split_first() {
+            Some((&OK, result)) => Ok(result.to_vec()),
+            Some((&ERROR, err)) => Err(WireError::decode(err)?.into()),
+            _ => Err(TransportError::InvalidMessage),
+        }
+    }
+}
+
+impl Transport for QuicTransport {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        Ok(ServerHello::decode(&self.request(HANDSHAKE, &hello.encode())?)?)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        match self.find_bucket_or_split(prefix)? {
+            (epoch, FoundBucket::Bucket(bucket)) => Ok((epoch, bucket)),
+            (_, FoundBucket::Split(_)) => Err(TransportError::InvalidMessage),
+        }
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        let result = self.request(BLIND_PHONE_NUMBER, c_p.as_bytes())?;
+        let (epoch, sc_p) = split_epoch(&result)?;
+        Ok((epoch, EncodedPoint::from_bytes(sc_p).map_err(|_| TransportError::InvalidPoint)?))
+    }
+
+    fn find_bucket_or_split(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, FoundBucket), TransportError> {
+        let result = self.request(FIND_BUCKET, &prefix)?;
+        let (epoch, found) = split_epoch(&result)?;
+        match found.split_first() {
+            Some((&BUCKET, bucket)) => Ok((epoch, FoundBucket::Bucket(decode_bucket(bucket)?))),
+            Some((&SPLIT, &[n])) => Ok((epoch, FoundBucket::Split(n))),
+            _ => Err(TransportError::InvalidMessage),
+        }
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        match self.request(PIN_EPOCH, &epoch.to_be_bytes())?.as_slice() {
+            [] => Ok(()),
+            _ => Err(TransportError::InvalidMessage),
+        }
+    }
+}
+
+/// Why an exchange failed.
+enum Failure {
+    /// The server rejected the connection's early data, so the request must be resent.
+    EarlyDataRejected,
+    /// The request failed.
+    Transport(TransportError),
+}
+
+/// Send a request on a new stream and read its response.
+async fn exchange(connection: &Connection, kind: u8, body: &[u8]) -> Result<Vec<u8>, Failure> {
+    let (mut send, mut recv) =
+        connection.open_bi().await.map_err(|err| Failure::Transport(connection_error(err)))?;
+    let written = match send.write_all(&[kind]).await {
+        Ok(()) => send.write_all(body).await,
+        Err(err) => Err(err),
+    };
+    match written {
+        Ok(()) => {}
+        Err(WriteError::ZeroRttRejected) => return Err(Failure::EarlyDataRejected),
+        Err(WriteError::ConnectionLost(err)) => {
+            return Err(Failure::Transport(connection_error(err)))
+        }
+        Err(_) => return Err(Failure::Transport(TransportError::InvalidMessage)),
+    }
+    let _ = send.finish();
+    match recv.read_to_end(MAX_RESPONSE_LEN).await {
+        Ok(resp) => Ok(resp),
+        Err(ReadToEndError::Read(ReadError::ZeroRttRejected)) => Err(Failure::EarlyDataRejected),
+        Err(ReadToEndError::Read(ReadError::ConnectionLost(err))) => {
+            Err(Failure::Transport(connection_error(err)))
+        }
+        Err(_) => Err(Failure::Transport(TransportError::InvalidMessage)),
+    }
+}
+
+/// Convert a connection error to a transport error.
+fn connection_error(err: ConnectionError) -> TransportError {
+    match err {
+        ConnectionError::TimedOut => TransportError::Timeout,
+        _ => TransportError::Disconnected,
+    }
+}
+
+/// Split the epoch from the start of a result.
+fn split_epoch(b: &[u8]) -> Result<(Epoch, &[u8]), TransportError> {
+    let (epoch, rest) = b.split_first_chunk::<8>().ok_or(TransportError::InvalidMessage)?;
+    Ok((Epoch::from_be_bytes(*epoch), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use quinn::rustls::pki_types::PrivatePkcs8KeyDer;
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::session::ClientSession;
+
+    #[test]
+    fn resumption() {
+        let users = HashMap::from([(1234567890, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .expect("should generate a certificate");
+        let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let config = server_config(vec![cert.cert.der().clone()], key.into())
+            .expect("should create a server config");
+
+        let (tx, rx) = mpsc::channel();
+        let serving = {
+            let server = server.clone();
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("should build a runtime");
+                runtime.block_on(async {
+                    let endpoint = Endpoint::server(config, ([127, 0, 0, 1], 0).into())
+                        .expect("should bind the server");
+                    tx.send(endpoint.clone()).expect("should send the endpoint");
+                    serve(endpoint, server).await;
+                });
+            })
+        };
+        let endpoint = rx.recv().expect("should receive the endpoint");
+        let addr = endpoint.local_addr().expect("should have an address");
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).expect("should add the certificate");
+        let client =
+            QuicClient::new(([127, 0, 0, 1], 0).into(), roots).expect("should create a client");
+
+        // The first connection has no session ticket to resume.
+        let mut transport = client.connect(addr, "localhost").expect("should connect");
+        let mut session = ClientSession::new(&mut transport, OsRng);
+        let s_u = session.lookup(1234567890).expect("should succeed").expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), users.get(&1234567890).cloned());
+        assert!(!transport.early_data_accepted());
+
+        // The second resumes with 0-RTT.
+        let mut transport = client.connect(addr, "localhost").expect("should connect");
+        let mut session = ClientSession::new(&mut transport, OsRng);
+        let s_u = session.lookup(1234567890).expect("should succeed").expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), users.get(&1234567890).cloned());
+        assert!(transport.early_data_accepted());
+
+        // Errors are sent back as wire errors.
+        assert_eq!(
+            transport.pin_epoch(server.epoch() + 1),
+            Err(TransportError::StaleEpoch { current: server.epoch() })
+        );
+
+        endpoint.close(VarInt::from_u32(0), b"");
+        serving.join().expect("should stop serving");
+    }
+}
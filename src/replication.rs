@@ -0,0 +1,529 @@
+//! Replication of a primary server's directory to replicas in other regions.
+//!
+//! A [`Primary`] publishes a signed [`Manifest`] for each epoch, naming the epoch and a digest of
+//! the server's state. Alongside it, the primary publishes either a [`Snapshot`] of the whole
+//! server or, if the epoch was produced by [`Server::rotate`], a much smaller [`Delta`] which lets a
+//! replica at the previous epoch re-blind its own buckets. A [`Replica`] checks the manifest's
+//! signature, applies the snapshot or delta, checks the result against the manifest's digest, and
+//! only then atomically adopts the new server. Replicas never need the plaintext address book.
+//!
+//! **N.B.:** Snapshots contain the server secret, and deltas contain the ratio between consecutive
+//! secrets, so both must be transferred over confidential channels. Manifests are public.
+
+use std::{
+    fmt,
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, RwLock},
+};
+
+use p256::{
+    ecdsa::{
+        signature::{Signer, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    elliptic_curve::{Field, PrimeField},
+    EncodedPoint, FieldBytes, Scalar,
+};
+use rand::{CryptoRng, RngCore};
+
+use crate::{
+    arena::{Arena, PointBytes},
+    bundle::{signed_message, Bundle},
+    changes::ChangeLog,
+    encoding::{BucketCache, POINT_LEN},
+    suite::Ciphersuite,
+    Epoch, HashFunction, PreHash, Server, DEFAULT_BUCKET_CACHE_CAPACITY, PREFIX_LEN,
+};
+
+/// The length of an encoded [`Manifest`], in bytes.
+pub const MANIFEST_LEN: usize = 8 + 1 + 32 + 64;
+
+/// The length of an encoded [`Delta`], in bytes.
+pub const DELTA_LEN: usize = 8 + 32;
+
+/// The length of an encoded [`Snapshot`]'s header, without its split buckets' prefixes, in bytes.
+const SNAPSHOT_HEADER_LEN: usize = 8 + 1 + 32 + 8 + 8 + 8;
+
+/// The length of a row in an encoded [`Snapshot`], in bytes.
+const ROW_LEN: usize = PREFIX_LEN + 2 * POINT_LEN;
+
+/// A signed statement of a server's state at an epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The epoch of the server.
+    pub epoch: Epoch,
+    /// The server's ciphersuite.
+    pub suite: Ciphersuite,
+    /// A digest of the server's public key and buckets.
+    pub digest: [u8; 32],
+    signature: Signature,
+}
+
+impl Manifest {
+    /// Encode the manifest.
+    pub fn encode(&self) -> [u8; MANIFEST_LEN] {
+        let mut b = [0u8; MANIFEST_LEN];
+        b[..8].copy_from_slice(&self.epoch.to_be_bytes());
+        b[8] = self.suite.id();
+        b[9..41].copy_from_slice(&self.digest);
+        b[41..].copy_from_slice(&self.signature.to_bytes());
+        b
+    }
+
+    /// Decode a manifest, without verifying its signature.
+    pub fn decode(b: &[u8]) -> Result<Manifest, ReplicationError> {
+        let b: &[u8; MANIFEST_LEN] = b.try_into().map_err(|_| ReplicationError::InvalidMessage)?;
+        Ok(Manifest {
+            epoch: Epoch::from_be_bytes(b[..8].try_into().expect("should be 8 bytes")),
+            suite: Ciphersuite::from_id(b[8]).map_err(|_| ReplicationError::SuiteMismatch)?,
+            digest: b[9..41].try_into().expect("should be 32 bytes"),
+            signature: Signature::from_slice(&b[41..])
+                .map_err(|_| ReplicationError::InvalidMessage)?,
+        })
+    }
+
+    /// Return the signed message for the given epoch, suite, and digest.
+    fn message(epoch: Epoch, suite: Ciphersuite, digest: &[u8; 32]) -> Vec<u8> {
+        [b"zk-cds-manifest".as_slice(), &epoch.to_be_bytes(), &[suite.id()], digest].concat()
+    }
+}
+
+/// A complete copy of a server's state.
+///
+/// Rows are sorted by prefix and then by `sP`, so identical servers have byte-identical snapshots.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Snapshot(Vec<u8>);
+
+impl Snapshot {
+    /// The encoded snapshot.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Wrap an encoded snapshot, without validating it.
+    pub fn from_bytes(b: Vec<u8>) -> Snapshot {
+        Snapshot(b)
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the server secret into logs.
+        write!(f, "Snapshot({} bytes)", self.0.len())
+    }
+}
+
+/// The change from one epoch's server to the next, produced by [`Server::rotate`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// The epoch the delta applies to.
+    pub from_epoch: Epoch,
+    ratio: Scalar,
+}
+
+impl Delta {
+    /// Encode the delta.
+    pub fn encode(&self) -> [u8; DELTA_LEN] {
+        let mut b = [0u8; DELTA_LEN];
+        b[..8].copy_from_slice(&self.from_epoch.to_be_bytes());
+        b[8..].copy_from_slice(&self.ratio.to_repr());
+        b
+    }
+
+    /// Decode a delta.
+    pub fn decode(b: &[u8]) -> Result<Delta, ReplicationError> {
+        let b: &[u8; DELTA_LEN] = b.try_into().map_err(|_| ReplicationError::InvalidMessage)?;
+        Ok(Delta {
+            from_epoch: Epoch::from_be_bytes(b[..8].try_into().expect("should be 8 bytes")),
+            ratio: decode_scalar(&b[8..])?,
+        })
+    }
+}
+
+impl fmt::Debug for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delta").field("from_epoch", &self.from_epoch).finish_non_exhaustive()
+    }
+}
+
+/// The primary server, which publishes manifests, snapshots, and deltas.
+#[derive(Debug)]
+pub struct Primary {
+    signing_key: SigningKey,
+}
+
+impl Primary {
+    /// Create a primary with a random signing key.
+    pub fn new(mut rng: impl CryptoRng + RngCore) -> Primary {
+        Primary { signing_key: SigningKey::random(&mut rng) }
+    }
+
+    /// The key replicas use to verify manifests.
+    pub fn verifying_key(&self) -> EncodedPoint {
+        self.signing_key.verifying_key().to_encoded_point(true)
+    }
+
+    /// Publish the given server's manifest and a snapshot of it.
+    pub fn snapshot<S: BuildHasher + Clone>(&self, server: &Server<S>) -> (Manifest, Snapshot) {
+        let mut b =
+            Vec::with_capacity(SNAPSHOT_HEADER_LEN + server.buckets.sorted().len() * ROW_LEN);
+        b.extend_from_slice(&server.epoch.to_be_bytes());
+        b.push(server.ciphersuite().id());
+        b.extend_from_slice(&server.d_s.to_repr());
+        b.extend_from_slice(&(server.min_bucket_size as u64).to_be_bytes());
+        b.extend_from_slice(&server.max_bucket_size.map_or(u64::MAX, |n| n as u64).to_be_bytes());
+        b.extend_from_slice(&(server.buckets.split().len() as u64).to_be_bytes());
+        for prefix in server.buckets.split() {
+            b.extend_from_slice(prefix);
+        }
+        for (prefix, bucket) in server.buckets.sorted() {
+            for (s_p, hs_u) in bucket {
+                b.extend_from_slice(&prefix);
+                b.extend_from_slice(s_p);
+                b.extend_from_slice(hs_u);
+            }
+        }
+        (self.manifest(server), Snapshot(b))
+    }
+
+    /// Publish the manifest of `next` and the delta from `previous` to it.
+    ///
+    /// `next` must have been produced by rotating `previous`, or replicas will reject the delta
+    /// with [`ReplicationError::DigestMismatch`]. Returns [`ReplicationError::BaseMismatch`] if
+    /// `next` isn't from the following epoch.
+    pub fn delta<S: BuildHasher + Clone>(
+        &self,
+        previous: &Server<S>,
+        next: &Server<S>,
+    ) -> Result<(Manifest, Delta), ReplicationError> {
+        if next.epoch != previous.epoch + 1 {
+            return Err(ReplicationError::BaseMismatch);
+        }
+        let ratio = next.d_s * previous.d_s.invert().expect("should be invertible");
+        Ok((self.manifest(next), Delta { from_epoch: previous.epoch, ratio }))
+    }
+
+    /// Sign the given bundle, so clients can verify it with
+    /// [`BundleHeader::verify`](crate::bundle::BundleHeader::verify).
+    pub fn sign_bundle(&self, bundle: &mut Bundle) {
+        let signature: Signature = self.signing_key.sign(&signed_message(&bundle.id()));
+        bundle.set_signature(&signature.to_bytes().into());
+    }
+
+    fn manifest<S: BuildHasher + Clone>(&self, server: &Server<S>) -> Manifest {
+        let digest = server.digest();
+        let suite = server.ciphersuite();
+        let signature = self.signing_key.sign(&Manifest::message(server.epoch, suite, &digest));
+        Manifest { epoch: server.epoch, suite, digest, signature }
+    }
+}
+
+/// A replica, which serves the latest verified server published by a primary.
+#[derive(Debug)]
+pub struct Replica {
+    verifying_key: VerifyingKey,
+    hash: HashFunction,
+    pre_hash: PreHash,
+    current: RwLock<Option<Arc<Server>>>,
+}
+
+impl Replica {
+    /// Create a replica which trusts the given primary's key and uses the given hash functions,
+    /// which must match the primary's.
+    pub fn new(
+        verifying_key: &EncodedPoint,
+        hash: HashFunction,
+        pre_hash: PreHash,
+    ) -> Result<Replica, ReplicationError> {
+        Ok(Replica {
+            verifying_key: VerifyingKey::from_encoded_point(verifying_key)
+                .map_err(|_| ReplicationError::InvalidMessage)?,
+            hash,
+            pre_hash,
+            current: RwLock::new(None),
+        })
+    }
+
+    /// The server currently being served, if any.
+    pub fn current(&self) -> Option<Arc<Server>> {
+        self.current.read().expect("should not be poisoned").clone()
+    }
+
+    /// Verify and adopt the server in the given snapshot.
+    pub fn apply_snapshot(
+        &self,
+        manifest: &Manifest,
+        snapshot: &Snapshot,
+    ) -> Result<(), ReplicationError> {
+        self.verify(manifest)?;
+
+        // Decode the snapshot's header, split buckets, and rows.
+        let b = snapshot.as_bytes();
+        if b.len() < SNAPSHOT_HEADER_LEN {
+            return Err(ReplicationError::InvalidMessage);
+        }
+        let (header, b) = b.split_at(SNAPSHOT_HEADER_LEN);
+        let u64_at =
+            |i: usize| u64::from_be_bytes(header[i..i + 8].try_into().expect("should be 8 bytes"));
+        let epoch = u64_at(0);
+        if header[8] != manifest.suite.id() {
+            return Err(ReplicationError::SuiteMismatch);
+        }
+        let d_s = decode_scalar(&header[9..41])?;
+        let (min_bucket_size, max_bucket_size, split) = (u64_at(41), u64_at(49), u64_at(57));
+        let split_len = usize::try_from(split)
+            .ok()
+            .and_then(|n| n.checked_mul(PREFIX_LEN))
+            .filter(|&n| n <= b.len())
+            .ok_or(ReplicationError::InvalidMessage)?;
+        let (split, b) = b.split_at(split_len);
+        if !b.len().is_multiple_of(ROW_LEN) {
+            return Err(ReplicationError::InvalidMessage);
+        }
+        let split = split
+            .chunks_exact(PREFIX_LEN)
+            .map(|prefix| prefix.try_into().expect("should be a prefix"))
+            .collect();
+        let size = |n: u64| usize::try_from(n).map_err(|_| ReplicationError::InvalidMessage);
+        let rows = b
+            .chunks_exact(ROW_LEN)
+            .map(|row| {
+                let (prefix, points) = row.split_at(PREFIX_LEN);
+                let (s_p, hs_u) = points.split_at(POINT_LEN);
+                Ok((prefix.try_into().expect("should be a prefix"), point(s_p)?, point(hs_u)?))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let server = Server {
+            d_s,
+            epoch,
+            hash: self.hash,
+            pre_hash: self.pre_hash,
+            buckets: Arena::from_rows(rows, RandomState::new()).with_split(split),
+            min_bucket_size: size(min_bucket_size)?,
+            max_bucket_size: (max_bucket_size != u64::MAX)
+                .then(|| size(max_bucket_size))
+                .transpose()?,
+            cache: BucketCache::new(DEFAULT_BUCKET_CACHE_CAPACITY),
+            changes: ChangeLog::new(epoch),
+        };
+        self.adopt(manifest, server)
+    }
+
+    /// Verify and adopt the server produced by applying the given delta to the current one.
+    ///
+    /// Returns [`ReplicationError::BaseMismatch`] if the replica isn't at the delta's base epoch,
+    /// in which case it needs a snapshot.
+    pub fn apply_delta(&self, manifest: &Manifest, delta: &Delta) -> Result<(), ReplicationError> {
+        self.verify(manifest)?;
+        let current = self.current().ok_or(ReplicationError::BaseMismatch)?;
+        if current.epoch != delta.from_epoch {
+            return Err(ReplicationError::BaseMismatch);
+        }
+        self.adopt(manifest, current.rotate_to(delta.ratio * current.d_s))
+    }
+
+    /// Check the manifest's signature, that it's for the replica's suite, and that it's newer than
+    /// the current server.
+    fn verify(&self, manifest: &Manifest) -> Result<(), ReplicationError> {
+        let message = Manifest::message(manifest.epoch, manifest.suite, &manifest.digest);
+        self.verifying_key
+            .verify(&message, &manifest.signature)
+            .map_err(|_| ReplicationError::InvalidSignature)?;
+        if manifest.suite != self.hash.into() {
+            return Err(ReplicationError::SuiteMismatch);
+        }
+        match self.current() {
+            Some(current) if current.epoch >= manifest.epoch => {
+                Err(ReplicationError::StaleEpoch { current: current.epoch })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the current server with the given one if it matches the manifest.
+    fn adopt(&self, manifest: &Manifest, server: Server) -> Result<(), ReplicationError> {
+        if server.epoch != manifest.epoch || server.digest() != manifest.digest {
+            return Err(ReplicationError::DigestMismatch);
+        }
+
+        // Check the epoch again, in case another update was adopted concurrently.
+        let mut current = self.current.write().expect("should not be poisoned");
+        if let Some(current) = current.as_ref().filter(|c| c.epoch >= server.epoch) {
+            return Err(ReplicationError::StaleEpoch { current: current.epoch });
+        }
+        *current = Some(Arc::new(server));
+        Ok(())
+    }
+}
+
+/// An error returned when replicating a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationError {
+    /// A message was not validly encoded.
+    InvalidMessage,
+    /// A manifest's signature was invalid.
+    InvalidSignature,
+    /// A snapshot or delta didn't match its manifest.
+    DigestMismatch,
+    /// A delta didn't apply to the replica's current server.
+    BaseMismatch,
+    /// A manifest or snapshot was for a different ciphersuite than the replica's.
+    SuiteMismatch,
+    /// A manifest was for an epoch no newer than the replica's current one.
+    StaleEpoch {
+        /// The replica's current epoch.
+        current: Epoch,
+    },
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationError::InvalidMessage => write!(f, "invalid message encoding"),
+            ReplicationError::InvalidSignature => write!(f, "invalid manifest signature"),
+            ReplicationError::DigestMismatch => write!(f, "digest mismatch"),
+            ReplicationError::BaseMismatch => write!(f, "delta doesn't apply to current epoch"),
+            ReplicationError::SuiteMismatch => write!(f, "mismatched ciphersuites"),
+            ReplicationError::StaleEpoch { current } => {
+                write!(f, "stale epoch, current epoch is {current}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplicationError {}
+
+fn decode_scalar(b: &[u8]) -> Result<Scalar, ReplicationError> {
+    let b: [u8; 32] = b.try_into().map_err(|_| ReplicationError::InvalidMessage)?;
+    Option::from(Scalar::from_repr(FieldBytes::from(b)))
+        .filter(|s: &Scalar| !bool::from(s.is_zero()))
+        .ok_or(ReplicationError::InvalidMessage)
+}
+
+fn point(b: &[u8]) -> Result<EncodedPoint, ReplicationError> {
+    let b: PointBytes = b.try_into().map_err(|_| ReplicationError::InvalidMessage)?;
+    EncodedPoint::from_bytes(b).map_err(|_| ReplicationError::InvalidMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn snapshots_and_deltas() {
+        let users = (0..50).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+        let server = Server::new(OsRng, &users);
+        let primary = Primary::new(OsRng);
+        let replica =
+            Replica::new(&primary.verifying_key(), HashFunction::default(), PreHash::None)
+                .expect("should be a valid key");
+
+        // A replica can't apply a delta without a base.
+        let rotated = server.rotate(OsRng);
+        let (manifest, delta) = primary.delta(&server, &rotated).expect("should be a rotation");
+        assert_eq!(replica.apply_delta(&manifest, &delta), Err(ReplicationError::BaseMismatch));
+
+        // It adopts a snapshot, then the delta.
+        let (base, snapshot) = primary.snapshot(&server);
+        let base = Manifest::decode(&base.encode()).expect("should be a valid manifest");
+        assert_eq!(replica.apply_snapshot(&base, &snapshot), Ok(()));
+        assert_eq!(replica.current().expect("should have a server").verify(&users), vec![]);
+        let delta = Delta::decode(&delta.encode()).expect("should be a valid delta");
+        assert_eq!(replica.apply_delta(&manifest, &delta), Ok(()));
+        let current = replica.current().expect("should have a server");
+        assert_eq!(current.epoch(), 1);
+        assert_eq!(current.verify(&users), vec![]);
+
+        // Old epochs aren't re-adopted.
+        assert_eq!(
+            replica.apply_snapshot(&base, &snapshot),
+            Err(ReplicationError::StaleEpoch { current: 1 })
+        );
+    }
+
+    #[test]
+    fn tampering() {
+        let users = (0..10).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+        let server = Server::new(OsRng, &users);
+        let primary = Primary::new(OsRng);
+        let replica =
+            Replica::new(&primary.verifying_key(), HashFunction::default(), PreHash::None)
+                .expect("should be a valid key");
+        let (manifest, snapshot) = primary.snapshot(&server);
+
+        // A snapshot which doesn't match its manifest is rejected.
+        let mut b = snapshot.as_bytes().to_vec();
+        b.truncate(b.len() - ROW_LEN);
+        assert_eq!(
+            replica.apply_snapshot(&manifest, &Snapshot::from_bytes(b)),
+            Err(ReplicationError::DigestMismatch)
+        );
+
+        // As is a manifest signed by another key.
+        let (forged, _) = Primary::new(OsRng).snapshot(&server);
+        assert_eq!(
+            replica.apply_snapshot(&forged, &snapshot),
+            Err(ReplicationError::InvalidSignature)
+        );
+        assert!(replica.current().is_none());
+
+        // Replicas reject servers with other ciphersuites.
+        let other = Replica::new(&primary.verifying_key(), HashFunction::Sha512_256, PreHash::None)
+            .expect("should be a valid key");
+        assert_eq!(
+            other.apply_snapshot(&manifest, &snapshot),
+            Err(ReplicationError::SuiteMismatch)
+        );
+        assert_eq!(Manifest::decode(&manifest.encode()), Ok(manifest));
+
+        // Bundles are signed by the primary, too.
+        let mut bundle = server.export_bundle();
+        assert_eq!(bundle.header().verify(&primary.verifying_key()), Err(Error::Unauthorized));
+        primary.sign_bundle(&mut bundle);
+        assert_eq!(bundle.header().verify(&primary.verifying_key()), Ok(()));
+        let other = Primary::new(OsRng).verifying_key();
+        assert_eq!(bundle.header().verify(&other), Err(Error::Unauthorized));
+    }
+
+    #[test]
+    fn bucket_policies() {
+        let users = (0..10).map(|p| (p, Uuid::new_v4())).collect::<Vec<_>>();
+        let (server, _) = crate::ServerBuilder::new()
+            .min_bucket_size(2, crate::SmallBucketPolicy::Pad)
+            .max_bucket_size(4, crate::OverflowPolicy::Split)
+            .build(OsRng, users)
+            .expect("should build");
+        let server = crate::tests::split_bucket(server, 22);
+        let primary = Primary::new(OsRng);
+        let replica =
+            Replica::new(&primary.verifying_key(), HashFunction::default(), PreHash::None)
+                .expect("should be a valid key");
+
+        // Replicas adopt the primary's bucket size limits and split buckets.
+        let (manifest, snapshot) = primary.snapshot(&server);
+        assert_eq!(replica.apply_snapshot(&manifest, &snapshot), Ok(()));
+        let current = replica.current().expect("should have a server");
+        assert_eq!((current.min_bucket_size(), current.max_bucket_size()), (2, Some(4)));
+        let (prefix, _) = crate::Client::new(OsRng).request_phone_number(22);
+        assert!(current.is_split(prefix));
+        assert_eq!(current.export_bundle(), server.export_bundle());
+
+        // Which the manifest covers.
+        let replica =
+            Replica::new(&primary.verifying_key(), HashFunction::default(), PreHash::None)
+                .expect("should be a valid key");
+        let mut b = snapshot.as_bytes().to_vec();
+        b[48] = 1;
+        assert_eq!(
+            replica.apply_snapshot(&manifest, &Snapshot::from_bytes(b)),
+            Err(ReplicationError::DigestMismatch)
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Injectable randomness for long-lived components.
+//!
+//! Constructors and one-off operations like [`Server::rotate`](crate::Server::rotate) take an RNG
+//! argument, but components which need randomness long after they're constructed (e.g. a
+//! [`LookupService`](crate::service::LookupService) sealing session tokens, or an
+//! [`InMemoryJobQueue`](crate::jobs::InMemoryJobQueue) generating job IDs) draw it from an
+//! [`RngProvider`] instead. By default that's [`OsRng`], but a provider can wrap a seeded RNG for
+//! deterministic tests, or an HSM's entropy source.
+
+use std::{fmt, sync::Mutex};
+
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+
+/// A shared source of cryptographically secure randomness.
+pub trait RngProvider: Send + Sync {
+    /// Fill `dest` with random bytes.
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+impl fmt::Debug for dyn RngProvider + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RngProvider").finish_non_exhaustive()
+    }
+}
+
+impl RngProvider for OsRng {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        RngCore::fill_bytes(&mut OsRng, dest);
+    }
+}
+
+/// Any cryptographically secure RNG can be shared behind a mutex, e.g. a seeded one for tests.
+impl<R: CryptoRng + RngCore + Send> RngProvider for Mutex<R> {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        self.lock().expect("should not be poisoned").fill_bytes(dest);
+    }
+}
+
+impl<'a> dyn RngProvider + 'a {
+    /// Borrow the provider as an RNG, for functions which take one.
+    pub fn rng(&self) -> ProviderRng<'_, 'a> {
+        ProviderRng(self)
+    }
+}
+
+/// An [`RngProvider`] borrowed as an RNG.
+#[derive(Debug)]
+pub struct ProviderRng<'r, 'a>(&'r (dyn RngProvider + 'a));
+
+impl RngCore for ProviderRng<'_, '_> {
+    fn next_u32(&mut self) -> u32 {
+        let mut b = [0; 4];
+        self.0.fill_bytes(&mut b);
+        u32::from_le_bytes(b)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut b = [0; 8];
+        self.0.fill_bytes(&mut b);
+        u64::from_le_bytes(b)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for ProviderRng<'_, '_> {}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use super::*;
+
+    #[test]
+    fn seeded_providers() {
+        let a: Box<dyn RngProvider> = Box::new(Mutex::new(ChaChaRng::seed_from_u64(22)));
+        let b: Box<dyn RngProvider> = Box::new(Mutex::new(ChaChaRng::seed_from_u64(22)));
+        assert_eq!(a.rng().next_u64(), b.rng().next_u64());
+        assert_eq!(a.rng().next_u32(), b.rng().next_u32());
+        assert_ne!(a.rng().next_u64(), (&OsRng as &dyn RngProvider).rng().next_u64());
+    }
+}
@@ -0,0 +1,366 @@
+//! Automatic rotation of the server secret on a schedule.
+//!
+//! A [`Rotator`] runs as a tokio task which, every rotation period, [rotates](Server::rotate) the
+//! current server on a blocking thread and publishes the result to an [`Epochs`] handle (and, with
+//! the `tower` feature, to a [`LookupService`]). The previous epoch stays available for an overlap
+//! window, so clients which started a lookup just before the rotation can finish it, and is then
+//! retired, along with any epochs beyond the handle's [retention](Epochs::with_retention). Each
+//! rotation emits a [`RotationEvent`], with a [`MetricsReport`] for the epoch which
+//! ended if the rotator has [`Metrics`], for operators to log or export.
+//!
+//! A rotator [with a job queue](Rotator::with_jobs) tracks each rotation as a job, so operators can
+//! watch it through the [`admin`](crate::admin) API, and cancel it before it's published.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, RandomState},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use p256::EncodedPoint;
+use rand::{CryptoRng, RngCore};
+
+#[cfg(feature = "tower")]
+use crate::service::LookupService;
+use crate::{
+    jobs::{JobKind, JobQueue},
+    metrics::{Metrics, MetricsReport},
+    transport::TransportError,
+    Epoch, Error, Prefix, Server,
+};
+
+/// The default window during which the previous epoch is still served after a rotation.
+pub const DEFAULT_OVERLAP: Duration = Duration::from_secs(10 * 60);
+
+/// A shared handle to the servers for the current epoch and recent past epochs.
+///
+/// Past epochs are served during the overlap window after a rotation, and the most recent
+/// [retained](Epochs::with_retention) ones for longer, so clients which cached state from a recent
+/// epoch can complete their sync against it instead of restarting.
+#[derive(Debug)]
+pub struct Epochs<S = RandomState> {
+    state: Arc<RwLock<EpochState<S>>>,
+}
+
+#[derive(Debug)]
+struct EpochState<S> {
+    current: Arc<Server<S>>,
+    past: VecDeque<Arc<Server<S>>>,
+    retention: usize,
+}
+
+impl<S> Epochs<S> {
+    /// Create a handle serving the given server, which retains no past epochs after the overlap
+    /// window.
+    pub fn new(server: Arc<Server<S>>) -> Epochs<S> {
+        let state = EpochState { current: server, past: VecDeque::new(), retention: 0 };
+        Epochs { state: Arc::new(RwLock::new(state)) }
+    }
+
+    /// Keep the `n` most recent past epochs resident after the overlap window.
+    pub fn with_retention(self, n: usize) -> Epochs<S> {
+        self.state.write().expect("should not be poisoned").retention = n;
+        self
+    }
+
+    /// The server for the current epoch.
+    pub fn current(&self) -> Arc<Server<S>> {
+        self.state.read().expect("should not be poisoned").current.clone()
+    }
+
+    /// The server for the previous epoch, if it's still being served.
+    pub fn previous(&self) -> Option<Arc<Server<S>>> {
+        self.state.read().expect("should not be poisoned").past.front().cloned()
+    }
+
+    /// The server for the given epoch, if it's still being served.
+    pub fn get(&self, epoch: Epoch) -> Option<Arc<Server<S>>> {
+        let state = self.state.read().expect("should not be poisoned");
+        if state.current.epoch == epoch {
+            return Some(state.current.clone());
+        }
+        state.past.iter().find(|server| server.epoch == epoch).cloned()
+    }
+
+    /// The server for the epoch a client [pinned](crate::transport::Transport::pin_epoch) its sync
+    /// to, or [`TransportError::StaleEpoch`] if it's no longer being served.
+    pub fn pinned(&self, epoch: Epoch) -> Result<Arc<Server<S>>, TransportError> {
+        self.get(epoch).ok_or_else(|| TransportError::StaleEpoch { current: self.current().epoch })
+    }
+
+    /// The epochs being served, newest first.
+    pub fn epochs(&self) -> Vec<Epoch> {
+        let state = self.state.read().expect("should not be poisoned");
+        [&state.current].into_iter().chain(&state.past).map(|server| server.epoch).collect()
+    }
+
+    /// Make the given server current, keeping the current one as the previous epoch.
+    fn publish(&self, server: Arc<Server<S>>) {
+        let mut state = self.state.write().expect("should not be poisoned");
+        let previous = std::mem::replace(&mut state.current, server);
+        state.past.push_front(previous);
+        let n = state.retention.max(1);
+        state.past.truncate(n);
+    }
+
+    /// Stop serving past epochs beyond the retention limit.
+    fn retire(&self) {
+        let mut state = self.state.write().expect("should not be poisoned");
+        let n = state.retention;
+        state.past.truncate(n);
+    }
+}
+
+impl<S: BuildHasher + Clone> Epochs<S> {
+    /// Given a hash prefix and an epoch, return the bucket of users from that epoch's server, or
+    /// `None` if the epoch isn't being served.
+    pub fn find_bucket(
+        &self,
+        prefix: Prefix,
+        epoch: Epoch,
+    ) -> Option<HashMap<EncodedPoint, EncodedPoint, S>> {
+        self.get(epoch).map(|server| server.find_bucket(prefix))
+    }
+
+    /// Given a client-blinded phone number point and an epoch, return the point double-blinded by
+    /// that epoch's server, or `None` if the epoch isn't being served. Returns
+    /// [`Error::InvalidPoint`] if the point is invalid.
+    pub fn blind_phone_number(
+        &self,
+        c_p: &EncodedPoint,
+        epoch: Epoch,
+    ) -> Result<Option<EncodedPoint>, Error> {
+        self.get(epoch).map(|server| server.blind_phone_number(c_p)).transpose()
+    }
+}
+
+impl<S> Clone for Epochs<S> {
+    fn clone(&self) -> Self {
+        Epochs { state: self.state.clone() }
+    }
+}
+
+/// A record of a completed rotation.
+#[derive(Debug, Clone)]
+pub struct RotationEvent {
+    /// The new epoch.
+    pub epoch: Epoch,
+    /// How long re-blinding the buckets took.
+    pub elapsed: Duration,
+    /// The metrics report for the epoch which ended, if the rotator has metrics.
+    pub metrics: Option<MetricsReport>,
+}
+
+/// A callback for [`RotationEvent`]s.
+type RotationHook = Box<dyn FnMut(&RotationEvent) + Send>;
+
+/// A task which rotates the server secret on a schedule.
+pub struct Rotator<R, S = RandomState> {
+    epochs: Epochs<S>,
+    rng: Option<R>,
+    period: Duration,
+    overlap: Duration,
+    metrics: Option<Arc<Metrics>>,
+    on_rotate: Option<RotationHook>,
+    jobs: Option<Arc<dyn JobQueue>>,
+    #[cfg(feature = "tower")]
+    service: Option<LookupService<S>>,
+}
+
+impl<R, S> Rotator<R, S>
+where
+    R: CryptoRng + RngCore + Send + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Create a rotator which rotates the current server of `epochs` every `period`, with an
+    /// overlap of [`DEFAULT_OVERLAP`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn new(epochs: Epochs<S>, rng: R, period: Duration) -> Rotator<R, S> {
+        assert!(!period.is_zero(), "period should be positive");
+        Rotator {
+            epochs,
+            rng: Some(rng),
+            period,
+            overlap: DEFAULT_OVERLAP,
+            metrics: None,
+            on_rotate: None,
+            jobs: None,
+            #[cfg(feature = "tower")]
+            service: None,
+        }
+    }
+
+    /// Serve the previous epoch for `overlap` after each rotation, instead of [`DEFAULT_OVERLAP`].
+    /// Overlaps longer than the rotation period are cut short by the next rotation.
+    pub fn with_overlap(self, overlap: Duration) -> Rotator<R, S> {
+        Rotator { overlap, ..self }
+    }
+
+    /// Release a report of the given metrics for each epoch which ends.
+    pub fn with_metrics(self, metrics: Arc<Metrics>) -> Rotator<R, S> {
+        Rotator { metrics: Some(metrics), ..self }
+    }
+
+    /// Call `f` with each rotation's event.
+    pub fn on_rotate(self, f: impl FnMut(&RotationEvent) + Send + 'static) -> Rotator<R, S> {
+        Rotator { on_rotate: Some(Box::new(f)), ..self }
+    }
+
+    /// Track each rotation as a job in the given queue.
+    pub fn with_jobs(self, jobs: Arc<dyn JobQueue>) -> Rotator<R, S> {
+        Rotator { jobs: Some(jobs), ..self }
+    }
+
+    /// Load each new epoch's server into the given service.
+    #[cfg(feature = "tower")]
+    pub fn with_service(self, service: LookupService<S>) -> Rotator<R, S> {
+        Rotator { service: Some(service), ..self }
+    }
+
+    /// Spawn the rotator onto the current tokio runtime.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    /// Rotate the server every period, forever.
+    pub async fn run(mut self) {
+        let overlap = self.overlap.min(self.period);
+        tokio::time::sleep(self.period).await;
+        loop {
+            self.rotate().await;
+            tokio::time::sleep(overlap).await;
+            self.epochs.retire();
+            tokio::time::sleep(self.period - overlap).await;
+        }
+    }
+
+    /// Rotate the current server on a blocking thread and publish the result.
+    async fn rotate(&mut self) {
+        let current = self.epochs.current();
+        let job = self.jobs.as_ref().map(|jobs| jobs.create(JobKind::Rotation, 1));
+        let mut rng = self.rng.take().expect("should have an RNG");
+        let (next, rng, elapsed) = tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let next = current.rotate(&mut rng);
+            (next, rng, start.elapsed())
+        })
+        .await
+        .expect("rotation should not panic");
+        self.rng = Some(rng);
+
+        // Discard the new epoch if its job was cancelled while it was being rotated.
+        if let (Some(jobs), Some(id)) = (&self.jobs, job) {
+            if jobs.progress(id, 1).is_err() {
+                return;
+            }
+        }
+
+        // Publish the new epoch, then report on the one which ended.
+        let next = Arc::new(next);
+        let epoch = next.epoch;
+        self.epochs.publish(next.clone());
+        #[cfg(feature = "tower")]
+        if let Some(service) = &self.service {
+            service.load(next);
+        }
+        let rng = self.rng.as_mut().expect("should have an RNG");
+        let metrics = self.metrics.as_ref().map(|metrics| metrics.report(epoch - 1, rng));
+        if let Some(f) = &mut self.on_rotate {
+            f(&RotationEvent { epoch, elapsed, metrics });
+        }
+        if let (Some(jobs), Some(id)) = (&self.jobs, job) {
+            jobs.finish(id, Ok(()));
+        }
+    }
+}
+
+impl<R, S> fmt::Debug for Rotator<R, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rotator")
+            .field("period", &self.period)
+            .field("overlap", &self.overlap)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::jobs::{InMemoryJobQueue, JobState};
+
+    #[tokio::test(start_paused = true)]
+    async fn schedule() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let epochs = Epochs::new(server);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        let jobs = Arc::new(InMemoryJobQueue::new());
+        let handle = Rotator::new(epochs.clone(), OsRng, Duration::from_secs(60))
+            .with_overlap(Duration::from_secs(10))
+            .with_metrics(Arc::new(Metrics::new(1.0, 4)))
+            .on_rotate(move |event| log.lock().expect("should not be poisoned").push(event.clone()))
+            .with_jobs(jobs.clone())
+            .spawn();
+
+        // Nothing happens before the first period ends.
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert_eq!(epochs.current().epoch(), 0);
+
+        // Then the server is rotated, and the previous epoch is served during the overlap.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(epochs.current().epoch(), 1);
+        assert_eq!(epochs.get(0).map(|s| s.epoch()), Some(0));
+        let events = events.lock().expect("should not be poisoned").clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].epoch, 1);
+        assert_eq!(events[0].metrics.as_ref().map(|m| m.epoch), Some(0));
+        let rotations = jobs.list();
+        assert_eq!(rotations.len(), 1);
+        assert_eq!(rotations[0].1.kind, JobKind::Rotation);
+        assert_eq!(rotations[0].1.state, JobState::Complete);
+
+        // After which it's retired.
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(epochs.previous().is_none());
+        assert!(epochs.get(0).is_none());
+        handle.abort();
+    }
+
+    #[test]
+    fn retention() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let epochs = Epochs::new(server.clone()).with_retention(2);
+        let client = crate::Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+
+        // Rotate three times, retiring after each.
+        for _ in 0..3 {
+            epochs.publish(Arc::new(epochs.current().rotate(OsRng)));
+            epochs.retire();
+        }
+        assert_eq!(epochs.epochs(), vec![3, 2, 1]);
+
+        // A client can still finish a lookup against a retained epoch.
+        let sc_p = epochs
+            .blind_phone_number(&c_p, 1)
+            .expect("should be a valid point")
+            .expect("should be retained");
+        let bucket = epochs.find_bucket(prefix, 1).expect("should be retained");
+        assert!(client.find_user_id(&sc_p, &bucket, 22).expect("should be valid").is_some());
+        assert!(epochs.find_bucket(prefix, 0).is_none());
+
+        // Syncs pinned to a retired epoch must restart.
+        assert_eq!(epochs.pinned(1).map(|server| server.epoch()), Ok(1));
+        assert_eq!(epochs.pinned(0).map(|_| ()), Err(TransportError::StaleEpoch { current: 3 }));
+    }
+}
@@ -0,0 +1,335 @@
+//! A router which fans batches of lookups out to the shards of a sharded deployment.
+//!
+//! A [`Router`] groups a batch of [`LookupRequest`]s by shard using a [`ShardMap`], sends each
+//! shard its requests over that shard's [`Transport`] on a thread of its own, and reassembles the
+//! responses in request order. Every request gets its own result, so a failing shard only fails
+//! the requests routed to it.
+//!
+//! If a shard times out, sheds load, or isn't ready, the router stops sending it requests for the
+//! rest of the batch and reports its remaining requests as [`RouteError::Unavailable`], with a hint
+//! of when to retry them, so clients can finish syncing the rest of their address book.
+//!
+//! Like a [`ClientSession`](crate::session::ClientSession), the router follows a shard's
+//! redirection from a [split](crate::Server::is_split) bucket to the request's sub-bucket, and
+//! fetches each shard's [overflow bucket](crate::Server::overflow_bucket) once per batch and epoch,
+//! whether or not any phone number is missing from its bucket, adding it to every response.
+
+use std::{collections::HashMap, fmt, thread, time::Duration};
+
+use p256::EncodedPoint;
+
+use crate::{
+    shard::{ShardId, ShardMap},
+    transport::{FoundBucket, Transport, TransportError},
+    Epoch, Error, Prefix, OVERFLOW_PREFIX, SUB_PREFIX_LEN,
+};
+
+/// A lookup of a client-blinded phone number and its hash prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupRequest {
+    /// The hash prefix of the phone number.
+    pub prefix: Prefix,
+    /// The [sub-prefix](crate::Client::sub_prefix) of the phone number, which is only sent to the
+    /// shard if its bucket was split.
+    pub sub_prefix: Prefix,
+    /// The client-blinded phone number point.
+    pub c_p: EncodedPoint,
+}
+
+/// A shard's response to a [`LookupRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupResponse {
+    /// The epoch of the shard's secret.
+    pub epoch: Epoch,
+    /// The bucket of users with the request's hash prefix, or its sub-bucket if the bucket was
+    /// split, along with the entries of the shard's overflow bucket.
+    pub bucket: HashMap<EncodedPoint, EncodedPoint>,
+    /// The double-blinded phone number point.
+    pub sc_p: EncodedPoint,
+}
+
+/// A routed request which failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    /// No shard was responsible for the request's prefix.
+    NoShard,
+    /// The responsible shard timed out, shed load, or had no transport.
+    Unavailable {
+        /// The unavailable shard.
+        shard: ShardId,
+        /// How long the client should wait before retrying.
+        retry_after: Duration,
+    },
+    /// The responsible shard's transport failed.
+    Transport(TransportError),
+}
+
+impl From<TransportError> for RouteError {
+    fn from(value: TransportError) -> Self {
+        RouteError::Transport(value)
+    }
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::NoShard => write!(f, "no shard for prefix"),
+            RouteError::Unavailable { shard, retry_after } => {
+                write!(f, "shard {shard} unavailable, retry after {retry_after:?}")
+            }
+            RouteError::Transport(err) => write!(f, "shard request failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// A router which fans lookups out to shards.
+#[derive(Debug)]
+pub struct Router<T> {
+    map: ShardMap,
+    shards: HashMap<ShardId, T>,
+    retry_after: Duration,
+}
+
+/// The default delay before retrying requests to an unavailable shard, if it gave none.
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+impl<T: Transport + Send> Router<T> {
+    /// Create a router which routes requests according to `map` over the given shards' transports.
+    pub fn new(map: ShardMap, shards: impl IntoIterator<Item = (ShardId, T)>) -> Router<T> {
+        Router { map, shards: shards.into_iter().collect(), retry_after: DEFAULT_RETRY_AFTER }
+    }
+
+    /// Suggest retrying requests to unavailable shards which gave no delay of their own after
+    /// `retry_after`, instead of [`DEFAULT_RETRY_AFTER`].
+    pub fn with_retry_after(self, retry_after: Duration) -> Router<T> {
+        Router { retry_after, ..self }
+    }
+
+    /// The map used to route requests.
+    pub fn shard_map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    /// Send each request to its shard, concurrently across shards, returning the results in
+    /// request order.
+    pub fn lookup(
+        &mut self,
+        requests: &[LookupRequest],
+    ) -> Vec<Result<LookupResponse, RouteError>> {
+        // Group the requests' indexes by shard.
+        let mut results = vec![Err(RouteError::NoShard); requests.len()];
+        let mut batches = HashMap::<ShardId, Vec<usize>>::new();
+        for (i, req) in requests.iter().enumerate() {
+            if let Some(shard) = self.map.shard_for(&req.prefix) {
+                batches.entry(shard).or_default().push(i);
+            }
+        }
+
+        // Send each shard its batch on its own thread.
+        let retry_after = self.retry_after;
+        thread::scope(|s| {
+            let handles = self
+                .shards
+                .iter_mut()
+                .filter_map(|(&shard, transport)| {
+                    let batch = batches.remove(&shard)?;
+                    Some(s.spawn(move || {
+                        let mut overflow = None;
+                        let mut unavailable = None;
+                        batch
+                            .into_iter()
+                            .map(|i| {
+                                // Once the shard is unavailable, stop sending it requests.
+                                if let Some(err) = unavailable {
+                                    return (i, Err(err));
+                                }
+                                let res = send(transport, &mut overflow, &requests[i]);
+                                if let Err(RouteError::Transport(err)) = res {
+                                    unavailable = outage(shard, err, retry_after);
+                                }
+                                (i, unavailable.map_or(res, Err))
+                            })
+                            .collect::<Vec<_>>()
+                    }))
+                })
+                .collect::<Vec<_>>();
+
+            // Shards without transports are unavailable.
+            for (shard, batch) in batches {
+                for i in batch {
+                    results[i] = Err(RouteError::Unavailable { shard, retry_after });
+                }
+            }
+
+            // Reassemble the responses in request order.
+            for handle in handles {
+                for (i, res) in handle.join().expect("should not panic") {
+                    results[i] = res;
+                }
+            }
+        });
+        results
+    }
+}
+
+/// Return the error for an unavailable shard, if the given transport error indicates an outage.
+fn outage(shard: ShardId, err: TransportError, retry_after: Duration) -> Option<RouteError> {
+    match err {
+        TransportError::Timeout | TransportError::NotReady | TransportError::ShardUnavailable => {
+            Some(RouteError::Unavailable { shard, retry_after })
+        }
+        TransportError::RateLimited { retry_after } => {
+            Some(RouteError::Unavailable { shard, retry_after })
+        }
+        _ => None,
+    }
+}
+
+/// Send the given request over the given transport, following any redirection to its sub-bucket,
+/// and add the shard's overflow bucket to the response, fetching it unless it's cached for the
+/// response's epoch.
+fn send(
+    transport: &mut impl Transport,
+    overflow: &mut Option<(Epoch, HashMap<EncodedPoint, EncodedPoint>)>,
+    req: &LookupRequest,
+) -> Result<LookupResponse, RouteError> {
+    let (epoch, mut bucket) = match transport.find_bucket_or_split(req.prefix)? {
+        (epoch, FoundBucket::Bucket(bucket)) => (epoch, bucket),
+
+        // If the bucket was split, re-query its sub-bucket, as long as the shard agrees on how
+        // many more hash bytes select it.
+        (_, FoundBucket::Split(n)) if usize::from(n) == SUB_PREFIX_LEN => {
+            transport.find_bucket(req.sub_prefix)?
+        }
+        (_, FoundBucket::Split(_)) => {
+            return Err(TransportError::from(Error::InvalidMessage).into())
+        }
+    };
+    let (sc_p_epoch, sc_p) = transport.blind_phone_number(&req.c_p)?;
+    if sc_p_epoch != epoch {
+        return Err(TransportError::StaleEpoch { current: epoch.max(sc_p_epoch) }.into());
+    }
+    if overflow.as_ref().is_none_or(|&(overflow_epoch, _)| overflow_epoch != epoch) {
+        let (overflow_epoch, b) = transport.find_bucket(OVERFLOW_PREFIX)?;
+        if overflow_epoch != epoch {
+            return Err(TransportError::StaleEpoch { current: epoch.max(overflow_epoch) }.into());
+        }
+        *overflow = Some((epoch, b));
+    }
+    let (_, b) = overflow.as_ref().expect("should have the overflow bucket");
+    bucket.extend(b.iter().map(|(&s_p, &hs_u)| (s_p, hs_u)));
+    Ok(LookupResponse { epoch, bucket, sc_p })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        testing::{Fault, MockServer},
+        Client, Server,
+    };
+
+    #[test]
+    fn fan_out() {
+        // Split a directory between two shards.
+        let mut map = ShardMap::default();
+        map.add(0);
+        map.add(1);
+        let client = Client::new(OsRng);
+        let users = (0..20).map(|p| (p, Uuid::new_v4())).collect::<HashMap<u64, Uuid>>();
+        let mut books = [HashMap::new(), HashMap::new()];
+        for (&p, &u) in &users {
+            let (prefix, _) = client.request_phone_number(p);
+            let shard = map.shard_for(&prefix).expect("should have a shard");
+            books[shard as usize].insert(p, u);
+        }
+        let mut shards = books.map(|book| MockServer::new(OsRng, &book));
+
+        // Shard 1 sheds load.
+        let retry_after = Duration::from_secs(30);
+        shards[1].inject(Fault::RateLimited { retry_after });
+
+        let phone_numbers = (0..20).collect::<Vec<u64>>();
+        let requests = phone_numbers
+            .iter()
+            .map(|&p| {
+                let (prefix, c_p) = client.request_phone_number(p);
+                LookupRequest { prefix, sub_prefix: client.sub_prefix(p), c_p }
+            })
+            .collect::<Vec<_>>();
+        let [a, b] = &mut shards;
+        let mut router = Router::new(map.clone(), [(0, a), (1, b)]);
+        let results = router.lookup(&requests);
+
+        // Its requests are unavailable, and the others succeed, in order.
+        for (&p, res) in phone_numbers.iter().zip(results) {
+            if map.shard_for(&client.request_phone_number(p).0) == Some(1) {
+                assert_eq!(res, Err(RouteError::Unavailable { shard: 1, retry_after }));
+                continue;
+            }
+            let res = res.expect("should succeed");
+            assert!(client
+                .find_user_id(&res.sc_p, &res.bucket, p)
+                .expect("should be a valid response")
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn missing_shard() {
+        let mut map = ShardMap::default();
+        map.add(0);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let sub_prefix = client.sub_prefix(22);
+
+        // A shard without a transport is unavailable.
+        let mut router =
+            Router::<&mut MockServer>::new(map, []).with_retry_after(Duration::from_secs(1));
+        assert_eq!(
+            router.lookup(&[LookupRequest { prefix, sub_prefix, c_p }]),
+            vec![Err(RouteError::Unavailable { shard: 0, retry_after: Duration::from_secs(1) })]
+        );
+
+        // And a router without shards has nowhere to send requests.
+        let mut router = Router::<&mut MockServer>::new(ShardMap::default(), []);
+        assert_eq!(
+            router.lookup(&[LookupRequest { prefix, sub_prefix, c_p }]),
+            vec![Err(RouteError::NoShard)]
+        );
+    }
+
+    #[test]
+    fn split_and_spilled_buckets() {
+        let mut map = ShardMap::default();
+        map.add(0);
+        let client = Client::new(OsRng);
+        let users = HashMap::from([(22, Uuid::new_v4()), (23, Uuid::new_v4())]);
+        let request = |p| {
+            let (prefix, c_p) = client.request_phone_number(p);
+            LookupRequest { prefix, sub_prefix: client.sub_prefix(p), c_p }
+        };
+        let found = |res: &Result<LookupResponse, RouteError>, p| {
+            let res = res.as_ref().expect("should succeed");
+            client.find_user_id(&res.sc_p, &res.bucket, p).expect("should be a valid response")
+        };
+
+        // The router follows redirections to sub-buckets.
+        let server = crate::tests::split_bucket(Server::new(OsRng, &users), 22);
+        let mut router = Router::new(map.clone(), [(0, &server)]);
+        let results = router.lookup(&[request(22), request(23)]);
+        assert!(found(&results[0], 22).is_some() && found(&results[1], 23).is_some());
+
+        // And adds the overflow bucket to its responses.
+        let server = crate::tests::spill_bucket(Server::new(OsRng, &users), 22);
+        let mut router = Router::new(map, [(0, &server)]);
+        let results = router.lookup(&[request(22), request(23), request(24)]);
+        assert!(found(&results[0], 22).is_some() && found(&results[1], 23).is_some());
+        assert!(found(&results[2], 24).is_none());
+    }
+}
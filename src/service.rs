@@ -0,0 +1,1553 @@
+//! A [`tower::Service`] for serving lookups from a [`Server`].
+//!
+//! [`LookupService`] does its work when its response futures are polled, not when requests are
+//! made, so it composes with middleware like [`tower::limit::ConcurrencyLimit`] and
+//! `tower::load_shed::LoadShed` to bound the CPU spent on in-flight requests. [`service`] builds
+//! a concurrency-limited stack which applies backpressure via [`Service::poll_ready`].
+//!
+//! A service can also start without a server and [load](LookupService::load) one later, e.g. once
+//! a replica receives its first snapshot. Until then it answers health checks but reports itself as
+//! not ready, and fails lookups with [`Error::NotReady`]. [`Request::from_path`] maps the
+//! `/healthz`, `/readyz`, `/v1/info`, and `/v1/stats` endpoints to requests, for load balancers
+//! and clients. A service [with public stats](LookupService::with_stats) answers `/v1/stats` with
+//! the latest noisy [`PublicStats`] about the directory.
+//!
+//! A service [with session tokens](LookupService::with_session_tokens) returns a
+//! [`SessionToken`] with each batch of blinded phone numbers, and rejects unblind requests which
+//! don't present a valid, current one with [`Error::Unauthorized`]. Each token unblinds at most as
+//! many user IDs as the batch had phone numbers, so a client can't use one lookup's token to turn
+//! the service into an oracle for every bucket entry it downloads. Without session tokens, the
+//! service refuses [`Request::UnblindUserId`] outright, since nothing would tie the point to a
+//! lookup. A service with [strict unblinding](LookupService::with_strict_unblinding) only answers
+//! [`Request::UnblindProven`], so it can't be used to unblind arbitrary points at all.
+//!
+//! A service [with lookup tokens](LookupService::with_lookup_tokens) enforces each client's
+//! [lookup budget](crate::budget): it refuses [`Request::BlindPhoneNumbers`], and only blinds
+//! phone numbers sent with [`Request::BlindWithTokens`] and one unspent token each.
+//!
+//! A service [with an abuse hook](LookupService::with_abuse_hook) reports the anonymized
+//! [features](RequestFeatures) of each lookup request to it, including attempts to reuse spent
+//! tokens.
+//!
+//! A service can also [load a shadow](LookupService::load_shadow) candidate server, e.g. for the
+//! next epoch, and mirror a sample of its lookups against it before it's promoted. See
+//! [`shadow`](crate::shadow).
+//!
+//! Nothing the service answers maps a user ID back to a phone number: bucket entries are keyed by
+//! server-blinded phone number points, their values are still blinded by the phone number's hash,
+//! and the only way to remove that blinding is to know the phone number.
+//!
+//! Clients which pipeline requests wrap them in [`Request::Tagged`] with a [`RequestHeader`], and
+//! get back a [`Response::Tagged`] echoing the request's ID. A service
+//! [with idempotency](LookupService::with_idempotency) also remembers the responses to requests
+//! with idempotency keys, and answers retries with the original response, so a retried blinding
+//! request returns the same session token rather than a second one which counts the same lookups
+//! against the client's quota again. The first attempt reserves its key, so retries which arrive
+//! while it's still being answered wait for its response instead of answering the request again.
+//!
+//! Requests for the same bucket are coalesced, both within a batch and across concurrent requests:
+//! the first request finds the bucket, and the others wait for it and share the result, so a burst
+//! of lookups for a popular prefix finds its bucket once. Waiting requests return
+//! [`Poll::Pending`] and are woken once the bucket is found, rather than blocking their threads.
+//!
+//! A service [with a timeout](LookupService::with_timeout) gives each request a
+//! [`Deadline`](crate::deadline::Deadline), and abandons batches part-way through once it passes or
+//! the request is cancelled, rather than spending CPU on answers nobody is waiting for.
+//!
+//! Clients and replicas which keep a full copy of the directory can subscribe to changes by
+//! long-polling with [`Request::WaitForChanges`], which resolves once a server for a later epoch is
+//! loaded, with the prefixes whose buckets [changed](Server::changes_since) since their epoch.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use p256::EncodedPoint;
+use rand::rngs::OsRng;
+use tower::{limit::ConcurrencyLimit, Service};
+use uuid::Uuid;
+
+use crate::{
+    abuse::{prefix_entropy, AbuseHook, RequestFeatures, RequestKind},
+    budget::{LookupToken, TokenIssuer},
+    deadline::Deadline,
+    encoding::ENTRY_LEN,
+    metrics::{DirectoryStats, HourlyMetrics, PrefixClass, PublicStats},
+    point::normalize_point,
+    protocol::ServerInfo,
+    rng::RngProvider,
+    shadow::{Shadow, ShadowReport},
+    suite::Ciphersuite,
+    token::{SessionToken, TokenClaims, TokenKey},
+    transport::{IdempotencyKey, RequestHeader, RequestId},
+    unblind::UnblindRequest,
+    Epoch, Error, Prefix, Server, OVERFLOW_PREFIX, SUB_PREFIX_LEN,
+};
+
+/// A request to a [`LookupService`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Find the buckets for a batch of hash prefixes.
+    FindBuckets(Vec<Prefix>),
+    /// Double-blind a batch of client-blinded phone number points.
+    BlindPhoneNumbers(Vec<EncodedPoint>),
+    /// Double-blind a batch of client-blinded phone number points, spending one lookup token for
+    /// each.
+    BlindWithTokens(Vec<EncodedPoint>, Vec<LookupToken>),
+    /// Unblind a blinded user ID point, with the session token from the lookup, if any.
+    UnblindUserId(EncodedPoint, Option<SessionToken>),
+    /// Unblind a user ID point in strict mode, with the session token from the lookup, if any.
+    UnblindProven(UnblindRequest, Option<SessionToken>),
+    /// Check the service is running.
+    Health,
+    /// Check the service has loaded a server and can answer lookups.
+    Ready,
+    /// Describe the loaded server's deployment.
+    Info,
+    /// Return the latest public statistics about the directory.
+    Stats,
+    /// Return the prefixes whose buckets changed since the given epoch.
+    Changes(Epoch),
+    /// Wait until a server for an epoch after the given one is loaded, then return the prefixes
+    /// whose buckets changed since the given epoch.
+    WaitForChanges(Epoch),
+    /// A request with a header giving its ID and idempotency key, if any. Tagged requests can't be
+    /// nested.
+    Tagged(RequestHeader, Box<Request>),
+}
+
+impl Request {
+    /// Return the request for the given HTTP endpoint path, if it's `/healthz`, `/readyz`,
+    /// `/v1/info`, or `/v1/stats`.
+    pub fn from_path(path: &str) -> Option<Request> {
+        match path {
+            "/healthz" => Some(Request::Health),
+            "/readyz" => Some(Request::Ready),
+            "/v1/info" => Some(Request::Info),
+            "/v1/stats" => Some(Request::Stats),
+            _ => None,
+        }
+    }
+}
+
+/// A response from a [`LookupService`].
+#[derive(Debug, Clone)]
+pub enum Response<S = RandomState> {
+    /// The buckets for each prefix, in request order. Identical prefixes share a bucket.
+    Buckets(Vec<Arc<HashMap<EncodedPoint, EncodedPoint, S>>>),
+    /// The requested prefixes whose buckets were [split](Server::is_split), in request order, and
+    /// the number of extra hash bytes which select their sub-buckets. Clients should re-request the
+    /// batch with those prefixes replaced by their [sub-prefixes](crate::Client::sub_prefix).
+    Split(Vec<Prefix>, u8),
+    /// The double-blinded phone number points, in request order, and a session token for
+    /// unblinding the results, if the service issues them.
+    BlindedPhoneNumbers(Vec<EncodedPoint>, Option<SessionToken>),
+    /// The unblinded user ID, if it was a valid UUID.
+    UserId(Option<Uuid>),
+    /// The user ID point, still blinded by the phone number's hash, if the phone number had one.
+    UserIdPoint(Option<EncodedPoint>),
+    /// The service is running.
+    Healthy,
+    /// Whether the service has loaded a server.
+    Ready(bool),
+    /// The loaded server's deployment.
+    Info(Arc<ServerInfo>),
+    /// The latest public statistics, or `None` if the service doesn't publish any or none have
+    /// been released.
+    Stats(Option<PublicStats>),
+    /// The loaded server's epoch and the prefixes whose buckets changed since the requested epoch,
+    /// or `None` if every bucket must be refetched.
+    Changes(Epoch, Option<Vec<Prefix>>),
+    /// The response to a tagged request, with the request's ID.
+    Tagged(RequestId, Box<Response<S>>),
+}
+
+/// A [`Service`] which answers [`Request`]s using a shared [`Server`].
+///
+/// Clones of a service share its server, so loading a new one (e.g. after a rotation) updates them
+/// all.
+#[derive(Debug)]
+pub struct LookupService<S = RandomState> {
+    shared: Arc<Shared<S>>,
+    tokens: Option<Arc<Tokens>>,
+    idempotency: Option<Arc<Idempotency<S>>>,
+    stats: Option<Arc<DirectoryStats>>,
+    hourly: Option<Arc<HourlyMetrics>>,
+    budget: Option<Arc<TokenIssuer>>,
+    abuse: Option<Arc<dyn AbuseHook>>,
+    strict: bool,
+    timeout: Option<Duration>,
+    rng: Arc<dyn RngProvider>,
+}
+
+/// The state shared by clones of a service and their response futures.
+#[derive(Debug)]
+struct Shared<S> {
+    loaded: RwLock<Option<Arc<Loaded<S>>>>,
+    shadow: RwLock<Option<Arc<Shadow<S>>>>,
+    /// The wakers of pending long-polls, by waiter ID, so each one's is replaced when it's polled
+    /// again and removed when it's dropped.
+    waiters: Mutex<HashMap<u64, Waker>>,
+    next_waiter: AtomicU64,
+}
+
+impl<S> Shared<S> {
+    fn current(&self) -> Option<Arc<Loaded<S>>> {
+        self.loaded.read().expect("should not be poisoned").clone()
+    }
+}
+
+/// The responses to the most recent requests with idempotency keys.
+#[derive(Debug)]
+struct Idempotency<S> {
+    capacity: usize,
+    responses: Mutex<IdempotentResponses<S>>,
+}
+
+#[derive(Debug)]
+struct IdempotentResponses<S> {
+    entries: HashMap<IdempotencyKey, (Request, Attempt<S>)>,
+    order: VecDeque<IdempotencyKey>,
+}
+
+/// The state of the first attempt of a request with an idempotency key.
+#[derive(Debug)]
+enum Attempt<S> {
+    /// The first attempt is being answered, and retries are waiting for it, by waiter ID.
+    InFlight(HashMap<u64, Waker>),
+    /// The first attempt was answered with the given response.
+    Answered(Response<S>),
+}
+
+/// The outcome of [reserving](Idempotency::reserve) an idempotency key.
+#[derive(Debug)]
+enum Reservation<S> {
+    /// The key is reserved for this attempt, which must [complete](Idempotency::complete) it.
+    Reserved,
+    /// An earlier attempt is in flight, and this one will be woken once it's answered.
+    Waiting,
+    /// An earlier attempt was answered with the given response.
+    Answered(Response<S>),
+}
+
+impl<S> Idempotency<S> {
+    /// Atomically reserve the given key for an attempt of the given request, or return the state
+    /// of an earlier attempt, registering the waker to wait for it if it's in flight. Fails with
+    /// [`Error::InvalidMessage`] if the key was used for a different request.
+    fn reserve(
+        &self,
+        key: IdempotencyKey,
+        req: &Request,
+        waiter: u64,
+        waker: &Waker,
+    ) -> Result<Reservation<S>, Error>
+    where
+        S: Clone,
+    {
+        let mut responses = self.responses.lock().expect("should not be poisoned");
+        match responses.entries.get_mut(&key) {
+            Some((original, _)) if original != req => return Err(Error::InvalidMessage),
+            Some((_, Attempt::Answered(resp))) => return Ok(Reservation::Answered(resp.clone())),
+            Some((_, Attempt::InFlight(waiters))) => {
+                waiters.insert(waiter, waker.clone());
+                return Ok(Reservation::Waiting);
+            }
+            None if self.capacity == 0 => return Ok(Reservation::Reserved),
+            None => {}
+        }
+
+        // Evict the oldest entry if full, waking any retries waiting for it to try again.
+        if responses.entries.len() >= self.capacity {
+            if let Some(oldest) = responses.order.pop_front() {
+                if let Some((_, Attempt::InFlight(waiters))) = responses.entries.remove(&oldest) {
+                    waiters.into_values().for_each(Waker::wake);
+                }
+            }
+        }
+        responses.entries.insert(key, (req.clone(), Attempt::InFlight(HashMap::new())));
+        responses.order.push_back(key);
+        Ok(Reservation::Reserved)
+    }
+
+    /// Complete the attempt which reserved the given key, remembering its response if it
+    /// succeeded or releasing the key if it failed, and wake the retries waiting for it.
+    fn complete(&self, key: IdempotencyKey, res: &Result<Response<S>, Error>)
+    where
+        S: Clone,
+    {
+        let mut responses = self.responses.lock().expect("should not be poisoned");
+        let Some((_, attempt)) = responses.entries.get_mut(&key) else {
+            return;
+        };
+        let waiters = match (mem::replace(attempt, Attempt::InFlight(HashMap::new())), res) {
+            (Attempt::InFlight(waiters), Ok(resp)) => {
+                *attempt = Attempt::Answered(resp.clone());
+                waiters
+            }
+            (Attempt::InFlight(waiters), Err(_)) => {
+                responses.entries.remove(&key);
+                responses.order.retain(|k| *k != key);
+                waiters
+            }
+            (answered, _) => {
+                *attempt = answered;
+                HashMap::new()
+            }
+        };
+        drop(responses);
+        waiters.into_values().for_each(Waker::wake);
+    }
+
+    /// Stop the given waiter from waiting for an in-flight attempt with the given key.
+    fn forget(&self, key: IdempotencyKey, waiter: u64) {
+        let mut responses = self.responses.lock().expect("should not be poisoned");
+        if let Some((_, Attempt::InFlight(waiters))) = responses.entries.get_mut(&key) {
+            waiters.remove(&waiter);
+        }
+    }
+}
+
+/// The key and lifetime of session tokens, and when each unexpired token was issued, how many
+/// unblinds it has spent, and how many it may.
+#[derive(Debug)]
+struct Tokens {
+    key: TokenKey,
+    ttl: Duration,
+    spent: Mutex<HashMap<SessionToken, (u64, u32, u32)>>,
+}
+
+impl Tokens {
+    /// Returns `true` if the given token has spent all its unblinds.
+    fn is_exhausted(&self, token: &SessionToken) -> bool {
+        let spent = self.spent.lock().expect("should not be poisoned");
+        spent.get(token).is_some_and(|&(_, n, lookups)| n >= lookups)
+    }
+}
+
+/// A loaded server and its deployment info, which is computed once per server.
+#[derive(Debug)]
+struct Loaded<S> {
+    server: Arc<Server<S>>,
+    info: Arc<ServerInfo>,
+    /// The buckets being found for in-flight requests, by prefix.
+    pending: Mutex<HashMap<Prefix, PendingBucket<S>>>,
+}
+
+/// A bucket shared between the requests for it.
+type SharedBucket<S> = Arc<HashMap<EncodedPoint, EncodedPoint, S>>;
+
+/// A bucket which a request is finding, and the concurrent requests for it which are waiting to
+/// share it, by waiter ID.
+#[derive(Debug)]
+struct PendingBucket<S> {
+    finder: u64,
+    bucket: Option<SharedBucket<S>>,
+    waiters: HashMap<u64, Waker>,
+}
+
+impl<S: BuildHasher + Clone> Loaded<S> {
+    /// Collect the buckets for the given prefixes which concurrent requests have found, and claim
+    /// the rest for the given waiter to find. If any are still being found, the waiter claims
+    /// nothing and is woken once they're found instead.
+    ///
+    /// Waiters never hold claims while waiting, so requests can't deadlock waiting for each other.
+    fn claim(
+        &self,
+        prefixes: &[Prefix],
+        found: &mut HashMap<Prefix, SharedBucket<S>>,
+        waiter: u64,
+        waker: &Waker,
+    ) -> Poll<()> {
+        let mut pending = self.pending.lock().expect("should not be poisoned");
+        let mut unclaimed = Vec::new();
+        let mut waiting = false;
+        for &prefix in prefixes {
+            if found.contains_key(&prefix) || self.server.is_split(prefix) {
+                continue;
+            }
+            let Some(entry) = pending.get_mut(&prefix) else {
+                unclaimed.push(prefix);
+                continue;
+            };
+            match &entry.bucket {
+                Some(bucket) => {
+                    found.insert(prefix, bucket.clone());
+                    entry.waiters.remove(&waiter);
+                    if entry.waiters.is_empty() {
+                        pending.remove(&prefix);
+                    }
+                }
+                None => {
+                    entry.waiters.insert(waiter, waker.clone());
+                    waiting = true;
+                }
+            }
+        }
+        if waiting {
+            return Poll::Pending;
+        }
+        for prefix in unclaimed {
+            let entry = PendingBucket { finder: waiter, bucket: None, waiters: HashMap::new() };
+            pending.insert(prefix, entry);
+        }
+        Poll::Ready(())
+    }
+
+    /// Find the bucket for the given prefix, which the given waiter claimed, and share it with any
+    /// requests waiting for it.
+    fn find_bucket(&self, prefix: Prefix, finder: u64) -> SharedBucket<S> {
+        let bucket = Arc::new(self.server.find_bucket(prefix));
+        let mut pending = self.pending.lock().expect("should not be poisoned");
+        if let Some(entry) = pending.get_mut(&prefix).filter(|entry| entry.finder == finder) {
+            if entry.waiters.is_empty() {
+                pending.remove(&prefix);
+            } else {
+                entry.bucket = Some(bucket.clone());
+                entry.waiters.values().for_each(Waker::wake_by_ref);
+            }
+        }
+        bucket
+    }
+}
+
+impl<S> Loaded<S> {
+    /// Release the given waiter's unfound claims, e.g. if its request failed, waking the requests
+    /// waiting for them to find them instead, and stop it waiting for any buckets.
+    fn release(&self, waiter: u64) {
+        let mut pending = self.pending.lock().expect("should not be poisoned");
+        pending.retain(|_, entry| {
+            if entry.finder == waiter && entry.bucket.is_none() {
+                entry.waiters.values().for_each(Waker::wake_by_ref);
+                return false;
+            }
+            entry.waiters.remove(&waiter);
+            entry.bucket.is_none() || !entry.waiters.is_empty()
+        });
+    }
+}
+
+impl<S: BuildHasher + Clone> LookupService<S> {
+    /// Create a new [`LookupService`] for the given server.
+    pub fn new(server: Arc<Server<S>>) -> LookupService<S> {
+        let svc = LookupService::unloaded();
+        svc.load(server);
+        svc
+    }
+
+    /// Create a new [`LookupService`] which isn't ready until a server is loaded.
+    pub fn unloaded() -> LookupService<S> {
+        let shared = Shared {
+            loaded: RwLock::new(None),
+            shadow: RwLock::new(None),
+            waiters: Mutex::new(HashMap::new()),
+            next_waiter: AtomicU64::new(0),
+        };
+        LookupService {
+            shared: Arc::new(shared),
+            tokens: None,
+            idempotency: None,
+            stats: None,
+            hourly: None,
+            budget: None,
+            abuse: None,
+            strict: false,
+            timeout: None,
+            rng: Arc::new(OsRng),
+        }
+    }
+
+    /// Issue session tokens sealed with the given key when blinding phone numbers, and require
+    /// tokens no older than `ttl` from the same epoch when unblinding user IDs. Each token unblinds
+    /// at most as many user IDs as phone numbers were blinded when it was issued.
+    ///
+    /// Clones of the service share their count of each token's unblinds, but other services with
+    /// the same key don't, so frontends should route a client's requests to the same service.
+    pub fn with_session_tokens(self, key: TokenKey, ttl: Duration) -> LookupService<S> {
+        let tokens = Tokens { key, ttl, spent: Mutex::new(HashMap::new()) };
+        LookupService { tokens: Some(Arc::new(tokens)), ..self }
+    }
+
+    /// Remember the responses to the `capacity` most recent requests with idempotency keys, and
+    /// answer retries of them with the original responses, waiting for them if they're still in
+    /// flight. Reusing a key for a different request fails with [`Error::InvalidMessage`].
+    pub fn with_idempotency(self, capacity: usize) -> LookupService<S> {
+        let responses = IdempotentResponses { entries: HashMap::new(), order: VecDeque::new() };
+        let idempotency = Idempotency { capacity, responses: Mutex::new(responses) };
+        LookupService { idempotency: Some(Arc::new(idempotency)), ..self }
+    }
+
+    /// Answer [`Request::Stats`] with the latest statistics released by `stats`.
+    pub fn with_stats(self, stats: Arc<DirectoryStats>) -> LookupService<S> {
+        LookupService { stats: Some(stats), ..self }
+    }
+
+    /// Record the hourly totals of lookups, matches, and buckets served in `hourly`.
+    pub fn with_hourly_metrics(self, hourly: Arc<HourlyMetrics>) -> LookupService<S> {
+        LookupService { hourly: Some(hourly), ..self }
+    }
+
+    /// Require one lookup token issued by `issuer` for each phone number blinded, rejecting
+    /// [`Request::BlindPhoneNumbers`] and requests without enough valid, unspent tokens with
+    /// [`Error::Unauthorized`].
+    pub fn with_lookup_tokens(self, issuer: Arc<TokenIssuer>) -> LookupService<S> {
+        LookupService { budget: Some(issuer), ..self }
+    }
+
+    /// Call `hook` with the anonymized features of each lookup request, once it's answered.
+    pub fn with_abuse_hook(self, hook: Arc<dyn AbuseHook>) -> LookupService<S> {
+        LookupService { abuse: Some(hook), ..self }
+    }
+
+    /// Reject [`Request::UnblindUserId`] with [`Error::Unauthorized`], so user IDs can only be
+    /// unblinded with proof of a lookup.
+    pub fn with_strict_unblinding(self) -> LookupService<S> {
+        LookupService { strict: true, ..self }
+    }
+
+    /// Abandon requests which haven't been answered within `timeout` of being made with
+    /// [`Error::DeadlineExceeded`], checking between every
+    /// [`CHECK_INTERVAL`](crate::deadline::CHECK_INTERVAL) bucket lookups or
+    /// blindings of a batch.
+    ///
+    /// Long-polls with [`Request::WaitForChanges`] are exempt, since they're meant to wait until a
+    /// new server is loaded, but can still be [cancelled](LookupFuture::deadline).
+    pub fn with_timeout(self, timeout: Duration) -> LookupService<S> {
+        LookupService { timeout: Some(timeout), ..self }
+    }
+
+    /// Draw the randomness for session tokens from the given provider, instead of [`OsRng`].
+    pub fn with_rng(self, rng: Arc<dyn RngProvider>) -> LookupService<S> {
+        LookupService { rng, ..self }
+    }
+
+    /// Serve requests from the given server, replacing any previously loaded one, and wake any
+    /// requests waiting for changes.
+    pub fn load(&self, server: Arc<Server<S>>) {
+        let info = Arc::new(server.info());
+        *self.shared.loaded.write().expect("should not be poisoned") =
+            Some(Arc::new(Loaded { server, info, pending: Mutex::new(HashMap::new()) }));
+        let waiters = mem::take(&mut *self.shared.waiters.lock().expect("should not be poisoned"));
+        waiters.into_values().for_each(Waker::wake);
+    }
+
+    /// The loaded server, if any.
+    pub fn server(&self) -> Option<Arc<Server<S>>> {
+        self.shared.current().map(|loaded| loaded.server.clone())
+    }
+
+    /// Mirror a sample of lookups against the given shadow's candidate server, replacing any
+    /// previously loaded shadow.
+    pub fn load_shadow(&self, shadow: Arc<Shadow<S>>) {
+        *self.shared.shadow.write().expect("should not be poisoned") = Some(shadow);
+    }
+
+    /// The loaded shadow, if any.
+    pub fn shadow(&self) -> Option<Arc<Shadow<S>>> {
+        self.shared.shadow.read().expect("should not be poisoned").clone()
+    }
+
+    /// Serve requests from the loaded shadow's candidate server and stop mirroring, returning the
+    /// shadow's final report, or `None` if no shadow is loaded.
+    pub fn promote_shadow(&self) -> Option<ShadowReport> {
+        let shadow = self.shared.shadow.write().expect("should not be poisoned").take()?;
+        self.load(shadow.candidate());
+        Some(shadow.report())
+    }
+
+    /// Stop mirroring without promoting the loaded shadow's candidate server, returning the
+    /// shadow's final report, or `None` if no shadow is loaded.
+    pub fn discard_shadow(&self) -> Option<ShadowReport> {
+        let shadow = self.shared.shadow.write().expect("should not be poisoned").take()?;
+        Some(shadow.report())
+    }
+}
+
+impl<S> Clone for LookupService<S> {
+    fn clone(&self) -> Self {
+        LookupService {
+            shared: self.shared.clone(),
+            tokens: self.tokens.clone(),
+            idempotency: self.idempotency.clone(),
+            stats: self.stats.clone(),
+            hourly: self.hourly.clone(),
+            budget: self.budget.clone(),
+            abuse: self.abuse.clone(),
+            strict: self.strict,
+            timeout: self.timeout,
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+impl<S: BuildHasher + Clone> Service<Request> for LookupService<S> {
+    type Response = Response<S>;
+    type Error = Error;
+    type Future = LookupFuture<S>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> LookupFuture<S> {
+        let (header, req) = match req {
+            Request::Tagged(header, req) => (Some(header), *req),
+            req => (None, req),
+        };
+        let deadline = match (&req, self.timeout) {
+            (Request::WaitForChanges(_), _) | (_, None) => Deadline::never(),
+            (_, Some(timeout)) => Deadline::after(timeout),
+        };
+        LookupFuture {
+            loaded: self.shared.current(),
+            shadow: self.shared.shadow.read().expect("should not be poisoned").clone(),
+            shared: self.shared.clone(),
+            tokens: self.tokens.clone(),
+            idempotency: self.idempotency.clone(),
+            stats: self.stats.clone(),
+            hourly: self.hourly.clone(),
+            budget: self.budget.clone(),
+            abuse: self.abuse.clone(),
+            strict: self.strict,
+            rng: self.rng.clone(),
+            deadline,
+            header,
+            req: Some(req),
+            waiter: None,
+            found: HashMap::new(),
+        }
+    }
+}
+
+/// The response future of a [`LookupService`].
+#[derive(Debug)]
+pub struct LookupFuture<S = RandomState> {
+    loaded: Option<Arc<Loaded<S>>>,
+    shadow: Option<Arc<Shadow<S>>>,
+    shared: Arc<Shared<S>>,
+    tokens: Option<Arc<Tokens>>,
+    idempotency: Option<Arc<Idempotency<S>>>,
+    stats: Option<Arc<DirectoryStats>>,
+    hourly: Option<Arc<HourlyMetrics>>,
+    budget: Option<Arc<TokenIssuer>>,
+    abuse: Option<Arc<dyn AbuseHook>>,
+    strict: bool,
+    rng: Arc<dyn RngProvider>,
+    deadline: Deadline,
+    header: Option<RequestHeader>,
+    req: Option<Request>,
+    waiter: Option<u64>,
+    found: HashMap<Prefix, SharedBucket<S>>,
+}
+
+impl<S> LookupFuture<S> {
+    /// The request's deadline, which can be [cancelled](Deadline::cancel) to abandon it, e.g. when
+    /// the client disconnects. Dropping the future also cancels it.
+    pub fn deadline(&self) -> Deadline {
+        self.deadline.clone()
+    }
+}
+
+impl<S> LookupFuture<S> {
+    /// Return the ID the future registers its waker under while it waits, assigning one if needed.
+    fn waiter_id(&mut self) -> u64 {
+        let next_waiter = &self.shared.next_waiter;
+        *self.waiter.get_or_insert_with(|| next_waiter.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Release any buckets the future claimed but didn't find, and stop it waiting for any.
+    fn release_buckets(&self) {
+        if let (Some(loaded), Some(id)) = (&self.loaded, self.waiter) {
+            loaded.release(id);
+        }
+    }
+
+    /// Stop waiting for changes, removing the future's waker.
+    fn unregister(&mut self, waiters: &mut HashMap<u64, Waker>) {
+        if let Some(id) = self.waiter.take() {
+            waiters.remove(&id);
+        }
+    }
+}
+
+impl<S> Drop for LookupFuture<S> {
+    fn drop(&mut self) {
+        self.deadline.cancel();
+        let key = self.header.and_then(|header| header.idempotency_key);
+        if let (Some(idempotency), Some(key), Some(id)) = (&self.idempotency, key, self.waiter) {
+            idempotency.forget(key, id);
+        }
+        self.release_buckets();
+        if self.waiter.is_some() {
+            let shared = self.shared.clone();
+            self.unregister(&mut shared.waiters.lock().expect("should not be poisoned"));
+        }
+    }
+}
+
+impl<S: BuildHasher + Clone> Future for LookupFuture<S> {
+    type Output = Result<Response<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(Request::WaitForChanges(epoch)) = self.req {
+            // Check under the waiters lock, so a server loaded before we register isn't missed.
+            let shared = self.shared.clone();
+            let mut waiters = shared.waiters.lock().expect("should not be poisoned");
+            match shared.current() {
+                Some(loaded) if loaded.server.epoch() > epoch => {
+                    self.unregister(&mut waiters);
+                    self.loaded = Some(loaded);
+                }
+                _ if self.deadline.is_expired() => {
+                    self.unregister(&mut waiters);
+                    self.req = None;
+                    return Poll::Ready(Err(Error::DeadlineExceeded));
+                }
+                _ => {
+                    // Replace the future's waker, if it has one, rather than adding another.
+                    let id = self.waiter_id();
+                    waiters.insert(id, cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+
+        // Wait for any buckets which concurrent requests are finding, rather than finding them
+        // again, and claim the rest.
+        let this = &mut *self;
+        if let (Some(Request::FindBuckets(prefixes)), Some(loaded)) = (&this.req, &this.loaded) {
+            let next_waiter = &this.shared.next_waiter;
+            let id =
+                *this.waiter.get_or_insert_with(|| next_waiter.fetch_add(1, Ordering::Relaxed));
+            if loaded.claim(prefixes, &mut this.found, id, cx.waker()).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let header = self.header;
+        let key = header.and_then(|header| header.idempotency_key);
+
+        // Answer retries of idempotent requests with their original responses, waiting for them
+        // if they're still in flight, and reserve the keys of first attempts so concurrent
+        // retries don't answer them again.
+        let res = match self.idempotency.clone().zip(key) {
+            Some((idempotency, key)) => {
+                let id = self.waiter_id();
+                let req = self.req.as_ref().expect("should not be polled after completion");
+                match idempotency.reserve(key, req, id, cx.waker()) {
+                    Ok(Reservation::Reserved) => {
+                        let req = self.req.take().expect("should have a request");
+                        let res = self.respond(req);
+                        idempotency.complete(key, &res);
+                        res
+                    }
+                    Ok(Reservation::Waiting) => {
+                        self.release_buckets();
+                        return Poll::Pending;
+                    }
+                    Ok(Reservation::Answered(resp)) => Ok(resp),
+                    Err(err) => Err(err),
+                }
+            }
+            None => {
+                let req = self.req.take().expect("should not be polled after completion");
+                self.respond(req)
+            }
+        };
+        self.req = None;
+        self.release_buckets();
+
+        // Echo tagged requests' IDs.
+        Poll::Ready(match header {
+            Some(header) => res.map(|resp| Response::Tagged(header.id, Box::new(resp))),
+            None => res,
+        })
+    }
+}
+
+impl<S: BuildHasher + Clone> LookupFuture<S> {
+    /// Answer the request, reporting its features to the abuse hook, mirroring it against the
+    /// shadow, and recording it in the hourly metrics, if any.
+    fn respond(&self, req: Request) -> Result<Response<S>, Error> {
+        let features = self.abuse.as_ref().and_then(|_| self.features(&req));
+        let classes = self.hourly.as_ref().map(|_| self.prefix_classes(&req));
+        let mirrored = self.shadow.as_ref().filter(|shadow| shadow.sample(&req));
+        let mirrored = mirrored.map(|shadow| (shadow, req.clone()));
+        let start = Instant::now();
+        let res = self.answer(req);
+        let live_time = start.elapsed();
+
+        if let Some((abuse, features)) = self.abuse.as_ref().zip(features) {
+            abuse.observe(&RequestFeatures { error: res.as_ref().err().copied(), ..features });
+        }
+        if let (Some((shadow, req)), Some(loaded), Ok(resp)) = (mirrored, &self.loaded, &res) {
+            shadow.mirror(&loaded.server, &req, resp, live_time);
+        }
+        if let (Some(hourly), Some(classes), Ok(resp)) = (&self.hourly, classes, &res) {
+            record_hourly(hourly, &classes, resp);
+        }
+        res
+    }
+
+    /// Return the class of each bucket a request is for, before it's answered.
+    fn prefix_classes(&self, req: &Request) -> Vec<PrefixClass> {
+        let (Request::FindBuckets(prefixes), Some(loaded)) = (req, &self.loaded) else {
+            return Vec::new();
+        };
+        let class = |&prefix: &Prefix| match prefix {
+            OVERFLOW_PREFIX => PrefixClass::Overflow,
+            _ if loaded.server.is_sub_bucket(prefix) => PrefixClass::SubBucket,
+            _ => PrefixClass::Bucket,
+        };
+        prefixes.iter().map(class).collect()
+    }
+
+    /// Return the anonymized features of a lookup request, before it's answered.
+    fn features(&self, req: &Request) -> Option<RequestFeatures> {
+        let (kind, batch_size, prefix_entropy, reused_tokens) = match req {
+            Request::FindBuckets(prefixes) => {
+                (RequestKind::FindBuckets, prefixes.len(), prefix_entropy(prefixes), 0)
+            }
+            Request::BlindPhoneNumbers(c_ps) => (RequestKind::Blind, c_ps.len(), 0.0, 0),
+            Request::BlindWithTokens(c_ps, tokens) => {
+                let reused = self.budget.as_ref().map_or(0, |budget| {
+                    tokens.iter().filter(|token| budget.is_spent(token)).count()
+                });
+                (RequestKind::Blind, c_ps.len(), 0.0, reused)
+            }
+            Request::UnblindUserId(_, token) | Request::UnblindProven(_, token) => {
+                let reused = self.tokens.as_ref().zip(token.as_ref());
+                let reused = reused.is_some_and(|(tokens, token)| tokens.is_exhausted(token));
+                (RequestKind::Unblind, 1, 0.0, usize::from(reused))
+            }
+            _ => return None,
+        };
+        Some(RequestFeatures { kind, batch_size, prefix_entropy, reused_tokens, error: None })
+    }
+
+    fn answer(&self, req: Request) -> Result<Response<S>, Error> {
+        match (req, &self.loaded) {
+            (Request::Health, _) => Ok(Response::Healthy),
+            (Request::Ready, loaded) => Ok(Response::Ready(loaded.is_some())),
+            (Request::Info, Some(loaded)) => Ok(Response::Info(loaded.info.clone())),
+            (Request::Stats, _) => {
+                Ok(Response::Stats(self.stats.as_ref().and_then(|stats| stats.latest())))
+            }
+            (Request::UnblindUserId(..), Some(_)) if self.strict || self.tokens.is_none() => {
+                Err(Error::Unauthorized)
+            }
+            (Request::Tagged(..), _) => Err(Error::InvalidMessage),
+            (req, Some(loaded)) => {
+                self.deadline.check()?;
+                self.spend(&req)?;
+                let (rng, finder) = (&*self.rng, self.waiter.unwrap_or(u64::MAX));
+                let found = (&self.found, finder);
+                handle(loaded, self.tokens.as_deref(), rng, &self.deadline, found, req)
+            }
+            (_, None) => Err(Error::NotReady),
+        }
+    }
+
+    /// If the service requires lookup tokens, spend exactly one per phone number blinded.
+    fn spend(&self, req: &Request) -> Result<(), Error> {
+        match (req, &self.budget) {
+            (Request::BlindPhoneNumbers(_), Some(_)) => Err(Error::Unauthorized),
+            (Request::BlindWithTokens(c_ps, tokens), Some(budget)) => {
+                if tokens.len() != c_ps.len() {
+                    return Err(Error::Unauthorized);
+                }
+                budget.redeem(tokens)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Create a [`LookupService`] for the given server which allows at most `max_in_flight` requests
+/// to be processed concurrently.
+pub fn service<S: BuildHasher + Clone>(
+    server: Arc<Server<S>>,
+    max_in_flight: usize,
+) -> ConcurrencyLimit<LookupService<S>> {
+    ConcurrencyLimit::new(LookupService::new(server), max_in_flight)
+}
+
+/// Answer the request with the loaded server. Buckets which concurrent requests found are taken
+/// from `found`, and the rest were [claimed](Loaded::claim) by `finder`.
+fn handle<S: BuildHasher + Clone>(
+    loaded: &Loaded<S>,
+    tokens: Option<&Tokens>,
+    rng: &dyn RngProvider,
+    deadline: &Deadline,
+    (found, finder): (&HashMap<Prefix, SharedBucket<S>>, u64),
+    req: Request,
+) -> Result<Response<S>, Error> {
+    let server = &*loaded.server;
+    match req {
+        Request::FindBuckets(prefixes) => {
+            // Redirect the client to the sub-buckets of any split buckets, so no response holds
+            // more than the maximum bucket size.
+            let split = prefixes.iter().copied().filter(|&prefix| server.is_split(prefix));
+            let split = split.collect::<Vec<_>>();
+            if !split.is_empty() {
+                return Ok(Response::Split(split, SUB_PREFIX_LEN as u8));
+            }
+
+            // Find each distinct prefix's bucket only once, sharing it with any concurrent requests
+            // for it.
+            let mut buckets = found.clone();
+            Ok(Response::Buckets(deadline.map(prefixes, |prefix| {
+                buckets.entry(prefix).or_insert_with(|| loaded.find_bucket(prefix, finder)).clone()
+            })?))
+        }
+        Request::BlindPhoneNumbers(c_ps) | Request::BlindWithTokens(c_ps, _) => {
+            // Every point is validated before any are blinded.
+            let sc_ps = server.blind_phone_numbers(&c_ps, deadline)?;
+
+            // Seal the round's claims into a session token.
+            let token = tokens.map(|tokens| {
+                let lookups = u32::try_from(c_ps.len()).unwrap_or(u32::MAX);
+                let claims = TokenClaims {
+                    suite: server.ciphersuite(),
+                    epoch: server.epoch(),
+                    issued_at: now(),
+                    lookups,
+                };
+                tokens.key.seal(rng.rng(), &claims)
+            });
+            Ok(Response::BlindedPhoneNumbers(sc_ps, token))
+        }
+        Request::UnblindUserId(s_u, token) => {
+            check_token(server, tokens, token.as_ref())?;
+            let s_u = normalize_point(&s_u)?;
+            Ok(Response::UserId(server.unblind_user_id(&s_u)))
+        }
+        Request::UnblindProven(req, token) => {
+            check_token(server, tokens, token.as_ref())?;
+            Ok(Response::UserIdPoint(server.unblind_proven(&req)?))
+        }
+        Request::Changes(epoch) | Request::WaitForChanges(epoch) => {
+            Ok(Response::Changes(server.epoch(), server.changes_since(epoch)))
+        }
+        Request::Health | Request::Ready | Request::Info | Request::Stats | Request::Tagged(..) => {
+            unreachable!("should be answered without a server")
+        }
+    }
+}
+
+/// Record a response in the hourly metrics, given the classes of any buckets it returns.
+fn record_hourly<S>(hourly: &HourlyMetrics, classes: &[PrefixClass], resp: &Response<S>) {
+    let now = SystemTime::now();
+    match resp {
+        Response::Buckets(buckets) => {
+            for (&class, bucket) in classes.iter().zip(buckets) {
+                hourly.record_bucket(now, class, (bucket.len() * ENTRY_LEN) as u64);
+            }
+        }
+        Response::BlindedPhoneNumbers(sc_ps, _) => hourly.record_lookups(now, sc_ps.len() as u64),
+        Response::UserId(Some(_)) | Response::UserIdPoint(Some(_)) => hourly.record_match(now),
+        _ => {}
+    }
+}
+
+/// If the service issues session tokens, require a valid, unexpired one from the server's epoch and
+/// ciphersuite, which hasn't already unblinded as many user IDs as it looked up phone numbers.
+fn check_token<S>(
+    server: &Server<S>,
+    tokens: Option<&Tokens>,
+    token: Option<&SessionToken>,
+) -> Result<(), Error> {
+    let Some(tokens) = tokens else {
+        return Ok(());
+    };
+    let token = token.ok_or(Error::Unauthorized)?;
+    let claims = tokens.key.open(token)?;
+    Ciphersuite::from(server.hash).check(claims.suite)?;
+    let now = now();
+    if claims.epoch != server.epoch || claims.is_expired(now, tokens.ttl) {
+        return Err(Error::Unauthorized);
+    }
+
+    // Spend one of the token's unblinds, forgetting expired tokens.
+    let mut spent = tokens.spent.lock().expect("should not be poisoned");
+    spent.retain(|_, &mut (issued_at, ..)| now.saturating_sub(issued_at) <= tokens.ttl.as_secs());
+    let (_, n, _) = spent.entry(token.clone()).or_insert((claims.issued_at, 0, claims.lookups));
+    if *n >= claims.lookups {
+        return Err(Error::Unauthorized);
+    }
+    *n += 1;
+    Ok(())
+}
+
+/// The current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rand::rngs::OsRng;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        budget::{TokenRequest, TokenWallet},
+        metrics::HourlyTotals,
+        Client,
+    };
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        // A huge privacy parameter makes the hourly totals' noise vanish.
+        let hourly = Arc::new(HourlyMetrics::new(2, 1e9, 1024));
+        let svc = LookupService::new(Arc::new(Server::new(OsRng, &users)))
+            .with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60))
+            .with_hourly_metrics(hourly.clone());
+        let mut svc = ConcurrencyLimit::new(svc, 2);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(1234567890);
+
+        // Duplicate prefixes are coalesced.
+        let Ok(Response::Buckets(buckets)) = svc
+            .ready()
+            .await
+            .expect("should be ready")
+            .call(Request::FindBuckets(vec![prefix, prefix]))
+            .await
+        else {
+            panic!("should return buckets");
+        };
+        assert!(Arc::ptr_eq(&buckets[0], &buckets[1]));
+
+        let Ok(Response::BlindedPhoneNumbers(sc_ps, token)) = svc
+            .ready()
+            .await
+            .expect("should be ready")
+            .call(Request::BlindPhoneNumbers(vec![c_p]))
+            .await
+        else {
+            panic!("should return blinded points");
+        };
+
+        let s_u = client
+            .find_user_id(&sc_ps[0], &*buckets[0], 1234567890)
+            .expect("should be a valid response")
+            .expect("should be a valid phone number");
+        let Ok(Response::UserId(user_id)) = svc
+            .ready()
+            .await
+            .expect("should be ready")
+            .call(Request::UnblindUserId(s_u, token))
+            .await
+        else {
+            panic!("should return a user ID");
+        };
+        assert_eq!(user_id, users.get(&1234567890).cloned());
+
+        // The round trip is counted in the hourly totals, which may span an hour boundary.
+        let history = hourly.history(SystemTime::now() + Duration::from_secs(3600), OsRng);
+        let total = |f: fn(&HourlyTotals) -> i64| history.iter().map(f).sum::<i64>();
+        assert_eq!((total(|t| t.lookups), total(|t| t.matches)), (1, 1));
+        assert_eq!(total(|t| t.bytes[&PrefixClass::Bucket]), 2 * ENTRY_LEN as i64);
+    }
+
+    #[test]
+    fn shared_buckets() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let mut svc = LookupService::new(server.clone());
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        let loaded = svc.shared.current().expect("should be loaded");
+        let mut cx = Context::from_waker(Waker::noop());
+        let pending = || loaded.pending.lock().expect("should not be poisoned").len();
+
+        // A request for a bucket which another request is finding waits for it, without blocking.
+        assert!(loaded.claim(&[prefix], &mut HashMap::new(), u64::MAX, Waker::noop()).is_ready());
+        let mut waiting = svc.call(Request::FindBuckets(vec![prefix, prefix]));
+        assert!(Pin::new(&mut waiting).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut waiting).poll(&mut cx).is_pending());
+
+        // Once it's found, the waiting request shares it.
+        let bucket = loaded.find_bucket(prefix, u64::MAX);
+        let Poll::Ready(Ok(Response::Buckets(buckets))) = Pin::new(&mut waiting).poll(&mut cx)
+        else {
+            panic!("should return buckets");
+        };
+        assert!(buckets.iter().all(|b| Arc::ptr_eq(b, &bucket)));
+        assert_eq!(pending(), 0);
+
+        // If the request finding it fails instead, waiting requests find it themselves.
+        assert!(loaded.claim(&[prefix], &mut HashMap::new(), u64::MAX, Waker::noop()).is_ready());
+        let mut waiting = svc.call(Request::FindBuckets(vec![prefix]));
+        assert!(Pin::new(&mut waiting).poll(&mut cx).is_pending());
+        loaded.release(u64::MAX);
+        let Poll::Ready(Ok(Response::Buckets(buckets))) = Pin::new(&mut waiting).poll(&mut cx)
+        else {
+            panic!("should return buckets");
+        };
+        assert_eq!(*buckets[0], *bucket);
+
+        // And dropped requests stop waiting.
+        assert!(loaded.claim(&[prefix], &mut HashMap::new(), u64::MAX, Waker::noop()).is_ready());
+        let mut dropped = svc.call(Request::FindBuckets(vec![prefix]));
+        assert!(Pin::new(&mut dropped).poll(&mut cx).is_pending());
+        drop(dropped);
+        loaded.find_bucket(prefix, u64::MAX);
+        assert_eq!(pending(), 0);
+    }
+
+    #[test]
+    fn concurrent_lookups() {
+        let users = (0..100).map(|p| (p, Uuid::new_v4())).collect::<HashMap<_, _>>();
+        let server = Arc::new(Server::new(OsRng, &users));
+        let svc = LookupService::new(server.clone());
+        let client = Client::new(OsRng);
+        let prefixes = (0..100).map(|p| client.request_phone_number(p).0).collect::<Vec<_>>();
+
+        // Concurrent requests for overlapping buckets all get them, and leave nothing pending.
+        let barrier = std::sync::Barrier::new(8);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (mut svc, barrier, prefixes, server) =
+                    (svc.clone(), &barrier, &prefixes, &server);
+                s.spawn(move || {
+                    let mut batch = prefixes.clone();
+                    batch.rotate_left(i * 10);
+                    barrier.wait();
+                    let Ok(Response::Buckets(buckets)) =
+                        block_on(svc.call(Request::FindBuckets(batch.clone())))
+                    else {
+                        panic!("should return buckets");
+                    };
+                    for (prefix, bucket) in batch.iter().zip(buckets) {
+                        assert_eq!(*bucket, server.find_bucket(*prefix));
+                    }
+                });
+            }
+        });
+        let loaded = svc.shared.current().expect("should be loaded");
+        assert!(loaded.pending.lock().expect("should not be poisoned").is_empty());
+    }
+
+    #[tokio::test]
+    async fn no_reverse_lookups() {
+        let target = Uuid::new_v4();
+        let users = HashMap::from([(22, target), (23, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let mut svc = LookupService::new(server.clone());
+
+        // Without session tokens, arbitrary points aren't unblinded at all.
+        let client = Client::new(OsRng);
+        let (prefix, _) = client.request_phone_number(22);
+        let bucket = server.find_bucket(prefix);
+        let (s_p, hs_u) = bucket.iter().next().expect("should not be empty");
+        assert!(matches!(
+            svc.call(Request::UnblindUserId(*hs_u, None)).await,
+            Err(Error::Unauthorized)
+        ));
+
+        // An attacker with a token from a batch of lookups of other phone numbers can't recover
+        // the target's user ID from its bucket entry, which is still blinded by the phone hash.
+        let mut svc = svc.with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60));
+        let c_ps = [23, 24].map(|p| client.request_phone_number(p).1).to_vec();
+        let Ok(Response::BlindedPhoneNumbers(_, Some(token))) =
+            svc.call(Request::BlindPhoneNumbers(c_ps)).await
+        else {
+            panic!("should return blinded points and a token");
+        };
+        for point in [s_p, hs_u] {
+            let Ok(Response::UserId(id)) =
+                svc.call(Request::UnblindUserId(*point, Some(token.clone()))).await
+            else {
+                panic!("should spend the token");
+            };
+            assert_ne!(id, Some(target));
+        }
+
+        // And the token can't be used for more unblinds than it looked up phone numbers.
+        assert!(matches!(
+            svc.call(Request::UnblindUserId(*hs_u, Some(token))).await,
+            Err(Error::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn idempotency() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let mut svc = LookupService::new(server)
+            .with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60))
+            .with_idempotency(16);
+        let (_, c_p) = Client::new(OsRng).request_phone_number(22);
+        let key = Some(IdempotencyKey::generate(OsRng));
+        let encoded = RequestHeader { id: RequestId(7), idempotency_key: key }.encode();
+        let (header, rest) = RequestHeader::decode(&encoded).expect("should be a valid header");
+        assert!(rest.is_empty());
+        let blind = Request::BlindPhoneNumbers(vec![c_p]);
+        let tagged = |id, req: &Request| {
+            Request::Tagged(RequestHeader { id: RequestId(id), ..header }, Box::new(req.clone()))
+        };
+
+        // Tagged responses echo their request's ID.
+        let Ok(Response::Tagged(RequestId(7), first)) = svc.call(tagged(7, &blind)).await else {
+            panic!("should return a tagged response");
+        };
+        let Response::BlindedPhoneNumbers(_, Some(token)) = *first else {
+            panic!("should return blinded points and a token");
+        };
+
+        // Retries get the original token, rather than one counting the lookups again.
+        let Ok(Response::Tagged(RequestId(8), retry)) = svc.call(tagged(8, &blind)).await else {
+            panic!("should return a tagged response");
+        };
+        assert!(matches!(*retry, Response::BlindedPhoneNumbers(_, Some(t)) if t == token));
+
+        // Retries of an attempt which is still in flight wait for its response.
+        let idempotency = svc.idempotency.clone().expect("should have idempotency");
+        let in_flight = IdempotencyKey::generate(OsRng);
+        let reserved = idempotency.reserve(in_flight, &blind, u64::MAX, Waker::noop());
+        assert!(matches!(reserved, Ok(Reservation::Reserved)));
+        let header = RequestHeader { id: RequestId(10), idempotency_key: Some(in_flight) };
+        let mut retry = svc.call(Request::Tagged(header, Box::new(blind.clone())));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut retry).poll(&mut cx).is_pending());
+        idempotency.complete(in_flight, &Ok(Response::Healthy));
+        let Ok(Response::Tagged(RequestId(10), retry)) = retry.await else {
+            panic!("should return a tagged response");
+        };
+        assert!(matches!(*retry, Response::Healthy));
+
+        // Keys can't be reused for different requests.
+        let other = Request::Changes(0);
+        assert!(matches!(svc.call(tagged(9, &other)).await, Err(Error::InvalidMessage)));
+        assert_eq!(
+            RequestHeader::decode(&[2; crate::transport::REQUEST_HEADER_LEN]),
+            Err(Error::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn concurrent_retries() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let svc = LookupService::new(server)
+            .with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60))
+            .with_idempotency(16);
+        let (_, c_p) = Client::new(OsRng).request_phone_number(22);
+        let header = RequestHeader {
+            id: RequestId(1),
+            idempotency_key: Some(IdempotencyKey::generate(OsRng)),
+        };
+        let req = Request::Tagged(header, Box::new(Request::BlindPhoneNumbers(vec![c_p; 64])));
+
+        // Concurrent retries of a request are answered once, and all get the same token.
+        let barrier = std::sync::Barrier::new(8);
+        let tokens = thread::scope(|s| {
+            let handles = (0..8)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut svc = svc.clone();
+                        barrier.wait();
+                        let Ok(Response::Tagged(_, resp)) = block_on(svc.call(req.clone())) else {
+                            panic!("should return a tagged response");
+                        };
+                        let Response::BlindedPhoneNumbers(_, Some(token)) = *resp else {
+                            panic!("should return blinded points and a token");
+                        };
+                        token
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().map(|h| h.join().expect("should not panic")).collect::<Vec<_>>()
+        });
+        assert!(tokens.iter().all(|token| *token == tokens[0]));
+    }
+
+    /// Drive a future to completion on the current thread, parking it while the future is pending.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        struct Unpark(thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(fut);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+            thread::park();
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let mut svc = LookupService::unloaded();
+        let mut call = |path| svc.call(Request::from_path(path).expect("should be an endpoint"));
+
+        // Until a server is loaded, the service is healthy but not ready.
+        assert!(matches!(call("/healthz").await, Ok(Response::Healthy)));
+        assert!(matches!(call("/readyz").await, Ok(Response::Ready(false))));
+        assert!(matches!(call("/v1/info").await, Err(Error::NotReady)));
+
+        // Once one is, it describes the server.
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        svc.clone().load(server.clone());
+        let mut call = |path| svc.call(Request::from_path(path).expect("should be an endpoint"));
+        assert!(matches!(call("/readyz").await, Ok(Response::Ready(true))));
+        let Ok(Response::Info(info)) = call("/v1/info").await else {
+            panic!("should return info");
+        };
+        assert_eq!(info.epoch, server.epoch());
+        assert_eq!(info.digest, server.digest());
+        assert_eq!(info.prefix_len, 8);
+        assert_eq!(Request::from_path("/v1/lookup"), None);
+
+        // Public stats are only served if they're published.
+        assert!(matches!(call("/v1/stats").await, Ok(Response::Stats(None))));
+        let stats = Arc::new(DirectoryStats::new(1.0));
+        let released = stats.release(server.epoch(), 1, OsRng);
+        let mut svc = svc.with_stats(stats);
+        let Ok(Response::Stats(Some(latest))) = svc.call(Request::Stats).await else {
+            panic!("should return stats");
+        };
+        assert_eq!(latest, released);
+    }
+
+    #[tokio::test]
+    async fn session_tokens() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let mut svc = LookupService::new(server.clone())
+            .with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60));
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+
+        // Blinding issues a token.
+        let Ok(Response::BlindedPhoneNumbers(sc_ps, Some(token))) =
+            svc.call(Request::BlindPhoneNumbers(vec![c_p])).await
+        else {
+            panic!("should return blinded points and a token");
+        };
+        let s_u = client
+            .find_user_id(&sc_ps[0], &server.find_bucket(prefix), 22)
+            .expect("should be a valid response")
+            .expect("should be a valid phone number");
+
+        // Unblinding requires it.
+        assert!(matches!(
+            svc.call(Request::UnblindUserId(s_u, None)).await,
+            Err(Error::Unauthorized)
+        ));
+        let forged = TokenKey::random(OsRng).seal(
+            OsRng,
+            &TokenClaims { suite: server.ciphersuite(), epoch: 0, issued_at: now(), lookups: 1 },
+        );
+        assert!(matches!(
+            svc.call(Request::UnblindUserId(s_u, Some(forged))).await,
+            Err(Error::Unauthorized)
+        ));
+        let Ok(Response::UserId(user_id)) =
+            svc.call(Request::UnblindUserId(s_u, Some(token))).await
+        else {
+            panic!("should return a user ID");
+        };
+        assert_eq!(user_id, users.get(&22).cloned());
+    }
+
+    #[tokio::test]
+    async fn lookup_budget() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let issuer = Arc::new(TokenIssuer::new(OsRng, server.epoch()));
+        let mut svc = LookupService::new(server).with_lookup_tokens(issuer.clone());
+        let c_ps = [22, 23].map(|p| Client::new(OsRng).request_phone_number(p).1).to_vec();
+
+        // The client is issued a budget of three lookups.
+        let mut wallet = TokenWallet::new();
+        let (request, blinded) = TokenRequest::new(OsRng, 3);
+        let signed = issuer.issue(&blinded).expect("should be valid points");
+        wallet.add(request.finish(&signed).expect("should be a valid response"));
+
+        // Unpaid and underpaid requests are refused.
+        assert!(matches!(
+            svc.call(Request::BlindPhoneNumbers(c_ps.clone())).await,
+            Err(Error::Unauthorized)
+        ));
+        let tokens = wallet.take(1).expect("should have a token");
+        assert!(matches!(
+            svc.call(Request::BlindWithTokens(c_ps.clone(), tokens.clone())).await,
+            Err(Error::Unauthorized)
+        ));
+        wallet.add(tokens);
+
+        // Each contact spends one token, which can't be spent again.
+        let tokens = wallet.take(2).expect("should have two tokens");
+        assert!(matches!(
+            svc.call(Request::BlindWithTokens(c_ps.clone(), tokens.clone())).await,
+            Ok(Response::BlindedPhoneNumbers(sc_ps, None)) if sc_ps.len() == 2
+        ));
+        assert!(matches!(
+            svc.call(Request::BlindWithTokens(c_ps, tokens)).await,
+            Err(Error::Unauthorized)
+        ));
+        assert_eq!(wallet.remaining(), 1);
+        assert_eq!(issuer.spent(), 2);
+    }
+
+    #[tokio::test]
+    async fn abuse_hook() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let issuer = Arc::new(TokenIssuer::new(OsRng, server.epoch()));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let log = observed.clone();
+        let hook = move |features: &RequestFeatures| {
+            log.lock().expect("should not be poisoned").push(*features);
+        };
+        let mut svc = LookupService::new(server)
+            .with_lookup_tokens(issuer.clone())
+            .with_abuse_hook(Arc::new(hook));
+        let (prefix, c_p) = Client::new(OsRng).request_phone_number(22);
+        let (request, blinded) = TokenRequest::new(OsRng, 1);
+        let tokens = request
+            .finish(&issuer.issue(&blinded).expect("should be valid points"))
+            .expect("should be a valid response");
+
+        // Lookup requests are observed, and health checks aren't.
+        svc.call(Request::FindBuckets(vec![prefix; 4])).await.expect("should find buckets");
+        svc.call(Request::Health).await.expect("should be healthy");
+        svc.call(Request::BlindWithTokens(vec![c_p], tokens.clone())).await.expect("should blind");
+        assert!(svc.call(Request::BlindWithTokens(vec![c_p], tokens)).await.is_err());
+        let observed = observed.lock().expect("should not be poisoned");
+        assert_eq!(observed.len(), 3);
+        assert_eq!(observed[0].kind, RequestKind::FindBuckets);
+        assert_eq!((observed[0].batch_size, observed[0].prefix_entropy), (4, 0.0));
+        assert_eq!((observed[1].reused_tokens, observed[1].error), (0, None));
+
+        // Including attempts to spend a token twice.
+        assert_eq!((observed[2].reused_tokens, observed[2].error), (1, Some(Error::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn strict_unblinding() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let mut svc = LookupService::new(server.clone()).with_strict_unblinding();
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let bucket = server.find_bucket(prefix);
+
+        // Bare points aren't unblinded.
+        let s_u = client
+            .find_user_id(&sc_p, &bucket, 22)
+            .expect("should be a valid response")
+            .expect("should be a valid phone number");
+        assert!(matches!(
+            svc.call(Request::UnblindUserId(s_u, None)).await,
+            Err(Error::Unauthorized)
+        ));
+
+        // Proven requests are.
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &bucket)
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let Ok(Response::UserIdPoint(Some(h_u))) =
+            svc.call(Request::UnblindProven(req, None)).await
+        else {
+            panic!("should return a user ID point");
+        };
+        assert_eq!(client.finish_unblind(22, &h_u), Ok(users.get(&22).cloned()));
+    }
+
+    #[tokio::test]
+    async fn deadlines() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let (prefix, c_p) = Client::new(OsRng).request_phone_number(22);
+
+        // Requests which outlive their timeout are abandoned.
+        let mut svc = LookupService::new(server.clone()).with_timeout(Duration::ZERO);
+        assert_eq!(
+            svc.call(Request::BlindPhoneNumbers(vec![c_p])).await.err(),
+            Some(Error::DeadlineExceeded)
+        );
+
+        // So are cancelled ones, but not their siblings.
+        let mut svc = LookupService::new(server).with_timeout(Duration::from_secs(60));
+        let cancelled = svc.call(Request::FindBuckets(vec![prefix]));
+        cancelled.deadline().cancel();
+        assert_eq!(cancelled.await.err(), Some(Error::DeadlineExceeded));
+        svc.call(Request::FindBuckets(vec![prefix])).await.expect("should find buckets");
+    }
+
+    #[tokio::test]
+    async fn subscriptions() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let mut svc = LookupService::new(server.clone());
+        let (prefix, _) = Client::new(OsRng).request_phone_number(23);
+
+        // Waiting for changes since the current epoch blocks until a new server is loaded.
+        let wait = tokio::spawn(svc.call(Request::WaitForChanges(0)));
+        tokio::task::yield_now().await;
+        assert!(!wait.is_finished());
+        let next = server.update(OsRng, [(23, Uuid::new_v4())], []).expect("should update");
+        svc.load(Arc::new(next));
+        let Ok(Ok(Response::Changes(1, Some(prefixes)))) = wait.await else {
+            panic!("should return changes");
+        };
+        assert_eq!(prefixes, vec![prefix]);
+
+        // Changes since an earlier epoch are returned immediately.
+        assert!(matches!(
+            svc.call(Request::WaitForChanges(0)).await,
+            Ok(Response::Changes(1, Some(_)))
+        ));
+        let changes = svc.call(Request::Changes(1)).await;
+        assert!(matches!(changes, Ok(Response::Changes(1, Some(p))) if p.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn long_polls() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let mut svc = LookupService::new(server.clone()).with_timeout(Duration::ZERO);
+        let mut cx = Context::from_waker(Waker::noop());
+        let waiters =
+            |svc: &LookupService| svc.shared.waiters.lock().expect("should not be poisoned").len();
+
+        // Long-polls outlive the service's timeout, and keep one waker however often they're
+        // polled.
+        let mut wait = svc.call(Request::WaitForChanges(0));
+        for _ in 0..3 {
+            assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        }
+        assert_eq!(waiters(&svc), 1);
+        svc.load(Arc::new(server.update(OsRng, [], [22]).expect("should update")));
+        assert!(matches!(wait.await, Ok(Response::Changes(1, Some(_)))));
+        assert_eq!(waiters(&svc), 0);
+
+        // Dropped long-polls remove their wakers.
+        let mut wait = svc.call(Request::WaitForChanges(1));
+        assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        drop(wait);
+        assert_eq!(waiters(&svc), 0);
+
+        // Cancelled ones resolve when next polled.
+        let mut wait = svc.call(Request::WaitForChanges(1));
+        assert!(Pin::new(&mut wait).poll(&mut cx).is_pending());
+        wait.deadline().cancel();
+        assert_eq!(wait.await.err(), Some(Error::DeadlineExceeded));
+        assert_eq!(waiters(&svc), 0);
+    }
+}
@@ -0,0 +1,663 @@
+//! Client sessions which drive lookups over a [`Transport`], retrying failed requests.
+//!
+//! Batches of lookups [pipeline](ClientSession::lookup_batch) their bucket requests, and the
+//! session tracks which prefix each outstanding request ID was for. Responses whose IDs are unknown,
+//! repeated, missing, or echo the wrong prefix fail with [`TransportError::BucketMismatch`], so a
+//! transport which transposes responses is detected instead of causing silent non-matches.
+//!
+//! Buckets which were too large may have been [split](crate::Server::is_split), in which case the
+//! session follows the server's redirection to the sub-bucket, or spilled to the server's
+//! [overflow bucket](crate::Server::overflow_bucket), which the session searches when a phone
+//! number isn't in its own bucket. The session fetches the overflow bucket once per epoch, whether
+//! or not a lookup misses and for decoys too, so the request doesn't reveal a miss.
+//!
+//! A sync of a large address book can take many minutes, during which the server's secret may
+//! rotate. A session [with epoch pinning](ClientSession::with_epoch_pinning) pins its lookups to
+//! the epoch of its first one, and the server answers them from that epoch for as long as it's
+//! retained, so the whole sync sees one consistent view of the directory. Once the server stops
+//! serving the epoch, lookups fail with [`TransportError::StaleEpoch`] and the sync must restart.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use p256::EncodedPoint;
+use rand::{CryptoRng, Rng, RngCore};
+
+use crate::{
+    decoy::decoy_request,
+    planner::{Step, SyncPlan},
+    protocol::ProtocolVersion,
+    transport::{FoundBucket, RequestId, Transport, TransportError},
+    Client, Epoch, Error, Prefix, OVERFLOW_PREFIX, SUB_PREFIX_LEN,
+};
+
+/// A policy for retrying failed requests with exponential backoff and jitter.
+///
+/// Only errors which are [retryable](TransportError::is_retryable) are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of times to retry a request.
+    pub max_retries: u32,
+    /// The maximum delay before the first retry.
+    pub initial_backoff: Duration,
+    /// The maximum delay before any retry.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries requests.
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_retries: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Return how long to wait before the given retry of a request (starting with zero) which
+    /// failed with the given error.
+    ///
+    /// The delay is chosen uniformly at random from zero to an exponentially increasing cap, but is
+    /// never shorter than a rate-limited request's `retry_after`.
+    pub fn backoff(&self, retry: u32, err: &TransportError, rng: &mut impl RngCore) -> Duration {
+        let cap =
+            self.initial_backoff.saturating_mul(2u32.saturating_pow(retry)).min(self.max_backoff);
+        let backoff = cap.mul_f64(rng.gen::<f64>());
+        match err {
+            TransportError::RateLimited { retry_after } => backoff.max(*retry_after),
+            _ => backoff,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A function which waits for the given duration between retries.
+#[derive(Clone)]
+struct Sleep(Arc<dyn Fn(Duration) + Send + Sync>);
+
+impl fmt::Debug for Sleep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sleep")
+    }
+}
+
+/// The outcome of a step of a [`SyncPlan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncProgress {
+    /// A chunk of phone numbers was looked up, with their server-blinded user ID points if found.
+    Found(Vec<(u64, Option<EncodedPoint>)>),
+    /// The quota is exhausted, and the next chunk may be looked up after the given duration.
+    Wait(Duration),
+    /// Every phone number in the plan has been looked up.
+    Done,
+}
+
+/// Whether a session's lookups are pinned to an epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EpochPin {
+    /// Lookups use the server's current epoch.
+    Unpinned,
+    /// Lookups will be pinned to the epoch of the first one.
+    First,
+    /// Lookups are pinned to the given epoch, which hasn't been sent to the server yet.
+    Unsent(Epoch),
+    /// Lookups are pinned to the given epoch.
+    Pinned(Epoch),
+}
+
+/// A client session which looks up phone numbers over a [`Transport`].
+///
+/// Requests which fail with retryable errors are retried according to the session's
+/// [`RetryPolicy`]. If a retry is due to the server's secret changing mid-lookup, the session
+/// generates a new client secret and re-blinds the phone number, so requests from different epochs
+/// can't be linked.
+#[derive(Debug)]
+pub struct ClientSession<T, R> {
+    transport: T,
+    rng: R,
+    client: Client,
+    version: Option<ProtocolVersion>,
+    epoch: Option<Epoch>,
+    retry_policy: RetryPolicy,
+    sleep: Sleep,
+    pin: EpochPin,
+    next_id: u64,
+    outstanding: HashMap<RequestId, Prefix>,
+    overflow: Option<(Epoch, HashMap<EncodedPoint, EncodedPoint>)>,
+}
+
+impl<T: Transport, R: CryptoRng + RngCore> ClientSession<T, R> {
+    /// Create a new session with a random client secret and the default [`RetryPolicy`].
+    pub fn new(transport: T, mut rng: R) -> ClientSession<T, R> {
+        let client = Client::new(&mut rng);
+        ClientSession {
+            transport,
+            rng,
+            client,
+            version: None,
+            epoch: None,
+            retry_policy: RetryPolicy::default(),
+            sleep: Sleep(Arc::new(thread::sleep)),
+            pin: EpochPin::Unpinned,
+            next_id: 0,
+            outstanding: HashMap::new(),
+            overflow: None,
+        }
+    }
+
+    /// Use the given retry policy instead of the default.
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> ClientSession<T, R> {
+        ClientSession { retry_policy, ..self }
+    }
+
+    /// Wait between retries by calling `sleep` instead of [`thread::sleep`], e.g. to advance a
+    /// simulated clock.
+    pub fn with_sleep(
+        self,
+        sleep: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> ClientSession<T, R> {
+        ClientSession { sleep: Sleep(Arc::new(sleep)), ..self }
+    }
+
+    /// Pin the session's lookups to the epoch of its first one. Lookups which the server can no
+    /// longer answer from that epoch fail with [`TransportError::StaleEpoch`] without being retried.
+    pub fn with_epoch_pinning(self) -> ClientSession<T, R> {
+        ClientSession { pin: EpochPin::First, ..self }
+    }
+
+    /// Pin the session's lookups to the given epoch, e.g. to resume an interrupted sync.
+    pub fn with_pinned_epoch(self, epoch: Epoch) -> ClientSession<T, R> {
+        ClientSession { pin: EpochPin::Unsent(epoch), ..self }
+    }
+
+    /// The epoch the session's lookups are pinned to, if any.
+    pub fn pinned_epoch(&self) -> Option<Epoch> {
+        match self.pin {
+            EpochPin::Unsent(epoch) | EpochPin::Pinned(epoch) => Some(epoch),
+            EpochPin::Unpinned | EpochPin::First => None,
+        }
+    }
+
+    /// The protocol version negotiated with the server, if any.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    /// The epoch of the most recent successful lookup, if any.
+    pub fn epoch(&self) -> Option<Epoch> {
+        self.epoch
+    }
+
+    /// Look up the given phone number, returning the server-blinded user ID point if it's found.
+    pub fn lookup(&mut self, p: u64) -> Result<Option<EncodedPoint>, TransportError> {
+        self.with_retries(|session| session.try_lookup(p))
+    }
+
+    /// Look up the given phone numbers, pipelining their bucket requests, and return the
+    /// server-blinded user ID points of those which are found, in order.
+    pub fn lookup_batch(
+        &mut self,
+        ps: &[u64],
+    ) -> Result<Vec<(u64, Option<EncodedPoint>)>, TransportError> {
+        self.with_retries(|session| session.try_lookup_batch(ps))
+    }
+
+    /// Call `f` until it succeeds, fails with a fatal error, or runs out of retries.
+    fn with_retries<U>(
+        &mut self,
+        mut f: impl FnMut(&mut Self) -> Result<U, TransportError>,
+    ) -> Result<U, TransportError> {
+        let mut retry = 0;
+        loop {
+            match f(self) {
+                // A pinned epoch which is no longer served won't come back.
+                Err(err @ TransportError::StaleEpoch { .. }) if self.pinned_epoch().is_some() => {
+                    return Err(err)
+                }
+                Err(err) if err.is_retryable() && retry < self.retry_policy.max_retries => {
+                    // If the server's secret changed, re-blind with a new client secret.
+                    if let TransportError::StaleEpoch { .. } = err {
+                        self.client = Client::new(&mut self.rng);
+                    }
+
+                    (self.sleep.0)(self.retry_policy.backoff(retry, &err, &mut self.rng));
+                    retry += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Take the next step of the given plan as of `now`: either look up its next chunk of phone
+    /// numbers, or return how long to wait before the next one.
+    ///
+    /// A chunk is only recorded in the plan once all of its lookups succeed, so callers should
+    /// store the [encoded](SyncPlan::encode) plan after each step to resume it after a restart.
+    pub fn sync(
+        &mut self,
+        plan: &mut SyncPlan,
+        now: SystemTime,
+    ) -> Result<SyncProgress, TransportError> {
+        match plan.next(now) {
+            Step::Lookup(chunk) => {
+                let found = chunk
+                    .iter()
+                    .map(|&p| Ok((p, self.lookup(p)?)))
+                    .collect::<Result<Vec<_>, TransportError>>()?;
+                plan.record(found.len(), now);
+                Ok(SyncProgress::Found(found))
+            }
+            Step::Wait(delay) => Ok(SyncProgress::Wait(delay)),
+            Step::Done => Ok(SyncProgress::Done),
+        }
+    }
+
+    /// Send a decoy lookup, as scheduled by a [`DecoyScheduler`](crate::decoy::DecoyScheduler): a
+    /// request for a random bucket and for the blinding of a random point.
+    ///
+    /// Decoys aren't retried, and their responses are discarded.
+    pub fn send_decoy(&mut self) -> Result<(), TransportError> {
+        let (prefix, c_p) = decoy_request(&mut self.rng);
+        let (epoch, _) = self.transport.find_bucket(prefix)?;
+        self.transport.blind_phone_number(&c_p)?;
+        self.fetch_overflow(epoch)
+    }
+
+    fn try_lookup(&mut self, p: u64) -> Result<Option<EncodedPoint>, TransportError> {
+        self.negotiate()?;
+
+        // Blind the phone number and fetch its bucket and double-blinded point.
+        let (prefix, c_p) = self.client.request_phone_number(p);
+        let (bucket_epoch, bucket) = match self.transport.find_bucket_or_split(prefix)? {
+            (epoch, FoundBucket::Bucket(bucket)) => (epoch, bucket),
+
+            // If the bucket was split, re-query its sub-bucket, as long as the server agrees on how
+            // many more hash bytes select it.
+            (_, FoundBucket::Split(n)) if usize::from(n) == SUB_PREFIX_LEN => {
+                self.transport.find_bucket(self.client.sub_prefix(p))?
+            }
+            (_, FoundBucket::Split(_)) => return Err(Error::InvalidMessage.into()),
+        };
+        let (epoch, sc_p) = self.transport.blind_phone_number(&c_p)?;
+
+        // Responses from different epochs can't be combined.
+        if bucket_epoch != epoch {
+            return Err(TransportError::StaleEpoch { current: bucket_epoch.max(epoch) });
+        }
+        self.check_pin(epoch)?;
+        self.fetch_overflow(epoch)?;
+
+        let s_u = self.find_user_id(&sc_p, &bucket, p)?;
+        self.epoch = Some(epoch);
+        Ok(s_u)
+    }
+
+    fn try_lookup_batch(
+        &mut self,
+        ps: &[u64],
+    ) -> Result<Vec<(u64, Option<EncodedPoint>)>, TransportError> {
+        self.negotiate()?;
+
+        // Blind the phone numbers and pipeline their bucket requests, tracking each request's
+        // prefix by its ID.
+        let blinded = self.client.request_phone_numbers(ps);
+        let requests = self.track(blinded.iter().map(|&(prefix, _)| prefix));
+        let responses = self.transport.find_buckets_or_split(&requests)?;
+        let mut found =
+            self.correlate(responses.into_iter().map(|r| (r.id, r.prefix, (r.epoch, r.bucket))))?;
+
+        // If any buckets were split, pipeline requests for their sub-buckets, as long as the server
+        // agrees on how many more hash bytes select them.
+        let mut buckets = HashMap::with_capacity(requests.len());
+        let mut redirects = Vec::new();
+        for (&p, (id, _)) in ps.iter().zip(&requests) {
+            match found.remove(id).expect("should have a response") {
+                (epoch, FoundBucket::Bucket(bucket)) => {
+                    buckets.insert(*id, (epoch, bucket));
+                }
+                (_, FoundBucket::Split(n)) if usize::from(n) == SUB_PREFIX_LEN => {
+                    redirects.push((*id, self.client.sub_prefix(p)));
+                }
+                (_, FoundBucket::Split(_)) => return Err(Error::InvalidMessage.into()),
+            }
+        }
+        if !redirects.is_empty() {
+            let sub_requests = self.track(redirects.iter().map(|&(_, prefix)| prefix));
+            let responses = self.transport.find_buckets(&sub_requests)?;
+            let mut sub_buckets = self
+                .correlate(responses.into_iter().map(|r| (r.id, r.prefix, (r.epoch, r.bucket))))?;
+            for ((id, _), (sub_id, _)) in redirects.iter().zip(&sub_requests) {
+                buckets.insert(*id, sub_buckets.remove(sub_id).expect("should have a response"));
+            }
+        }
+
+        // Double-blind each phone number and find it in its bucket.
+        let mut found = Vec::with_capacity(ps.len());
+        let mut epochs = None;
+        for ((&p, (_, c_p)), (id, _)) in ps.iter().zip(&blinded).zip(&requests) {
+            let (bucket_epoch, bucket) = &buckets[id];
+            let (epoch, sc_p) = self.transport.blind_phone_number(c_p)?;
+
+            // Responses from different epochs can't be combined.
+            let current = *epochs.get_or_insert(epoch);
+            if *bucket_epoch != epoch || current != epoch {
+                return Err(TransportError::StaleEpoch {
+                    current: epoch.max(*bucket_epoch).max(current),
+                });
+            }
+            self.fetch_overflow(epoch)?;
+            found.push((p, self.find_user_id(&sc_p, bucket, p)?));
+        }
+        if let Some(epoch) = epochs {
+            self.check_pin(epoch)?;
+            self.epoch = Some(epoch);
+        }
+        Ok(found)
+    }
+
+    /// Fetch the overflow bucket, unless the session already has the given epoch's. This happens
+    /// before the session knows whether any lookup missed, so it doesn't reveal one.
+    fn fetch_overflow(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        if self.overflow.as_ref().is_none_or(|&(overflow_epoch, _)| overflow_epoch != epoch) {
+            let (overflow_epoch, bucket) = self.transport.find_bucket(OVERFLOW_PREFIX)?;
+            if overflow_epoch != epoch {
+                return Err(TransportError::StaleEpoch { current: overflow_epoch.max(epoch) });
+            }
+            self.overflow = Some((epoch, bucket));
+        }
+        Ok(())
+    }
+
+    /// Find a phone number in its bucket or, failing that, in the
+    /// [fetched](ClientSession::fetch_overflow) overflow bucket.
+    fn find_user_id(
+        &self,
+        sc_p: &EncodedPoint,
+        bucket: &HashMap<EncodedPoint, EncodedPoint>,
+        p: u64,
+    ) -> Result<Option<EncodedPoint>, TransportError> {
+        if let Some(s_u) = self.client.find_user_id(sc_p, bucket, p)? {
+            return Ok(Some(s_u));
+        }
+        let (_, overflow) = self.overflow.as_ref().expect("should have the overflow bucket");
+        Ok(self.client.find_user_id(sc_p, overflow, p)?)
+    }
+
+    /// Assign each prefix's request an ID, tracking it as outstanding.
+    fn track(&mut self, prefixes: impl Iterator<Item = Prefix>) -> Vec<(RequestId, Prefix)> {
+        self.outstanding.clear();
+        prefixes
+            .map(|prefix| {
+                let id = RequestId(self.next_id);
+                self.next_id += 1;
+                self.outstanding.insert(id, prefix);
+                (id, prefix)
+            })
+            .collect()
+    }
+
+    /// Match each response to its outstanding request, rejecting any which don't correlate.
+    fn correlate<V>(
+        &mut self,
+        responses: impl IntoIterator<Item = (RequestId, Prefix, V)>,
+    ) -> Result<HashMap<RequestId, V>, TransportError> {
+        let mut matched = HashMap::with_capacity(self.outstanding.len());
+        for (id, prefix, resp) in responses {
+            if self.outstanding.remove(&id) != Some(prefix) {
+                self.outstanding.clear();
+                return Err(TransportError::BucketMismatch);
+            }
+            matched.insert(id, resp);
+        }
+        if !self.outstanding.is_empty() {
+            self.outstanding.clear();
+            return Err(TransportError::BucketMismatch);
+        }
+        Ok(matched)
+    }
+
+    /// Negotiate a protocol version and send any pinned epoch before the first lookup.
+    fn negotiate(&mut self) -> Result<(), TransportError> {
+        if self.version.is_none() {
+            self.version = Some(self.transport.handshake(&self.client.hello())?.version);
+        }
+        if let EpochPin::Unsent(epoch) = self.pin {
+            self.transport.pin_epoch(epoch)?;
+            self.pin = EpochPin::Pinned(epoch);
+        }
+        Ok(())
+    }
+
+    /// Check a lookup's responses came from the pinned epoch, pinning the first lookup's epoch if
+    /// the session pins to it.
+    fn check_pin(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        match self.pin {
+            EpochPin::First => {
+                self.transport.pin_epoch(epoch)?;
+                self.pin = EpochPin::Pinned(epoch);
+            }
+            EpochPin::Unsent(pinned) | EpochPin::Pinned(pinned) if pinned != epoch => {
+                return Err(TransportError::StaleEpoch { current: epoch.max(pinned) });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        planner::Quota,
+        testing::{Fault, MockServer},
+        transcript::{Call, TranscriptRecorder},
+        Server,
+    };
+
+    #[test]
+    fn retries() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let mut server = MockServer::new(OsRng, &users);
+        let policy = RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+
+        // Retryable errors are retried, including responses from mixed epochs.
+        server.inject(Fault::RateLimited { retry_after: Duration::ZERO });
+        server.inject(Fault::None);
+        server.inject(Fault::WrongEpoch);
+        let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(policy);
+        let s_u = session.lookup(1234567890).expect("should succeed").expect("should be found");
+        assert_eq!(session.epoch(), Some(1));
+        assert_eq!(session.version(), Some(ProtocolVersion::V1));
+        assert_eq!(server.unblind_user_id(&s_u), Ok(users.get(&1234567890).cloned()));
+
+        // Fatal errors aren't.
+        server.inject(Fault::CorruptPoint);
+        let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(policy);
+        assert_eq!(session.lookup(1234567890), Err(TransportError::InvalidPoint));
+
+        // Retries are limited.
+        for _ in 0..3 {
+            server.inject(Fault::Timeout);
+        }
+        let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(policy);
+        assert_eq!(session.lookup(1234567890), Err(TransportError::Timeout));
+    }
+
+    #[test]
+    fn sync() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let mut server = MockServer::new(OsRng, &users);
+        let quota = Quota { contacts_per_day: 10, requests_per_minute: 4 };
+        let mut plan = SyncPlan::new(quota, [21, 22, 23]);
+        let mut session = ClientSession::new(&mut server, OsRng);
+
+        // The first chunk fills the per-minute quota, then the session must wait.
+        let now = SystemTime::now();
+        let Ok(SyncProgress::Found(found)) = session.sync(&mut plan, now) else {
+            panic!("should look up a chunk");
+        };
+        assert_eq!(found.iter().map(|&(p, _)| p).collect::<Vec<_>>(), vec![21, 22]);
+        assert!(found[1].1.is_some());
+        assert!(matches!(session.sync(&mut plan, now), Ok(SyncProgress::Wait(_))));
+
+        // The rest is looked up in the next minute.
+        let now = now + Duration::from_secs(60);
+        assert!(matches!(session.sync(&mut plan, now), Ok(SyncProgress::Found(f)) if f.len() == 1));
+        assert_eq!(session.sync(&mut plan, now), Ok(SyncProgress::Done));
+    }
+
+    #[test]
+    fn pipelined_batches() {
+        let users = HashMap::from([(22, Uuid::new_v4()), (23, Uuid::new_v4())]);
+        let mut server = MockServer::new(OsRng, &users);
+
+        // Pipelined responses are matched to their requests.
+        let mut session =
+            ClientSession::new(&mut server, OsRng).with_retry_policy(RetryPolicy::NONE);
+        let found = session.lookup_batch(&[21, 22, 23]).expect("should succeed");
+        assert_eq!(found.iter().map(|&(p, _)| p).collect::<Vec<_>>(), vec![21, 22, 23]);
+        assert!(found[0].1.is_none());
+        let s_u = found[1].1.expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Ok(users.get(&22).copied()));
+
+        // Transposed responses are detected, unless their prefixes happen to collide.
+        server.inject(Fault::TransposeBuckets);
+        let mut session =
+            ClientSession::new(&mut server, OsRng).with_retry_policy(RetryPolicy::NONE);
+        let prefixes = [21, 22].map(|p| Client::new(OsRng).request_phone_number(p).0);
+        if prefixes[0] != prefixes[1] {
+            assert_eq!(session.lookup_batch(&[21, 22]), Err(TransportError::BucketMismatch));
+        }
+    }
+
+    #[test]
+    fn pinned_epochs() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let mut server = MockServer::new(OsRng, &users);
+
+        // A session pins the epoch of its first lookup, and fails rather than mixing in another.
+        let mut session = ClientSession::new(&mut server, OsRng).with_epoch_pinning();
+        assert_eq!(session.pinned_epoch(), None);
+        assert!(session.lookup(22).expect("should succeed").is_some());
+        assert_eq!(session.pinned_epoch(), Some(1));
+        server.inject(Fault::WrongEpoch);
+        let mut session = ClientSession::new(&mut server, OsRng).with_pinned_epoch(1);
+        assert_eq!(session.lookup(22), Err(TransportError::StaleEpoch { current: 1 }));
+
+        // A resumed sync is answered from its pinned epoch while the server retains it.
+        let mut session = ClientSession::new(&mut server, OsRng).with_pinned_epoch(0);
+        let s_u = session.lookup(22).expect("should succeed").expect("should be found");
+        assert_eq!(session.epoch(), Some(0));
+        assert_eq!(server.unblind_user_id(&s_u), Ok(users.get(&22).copied()));
+        let mut session = ClientSession::new(&mut server, OsRng).with_pinned_epoch(7);
+        assert_eq!(session.lookup(22), Err(TransportError::StaleEpoch { current: 1 }));
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        let retry_after = Duration::from_secs(60);
+        for retry in 0..10 {
+            assert!(
+                policy.backoff(retry, &TransportError::Timeout, &mut OsRng) <= policy.max_backoff
+            );
+            assert!(
+                policy.backoff(retry, &TransportError::RateLimited { retry_after }, &mut OsRng)
+                    >= retry_after
+            );
+        }
+    }
+
+    #[test]
+    fn split_buckets() {
+        let u = Uuid::new_v4();
+        let server = Server::new(OsRng, &HashMap::from([(22, u), (23, Uuid::new_v4())]));
+        let server = crate::tests::split_bucket(server, 22);
+        let (prefix, _) = Client::new(OsRng).request_phone_number(22);
+        assert_eq!((&server).find_bucket_or_split(prefix), Ok((0, FoundBucket::Split(1))));
+
+        // Sessions follow the redirection to the sub-bucket.
+        let mut session = ClientSession::new(&server, OsRng);
+        let s_u = session.lookup(22).expect("should succeed").expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+
+        // Batches do, too.
+        let found = session.lookup_batch(&[23, 22, 24]).expect("should succeed");
+        assert_eq!(found[1].0, 22);
+        let s_u = found[1].1.expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+        assert!(found[0].1.is_some() && found[2].1.is_none());
+    }
+
+    #[test]
+    fn spilled_buckets() {
+        let u = Uuid::new_v4();
+        let server = Server::new(OsRng, &HashMap::from([(22, u), (23, Uuid::new_v4())]));
+        let server = crate::tests::spill_bucket(server, 22);
+
+        // Sessions search the overflow bucket for phone numbers missing from their buckets.
+        let mut session = ClientSession::new(&server, OsRng);
+        let s_u = session.lookup(22).expect("should succeed").expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+        let found = session.lookup_batch(&[23, 22, 24]).expect("should succeed");
+        let s_u = found[1].1.expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(u));
+        assert!(found[0].1.is_some() && found[2].1.is_none());
+
+        // Including those spilled by the builder.
+        let users = [(22, Uuid::new_v4()), (22, Uuid::new_v4()), (22, Uuid::new_v4())];
+        let server = crate::tests::spilled_server(&users);
+        let mut session = ClientSession::new(&server, OsRng);
+        let s_u = session.lookup(22).expect("should succeed").expect("should be found");
+        assert_eq!(server.unblind_user_id(&s_u), Some(users[0].1));
+    }
+
+    #[test]
+    fn overflow_fetched_once_per_epoch() {
+        let server = Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())]));
+        let overflow_requests = |recorder: &TranscriptRecorder<&Server>| {
+            let exchanges = &recorder.transcript().exchanges;
+            exchanges
+                .iter()
+                .filter(|e| e.call == Call::FindBucket && e.request == OVERFLOW_PREFIX)
+                .count()
+        };
+
+        // Sessions fetch the overflow bucket once per epoch, whether their lookups hit or miss.
+        let mut recorder = TranscriptRecorder::new(&server);
+        let mut session = ClientSession::new(&mut recorder, OsRng);
+        assert!(session.lookup(22).expect("should succeed").is_some());
+        assert!(session.lookup(23).expect("should succeed").is_none());
+        session.lookup_batch(&[22, 23]).expect("should succeed");
+        assert_eq!(overflow_requests(&recorder), 1);
+
+        // As do decoys, so a session's first miss can't be told from its first decoy.
+        let mut recorder = TranscriptRecorder::new(&server);
+        let mut session = ClientSession::new(&mut recorder, OsRng);
+        session.send_decoy().expect("should succeed");
+        assert_eq!(overflow_requests(&recorder), 1);
+    }
+}
@@ -0,0 +1,205 @@
+//! Shadow serving of candidate epochs.
+//!
+//! A bad build or a botched rotation is much cheaper to catch before its server is promoted than
+//! after clients start getting wrong answers. A [`LookupService`](crate::service::LookupService)
+//! can [load a shadow](crate::service::LookupService::load_shadow) candidate alongside the server
+//! it's serving, and mirror a sample of its live lookups against it. Clients only ever see the live
+//! server's responses; the [`Shadow`] compares the candidate's responses with them and times both,
+//! so operators can check its [`ShadowReport`] before
+//! [promoting](crate::service::LookupService::promote_shadow) the candidate.
+//!
+//! The candidate's secret is usually different from the live server's, so the live responses are
+//! re-blinded with the ratio of the two secrets before they're compared, the same way a
+//! [rotation](Server::rotate) re-blinds buckets. A candidate built from the same address book
+//! matches exactly; any entry which was dropped, added, or corrupted is a mismatch.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, EncodedPoint, Scalar};
+
+use crate::{
+    decode_point,
+    service::{Request, Response},
+    Server,
+};
+
+/// By default, one in this many lookups is mirrored against the candidate.
+pub const DEFAULT_SAMPLE_RATE: u64 = 100;
+
+/// A comparison of a candidate server with the live one, over the lookups mirrored so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShadowReport {
+    /// The number of lookups mirrored against the candidate.
+    pub mirrored: u64,
+    /// The number of mirrored lookups for which the candidate's response differed from the live
+    /// one.
+    pub mismatches: u64,
+    /// The total time the live server spent answering mirrored lookups.
+    pub live_time: Duration,
+    /// The total time the candidate spent answering mirrored lookups.
+    pub candidate_time: Duration,
+}
+
+/// A candidate server which mirrors a sample of live lookups.
+#[derive(Debug)]
+pub struct Shadow<S = RandomState> {
+    candidate: Arc<Server<S>>,
+    sample_rate: u64,
+    lookups: AtomicU64,
+    report: Mutex<ShadowReport>,
+}
+
+impl<S: BuildHasher + Clone> Shadow<S> {
+    /// Create a shadow for the given candidate server, which mirrors one in
+    /// [`DEFAULT_SAMPLE_RATE`] lookups.
+    pub fn new(candidate: Arc<Server<S>>) -> Shadow<S> {
+        Shadow {
+            candidate,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            lookups: AtomicU64::new(0),
+            report: Mutex::default(),
+        }
+    }
+
+    /// Mirror one in `n` lookups instead of one in [`DEFAULT_SAMPLE_RATE`].
+    pub fn with_sample_rate(self, n: u64) -> Shadow<S> {
+        Shadow { sample_rate: n.max(1), ..self }
+    }
+
+    /// The candidate server.
+    pub fn candidate(&self) -> Arc<Server<S>> {
+        self.candidate.clone()
+    }
+
+    /// The comparison of the candidate with the live server so far.
+    pub fn report(&self) -> ShadowReport {
+        *self.report.lock().expect("should not be poisoned")
+    }
+
+    /// Whether the given request should be mirrored. Only bucket and blinding requests are, since
+    /// unblinding requests are tied to the live epoch by their session tokens.
+    pub(crate) fn sample(&self, req: &Request) -> bool {
+        matches!(
+            req,
+            Request::FindBuckets(_) | Request::BlindPhoneNumbers(_) | Request::BlindWithTokens(..)
+        ) && self.lookups.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_rate)
+    }
+
+    /// Answer a sampled request with the candidate, and compare its response with the live server's
+    /// response to it.
+    pub(crate) fn mirror(
+        &self,
+        live: &Server<S>,
+        req: &Request,
+        resp: &Response<S>,
+        live_time: Duration,
+    ) {
+        // Calculate the ratio of the candidate's secret to the live one.
+        let r = self.candidate.d_s * live.d_s.invert().expect("should be invertible");
+
+        let start = Instant::now();
+        let (candidate_time, matches) = match (req, resp) {
+            (Request::FindBuckets(prefixes), Response::Buckets(buckets)) => {
+                let candidate: Vec<_> =
+                    prefixes.iter().map(|&prefix| self.candidate.find_bucket(prefix)).collect();
+                let candidate_time = start.elapsed();
+
+                // Every live entry must be in the candidate's bucket, and nothing else.
+                let matches = buckets.iter().zip(&candidate).all(|(live, candidate)| {
+                    live.len() == candidate.len()
+                        && live.iter().all(|(s_p, hs_u)| {
+                            let s_p = reblind(s_p, &r);
+                            s_p.and_then(|s_p| candidate.get(&s_p)) == reblind(hs_u, &r).as_ref()
+                        })
+                });
+                (candidate_time, matches)
+            }
+            (
+                Request::BlindPhoneNumbers(c_ps) | Request::BlindWithTokens(c_ps, _),
+                Response::BlindedPhoneNumbers(sc_ps, _),
+            ) => {
+                let candidate: Vec<_> =
+                    c_ps.iter().map(|c_p| self.candidate.blind_phone_number(c_p).ok()).collect();
+                let candidate_time = start.elapsed();
+                let matches = sc_ps.len() == candidate.len()
+                    && sc_ps
+                        .iter()
+                        .zip(&candidate)
+                        .all(|(sc_p, c)| c.is_some() && reblind(sc_p, &r) == *c);
+                (candidate_time, matches)
+            }
+            _ => return,
+        };
+
+        let mut report = self.report.lock().expect("should not be poisoned");
+        report.mirrored += 1;
+        report.mismatches += u64::from(!matches);
+        report.live_time += live_time;
+        report.candidate_time += candidate_time;
+    }
+}
+
+/// Multiply the given point by `r`, or return `None` if it isn't a valid point.
+fn reblind(p: &EncodedPoint, r: &Scalar) -> Option<EncodedPoint> {
+    decode_point(p).ok().map(|p| (p * r).to_affine().to_encoded_point(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use tower::Service;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{service::LookupService, Client};
+
+    #[tokio::test]
+    async fn shadow_rotation() {
+        let users = HashMap::from([(22, Uuid::new_v4()), (33, Uuid::new_v4())]);
+        let server = Arc::new(Server::new(OsRng, &users));
+        let candidate = Arc::new(server.rotate(OsRng));
+        let mut svc = LookupService::new(server);
+        svc.load_shadow(Arc::new(Shadow::new(candidate.clone()).with_sample_rate(1)));
+        let (prefix, c_p) = Client::new(OsRng).request_phone_number(22);
+
+        // Lookups are mirrored, and a correctly rotated candidate agrees with the live server.
+        svc.call(Request::FindBuckets(vec![prefix])).await.expect("should find buckets");
+        svc.call(Request::BlindPhoneNumbers(vec![c_p])).await.expect("should blind");
+        svc.call(Request::Health).await.expect("should be healthy");
+        let shadow = svc.shadow().expect("should have a shadow");
+        assert_eq!((shadow.report().mirrored, shadow.report().mismatches), (2, 0));
+
+        // Promoting the candidate serves it and stops mirroring.
+        let report = svc.promote_shadow().expect("should have a shadow");
+        assert_eq!(report.mirrored, 2);
+        assert_eq!(svc.server().expect("should be loaded").epoch(), candidate.epoch());
+        assert!(svc.shadow().is_none());
+    }
+
+    #[tokio::test]
+    async fn shadow_mismatch() {
+        let server = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let candidate = Arc::new(Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())])));
+        let mut svc = LookupService::new(server.clone());
+        svc.load_shadow(Arc::new(Shadow::new(candidate).with_sample_rate(2)));
+        let (prefix, c_p) = Client::new(OsRng).request_phone_number(22);
+
+        // A candidate with a different user ID for the phone number has a different bucket, but
+        // blinds phone numbers the same way.
+        svc.call(Request::FindBuckets(vec![prefix])).await.expect("should find buckets");
+        svc.call(Request::FindBuckets(vec![prefix])).await.expect("should find buckets");
+        svc.call(Request::BlindPhoneNumbers(vec![c_p])).await.expect("should blind");
+        let report = svc.discard_shadow().expect("should have a shadow");
+        assert_eq!((report.mirrored, report.mismatches), (2, 1));
+        assert_eq!(svc.server().expect("should be loaded").epoch(), server.epoch());
+    }
+}
@@ -0,0 +1,240 @@
+//! Assignment of hash prefixes to shards in sharded deployments.
+//!
+//! A [`ShardMap`] places each shard at a number of pseudo-random points (virtual nodes) on a ring
+//! of 64-bit values, and assigns each prefix, read as a big-endian integer, to the shard at the next
+//! point on the ring. Each shard thus owns a set of contiguous prefix ranges. Adding a shard only
+//! moves prefixes to it, and removing one only moves its prefixes, so rebalancing touches roughly
+//! `1/n` of the directory.
+//!
+//! The ring is derived from the shard IDs and the number of virtual nodes alone, so a map's
+//! [encoding](ShardMap::encode) is compact enough to distribute to clients and routers.
+
+use std::ops::RangeInclusive;
+
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Prefix};
+
+/// The identifier of a shard.
+pub type ShardId = u32;
+
+/// A consistent-hashing map from hash prefixes to shards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMap {
+    vnodes: u32,
+    shards: Vec<ShardId>,
+    ring: Vec<(u64, ShardId)>,
+}
+
+impl ShardMap {
+    /// The default number of virtual nodes per shard.
+    pub const DEFAULT_VNODES: u32 = 64;
+
+    /// The maximum number of virtual nodes per shard.
+    pub const MAX_VNODES: u32 = 1024;
+
+    /// The maximum number of shards in a map.
+    pub const MAX_SHARDS: usize = 1024;
+
+    /// Create an empty map which places each shard at `vnodes` points on the ring.
+    ///
+    /// More virtual nodes spread prefixes more evenly between shards, at the cost of more ranges.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `vnodes` is zero or greater than [`ShardMap::MAX_VNODES`].
+    pub fn new(vnodes: u32) -> ShardMap {
+        assert!(vnodes > 0, "vnodes should be positive");
+        assert!(vnodes <= ShardMap::MAX_VNODES, "vnodes should be at most MAX_VNODES");
+        ShardMap { vnodes, shards: Vec::new(), ring: Vec::new() }
+    }
+
+    /// The shards in the map, in order of ID.
+    pub fn shards(&self) -> &[ShardId] {
+        &self.shards
+    }
+
+    /// Add the given shard, returning `false` if it was already present or the map already has
+    /// [`ShardMap::MAX_SHARDS`] shards.
+    pub fn add(&mut self, shard: ShardId) -> bool {
+        let Err(i) = self.shards.binary_search(&shard) else {
+            return false;
+        };
+        if self.shards.len() >= ShardMap::MAX_SHARDS {
+            return false;
+        }
+        self.shards.insert(i, shard);
+        self.ring.extend((0..self.vnodes).map(|v| (point(shard, v), shard)));
+        self.ring.sort_unstable();
+        true
+    }
+
+    /// Remove the given shard, returning `false` if it wasn't present.
+    pub fn remove(&mut self, shard: ShardId) -> bool {
+        let Ok(i) = self.shards.binary_search(&shard) else {
+            return false;
+        };
+        self.shards.remove(i);
+        self.ring.retain(|&(_, s)| s != shard);
+        true
+    }
+
+    /// Return the shard responsible for the given prefix, or `None` if the map is empty.
+    pub fn shard_for(&self, prefix: &Prefix) -> Option<ShardId> {
+        let x = u64::from_be_bytes(*prefix);
+        let i = self.ring.partition_point(|&(p, _)| p < x);
+        self.ring.get(i).or(self.ring.first()).map(|&(_, shard)| shard)
+    }
+
+    /// Return the ranges of prefixes, read as big-endian integers, and the shard responsible for
+    /// each, in order.
+    pub fn ranges(&self) -> Vec<(RangeInclusive<u64>, ShardId)> {
+        let Some(&(_, first)) = self.ring.first() else {
+            return Vec::new();
+        };
+
+        // Each point owns the values after the previous point, up to and including itself, and the
+        // first point also owns the values after the last.
+        let mut ranges = Vec::with_capacity(self.ring.len() + 1);
+        let mut start = 0;
+        for &(p, shard) in &self.ring {
+            if p >= start {
+                ranges.push((start..=p, shard));
+            }
+            start = p.saturating_add(1);
+        }
+        if self.ring.last().is_some_and(|&(p, _)| p < u64::MAX) {
+            ranges.push((start..=u64::MAX, first));
+        }
+        ranges
+    }
+
+    /// Encode the map.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(4 + 4 * self.shards.len());
+        b.extend_from_slice(&self.vnodes.to_be_bytes());
+        for shard in &self.shards {
+            b.extend_from_slice(&shard.to_be_bytes());
+        }
+        b
+    }
+
+    /// Decode a map. Maps with more than [`ShardMap::MAX_VNODES`] virtual nodes per shard or
+    /// [`ShardMap::MAX_SHARDS`] shards are rejected before their rings are computed.
+    pub fn decode(b: &[u8]) -> Result<ShardMap, Error> {
+        if b.len() < 4 || !b.len().is_multiple_of(4) || b.len() / 4 - 1 > ShardMap::MAX_SHARDS {
+            return Err(Error::InvalidMessage);
+        }
+        let (vnodes, shards) = b.split_at(4);
+        let vnodes = u32::from_be_bytes(vnodes.try_into().expect("should be 4 bytes"));
+        if vnodes == 0 || vnodes > ShardMap::MAX_VNODES {
+            return Err(Error::InvalidMessage);
+        }
+
+        // Build the ring in one go, rather than re-sorting it as each shard is added.
+        let mut shards = shards
+            .chunks_exact(4)
+            .map(|shard| ShardId::from_be_bytes(shard.try_into().expect("should be 4 bytes")))
+            .collect::<Vec<_>>();
+        shards.sort_unstable();
+        if shards.windows(2).any(|w| w[0] == w[1]) {
+            return Err(Error::InvalidMessage);
+        }
+        let mut ring = shards
+            .iter()
+            .flat_map(|&shard| (0..vnodes).map(move |v| (point(shard, v), shard)))
+            .collect::<Vec<_>>();
+        ring.sort_unstable();
+        Ok(ShardMap { vnodes, shards, ring })
+    }
+}
+
+impl Default for ShardMap {
+    fn default() -> Self {
+        ShardMap::new(ShardMap::DEFAULT_VNODES)
+    }
+}
+
+/// Return the position of the given virtual node of the given shard on the ring.
+fn point(shard: ShardId, vnode: u32) -> u64 {
+    let h = Sha256::new()
+        .chain_update(b"zk-cds-shard")
+        .chain_update(shard.to_be_bytes())
+        .chain_update(vnode.to_be_bytes())
+        .finalize();
+    u64::from_be_bytes(h[..8].try_into().expect("should be 8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{prefix, HashFunction};
+
+    #[test]
+    fn rebalancing() {
+        let prefixes =
+            (0..10_000u64).map(|p| prefix(&HashFunction::default().hash(&p.to_be_bytes())));
+        let prefixes = prefixes.collect::<Vec<_>>();
+        let mut map = ShardMap::default();
+        assert_eq!(map.shard_for(&prefixes[0]), None);
+        for shard in 0..4 {
+            assert!(map.add(shard));
+        }
+        let before = prefixes.iter().map(|p| map.shard_for(p)).collect::<Vec<_>>();
+
+        // Adding a shard only moves prefixes to it, about a fifth of them.
+        assert!(map.add(4));
+        let after = prefixes.iter().map(|p| map.shard_for(p)).collect::<Vec<_>>();
+        let moved = before.iter().zip(&after).filter(|(b, a)| b != a).collect::<Vec<_>>();
+        assert!(moved.iter().all(|&(_, a)| a == &Some(4)));
+        assert!((1_000..3_000).contains(&moved.len()), "{} moved", moved.len());
+
+        // Removing it moves them back.
+        assert!(map.remove(4));
+        assert!(!map.remove(4));
+        assert_eq!(prefixes.iter().map(|p| map.shard_for(p)).collect::<Vec<_>>(), before);
+
+        // Ranges cover every prefix and agree with lookups.
+        let ranges = map.ranges();
+        assert_eq!(ranges.first().map(|(r, _)| *r.start()), Some(0));
+        assert_eq!(ranges.last().map(|(r, _)| *r.end()), Some(u64::MAX));
+        for p in &prefixes[..100] {
+            let x = u64::from_be_bytes(*p);
+            let (_, shard) =
+                ranges.iter().find(|(r, _)| r.contains(&x)).expect("should be covered");
+            assert_eq!(map.shard_for(p), Some(*shard));
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let mut map = ShardMap::new(8);
+        map.add(7);
+        map.add(3);
+        assert_eq!(ShardMap::decode(&map.encode()), Ok(map));
+        assert_eq!(ShardMap::decode(&[0, 0, 0, 0]), Err(Error::InvalidMessage));
+        assert_eq!(ShardMap::decode(&[0, 0, 0, 1, 0, 0, 0]), Err(Error::InvalidMessage));
+        assert_eq!(
+            ShardMap::decode(&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]),
+            Err(Error::InvalidMessage)
+        );
+
+        // Maps too large to compute the rings of are rejected.
+        let mut b = (ShardMap::MAX_VNODES + 1).to_be_bytes().to_vec();
+        b.extend_from_slice(&[0, 0, 0, 1]);
+        assert_eq!(ShardMap::decode(&b), Err(Error::InvalidMessage));
+        b[..4].copy_from_slice(&ShardMap::MAX_VNODES.to_be_bytes());
+        assert_eq!(ShardMap::decode(&b).map(|map| map.ring.len()), Ok(1024));
+        let mut b = 1u32.to_be_bytes().to_vec();
+        (0..=ShardMap::MAX_SHARDS as u32)
+            .for_each(|shard| b.extend_from_slice(&shard.to_be_bytes()));
+        assert_eq!(ShardMap::decode(&b), Err(Error::InvalidMessage));
+        b.truncate(b.len() - 4);
+        assert_eq!(ShardMap::decode(&b).map(|map| map.shards.len()), Ok(ShardMap::MAX_SHARDS));
+
+        // Including by adding shards.
+        let mut map = ShardMap::new(1);
+        assert!((0..ShardMap::MAX_SHARDS as u32).all(|shard| map.add(shard)));
+        assert!(!map.add(ShardMap::MAX_SHARDS as u32));
+    }
+}
@@ -0,0 +1,211 @@
+//! Blind signatures over lookup results, for presentation to third parties.
+//!
+//! After a successful lookup, a client may want to prove to another service that a user ID was
+//! discovered via the CDS in a given epoch. An [`Issuer`] signs `(epoch, user ID)` pairs using a
+//! blind Schnorr signature over P-256, so the resulting [`MatchSignature`] can't be linked to the
+//! issuance session (and thus the lookup) which produced it:
+//!
+//! 1. The issuer picks a nonce `k` and sends its commitment `R = [k]G`.
+//! 2. The client picks blinding scalars `α` and `β`, calculates `R' = R + [α]G + [β]X` and
+//!    `c' = H(R', X, epoch, u)`, and sends the blinded challenge `c = c' + β`.
+//! 3. The issuer responds with `s = k + c·x`.
+//! 4. The client unblinds the response, `s' = s + α`, giving the signature `(R', s')`, which
+//!    satisfies `[s']G = R' + [c']X`.
+//!
+//! **N.B.:** The issuer never sees the message it signs, so it must only start an issuance session
+//! for a client which has proven a match (e.g. by presenting a `sU` point which
+//! [`Server::unblind_user_id`](crate::Server::unblind_user_id) accepts). Even so, a client can have
+//! any user ID it knows signed. Blind Schnorr signatures are also vulnerable to forgeries if an
+//! issuer has many sessions open at once, so issuers should bound the number of concurrent sessions
+//! per key.
+
+use p256::{
+    elliptic_curve::{ops::Reduce, sec1::ToEncodedPoint, Field, PrimeField},
+    EncodedPoint, FieldBytes, ProjectivePoint, Scalar, U256,
+};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{decode_point, encoding::POINT_LEN, Epoch, Error};
+
+/// The length of an encoded scalar, in bytes.
+const SCALAR_LEN: usize = 32;
+
+/// The length of an encoded [`MatchSignature`], in bytes.
+pub const SIGNATURE_LEN: usize = POINT_LEN + SCALAR_LEN;
+
+/// A server which issues blind signatures over lookup results.
+#[derive(Debug)]
+pub struct Issuer {
+    x: Scalar,
+}
+
+impl Issuer {
+    /// Create a new issuer with a random signing key.
+    pub fn new(rng: impl CryptoRng + RngCore) -> Issuer {
+        Issuer { x: Scalar::random(rng) }
+    }
+
+    /// The issuer's public key, `X = [x]G`, which clients and verifiers need.
+    pub fn public_key(&self) -> EncodedPoint {
+        (ProjectivePoint::GENERATOR * self.x).to_affine().to_encoded_point(true)
+    }
+
+    /// Start an issuance session, returning it and the nonce commitment to send to the client.
+    pub fn commit(&self, rng: impl CryptoRng + RngCore) -> (IssuanceSession, EncodedPoint) {
+        let k = Scalar::random(rng);
+        (IssuanceSession { k }, (ProjectivePoint::GENERATOR * k).to_affine().to_encoded_point(true))
+    }
+
+    /// Given an issuance session and the client's blinded challenge, return the blinded response.
+    ///
+    /// The session is consumed, as reusing a nonce would reveal the signing key.
+    pub fn respond(&self, session: IssuanceSession, c: &[u8; 32]) -> Result<[u8; 32], Error> {
+        let c = decode_scalar(c)?;
+        Ok((session.k + c * self.x).to_repr().into())
+    }
+}
+
+/// The issuer's state for a single signature.
+#[derive(Debug)]
+pub struct IssuanceSession {
+    k: Scalar,
+}
+
+/// The client's state for a single signature.
+#[derive(Debug)]
+pub struct SignatureRequest {
+    alpha: Scalar,
+    r: EncodedPoint,
+    c: Scalar,
+    public_key: EncodedPoint,
+}
+
+impl SignatureRequest {
+    /// Given the issuer's public key and nonce commitment, start a request for a signature over the
+    /// given epoch and user ID, returning it and the blinded challenge to send to the issuer.
+    pub fn new(
+        rng: &mut (impl CryptoRng + RngCore),
+        public_key: &EncodedPoint,
+        r: &EncodedPoint,
+        epoch: Epoch,
+        user_id: &Uuid,
+    ) -> Result<(SignatureRequest, [u8; 32]), Error> {
+        let x = decode_point(public_key)?;
+        let r = decode_point(r)?;
+
+        // Blind the nonce commitment.
+        let alpha = Scalar::random(&mut *rng);
+        let beta = Scalar::random(&mut *rng);
+        let r = (ProjectivePoint::from(r) + ProjectivePoint::GENERATOR * alpha + x * beta)
+            .to_affine()
+            .to_encoded_point(true);
+
+        // Calculate the challenge and blind it.
+        let c = challenge(&r, public_key, epoch, user_id);
+        let request = SignatureRequest { alpha, r, c, public_key: *public_key };
+        Ok((request, (c + beta).to_repr().into()))
+    }
+
+    /// Given the issuer's blinded response, unblind it and return the signature.
+    ///
+    /// Returns [`Error::InvalidMessage`] if the issuer's response is not a valid signature.
+    pub fn finish(self, s: &[u8; 32]) -> Result<MatchSignature, Error> {
+        let s = decode_scalar(s)? + self.alpha;
+        let signature = MatchSignature { r: self.r, s };
+        if !signature.verify_challenge(&self.public_key, self.c) {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(signature)
+    }
+}
+
+/// A signature over an epoch and a user ID discovered in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchSignature {
+    r: EncodedPoint,
+    s: Scalar,
+}
+
+impl MatchSignature {
+    /// Returns `true` if this is a valid signature by the issuer with the given public key over the
+    /// given epoch and user ID.
+    pub fn verify(&self, public_key: &EncodedPoint, epoch: Epoch, user_id: &Uuid) -> bool {
+        self.verify_challenge(public_key, challenge(&self.r, public_key, epoch, user_id))
+    }
+
+    /// Encode the signature as `R'` followed by `s'`.
+    pub fn encode(&self) -> [u8; SIGNATURE_LEN] {
+        let mut b = [0u8; SIGNATURE_LEN];
+        b[..POINT_LEN].copy_from_slice(self.r.as_bytes());
+        b[POINT_LEN..].copy_from_slice(&self.s.to_repr());
+        b
+    }
+
+    /// Decode a signature.
+    pub fn decode(b: &[u8]) -> Result<MatchSignature, Error> {
+        if b.len() != SIGNATURE_LEN {
+            return Err(Error::InvalidMessage);
+        }
+        let r = EncodedPoint::from_bytes(&b[..POINT_LEN]).map_err(|_| Error::InvalidPoint)?;
+        decode_point(&r)?;
+        let s = decode_scalar(b[POINT_LEN..].try_into().expect("should be 32 bytes"))?;
+        Ok(MatchSignature { r, s })
+    }
+
+    fn verify_challenge(&self, public_key: &EncodedPoint, c: Scalar) -> bool {
+        let (Ok(x), Ok(r)) = (decode_point(public_key), decode_point(&self.r)) else {
+            return false;
+        };
+        ProjectivePoint::GENERATOR * self.s == ProjectivePoint::from(r) + x * c
+    }
+}
+
+/// Hash the nonce commitment, public key, epoch, and user ID to a challenge scalar.
+fn challenge(r: &EncodedPoint, public_key: &EncodedPoint, epoch: Epoch, user_id: &Uuid) -> Scalar {
+    let h = Sha256::new()
+        .chain_update(b"zk-cds-match-signature")
+        .chain_update(r.as_bytes())
+        .chain_update(public_key.as_bytes())
+        .chain_update(epoch.to_be_bytes())
+        .chain_update(user_id.as_bytes())
+        .finalize();
+    <Scalar as Reduce<U256>>::reduce_bytes(&h)
+}
+
+/// Decode a scalar, returning [`Error::InvalidMessage`] if it's not canonically encoded.
+fn decode_scalar(b: &[u8; 32]) -> Result<Scalar, Error> {
+    Option::from(Scalar::from_repr(FieldBytes::from(*b))).ok_or(Error::InvalidMessage)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn blind_signature() {
+        let issuer = Issuer::new(OsRng);
+        let public_key = issuer.public_key();
+        let user_id = Uuid::new_v4();
+
+        // The client and issuer run an issuance session.
+        let (session, r) = issuer.commit(OsRng);
+        let (request, c) = SignatureRequest::new(&mut OsRng, &public_key, &r, 3, &user_id)
+            .expect("should be a valid commitment");
+        let s = issuer.respond(session, &c).expect("should be a valid challenge");
+        let signature = request.finish(&s).expect("should be a valid response");
+
+        // The signature verifies for the signed epoch and user ID, and no others.
+        let signature = MatchSignature::decode(&signature.encode()).expect("should be valid");
+        assert!(signature.verify(&public_key, 3, &user_id));
+        assert!(!signature.verify(&public_key, 4, &user_id));
+        assert!(!signature.verify(&public_key, 3, &Uuid::new_v4()));
+        assert!(!signature.verify(&Issuer::new(OsRng).public_key(), 3, &user_id));
+
+        // The issuer's view of the session doesn't appear in the signature.
+        assert_ne!(&signature.encode()[..POINT_LEN], r.as_bytes());
+    }
+}
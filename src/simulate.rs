@@ -0,0 +1,136 @@
+//! Estimates of the cost of enumerating a directory.
+//!
+//! An attacker who wants to learn every user in the directory must make an online request to have
+//! each candidate phone number blinded, so the cost of enumeration is governed by the size of the
+//! phone number space and the server's rate limits, not by the directory itself. The prefix length
+//! and padding policy determine how much bucket data the attacker must also download. Operators can
+//! use [`estimate`] to choose parameters which make enumeration impractical.
+
+use std::time::Duration;
+
+use crate::encoding::{ENTRY_LEN, POINT_LEN};
+
+/// How buckets are padded with dummy entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    /// Buckets aren't padded.
+    None,
+    /// Buckets are padded to at least the given number of entries.
+    Fixed(u64),
+    /// Buckets are padded to a multiple of the given number of entries.
+    Multiple(u64),
+}
+
+/// A limit on the rate at which a single client can make requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of requests allowed per period.
+    pub requests: u64,
+    /// The length of the period.
+    pub period: Duration,
+}
+
+/// The parameters of a deployment and of an attacker trying to enumerate it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    /// The number of users in the directory.
+    pub directory_size: u64,
+    /// The number of possible phone numbers the attacker must try.
+    pub phone_number_space: u64,
+    /// The number of bits of the hash prefix used to group users into buckets.
+    pub prefix_bits: u32,
+    /// How buckets are padded.
+    pub padding: Padding,
+    /// The rate limit applied to each client.
+    pub rate_limit: RateLimit,
+    /// The number of client identities the attacker controls.
+    pub clients: u64,
+}
+
+/// The estimated cost of enumerating a directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// The expected number of entries in a non-empty bucket, including padding.
+    pub bucket_size: f64,
+    /// The expected number of requests to blind phone numbers.
+    pub blind_requests: f64,
+    /// The expected number of requests for buckets.
+    pub bucket_requests: f64,
+    /// The expected number of bytes downloaded.
+    pub bytes: f64,
+    /// The expected time taken by an attacker making requests as fast as allowed.
+    pub duration: Duration,
+}
+
+/// Estimate the cost of enumerating a directory with the given parameters.
+///
+/// # Panics
+///
+/// Panics if `prefix_bits` is greater than 64 or the rate limit allows no requests.
+pub fn estimate(params: &Parameters) -> Estimate {
+    assert!(params.prefix_bits <= 64, "prefix_bits should be at most 64");
+    assert!(
+        params.rate_limit.requests > 0 && params.clients > 0,
+        "rate limit should allow requests"
+    );
+
+    // Calculate the expected size of a non-empty bucket, before and after padding.
+    let buckets = 2f64.powi(params.prefix_bits as i32);
+    let directory = params.directory_size as f64;
+    let occupied = buckets * -(-directory / buckets).exp_m1();
+    let size = if occupied > 0.0 { directory / occupied } else { 0.0 };
+    let bucket_size = match params.padding {
+        Padding::None => size,
+        Padding::Fixed(n) => size.max(n as f64),
+        Padding::Multiple(n) if n > 0 => (size / n as f64).ceil() * n as f64,
+        Padding::Multiple(_) => size,
+    };
+
+    // Every candidate phone number must be blinded, and every distinct prefix among them fetched.
+    let candidates = params.phone_number_space as f64;
+    let blind_requests = candidates;
+    let bucket_requests = buckets * -(-candidates / buckets).exp_m1();
+    let bytes = blind_requests * (2 * POINT_LEN) as f64
+        + bucket_requests * (occupied / buckets) * bucket_size * ENTRY_LEN as f64;
+
+    // Requests are spread over all of the attacker's clients, each limited independently.
+    let rate = params.rate_limit.requests as f64 * params.clients as f64
+        / params.rate_limit.period.as_secs_f64();
+    let duration = Duration::try_from_secs_f64((blind_requests + bucket_requests) / rate)
+        .unwrap_or(Duration::MAX);
+
+    Estimate { bucket_size, blind_requests, bucket_requests, bytes, duration }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enumeration() {
+        // 100M users in 15-bit buckets, as in the README, with one client limited to 1000
+        // requests per day trying every ten-digit phone number.
+        let params = Parameters {
+            directory_size: 100_000_000,
+            phone_number_space: 10_000_000_000,
+            prefix_bits: 15,
+            padding: Padding::None,
+            rate_limit: RateLimit { requests: 1000, period: Duration::from_secs(86_400) },
+            clients: 1,
+        };
+        let estimate = estimate(&params);
+        assert!((estimate.bucket_size - 3051.8).abs() < 1.0);
+        assert!((estimate.bucket_requests - 32768.0).abs() < 1.0);
+        assert!(estimate.duration > Duration::from_secs(86_400 * 365 * 10_000));
+
+        // Padding increases the bucket size, and more clients shorten the attack.
+        let padded = super::estimate(&Parameters {
+            padding: Padding::Multiple(1024),
+            clients: 1000,
+            ..params
+        });
+        assert_eq!(padded.bucket_size, 3072.0);
+        assert!(padded.bytes > estimate.bytes);
+        assert!(padded.duration < estimate.duration);
+    }
+}
@@ -0,0 +1,391 @@
+//! Deterministic simulation of whole deployments.
+//!
+//! Bugs in rotation windows, retries, and rate limiting depend on the exact interleaving of
+//! requests, failures, and the passage of time, so they rarely reproduce. In a [`Simulation`],
+//! every source of nondeterminism comes from a single seed: server and client secrets are drawn
+//! from a seeded RNG, time is a [`SimClock`] which only advances when simulated requests take time
+//! or clients back off, and the network between clients and the server drops, delays, and
+//! rate-limits requests according to a [`NetworkConfig`] using the same RNG. A scenario run twice
+//! with the same seed produces the same [`Event`]s, so a failing seed can be replayed exactly, and
+//! hours of simulated rotation windows and rate-limit periods run in milliseconds.
+//!
+//! Simulations are single-threaded: the order in which connections make requests is part of the
+//! scenario.
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use p256::EncodedPoint;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use uuid::Uuid;
+
+use crate::{
+    decode_point,
+    protocol::{ClientHello, ServerHello},
+    rng::RngProvider,
+    session::ClientSession,
+    simulate::RateLimit,
+    transport::{Transport, TransportError},
+    Epoch, Prefix, Server,
+};
+
+/// The wall-clock time at which every simulation starts, as a duration since the Unix epoch
+/// (2024-01-01T00:00:00Z).
+pub const START_TIME: Duration = Duration::from_secs(1_704_067_200);
+
+/// By default, a rotated server keeps serving its previous epoch for this long.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(3600);
+
+/// A simulated clock, which only advances when told to.
+///
+/// Clones of a clock share its time.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl SimClock {
+    fn new() -> SimClock {
+        SimClock { start: Instant::now(), elapsed: Arc::default() }
+    }
+
+    /// The simulated time since the simulation started.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("should not be poisoned")
+    }
+
+    /// The simulated time, as an [`Instant`].
+    pub fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    /// The simulated time, as a [`SystemTime`] counted from [`START_TIME`].
+    pub fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + START_TIME + self.elapsed()
+    }
+
+    /// Advance the clock by the given duration.
+    pub fn advance(&self, d: Duration) {
+        *self.elapsed.lock().expect("should not be poisoned") += d;
+    }
+}
+
+/// The behavior of the simulated network between clients and the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConfig {
+    /// The least time a request takes.
+    pub min_latency: Duration,
+    /// The most time a request takes.
+    pub max_latency: Duration,
+    /// The probability that a request times out.
+    pub drop_rate: f64,
+    /// The rate limit applied to each connection, if any.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_rate: 0.0,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Something which happened in a simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A connection's request was answered from the given epoch, or failed.
+    Request {
+        /// The simulated time at which the request was answered.
+        at: Duration,
+        /// The connection which made the request.
+        connection: u64,
+        /// The outcome of the request.
+        outcome: Result<Epoch, TransportError>,
+    },
+    /// The server rotated to the given epoch.
+    Rotated {
+        /// The simulated time of the rotation.
+        at: Duration,
+        /// The new epoch.
+        epoch: Epoch,
+    },
+}
+
+/// The simulated server and its history.
+#[derive(Debug)]
+struct World {
+    current: Arc<Server>,
+    previous: Option<(Arc<Server>, Duration)>,
+    retention: Duration,
+    network: NetworkConfig,
+    connections: u64,
+    log: Vec<Event>,
+}
+
+impl World {
+    /// The server for the given epoch, if it's still served at the given time.
+    fn server(&self, epoch: Epoch, now: Duration) -> Option<&Arc<Server>> {
+        if self.current.epoch() == epoch {
+            return Some(&self.current);
+        }
+        self.previous
+            .as_ref()
+            .filter(|(server, until)| server.epoch() == epoch && now < *until)
+            .map(|(server, _)| server)
+    }
+}
+
+/// A seeded, simulated deployment of a server and its clients.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    clock: SimClock,
+    rng: Arc<Mutex<StdRng>>,
+    world: Arc<Mutex<World>>,
+}
+
+impl Simulation {
+    /// Create a simulation of a server with the given address book, with all its randomness drawn
+    /// from the given seed.
+    pub fn new(seed: u64, users: &HashMap<u64, Uuid>) -> Simulation {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let world = World {
+            current: Arc::new(Server::new(&mut rng, users)),
+            previous: None,
+            retention: DEFAULT_RETENTION,
+            network: NetworkConfig::default(),
+            connections: 0,
+            log: Vec::new(),
+        };
+        Simulation {
+            clock: SimClock::new(),
+            rng: Arc::new(Mutex::new(rng)),
+            world: Arc::new(Mutex::new(world)),
+        }
+    }
+
+    /// Simulate the given network instead of the default one.
+    pub fn with_network(self, network: NetworkConfig) -> Simulation {
+        self.world().network = network;
+        self
+    }
+
+    /// Keep serving a rotated server's previous epoch for the given duration, instead of
+    /// [`DEFAULT_RETENTION`].
+    pub fn with_retention(self, retention: Duration) -> Simulation {
+        self.world().retention = retention;
+        self
+    }
+
+    /// The simulation's clock.
+    pub fn clock(&self) -> SimClock {
+        self.clock.clone()
+    }
+
+    /// The simulation's RNG, e.g. for a [`LookupService`](crate::service::LookupService) or a job
+    /// queue under simulation.
+    pub fn rng(&self) -> Arc<dyn RngProvider> {
+        self.rng.clone()
+    }
+
+    /// The server for the current epoch.
+    pub fn server(&self) -> Arc<Server> {
+        self.world().current.clone()
+    }
+
+    /// Rotate the server's secret, serving its previous epoch until the retention period ends.
+    pub fn rotate(&self) {
+        let mut world = self.world();
+        let next =
+            Arc::new(world.current.rotate(&mut *self.rng.lock().expect("should not be poisoned")));
+        let now = self.clock.elapsed();
+        let previous = mem::replace(&mut world.current, next);
+        world.previous = Some((previous, now + world.retention));
+        let epoch = world.current.epoch();
+        world.log.push(Event::Rotated { at: now, epoch });
+    }
+
+    /// Open a new connection to the server.
+    pub fn connect(&self) -> SimTransport {
+        let mut world = self.world();
+        world.connections += 1;
+        SimTransport {
+            id: world.connections,
+            clock: self.clock.clone(),
+            rng: self.rng.clone(),
+            world: self.world.clone(),
+            pinned: None,
+            window: (Duration::ZERO, 0),
+        }
+    }
+
+    /// Create a client session on a new connection, with a seeded RNG and which backs off by
+    /// advancing the simulation's clock.
+    pub fn session(&self) -> ClientSession<SimTransport, StdRng> {
+        let seed = self.rng.lock().expect("should not be poisoned").next_u64();
+        let clock = self.clock.clone();
+        ClientSession::new(self.connect(), StdRng::seed_from_u64(seed))
+            .with_sleep(move |d| clock.advance(d))
+    }
+
+    /// Everything which has happened in the simulation so far, in order.
+    pub fn log(&self) -> Vec<Event> {
+        self.world().log.clone()
+    }
+
+    fn world(&self) -> MutexGuard<'_, World> {
+        self.world.lock().expect("should not be poisoned")
+    }
+}
+
+/// A simulated connection to a [`Simulation`]'s server.
+#[derive(Debug)]
+pub struct SimTransport {
+    id: u64,
+    clock: SimClock,
+    rng: Arc<Mutex<StdRng>>,
+    world: Arc<Mutex<World>>,
+    pinned: Option<Epoch>,
+    window: (Duration, u64),
+}
+
+impl SimTransport {
+    /// Send a request over the simulated network, and answer it with the server for the
+    /// connection's epoch.
+    fn send<T>(&mut self, f: impl FnOnce(&Server) -> T) -> Result<(Epoch, T), TransportError> {
+        let shared = self.world.clone();
+        let mut world = shared.lock().expect("should not be poisoned");
+        let network = world.network;
+
+        // Take some time, and maybe drop the request.
+        let (latency, dropped) = {
+            let mut rng = self.rng.lock().expect("should not be poisoned");
+            let latency = rng.gen_range(network.min_latency..=network.max_latency);
+            (latency, rng.gen_bool(network.drop_rate))
+        };
+        self.clock.advance(latency);
+        let now = self.clock.elapsed();
+
+        let res = if dropped {
+            Err(TransportError::Timeout)
+        } else if let Some(retry_after) = self.throttle(network.rate_limit, now) {
+            Err(TransportError::RateLimited { retry_after })
+        } else {
+            let epoch = self.pinned.unwrap_or(world.current.epoch());
+            match world.server(epoch, now) {
+                Some(server) => Ok((epoch, f(server))),
+                None => Err(TransportError::StaleEpoch { current: world.current.epoch() }),
+            }
+        };
+
+        let outcome = res.as_ref().map(|&(epoch, _)| epoch).map_err(|&err| err);
+        world.log.push(Event::Request { at: now, connection: self.id, outcome });
+        res
+    }
+
+    /// Count a request against the connection's rate limit, returning how long to wait if it's
+    /// over it.
+    fn throttle(&mut self, rate_limit: Option<RateLimit>, now: Duration) -> Option<Duration> {
+        let limit = rate_limit?;
+        let (start, n) = &mut self.window;
+        if now >= *start + limit.period {
+            (*start, *n) = (now, 0);
+        }
+        if *n >= limit.requests {
+            return Some(*start + limit.period - now);
+        }
+        *n += 1;
+        None
+    }
+}
+
+impl Transport for SimTransport {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        self.send(|server| server.handshake(hello))?.1.map_err(TransportError::from)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        self.send(|server| server.find_bucket(prefix))
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        decode_point(c_p)?;
+        let (epoch, sc_p) = self.send(|server| server.blind_phone_number(c_p))?;
+        Ok((epoch, sc_p?))
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        let world = self.world.lock().expect("should not be poisoned");
+        if world.server(epoch, self.clock.elapsed()).is_none() {
+            return Err(TransportError::StaleEpoch { current: world.current.epoch() });
+        }
+        self.pinned = Some(epoch);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::RetryPolicy;
+
+    /// Look up every user across a rotation with a lossy, rate-limited network, then look one up
+    /// once the pinned epoch is no longer served.
+    fn scenario(seed: u64) -> (Vec<Event>, Vec<Result<bool, TransportError>>) {
+        let users = (0..20).map(|p| (p, Uuid::from_u128(p.into()))).collect::<HashMap<_, _>>();
+        let sim = Simulation::new(seed, &users).with_network(NetworkConfig {
+            drop_rate: 0.1,
+            rate_limit: Some(RateLimit { requests: 10, period: Duration::from_secs(60) }),
+            ..NetworkConfig::default()
+        });
+        let mut session = sim.session().with_epoch_pinning().with_retry_policy(RetryPolicy {
+            max_retries: 20,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        });
+
+        let mut results = Vec::new();
+        for p in 0..20 {
+            if p == 10 {
+                sim.rotate();
+            }
+            results.push(session.lookup(p).map(|s_u| s_u.is_some()));
+        }
+        sim.clock().advance(DEFAULT_RETENTION);
+        results.push(session.lookup(0).map(|s_u| s_u.is_some()));
+        (sim.log(), results)
+    }
+
+    #[test]
+    fn deterministic_replay() {
+        let (log, results) = scenario(22);
+
+        // Pinned lookups succeed through the rotation window, despite drops and rate limits, and
+        // fail once it's over.
+        assert!(results[..20].iter().all(|found| found == &Ok(true)));
+        assert!(matches!(results[20], Err(TransportError::StaleEpoch { .. })));
+        let failed = |err: fn(&TransportError) -> bool| {
+            log.iter().any(|e| matches!(e, Event::Request { outcome: Err(e), .. } if err(e)))
+        };
+        assert!(failed(|e| *e == TransportError::Timeout));
+        assert!(failed(|e| matches!(e, TransportError::RateLimited { .. })));
+
+        // The same seed replays exactly; a different one doesn't.
+        assert_eq!(scenario(22), (log.clone(), results));
+        assert_ne!(scenario(23).0, log);
+    }
+}
@@ -0,0 +1,112 @@
+//! A streaming lookup API for clients syncing large address books.
+//!
+//! Rather than waiting for the server's responses to an entire batch of phone numbers,
+//! [`Client::lookup_stream`] processes each bucket as it arrives and yields a [`LookupResult`] for
+//! it, so neither side has to hold the whole batch in memory.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use p256::EncodedPoint;
+
+use crate::{Client, LookupResult};
+
+/// The server's response to a client's request for a phone number.
+#[derive(Debug, Clone)]
+pub struct BucketResponse<S = RandomState> {
+    /// The phone number which was requested.
+    pub phone_number: u64,
+    /// The double-blinded phone number point.
+    pub sc_p: EncodedPoint,
+    /// The bucket of users for the phone number's hash prefix.
+    pub bucket: HashMap<EncodedPoint, EncodedPoint, S>,
+}
+
+impl Client {
+    /// Given a stream of responses from the server, return a stream of lookup results in the same
+    /// order.
+    pub fn lookup_stream<S, St>(&self, responses: St) -> LookupStream<'_, St>
+    where
+        S: BuildHasher,
+        St: Stream<Item = BucketResponse<S>> + Unpin,
+    {
+        LookupStream { client: self, responses }
+    }
+}
+
+/// A stream of lookup results returned by [`Client::lookup_stream`].
+#[derive(Debug)]
+pub struct LookupStream<'a, St> {
+    client: &'a Client,
+    responses: St,
+}
+
+impl<S, St> Stream for LookupStream<'_, St>
+where
+    S: BuildHasher,
+    St: Stream<Item = BucketResponse<S>> + Unpin,
+{
+    type Item = LookupResult;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LookupResult>> {
+        Pin::new(&mut self.responses).poll_next(cx).map(|resp| {
+            resp.map(|resp| LookupResult {
+                phone_number: resp.phone_number,
+                blinded_user_id: self.client.find_user_id(
+                    &resp.sc_p,
+                    &resp.bucket,
+                    resp.phone_number,
+                ),
+            })
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.responses.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, StreamExt};
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn lookup_stream() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let server = Server::new(OsRng, &users);
+        let client = Client::new(OsRng);
+
+        let responses = stream::iter([1234567890, 1238675309]).map(|p| {
+            let (prefix, c_p) = client.request_phone_number(p);
+            BucketResponse {
+                phone_number: p,
+                sc_p: server.blind_phone_number(&c_p).expect("should be a valid point"),
+                bucket: server.find_bucket(prefix),
+            }
+        });
+        let results = client.lookup_stream(responses).collect::<Vec<_>>().await;
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].phone_number, 1234567890);
+        let s_u = results[0].blinded_user_id.expect("should be valid");
+        assert_eq!(
+            server.unblind_user_id(&s_u.expect("should be found")),
+            users.get(&1234567890).cloned()
+        );
+        assert_eq!(
+            results[1],
+            LookupResult { phone_number: 1238675309, blinded_user_id: Ok(None) }
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! The registry of ciphersuites.
+//!
+//! A ciphersuite fixes the curve, its hash-to-curve map, and the [`HashFunction`] used to derive
+//! hash prefixes and blinding scalars. Points and scalars from one suite are meaningless in
+//! another, but are often still valid encodings, so a client talking to a server with a different
+//! suite would silently find no users. Instead, the suite's identifier is embedded in handshakes,
+//! session tokens, replication manifests and snapshots, and sealed directories, and any mismatch
+//! fails with [`Error::SuiteMismatch`].
+
+use std::fmt;
+
+use crate::{Error, HashFunction};
+
+/// A combination of curve, hash-to-curve map, and hash function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Ciphersuite {
+    /// P-256 with SHA-256.
+    #[default]
+    P256Sha256,
+    /// P-256 with SHA-512/256.
+    P256Sha512_256,
+    /// P-256 with BLAKE3.
+    #[cfg(feature = "blake3")]
+    P256Blake3,
+}
+
+impl Ciphersuite {
+    /// The suite's identifier on the wire.
+    pub fn id(self) -> u8 {
+        self.hash_function().id()
+    }
+
+    /// Return the suite with the given identifier, or [`Error::SuiteMismatch`] if it's unknown.
+    pub fn from_id(id: u8) -> Result<Ciphersuite, Error> {
+        HashFunction::from_id(id).map(Ciphersuite::from).ok_or(Error::SuiteMismatch)
+    }
+
+    /// The suite's hash function.
+    pub fn hash_function(self) -> HashFunction {
+        match self {
+            Ciphersuite::P256Sha256 => HashFunction::Sha256,
+            Ciphersuite::P256Sha512_256 => HashFunction::Sha512_256,
+            #[cfg(feature = "blake3")]
+            Ciphersuite::P256Blake3 => HashFunction::Blake3,
+        }
+    }
+
+    /// Return [`Error::SuiteMismatch`] unless the other party's suite is this one.
+    pub fn check(self, other: Ciphersuite) -> Result<(), Error> {
+        if self == other {
+            Ok(())
+        } else {
+            Err(Error::SuiteMismatch)
+        }
+    }
+}
+
+impl From<HashFunction> for Ciphersuite {
+    fn from(value: HashFunction) -> Self {
+        match value {
+            HashFunction::Sha256 => Ciphersuite::P256Sha256,
+            HashFunction::Sha512_256 => Ciphersuite::P256Sha512_256,
+            #[cfg(feature = "blake3")]
+            HashFunction::Blake3 => Ciphersuite::P256Blake3,
+        }
+    }
+}
+
+impl fmt::Display for Ciphersuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ciphersuite::P256Sha256 => write!(f, "P256-SHA256"),
+            Ciphersuite::P256Sha512_256 => write!(f, "P256-SHA512/256"),
+            #[cfg(feature = "blake3")]
+            Ciphersuite::P256Blake3 => write!(f, "P256-BLAKE3"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Client, Server};
+
+    #[test]
+    fn registry() {
+        for suite in [Ciphersuite::P256Sha256, Ciphersuite::P256Sha512_256] {
+            assert_eq!(Ciphersuite::from_id(suite.id()), Ok(suite));
+            assert_eq!(Ciphersuite::from(suite.hash_function()), suite);
+        }
+        assert_eq!(Ciphersuite::from_id(0), Err(Error::SuiteMismatch));
+        assert_eq!(
+            Ciphersuite::P256Sha256.check(Ciphersuite::P256Sha512_256),
+            Err(Error::SuiteMismatch)
+        );
+
+        // Servers refuse handshakes from clients with other suites.
+        let server = Server::new(OsRng, &HashMap::from([(22, Uuid::new_v4())]));
+        let client = Client::new(OsRng);
+        assert!(server.handshake(&client.hello()).is_ok());
+        let client = client.with_hash_function(HashFunction::Sha512_256);
+        assert_eq!(server.handshake(&client.hello()), Err(Error::SuiteMismatch));
+    }
+}
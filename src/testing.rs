@@ -0,0 +1,248 @@
+//! Test doubles for exercising client retry and error paths without a real deployment.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use p256::{elliptic_curve::sec1::FromEncodedPoint, AffinePoint, EncodedPoint};
+use rand::{CryptoRng, RngCore};
+use uuid::Uuid;
+
+use crate::{
+    transport::{BucketResponse, RequestId, Transport, TransportError},
+    Epoch, Prefix, Server,
+};
+
+/// A fault which a [`MockServer`] will inject into its response to a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Respond normally.
+    None,
+    /// Replace the returned points with encodings which are not valid P-256 points.
+    CorruptPoint,
+    /// Respond using the server's previous secret, as if the server's keys had been rotated
+    /// between requests.
+    WrongEpoch,
+    /// Return at most the given number of entries from the bucket.
+    TruncateBucket(usize),
+    /// Reject the request as rate-limited.
+    RateLimited {
+        /// How long the client should wait before retrying.
+        retry_after: Duration,
+    },
+    /// Fail the request with a timeout.
+    Timeout,
+    /// Swap the request IDs of the first two responses to a pipelined batch of bucket requests.
+    TransposeBuckets,
+}
+
+/// A [`Server`] wrapper which can be scripted to misbehave.
+///
+/// Faults are queued with [`MockServer::inject`] and each request consumes the next queued fault,
+/// if any. Faults which don't apply to a request (e.g. [`Fault::TruncateBucket`] for
+/// [`Transport::blind_phone_number`]) are consumed without effect.
+///
+/// The server retains its previous epoch, so requests [pinned](Transport::pin_epoch) to epoch zero
+/// are answered with its previous secret.
+#[derive(Debug)]
+pub struct MockServer {
+    server: Server,
+    stale: Server,
+    pinned: bool,
+    faults: VecDeque<Fault>,
+}
+
+impl MockServer {
+    /// Create a new mock server with the given address book of phone numbers and user IDs.
+    ///
+    /// The server starts at epoch one, and [`Fault::WrongEpoch`] responses are from epoch zero.
+    pub fn new(mut rng: impl CryptoRng + RngCore, users: &HashMap<u64, Uuid>) -> MockServer {
+        let stale = Server::new(&mut rng, users);
+        let server = stale.rotate(&mut rng);
+        MockServer { server, stale, pinned: false, faults: VecDeque::new() }
+    }
+
+    /// Queue a fault to be injected into the response to a future request.
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push_back(fault);
+    }
+
+    /// The number of queued faults which have not yet been injected.
+    pub fn pending_faults(&self) -> usize {
+        self.faults.len()
+    }
+
+    /// The underlying, well-behaved server.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Like [`Server::unblind_user_id`], but subject to the next queued fault.
+    pub fn unblind_user_id(&mut self, s_u: &EncodedPoint) -> Result<Option<Uuid>, TransportError> {
+        let server = if self.pinned { &self.stale } else { &self.server };
+        match self.faults.pop_front() {
+            Some(Fault::RateLimited { retry_after }) => {
+                Err(TransportError::RateLimited { retry_after })
+            }
+            Some(Fault::Timeout) => Err(TransportError::Timeout),
+            Some(Fault::WrongEpoch) => Ok(self.stale.unblind_user_id(s_u)),
+            Some(
+                Fault::None
+                | Fault::CorruptPoint
+                | Fault::TruncateBucket(_)
+                | Fault::TransposeBuckets,
+            )
+            | None => Ok(server.unblind_user_id(s_u)),
+        }
+    }
+}
+
+impl Transport for MockServer {
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        let server = if self.pinned { &self.stale } else { &self.server };
+        let epoch = server.epoch();
+        match self.faults.pop_front() {
+            Some(Fault::RateLimited { retry_after }) => {
+                Err(TransportError::RateLimited { retry_after })
+            }
+            Some(Fault::Timeout) => Err(TransportError::Timeout),
+            Some(Fault::WrongEpoch) => Ok((self.stale.epoch(), self.stale.find_bucket(prefix))),
+            Some(Fault::TruncateBucket(n)) => {
+                Ok((epoch, server.find_bucket(prefix).into_iter().take(n).collect()))
+            }
+            Some(Fault::CorruptPoint) => Ok((
+                epoch,
+                server
+                    .find_bucket(prefix)
+                    .into_iter()
+                    .map(|(s_p, hs_u)| (s_p, corrupt(&hs_u)))
+                    .collect(),
+            )),
+            Some(Fault::None | Fault::TransposeBuckets) | None => {
+                Ok((epoch, server.find_bucket(prefix)))
+            }
+        }
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        let server = if self.pinned { &self.stale } else { &self.server };
+        let epoch = server.epoch();
+        match self.faults.pop_front() {
+            Some(Fault::RateLimited { retry_after }) => {
+                Err(TransportError::RateLimited { retry_after })
+            }
+            Some(Fault::Timeout) => Err(TransportError::Timeout),
+            Some(Fault::WrongEpoch) => {
+                Ok((self.stale.epoch(), self.stale.blind_phone_number(c_p)?))
+            }
+            Some(Fault::CorruptPoint) => Ok((epoch, corrupt(&server.blind_phone_number(c_p)?))),
+            Some(Fault::None | Fault::TruncateBucket(_) | Fault::TransposeBuckets) | None => {
+                Ok((epoch, server.blind_phone_number(c_p)?))
+            }
+        }
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        // Serve either epoch, but no others.
+        if epoch != self.stale.epoch() && epoch != self.server.epoch() {
+            return Err(TransportError::StaleEpoch { current: self.server.epoch() });
+        }
+        self.pinned = epoch == self.stale.epoch();
+        Ok(())
+    }
+
+    fn find_buckets(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<BucketResponse>, TransportError> {
+        if self.faults.front() != Some(&Fault::TransposeBuckets) {
+            return requests
+                .iter()
+                .map(|&(id, prefix)| {
+                    let (epoch, bucket) = self.find_bucket(prefix)?;
+                    Ok(BucketResponse { id, prefix, epoch, bucket })
+                })
+                .collect();
+        }
+
+        // Respond to the whole batch, then swap the first two responses' IDs.
+        self.faults.pop_front();
+        let server = if self.pinned { &self.stale } else { &self.server };
+        let epoch = server.epoch();
+        let mut responses = requests
+            .iter()
+            .map(|&(id, prefix)| BucketResponse {
+                id,
+                prefix,
+                epoch,
+                bucket: server.find_bucket(prefix),
+            })
+            .collect::<Vec<_>>();
+        if let [a, b, ..] = responses.as_mut_slice() {
+            std::mem::swap(&mut a.id, &mut b.id);
+        }
+        Ok(responses)
+    }
+}
+
+/// Perturb the x-coordinate of the given point until it no longer decodes as a P-256 point.
+fn corrupt(p: &EncodedPoint) -> EncodedPoint {
+    let mut buf = [0u8; 33];
+    buf.copy_from_slice(p.as_bytes());
+    loop {
+        buf[32] = buf[32].wrapping_add(1);
+        let corrupted = EncodedPoint::from_bytes(buf).expect("should be a compressed point");
+        if AffinePoint::from_encoded_point(&corrupted).is_none().into() {
+            return corrupted;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{Client, Error};
+
+    #[test]
+    fn faults() {
+        let mut users = HashMap::<u64, Uuid>::new();
+        users.insert(1234567890, Uuid::new_v4());
+        let mut server = MockServer::new(OsRng, &users);
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(1234567890);
+
+        // A rate-limited request fails outright.
+        let retry_after = Duration::from_secs(30);
+        server.inject(Fault::RateLimited { retry_after });
+        assert_eq!(server.find_bucket(prefix), Err(TransportError::RateLimited { retry_after }));
+
+        // A corrupted point is rejected by the client.
+        let (_, bucket) = server.find_bucket(prefix).expect("should not be rate limited");
+        server.inject(Fault::CorruptPoint);
+        let (_, sc_p) = server.blind_phone_number(&c_p).expect("should not be rate limited");
+        assert_eq!(client.find_user_id(&sc_p, &bucket, 1234567890), Err(Error::InvalidPoint));
+
+        // A response from the wrong epoch doesn't match.
+        server.inject(Fault::WrongEpoch);
+        let (epoch, sc_p) = server.blind_phone_number(&c_p).expect("should not be rate limited");
+        assert_eq!(epoch, 0);
+        assert_eq!(client.find_user_id(&sc_p, &bucket, 1234567890), Ok(None));
+
+        // A truncated bucket doesn't match.
+        server.inject(Fault::TruncateBucket(0));
+        let (_, bucket) = server.find_bucket(prefix).expect("should not be rate limited");
+        let (_, sc_p) = server.blind_phone_number(&c_p).expect("should not be rate limited");
+        assert_eq!(client.find_user_id(&sc_p, &bucket, 1234567890), Ok(None));
+
+        assert_eq!(server.pending_faults(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Session tokens which tie the rounds of a lookup together.
+//!
+//! A lookup takes more than one round trip, and a stateless frontend can't otherwise tell whether
+//! an unblind request follows a blinding request or how many phone numbers were looked up. When a
+//! server blinds a batch of phone numbers, it can seal a [`TokenClaims`] into an opaque
+//! [`SessionToken`] with its [`TokenKey`], which the client must present with each later unblind
+//! request. Any frontend holding the key can check the token's authenticity, epoch, and age, and
+//! rate-limit by its claims, without shared state.
+//!
+//! Tokens are sealed with encrypt-then-MAC, using HMAC-SHA256 both as a keystream generator and as
+//! the MAC, with a random nonce per token.
+
+use std::{fmt, time::Duration};
+
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+
+use crate::{suite::Ciphersuite, Epoch, Error};
+
+/// The length of a [`SessionToken`], in bytes.
+pub const TOKEN_LEN: usize = NONCE_LEN + CLAIMS_LEN + TAG_LEN;
+
+const NONCE_LEN: usize = 16;
+const CLAIMS_LEN: usize = 1 + 8 + 8 + 4;
+const TAG_LEN: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The claims sealed in a [`SessionToken`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenClaims {
+    /// The ciphersuite of the server which issued the token.
+    pub suite: Ciphersuite,
+    /// The epoch of the server which issued the token.
+    pub epoch: Epoch,
+    /// When the token was issued, in seconds since the Unix epoch.
+    pub issued_at: u64,
+    /// The number of phone numbers blinded in the round which issued the token.
+    pub lookups: u32,
+}
+
+impl TokenClaims {
+    /// Returns `true` if a token issued with these claims is older than `ttl` at `now`, in seconds
+    /// since the Unix epoch.
+    pub fn is_expired(&self, now: u64, ttl: Duration) -> bool {
+        now.saturating_sub(self.issued_at) > ttl.as_secs()
+    }
+
+    fn encode(&self) -> [u8; CLAIMS_LEN] {
+        let mut b = [0u8; CLAIMS_LEN];
+        b[0] = self.suite.id();
+        b[1..9].copy_from_slice(&self.epoch.to_be_bytes());
+        b[9..17].copy_from_slice(&self.issued_at.to_be_bytes());
+        b[17..].copy_from_slice(&self.lookups.to_be_bytes());
+        b
+    }
+
+    fn decode(b: &[u8; CLAIMS_LEN]) -> Result<TokenClaims, Error> {
+        Ok(TokenClaims {
+            suite: Ciphersuite::from_id(b[0])?,
+            epoch: Epoch::from_be_bytes(b[1..9].try_into().expect("should be 8 bytes")),
+            issued_at: u64::from_be_bytes(b[9..17].try_into().expect("should be 8 bytes")),
+            lookups: u32::from_be_bytes(b[17..].try_into().expect("should be 4 bytes")),
+        })
+    }
+}
+
+/// An opaque, sealed session token.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionToken([u8; TOKEN_LEN]);
+
+impl SessionToken {
+    /// The encoded token.
+    pub fn as_bytes(&self) -> &[u8; TOKEN_LEN] {
+        &self.0
+    }
+
+    /// Decode a token. Its authenticity is only checked when it's [opened](TokenKey::open).
+    pub fn from_bytes(b: &[u8]) -> Result<SessionToken, Error> {
+        b.try_into().map(SessionToken).map_err(|_| Error::InvalidMessage)
+    }
+}
+
+/// A secret key for sealing and opening [`SessionToken`]s.
+#[derive(Clone)]
+pub struct TokenKey {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl TokenKey {
+    /// Create a new random key.
+    pub fn random(mut rng: impl CryptoRng + RngCore) -> TokenKey {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        TokenKey::from_bytes(&key)
+    }
+
+    /// Derive a key from the given secret, e.g. one shared by a fleet of frontends.
+    pub fn from_bytes(secret: &[u8; 32]) -> TokenKey {
+        let derive = |label: &[u8]| -> [u8; 32] {
+            HmacSha256::new_from_slice(secret)
+                .expect("should accept any key length")
+                .chain_update(label)
+                .finalize()
+                .into_bytes()
+                .into()
+        };
+        TokenKey { enc: derive(b"zk-cds-token-enc"), mac: derive(b"zk-cds-token-mac") }
+    }
+
+    /// Seal the given claims into a token.
+    pub fn seal(&self, mut rng: impl CryptoRng + RngCore, claims: &TokenClaims) -> SessionToken {
+        let mut b = [0u8; TOKEN_LEN];
+        let (nonce, rest) = b.split_at_mut(NONCE_LEN);
+        let (ct, tag) = rest.split_at_mut(CLAIMS_LEN);
+        rng.fill_bytes(nonce);
+
+        // Encrypt the claims, then MAC the nonce and ciphertext.
+        ct.copy_from_slice(&claims.encode());
+        self.apply_keystream(nonce, ct);
+        tag.copy_from_slice(&self.mac(nonce, ct).finalize().into_bytes()[..TAG_LEN]);
+        SessionToken(b)
+    }
+
+    /// Open the given token, returning [`Error::Unauthorized`] if it wasn't sealed with this key.
+    pub fn open(&self, token: &SessionToken) -> Result<TokenClaims, Error> {
+        let (nonce, rest) = token.0.split_at(NONCE_LEN);
+        let (ct, tag) = rest.split_at(CLAIMS_LEN);
+
+        // Check the MAC in constant time before decrypting anything.
+        self.mac(nonce, ct).verify_truncated_left(tag).map_err(|_| Error::Unauthorized)?;
+        let mut claims: [u8; CLAIMS_LEN] = ct.try_into().expect("should be claims-sized");
+        self.apply_keystream(nonce, &mut claims);
+        TokenClaims::decode(&claims)
+    }
+
+    fn mac(&self, nonce: &[u8], ct: &[u8]) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.mac)
+            .expect("should accept any key length")
+            .chain_update(nonce)
+            .chain_update(ct)
+    }
+
+    fn apply_keystream(&self, nonce: &[u8], buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(32).enumerate() {
+            let block = HmacSha256::new_from_slice(&self.enc)
+                .expect("should accept any key length")
+                .chain_update(nonce)
+                .chain_update((i as u32).to_be_bytes())
+                .finalize()
+                .into_bytes();
+            for (b, k) in chunk.iter_mut().zip(block) {
+                *b ^= k;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenKey").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn sealing() {
+        let key = TokenKey::random(OsRng);
+        let claims =
+            TokenClaims { suite: Ciphersuite::default(), epoch: 3, issued_at: 1_000, lookups: 22 };
+        let token = key.seal(OsRng, &claims);
+        let token = SessionToken::from_bytes(token.as_bytes()).expect("should be a token");
+        assert_eq!(key.open(&token), Ok(claims));
+        assert!(!claims.is_expired(1_060, Duration::from_secs(60)));
+        assert!(claims.is_expired(1_061, Duration::from_secs(60)));
+
+        // Tokens are opaque and unique.
+        assert_ne!(&token.as_bytes()[NONCE_LEN..NONCE_LEN + CLAIMS_LEN], &claims.encode());
+        assert_ne!(key.seal(OsRng, &claims), token);
+
+        // Tampered tokens and tokens sealed with other keys are rejected.
+        let mut b = *token.as_bytes();
+        b[NONCE_LEN] ^= 1;
+        assert_eq!(key.open(&SessionToken(b)), Err(Error::Unauthorized));
+        assert_eq!(TokenKey::random(OsRng).open(&token), Err(Error::Unauthorized));
+        assert_eq!(SessionToken::from_bytes(&b[1..]), Err(Error::InvalidMessage));
+    }
+}
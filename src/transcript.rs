@@ -0,0 +1,428 @@
+//! Recording and replaying the bytes a client exchanges with a server.
+//!
+//! Interop bugs between this crate and third-party clients are usually a byte out of place, which
+//! is hard to see from either side's logs. A [`TranscriptRecorder`] wraps a [`Transport`] and
+//! records each request and response as it would be encoded on the wire, errors included, in a
+//! [`Transcript`]. A [`TranscriptReplayer`] then answers the same requests with the recorded
+//! responses, so a session can be re-run deterministically (e.g. with a seeded RNG) without a
+//! server, and fails any request which differs from the recorded one with
+//! [`TransportError::InvalidMessage`]. Conversely, [`Transcript::verify`] resends the recorded
+//! requests to a server and checks it still sends the recorded responses, byte for byte.
+//!
+//! Hash prefixes and client-blinded phone number points are derived from the phone numbers being
+//! looked up, so a recorder [with redaction](TranscriptRecorder::with_redaction) replaces them with
+//! zeros. Redacted requests match any request for the same call when replayed.
+
+use std::collections::{HashMap, VecDeque};
+
+use p256::EncodedPoint;
+
+use crate::{
+    decode_bucket, encode_bucket,
+    encoding::POINT_LEN,
+    protocol::{ClientHello, ServerHello},
+    transport::{Transport, TransportError},
+    wire::WireError,
+    Epoch, Error, Prefix,
+};
+
+/// A call to a [`Transport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Call {
+    /// [`Transport::handshake`].
+    Handshake = 1,
+    /// [`Transport::find_bucket`].
+    FindBucket = 2,
+    /// [`Transport::blind_phone_number`].
+    BlindPhoneNumber = 3,
+    /// [`Transport::pin_epoch`].
+    PinEpoch = 4,
+}
+
+impl Call {
+    fn from_u8(v: u8) -> Option<Call> {
+        match v {
+            1 => Some(Call::Handshake),
+            2 => Some(Call::FindBucket),
+            3 => Some(Call::BlindPhoneNumber),
+            4 => Some(Call::PinEpoch),
+            _ => None,
+        }
+    }
+}
+
+/// A request and its response, as encoded on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    /// The call which made the request.
+    pub call: Call,
+    /// The encoded request.
+    pub request: Vec<u8>,
+    /// The encoded response: a zero byte followed by the response if the request succeeded, or
+    /// the encoded error if it failed.
+    pub response: Vec<u8>,
+    /// Whether the request's contents were redacted.
+    pub redacted: bool,
+}
+
+/// A sequence of exchanges between a client and a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// The exchanges, in the order they were made.
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Transcript {
+    /// Encode the transcript as a sequence of exchanges, each a call byte, a redaction flag byte,
+    /// and the request and response, each prefixed with its big-endian `u32` length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        for exchange in &self.exchanges {
+            b.extend([exchange.call as u8, u8::from(exchange.redacted)]);
+            for msg in [&exchange.request, &exchange.response] {
+                let len = u32::try_from(msg.len()).expect("should be shorter than 4GiB");
+                b.extend_from_slice(&len.to_be_bytes());
+                b.extend_from_slice(msg);
+            }
+        }
+        b
+    }
+
+    /// Decode a transcript.
+    pub fn decode(mut b: &[u8]) -> Result<Transcript, Error> {
+        let mut exchanges = Vec::new();
+        while let Some((&[call, redacted], rest)) = b.split_first_chunk() {
+            let call = Call::from_u8(call).ok_or(Error::InvalidMessage)?;
+            let redacted = match redacted {
+                0 => false,
+                1 => true,
+                _ => return Err(Error::InvalidMessage),
+            };
+            let (request, rest) = split_message(rest)?;
+            let (response, rest) = split_message(rest)?;
+            exchanges.push(Exchange { call, request, response, redacted });
+            b = rest;
+        }
+        if !b.is_empty() {
+            return Err(Error::InvalidMessage);
+        }
+        Ok(Transcript { exchanges })
+    }
+
+    /// Send each recorded request to the given transport, and check its response is byte for byte
+    /// the recorded one. Returns the index of the first exchange whose response differs, or which
+    /// can't be resent because it's redacted or malformed.
+    pub fn verify(&self, transport: impl Transport) -> Result<(), usize> {
+        let mut recorder = TranscriptRecorder::new(transport);
+        for (i, exchange) in self.exchanges.iter().enumerate() {
+            if exchange.redacted || resend(&mut recorder, exchange).is_err() {
+                return Err(i);
+            }
+            let resent = recorder.transcript.exchanges.last().expect("should have an exchange");
+            if resent != exchange {
+                return Err(i);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode the exchange's request and send it again with the given recorder, ignoring the response.
+fn resend<T: Transport>(
+    recorder: &mut TranscriptRecorder<T>,
+    exchange: &Exchange,
+) -> Result<(), Error> {
+    let b = exchange.request.as_slice();
+    let _ = match exchange.call {
+        Call::Handshake => recorder.handshake(&ClientHello::decode(b)?).map(drop),
+        Call::FindBucket => {
+            recorder.find_bucket(b.try_into().map_err(|_| Error::InvalidMessage)?).map(drop)
+        }
+        Call::BlindPhoneNumber => recorder
+            .blind_phone_number(&EncodedPoint::from_bytes(b).map_err(|_| Error::InvalidPoint)?)
+            .map(drop),
+        Call::PinEpoch => recorder
+            .pin_epoch(Epoch::from_be_bytes(b.try_into().map_err(|_| Error::InvalidMessage)?)),
+    };
+    Ok(())
+}
+
+/// Split a length-prefixed message from the start of `b`.
+fn split_message(b: &[u8]) -> Result<(Vec<u8>, &[u8]), Error> {
+    let (len, b) = b.split_first_chunk::<4>().ok_or(Error::InvalidMessage)?;
+    let len = usize::try_from(u32::from_be_bytes(*len)).map_err(|_| Error::InvalidMessage)?;
+    let (msg, rest) = b.split_at_checked(len).ok_or(Error::InvalidMessage)?;
+    Ok((msg.to_vec(), rest))
+}
+
+/// A [`Transport`] wrapper which records a [`Transcript`] of its requests and responses.
+///
+/// Pipelined bucket requests are recorded as one [`Call::FindBucket`] exchange per request.
+#[derive(Debug)]
+pub struct TranscriptRecorder<T> {
+    inner: T,
+    redact: bool,
+    transcript: Transcript,
+}
+
+impl<T: Transport> TranscriptRecorder<T> {
+    /// Create a recorder which records the requests made through the given transport.
+    pub fn new(inner: T) -> TranscriptRecorder<T> {
+        TranscriptRecorder { inner, redact: false, transcript: Transcript::default() }
+    }
+
+    /// Record hash prefixes and client-blinded points as zeros.
+    pub fn with_redaction(self) -> TranscriptRecorder<T> {
+        TranscriptRecorder { redact: true, ..self }
+    }
+
+    /// The transcript recorded so far.
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    /// Return the recorded transcript.
+    pub fn into_transcript(self) -> Transcript {
+        self.transcript
+    }
+
+    /// Record the given exchange, redacting the request if it's derived from phone numbers.
+    fn record<R>(
+        &mut self,
+        call: Call,
+        mut request: Vec<u8>,
+        res: Result<R, TransportError>,
+        encode: impl FnOnce(&R) -> Vec<u8>,
+    ) -> Result<R, TransportError> {
+        let redacted = self.redact && matches!(call, Call::FindBucket | Call::BlindPhoneNumber);
+        if redacted {
+            request.fill(0);
+        }
+        let response = match &res {
+            Ok(resp) => [vec![0], encode(resp)].concat(),
+            Err(err) => encode_error(*err),
+        };
+        self.transcript.exchanges.push(Exchange { call, request, response, redacted });
+        res
+    }
+}
+
+impl<T: Transport> Transport for TranscriptRecorder<T> {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        let res = self.inner.handshake(hello);
+        self.record(Call::Handshake, hello.encode(), res, ServerHello::encode)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        let res = self.inner.find_bucket(prefix);
+        self.record(Call::FindBucket, prefix.to_vec(), res, |(epoch, bucket)| {
+            [epoch.to_be_bytes().to_vec(), encode_bucket(bucket)].concat()
+        })
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        let res = self.inner.blind_phone_number(c_p);
+        self.record(Call::BlindPhoneNumber, c_p.as_bytes().to_vec(), res, |(epoch, sc_p)| {
+            [&epoch.to_be_bytes(), sc_p.as_bytes()].concat()
+        })
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        let res = self.inner.pin_epoch(epoch);
+        self.record(Call::PinEpoch, epoch.to_be_bytes().to_vec(), res, |_| Vec::new())
+    }
+}
+
+/// A [`Transport`] which answers requests with the responses from a [`Transcript`], in order.
+#[derive(Debug)]
+pub struct TranscriptReplayer {
+    exchanges: VecDeque<Exchange>,
+}
+
+impl TranscriptReplayer {
+    /// Create a replayer for the given transcript.
+    pub fn new(transcript: Transcript) -> TranscriptReplayer {
+        TranscriptReplayer { exchanges: transcript.exchanges.into() }
+    }
+
+    /// The number of recorded exchanges which haven't been replayed.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    /// Check the request matches the next recorded one, and return its recorded response.
+    fn replay(&mut self, call: Call, request: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let exchange = self.exchanges.pop_front().ok_or(TransportError::InvalidMessage)?;
+        let matches = exchange.call == call
+            && exchange.request.len() == request.len()
+            && (exchange.redacted || exchange.request == request);
+        if !matches {
+            return Err(TransportError::InvalidMessage);
+        }
+        match exchange.response.split_first() {
+            Some((0, resp)) => Ok(resp.to_vec()),
+            _ => Err(decode_error(&exchange.response)?),
+        }
+    }
+}
+
+impl Transport for TranscriptReplayer {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        let resp = self.replay(Call::Handshake, &hello.encode())?;
+        Ok(ServerHello::decode(&resp)?)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        let resp = self.replay(Call::FindBucket, &prefix)?;
+        let (epoch, bucket) = split_epoch(&resp)?;
+        Ok((epoch, decode_bucket(bucket)?))
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        let resp = self.replay(Call::BlindPhoneNumber, c_p.as_bytes())?;
+        let (epoch, sc_p) = split_epoch(&resp)?;
+        if sc_p.len() != POINT_LEN {
+            return Err(TransportError::InvalidMessage);
+        }
+        Ok((epoch, EncodedPoint::from_bytes(sc_p).map_err(|_| TransportError::InvalidPoint)?))
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        match self.replay(Call::PinEpoch, &epoch.to_be_bytes())?.as_slice() {
+            [] => Ok(()),
+            _ => Err(TransportError::InvalidMessage),
+        }
+    }
+}
+
+/// Split the epoch from the start of a response.
+fn split_epoch(b: &[u8]) -> Result<(Epoch, &[u8]), TransportError> {
+    let (epoch, rest) = b.split_first_chunk::<8>().ok_or(TransportError::InvalidMessage)?;
+    Ok((Epoch::from_be_bytes(*epoch), rest))
+}
+
+/// The errors which clients detect themselves, rather than receiving them as [`WireError`]s, in
+/// the order of their codes.
+const LOCAL_ERRORS: [TransportError; 8] = [
+    TransportError::Timeout,
+    TransportError::Unauthorized,
+    TransportError::InvalidMessage,
+    TransportError::NotReady,
+    TransportError::SuiteMismatch,
+    TransportError::CorruptEntry,
+    TransportError::BucketMismatch,
+    TransportError::Disconnected,
+];
+
+/// The first code used for errors which aren't [`WireError`]s.
+const LOCAL_ERROR_BASE: u8 = 0x80;
+
+/// Encode an error as a [`WireError`] if the server would send it as one, or as a local error code
+/// followed by a zero argument otherwise.
+fn encode_error(err: TransportError) -> Vec<u8> {
+    if let Ok(err) = WireError::try_from(err) {
+        return err.encode();
+    }
+    let i = LOCAL_ERRORS.iter().position(|&e| e == err).expect("should be a local error");
+    let mut b = vec![0; 9];
+    b[0] = LOCAL_ERROR_BASE + i as u8;
+    b
+}
+
+/// Decode an error encoded with [`encode_error`].
+fn decode_error(b: &[u8]) -> Result<TransportError, TransportError> {
+    match b.first() {
+        Some(&code) if code >= LOCAL_ERROR_BASE && b[1..] == [0; 8] => LOCAL_ERRORS
+            .get(usize::from(code - LOCAL_ERROR_BASE))
+            .copied()
+            .ok_or(TransportError::InvalidMessage),
+        _ => Ok(WireError::decode(b)?.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        session::{ClientSession, RetryPolicy},
+        testing::{Fault, MockServer},
+    };
+
+    #[test]
+    fn record_and_replay() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let mut server = MockServer::new(ChaChaRng::seed_from_u64(1), &users);
+        server.inject(Fault::RateLimited { retry_after: Duration::ZERO });
+        let policy = RetryPolicy {
+            max_retries: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+
+        // Record a lookup which is retried after being rate limited.
+        let mut recorder = TranscriptRecorder::new(&mut server);
+        let mut session = ClientSession::new(&mut recorder, ChaChaRng::seed_from_u64(2))
+            .with_retry_policy(policy);
+        let recorded = session.lookup(22).expect("should succeed");
+        let transcript = recorder.into_transcript();
+        assert!(transcript.exchanges.iter().any(|e| e.response[0] != 0));
+        let transcript = Transcript::decode(&transcript.encode()).expect("should decode");
+
+        // The same session replays byte for byte, without the server.
+        let mut replayer = TranscriptReplayer::new(transcript.clone());
+        let mut session = ClientSession::new(&mut replayer, ChaChaRng::seed_from_u64(2))
+            .with_retry_policy(policy);
+        assert_eq!(session.lookup(22), Ok(recorded));
+        assert_eq!(replayer.remaining(), 0);
+
+        // A session which sends different bytes doesn't.
+        let mut replayer = TranscriptReplayer::new(transcript);
+        let mut session = ClientSession::new(&mut replayer, ChaChaRng::seed_from_u64(3))
+            .with_retry_policy(policy);
+        assert_eq!(session.lookup(22), Err(TransportError::InvalidMessage));
+
+        // Errors round trip, whether they're sent on the wire or not.
+        let retry_after = Duration::from_millis(1234);
+        for err in [TransportError::RateLimited { retry_after }].into_iter().chain(LOCAL_ERRORS) {
+            assert_eq!(decode_error(&encode_error(err)), Ok(err));
+        }
+    }
+
+    #[test]
+    fn redaction() {
+        let users = HashMap::from([(22, Uuid::new_v4())]);
+        let mut server = MockServer::new(ChaChaRng::seed_from_u64(1), &users);
+        let mut recorder = TranscriptRecorder::new(&mut server).with_redaction();
+        let mut session = ClientSession::new(&mut recorder, ChaChaRng::seed_from_u64(2));
+        let recorded = session.lookup(22).expect("should succeed");
+        let transcript = recorder.into_transcript();
+
+        // Nothing derived from the phone number is recorded, but the session still replays.
+        for exchange in &transcript.exchanges {
+            if exchange.call == Call::FindBucket || exchange.call == Call::BlindPhoneNumber {
+                assert!(exchange.redacted && exchange.request.iter().all(|&b| b == 0));
+            }
+        }
+        let mut replayer = TranscriptReplayer::new(transcript);
+        let mut session = ClientSession::new(&mut replayer, ChaChaRng::seed_from_u64(2));
+        assert_eq!(session.lookup(22), Ok(recorded));
+    }
+}
@@ -0,0 +1,388 @@
+//! The client's view of a connection to a server.
+
+use std::{collections::HashMap, fmt, hash::BuildHasher, time::Duration};
+
+use p256::EncodedPoint;
+use rand::{CryptoRng, RngCore};
+
+use crate::{
+    protocol::{ClientHello, ProtocolVersion, ServerHello},
+    Epoch, Error, Prefix, Server, SUB_PREFIX_LEN,
+};
+
+/// A connection to a server, as used by a [`ClientSession`](crate::session::ClientSession).
+///
+/// Every response is tagged with the epoch of the server secret used to produce it.
+pub trait Transport {
+    /// Negotiate a protocol version with the server.
+    ///
+    /// The default implementation is for servers which predate version negotiation, and only
+    /// accepts [`ProtocolVersion::V1`].
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        Ok(hello.negotiate(&[ProtocolVersion::V1])?)
+    }
+
+    /// Find the bucket of users with the given hash prefix.
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError>;
+
+    /// Double-blind the given client-blinded phone number point.
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError>;
+
+    /// Find the bucket of users with the given hash prefix, or learn that it was
+    /// [split](Server::is_split) and must be re-queried by sub-prefix.
+    ///
+    /// The default implementation is for servers which never split buckets, and returns
+    /// [`Transport::find_bucket`]'s bucket.
+    fn find_bucket_or_split(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, FoundBucket), TransportError> {
+        let (epoch, bucket) = self.find_bucket(prefix)?;
+        Ok((epoch, FoundBucket::Bucket(bucket)))
+    }
+
+    /// Pin the connection's later requests to the given epoch, so a sync spanning many requests
+    /// sees one consistent view of the directory. Servers which no longer serve the epoch fail with
+    /// [`TransportError::StaleEpoch`].
+    ///
+    /// The default implementation is for servers which only serve their current epoch, and does
+    /// nothing: responses are still tagged with their epoch, so callers can detect a rotation.
+    fn pin_epoch(&mut self, _epoch: Epoch) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    /// Find the buckets for a pipelined batch of requests, each with its ID and hash prefix.
+    /// Responses may be returned in any order, and echo their request's ID and prefix.
+    ///
+    /// The default implementation sends each request with [`Transport::find_bucket`] in turn.
+    fn find_buckets(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<BucketResponse>, TransportError> {
+        requests
+            .iter()
+            .map(|&(id, prefix)| {
+                let (epoch, bucket) = self.find_bucket(prefix)?;
+                Ok(BucketResponse { id, prefix, epoch, bucket })
+            })
+            .collect()
+    }
+
+    /// Like [`Transport::find_buckets`], but learn which of the buckets were
+    /// [split](Server::is_split), as [`Transport::find_bucket_or_split`] does.
+    ///
+    /// The default implementation is for servers which never split buckets, and returns
+    /// [`Transport::find_buckets`]'s buckets.
+    fn find_buckets_or_split(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<FoundBucketResponse>, TransportError> {
+        Ok(self
+            .find_buckets(requests)?
+            .into_iter()
+            .map(|resp| FoundBucketResponse {
+                id: resp.id,
+                prefix: resp.prefix,
+                epoch: resp.epoch,
+                bucket: FoundBucket::Bucket(resp.bucket),
+            })
+            .collect())
+    }
+}
+
+/// A bucket, or a redirection to the sub-buckets it was split into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FoundBucket {
+    /// The bucket of users with the prefix.
+    Bucket(HashMap<EncodedPoint, EncodedPoint>),
+    /// The bucket was split, and clients should re-query the sub-bucket with the prefix of this
+    /// many more bytes of their phone number's hash.
+    Split(u8),
+}
+
+/// The ID of a request, which its response echoes so the two can be matched up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub u64);
+
+/// A key which a client sends with every attempt of the same request, so the server can answer
+/// retries with its original response instead of doing (and charging for) the work again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(pub [u8; 16]);
+
+impl IdempotencyKey {
+    /// Generate a random key.
+    pub fn generate(mut rng: impl CryptoRng + RngCore) -> IdempotencyKey {
+        let mut key = [0u8; 16];
+        rng.fill_bytes(&mut key);
+        IdempotencyKey(key)
+    }
+}
+
+/// The length of an encoded [`RequestHeader`], in bytes.
+pub const REQUEST_HEADER_LEN: usize = 8 + 1 + 16;
+
+/// The header of a request on the wire: its ID and, if it may be retried, its idempotency key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestHeader {
+    /// The request's ID, which its response echoes.
+    pub id: RequestId,
+    /// The request's idempotency key, if any.
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+impl RequestHeader {
+    /// Encode the header as the request ID, a flag byte, and the idempotency key (or zeros).
+    pub fn encode(&self) -> [u8; REQUEST_HEADER_LEN] {
+        let mut b = [0u8; REQUEST_HEADER_LEN];
+        b[..8].copy_from_slice(&self.id.0.to_be_bytes());
+        if let Some(key) = self.idempotency_key {
+            b[8] = 1;
+            b[9..].copy_from_slice(&key.0);
+        }
+        b
+    }
+
+    /// Decode a header from the start of a message, returning it and the rest of the message.
+    pub fn decode(b: &[u8]) -> Result<(RequestHeader, &[u8]), Error> {
+        if b.len() < REQUEST_HEADER_LEN {
+            return Err(Error::InvalidMessage);
+        }
+        let (header, rest) = b.split_at(REQUEST_HEADER_LEN);
+        let id = RequestId(u64::from_be_bytes(header[..8].try_into().expect("should be 8 bytes")));
+        let key = IdempotencyKey(header[9..].try_into().expect("should be 16 bytes"));
+        let idempotency_key = match header[8] {
+            0 if key.0 == [0; 16] => None,
+            1 => Some(key),
+            _ => return Err(Error::InvalidMessage),
+        };
+        Ok((RequestHeader { id, idempotency_key }, rest))
+    }
+}
+
+/// A response to a pipelined bucket request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketResponse {
+    /// The ID of the request.
+    pub id: RequestId,
+    /// The hash prefix of the request.
+    pub prefix: Prefix,
+    /// The epoch of the server which returned the bucket.
+    pub epoch: Epoch,
+    /// The bucket of users with the prefix.
+    pub bucket: HashMap<EncodedPoint, EncodedPoint>,
+}
+
+/// A response to a pipelined bucket request, which may redirect to a sub-bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundBucketResponse {
+    /// The ID of the request.
+    pub id: RequestId,
+    /// The hash prefix of the request.
+    pub prefix: Prefix,
+    /// The epoch of the server which returned the bucket.
+    pub epoch: Epoch,
+    /// The bucket of users with the prefix, or a redirection to its sub-buckets.
+    pub bucket: FoundBucket,
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        (**self).handshake(hello)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        (**self).find_bucket(prefix)
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        (**self).blind_phone_number(c_p)
+    }
+
+    fn find_bucket_or_split(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, FoundBucket), TransportError> {
+        (**self).find_bucket_or_split(prefix)
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        (**self).pin_epoch(epoch)
+    }
+
+    fn find_buckets(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<BucketResponse>, TransportError> {
+        (**self).find_buckets(requests)
+    }
+
+    fn find_buckets_or_split(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<FoundBucketResponse>, TransportError> {
+        (**self).find_buckets_or_split(requests)
+    }
+}
+
+/// An in-process transport which talks directly to a [`Server`].
+impl<S: BuildHasher + Clone> Transport for &Server<S> {
+    fn handshake(&mut self, hello: &ClientHello) -> Result<ServerHello, TransportError> {
+        Ok(Server::handshake(self, hello)?)
+    }
+
+    fn find_bucket(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, HashMap<EncodedPoint, EncodedPoint>), TransportError> {
+        Ok((self.epoch(), Server::find_bucket(self, prefix).into_iter().collect()))
+    }
+
+    fn blind_phone_number(
+        &mut self,
+        c_p: &EncodedPoint,
+    ) -> Result<(Epoch, EncodedPoint), TransportError> {
+        Ok((self.epoch(), Server::blind_phone_number(self, c_p)?))
+    }
+
+    fn find_bucket_or_split(
+        &mut self,
+        prefix: Prefix,
+    ) -> Result<(Epoch, FoundBucket), TransportError> {
+        if self.is_split(prefix) {
+            return Ok((self.epoch(), FoundBucket::Split(SUB_PREFIX_LEN as u8)));
+        }
+        let (epoch, bucket) = self.find_bucket(prefix)?;
+        Ok((epoch, FoundBucket::Bucket(bucket)))
+    }
+
+    fn find_buckets_or_split(
+        &mut self,
+        requests: &[(RequestId, Prefix)],
+    ) -> Result<Vec<FoundBucketResponse>, TransportError> {
+        requests
+            .iter()
+            .map(|&(id, prefix)| {
+                let (epoch, bucket) = self.find_bucket_or_split(prefix)?;
+                Ok(FoundBucketResponse { id, prefix, epoch, bucket })
+            })
+            .collect()
+    }
+
+    fn pin_epoch(&mut self, epoch: Epoch) -> Result<(), TransportError> {
+        // A single server only retains its own epoch.
+        if epoch != self.epoch() {
+            return Err(TransportError::StaleEpoch { current: self.epoch() });
+        }
+        Ok(())
+    }
+}
+
+/// An error returned by a [`Transport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    /// The request timed out.
+    Timeout,
+    /// The request was rejected as rate-limited.
+    RateLimited {
+        /// How long the client should wait before retrying.
+        retry_after: Duration,
+    },
+    /// The request mixed responses from different epochs.
+    StaleEpoch {
+        /// The server's current epoch.
+        current: Epoch,
+    },
+    /// A point was not a valid encoding of a point on the P-256 curve.
+    InvalidPoint,
+    /// The client was not authorized to make the request.
+    Unauthorized,
+    /// A message was not validly encoded.
+    InvalidMessage,
+    /// The client and server have no protocol version in common.
+    VersionMismatch,
+    /// The server hasn't loaded an epoch yet.
+    NotReady,
+    /// The client and server use different ciphersuites.
+    SuiteMismatch,
+    /// The shard responsible for the request is unavailable.
+    ShardUnavailable,
+    /// The client has used up its quota of lookups.
+    QuotaExhausted,
+    /// A bucket entry was corrupted or substituted.
+    CorruptEntry,
+    /// A bucket response didn't match the request it was returned for.
+    BucketMismatch,
+    /// The connection to the server was lost.
+    Disconnected,
+}
+
+impl TransportError {
+    /// Returns `true` if the request may succeed if retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TransportError::Timeout
+                | TransportError::RateLimited { .. }
+                | TransportError::StaleEpoch { .. }
+                | TransportError::NotReady
+                | TransportError::ShardUnavailable
+                | TransportError::Disconnected
+        )
+    }
+}
+
+impl From<Error> for TransportError {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::InvalidPoint => TransportError::InvalidPoint,
+            Error::InvalidMessage => TransportError::InvalidMessage,
+            Error::VersionMismatch => TransportError::VersionMismatch,
+            Error::NotReady => TransportError::NotReady,
+            Error::Unauthorized => TransportError::Unauthorized,
+            Error::SuiteMismatch => TransportError::SuiteMismatch,
+            Error::CorruptEntry => TransportError::CorruptEntry,
+            Error::BucketMismatch => TransportError::BucketMismatch,
+            Error::QuotaExhausted => TransportError::QuotaExhausted,
+            Error::DeadlineExceeded => TransportError::Timeout,
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "request timed out"),
+            TransportError::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {retry_after:?}")
+            }
+            TransportError::StaleEpoch { current } => {
+                write!(f, "stale epoch, current epoch is {current}")
+            }
+            TransportError::InvalidPoint => write!(f, "invalid point encoding"),
+            TransportError::Unauthorized => write!(f, "unauthorized"),
+            TransportError::InvalidMessage => write!(f, "invalid message encoding"),
+            TransportError::VersionMismatch => write!(f, "no mutually supported protocol version"),
+            TransportError::NotReady => write!(f, "server not ready"),
+            TransportError::SuiteMismatch => write!(f, "mismatched ciphersuites"),
+            TransportError::ShardUnavailable => write!(f, "shard unavailable"),
+            TransportError::QuotaExhausted => write!(f, "lookup quota exhausted"),
+            TransportError::CorruptEntry => write!(f, "corrupt bucket entry"),
+            TransportError::BucketMismatch => write!(f, "bucket does not match request"),
+            TransportError::Disconnected => write!(f, "disconnected from server"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
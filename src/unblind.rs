@@ -0,0 +1,255 @@
+//! Strict unblinding, which only unblinds user IDs for clients which looked up their phone numbers.
+//!
+//! [`Server::unblind_user_id`] unblinds any point handed to it, so anyone who obtains a blinded
+//! user ID point (e.g. from a downloaded bucket) can use the server as an oracle to decrypt it. In
+//! strict mode, the client instead sends an [`UnblindRequest`] with its client-blinded phone number
+//! point `cP`, the server-blinded point `sP` it found in the bucket, and a Schnorr proof that it
+//! knows `c` such that `c·sP = s·cP`. Producing one requires knowing the phone number's point `P`,
+//! so the request is only as useful as a lookup of the phone number, which is rate-limited.
+//!
+//! The server finds the entry for `sP` itself and returns `h·U`, the user ID point still blinded
+//! by the phone number's hash, which only the client can remove with
+//! [`Client::finish_unblind`]. The server thus never learns which user ID was found.
+
+use std::hash::BuildHasher;
+
+use p256::{
+    elliptic_curve::{ops::ReduceNonZero, sec1::ToEncodedPoint, Field, PrimeField},
+    EncodedPoint, FieldBytes, ProjectivePoint, Scalar,
+};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    decode_point,
+    encoding::BucketLookup,
+    user_id::{check_uuid, tagged_point_id, Capabilities, OpaqueId},
+    Client, Error, Prefix, Server, PREFIX_LEN,
+};
+
+/// The length of an encoded [`UnblindRequest`], in bytes.
+pub const UNBLIND_REQUEST_LEN: usize = PREFIX_LEN + 4 + 3 * 33 + 32;
+
+/// A request to unblind the user ID for a phone number the client looked up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnblindRequest {
+    /// The hash prefix of the phone number.
+    pub prefix: Prefix,
+    /// The client-blinded phone number point.
+    pub c_p: EncodedPoint,
+    /// The server-blinded phone number point found in the bucket.
+    pub s_p: EncodedPoint,
+    /// Which of the phone number's user IDs to unblind, for servers which keep all of them.
+    pub index: u32,
+    r: EncodedPoint,
+    z: Scalar,
+}
+
+impl UnblindRequest {
+    /// Encode the request.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(UNBLIND_REQUEST_LEN);
+        b.extend_from_slice(&self.prefix);
+        b.extend_from_slice(&self.index.to_be_bytes());
+        b.extend_from_slice(self.c_p.as_bytes());
+        b.extend_from_slice(self.s_p.as_bytes());
+        b.extend_from_slice(self.r.as_bytes());
+        b.extend_from_slice(&self.z.to_bytes());
+        b
+    }
+
+    /// Decode a request.
+    pub fn decode(b: &[u8]) -> Result<UnblindRequest, Error> {
+        if b.len() != UNBLIND_REQUEST_LEN {
+            return Err(Error::InvalidMessage);
+        }
+        let (prefix, b) = b.split_at(PREFIX_LEN);
+        let (index, b) = b.split_at(4);
+        let (points, z) = b.split_at(3 * 33);
+        let point = |i: usize| {
+            EncodedPoint::from_bytes(&points[i * 33..(i + 1) * 33]).map_err(|_| Error::InvalidPoint)
+        };
+        let z: [u8; 32] = z.try_into().expect("should be 32 bytes");
+        let z =
+            Option::from(Scalar::from_repr(FieldBytes::from(z))).ok_or(Error::InvalidMessage)?;
+        Ok(UnblindRequest {
+            prefix: prefix.try_into().expect("should be a prefix"),
+            index: u32::from_be_bytes(index.try_into().expect("should be 4 bytes")),
+            c_p: point(0)?,
+            s_p: point(1)?,
+            r: point(2)?,
+            z,
+        })
+    }
+}
+
+impl Client {
+    /// Given a phone number, its double-blinded point, and its bucket from the server, return a
+    /// request to unblind its first user ID in strict mode, or `None` if it isn't in the bucket.
+    pub fn request_unblind(
+        &self,
+        rng: impl CryptoRng + RngCore,
+        p: u64,
+        sc_p: &EncodedPoint,
+        bucket: &impl BucketLookup,
+    ) -> Result<Option<UnblindRequest>, Error> {
+        // Unblind the double blinded point, giving us the server's point for this phone number.
+        let sc_p = ProjectivePoint::from(decode_point(sc_p)?);
+        let s_p = sc_p * self.d_c.invert().expect("should be invertible");
+        let s_p_enc = s_p.to_affine().to_encoded_point(true);
+        if bucket.get_entry(&s_p_enc).is_none() {
+            return Ok(None);
+        }
+
+        // Prove knowledge of the client secret which relates sP to scP.
+        let (prefix, c_p) = self.request_phone_number(p);
+        let k = Scalar::random(rng);
+        let r = (s_p * k).to_affine().to_encoded_point(true);
+        let e = challenge(&prefix, &c_p, &s_p_enc, &r);
+        Ok(Some(UnblindRequest { prefix, c_p, s_p: s_p_enc, index: 0, r, z: k + e * self.d_c }))
+    }
+
+    /// Given a phone number and the point returned by [`Server::unblind_proven`] for it, recover
+    /// the user ID, or `None` if it isn't a valid UUID.
+    pub fn finish_unblind(&self, p: u64, h_u: &EncodedPoint) -> Result<Option<Uuid>, Error> {
+        Ok(check_uuid(self.finish_unblind_opaque(p, h_u)?.0).ok())
+    }
+
+    /// Like [`Client::finish_unblind`], but recover the user ID without checking it's a valid
+    /// UUID.
+    ///
+    /// Returns [`Error::CorruptEntry`] if the user ID's integrity tag doesn't match the phone
+    /// number.
+    pub fn finish_unblind_opaque(&self, p: u64, h_u: &EncodedPoint) -> Result<OpaqueId, Error> {
+        Ok(self.finish_unblind_with_capabilities(p, h_u)?.0)
+    }
+
+    /// Like [`Client::finish_unblind_opaque`], but also return the user's capabilities.
+    pub fn finish_unblind_with_capabilities(
+        &self,
+        p: u64,
+        h_u: &EncodedPoint,
+    ) -> Result<(OpaqueId, Capabilities), Error> {
+        // Hash the phone number, reduce it to a scalar, and remove it from the user ID point.
+        let h = self.phone_number_hash(p);
+        let u = decode_point(h_u)?
+            * Scalar::reduce_nonzero_bytes(&h.into()).invert().expect("should be invertible");
+
+        // Check the user ID and capabilities belong to the phone number.
+        tagged_point_id(u, &h)
+    }
+}
+
+impl<S: BuildHasher> Server<S> {
+    /// Given a strict unblind request, check its proof and return the requested user ID point,
+    /// still blinded by the phone number's hash, or `None` if the phone number has no such entry.
+    ///
+    /// Returns [`Error::Unauthorized`] if the proof is invalid.
+    pub fn unblind_proven(&self, req: &UnblindRequest) -> Result<Option<EncodedPoint>, Error> {
+        // Blind the client's point and check the client knows how it relates to sP.
+        let sc_p = ProjectivePoint::from(decode_point(&req.c_p)?) * self.d_s;
+        let s_p = ProjectivePoint::from(decode_point(&req.s_p)?);
+        let r = ProjectivePoint::from(decode_point(&req.r)?);
+        let e = challenge(&req.prefix, &req.c_p, &req.s_p, &req.r);
+        if s_p * req.z != r + sc_p * e {
+            return Err(Error::Unauthorized);
+        }
+
+        // Find the requested entry and remove the server's blinding from its user ID point.
+        let s_p_i =
+            s_p + ProjectivePoint::GENERATOR * (self.d_s * Scalar::from(u64::from(req.index)));
+        let key = s_p_i.to_affine().to_encoded_point(true);
+        let Some(hs_u) = self.find_prefix_entry(&req.prefix, &key) else {
+            return Ok(None);
+        };
+        let hs_u = EncodedPoint::from_bytes(hs_u).map_err(|_| Error::InvalidPoint)?;
+        let h_u = decode_point(&hs_u)? * self.d_s.invert().expect("should be invertible");
+        Ok(Some(h_u.to_affine().to_encoded_point(true)))
+    }
+}
+
+/// Return the Fiat-Shamir challenge for a proof.
+fn challenge(prefix: &Prefix, c_p: &EncodedPoint, s_p: &EncodedPoint, r: &EncodedPoint) -> Scalar {
+    let h = Sha256::new()
+        .chain_update(b"zk-cds-unblind")
+        .chain_update(prefix)
+        .chain_update(c_p.as_bytes())
+        .chain_update(s_p.as_bytes())
+        .chain_update(r.as_bytes())
+        .finalize();
+    Scalar::reduce_nonzero_bytes(&h)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn strict_unblinding() {
+        let u = Uuid::new_v4();
+        let server = Server::new(OsRng, &HashMap::from([(22, u), (23, Uuid::new_v4())]));
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let bucket = server.find_bucket(prefix);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+
+        // A client which looked up the phone number can unblind its user ID.
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &bucket)
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let req = UnblindRequest::decode(&req.encode()).expect("should be a valid request");
+        let h_u = server.unblind_proven(&req).expect("should be authorized").expect("should exist");
+        assert_eq!(client.finish_unblind(22, &h_u), Ok(Some(u)));
+
+        // User IDs substituted from other phone numbers fail their integrity check.
+        assert_eq!(client.finish_unblind(23, &h_u), Err(Error::CorruptEntry));
+
+        // Proofs don't transfer to other phone numbers.
+        let (_, other) = client.request_phone_number(23);
+        let forged = UnblindRequest { c_p: other, ..req.clone() };
+        assert_eq!(server.unblind_proven(&forged), Err(Error::Unauthorized));
+
+        // Or other clients.
+        let (_, c_p) = Client::new(OsRng).request_phone_number(22);
+        assert_eq!(server.unblind_proven(&UnblindRequest { c_p, ..req }), Err(Error::Unauthorized));
+    }
+
+    #[test]
+    fn moved_entries() {
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+
+        // The third user ID for a phone number is spilled to the overflow bucket.
+        let users = [(22, Uuid::new_v4()), (22, Uuid::new_v4()), (22, Uuid::new_v4())];
+        let server = crate::tests::spilled_server(&users);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let mut bucket = server.find_bucket(prefix);
+        bucket.extend(server.overflow_bucket());
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &bucket)
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let req = UnblindRequest { index: 2, ..req };
+        let h_u = server.unblind_proven(&req).expect("should be authorized").expect("should exist");
+        let u = client.finish_unblind(22, &h_u).expect("should be valid").expect("should exist");
+        assert!(users.iter().any(|&(_, other)| other == u));
+
+        // A phone number's entry in a sub-bucket is found from its bucket's prefix.
+        let u = Uuid::new_v4();
+        let server = Server::new(OsRng, &HashMap::from([(22, u), (23, Uuid::new_v4())]));
+        let server = crate::tests::split_bucket(server, 22);
+        let sc_p = server.blind_phone_number(&c_p).expect("should be a valid point");
+        let req = client
+            .request_unblind(OsRng, 22, &sc_p, &server.find_bucket(client.sub_prefix(22)))
+            .expect("should be a valid response")
+            .expect("should be in the bucket");
+        let h_u = server.unblind_proven(&req).expect("should be authorized").expect("should exist");
+        assert_eq!(client.finish_unblind(22, &h_u), Ok(Some(u)));
+    }
+}
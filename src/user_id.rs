@@ -0,0 +1,201 @@
+//! Validation of unblinded user IDs.
+//!
+//! Unblinding any point yields 16 bytes, whether or not the point encoded a user ID: a corrupted
+//! bucket entry or the wrong server secret gives random bytes, which are almost never a valid RFC
+//! 9562 UUID. Unblinded UUIDs are checked for a valid variant and version, so such mistakes are
+//! reported instead of returning a plausible-looking wrong UUID. Deployments whose user IDs aren't
+//! UUIDs use [`OpaqueId`]s instead, which are never validated, and convert them to and from
+//! [`Uuid`]s when building a server.
+//!
+//! A user ID point also encodes an integrity tag, a truncated hash of the user ID and the hash of
+//! the phone number it belongs to. Clients which know the phone number check the tag when they
+//! [finish unblinding](crate::Client::finish_unblind), so a corrupted entry, or one substituted
+//! from another phone number's bucket, fails with [`Error::CorruptEntry`] even if it's a valid UUID.
+//!
+//! Finally, a user ID point encodes the user's [`Capabilities`], flags which tell apps which
+//! features the matched user supports. They're blinded along with the user ID, so only a client
+//! which matched the user learns them, and covered by its integrity tag. Servers change them with
+//! [`Server::set_capabilities`](crate::Server::set_capabilities), without re-registering the user.
+
+use std::{fmt, ops::BitOr};
+
+use p256::{elliptic_curve::sec1::ToEncodedPoint, ProjectivePoint};
+use sha2::{Digest, Sha256};
+use uuid::{Uuid, Variant};
+
+use crate::Error;
+
+/// The length of a user ID's integrity tag, in bytes.
+const TAG_LEN: usize = 8;
+
+/// The length of the user ID, integrity tag, and capabilities encoded in a user ID point, in
+/// bytes. The rest of the point's x-coordinate is a counter for try-and-increment encoding.
+pub(crate) const PAYLOAD_LEN: usize = 16 + TAG_LEN + 4;
+
+/// Capability flags associated with a user ID, e.g. for the features its user's app supports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// No capabilities.
+    pub const NONE: Capabilities = Capabilities(0);
+
+    /// Returns `true` if every flag in `other` is set.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+/// A 16-byte user ID which isn't necessarily a UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OpaqueId(pub [u8; 16]);
+
+impl From<OpaqueId> for Uuid {
+    fn from(value: OpaqueId) -> Self {
+        Uuid::from_bytes(value.0)
+    }
+}
+
+impl From<Uuid> for OpaqueId {
+    fn from(value: Uuid) -> Self {
+        OpaqueId(value.into_bytes())
+    }
+}
+
+/// An error returned when an unblinded user ID isn't a valid UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserIdError {
+    /// The blinded user ID point was not a valid encoding of a point on the P-256 curve.
+    InvalidPoint,
+    /// The UUID's variant isn't the RFC 9562 variant.
+    InvalidVariant,
+    /// The UUID's version isn't one defined by RFC 9562.
+    InvalidVersion(usize),
+}
+
+impl From<Error> for UserIdError {
+    fn from(_: Error) -> Self {
+        UserIdError::InvalidPoint
+    }
+}
+
+impl fmt::Display for UserIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserIdError::InvalidPoint => write!(f, "invalid point encoding"),
+            UserIdError::InvalidVariant => write!(f, "invalid UUID variant"),
+            UserIdError::InvalidVersion(v) => write!(f, "invalid UUID version {v}"),
+        }
+    }
+}
+
+impl std::error::Error for UserIdError {}
+
+/// Encode the given user ID, its integrity tag for the phone number with the given hash, and its
+/// capabilities.
+pub(crate) fn encode_payload(id: &[u8; 16], caps: Capabilities, h: &[u8; 32]) -> [u8; PAYLOAD_LEN] {
+    let mut b = [0u8; PAYLOAD_LEN];
+    b[..16].copy_from_slice(id);
+    b[16..16 + TAG_LEN].copy_from_slice(&user_id_tag(h, id, caps));
+    b[16 + TAG_LEN..].copy_from_slice(&caps.0.to_be_bytes());
+    b
+}
+
+/// Return the user ID and capabilities encoded by the given unblinded user ID point.
+pub(crate) fn point_id(u: ProjectivePoint) -> Result<(OpaqueId, Capabilities), Error> {
+    let (id, _, caps) = decode_payload(u)?;
+    Ok((id, caps))
+}
+
+/// Like [`point_id`], but check the integrity tag for the phone number with the given hash.
+pub(crate) fn tagged_point_id(
+    u: ProjectivePoint,
+    h: &[u8; 32],
+) -> Result<(OpaqueId, Capabilities), Error> {
+    let (id, tag, caps) = decode_payload(u)?;
+    if user_id_tag(h, &id.0, caps) != tag {
+        return Err(Error::CorruptEntry);
+    }
+    Ok((id, caps))
+}
+
+/// Return the user ID, integrity tag, and capabilities encoded by the given unblinded user ID
+/// point.
+fn decode_payload(u: ProjectivePoint) -> Result<(OpaqueId, [u8; TAG_LEN], Capabilities), Error> {
+    let u = u.to_affine().to_encoded_point(true);
+    let b = u.as_bytes().get(1..1 + PAYLOAD_LEN).ok_or(Error::InvalidPoint)?;
+    let (id, b) = b.split_at(16);
+    let (tag, caps) = b.split_at(TAG_LEN);
+    Ok((
+        OpaqueId(id.try_into().expect("should be 16 bytes")),
+        tag.try_into().expect("should be a tag"),
+        Capabilities(u32::from_be_bytes(caps.try_into().expect("should be 4 bytes"))),
+    ))
+}
+
+/// Return the integrity tag binding the given user ID and capabilities to the phone number with the
+/// given hash.
+fn user_id_tag(h: &[u8; 32], id: &[u8; 16], caps: Capabilities) -> [u8; TAG_LEN] {
+    let tag = Sha256::new()
+        .chain_update(b"zk-cds-user-id-tag")
+        .chain_update(h)
+        .chain_update(id)
+        .chain_update(caps.0.to_be_bytes());
+    tag.finalize()[..TAG_LEN].try_into().expect("should be 8 bytes")
+}
+
+/// Return the given bytes as a UUID, if they have a valid variant and version.
+pub(crate) fn check_uuid(b: [u8; 16]) -> Result<Uuid, UserIdError> {
+    let u = Uuid::from_bytes(b);
+    if u.get_variant() != Variant::RFC4122 {
+        return Err(UserIdError::InvalidVariant);
+    }
+    match u.get_version_num() {
+        1..=8 => Ok(u),
+        v => Err(UserIdError::InvalidVersion(v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::{Client, ServerBuilder};
+
+    #[test]
+    fn validation() {
+        let u = Uuid::new_v4();
+        assert_eq!(check_uuid(u.into_bytes()), Ok(u));
+        assert_eq!(check_uuid([0; 16]), Err(UserIdError::InvalidVariant));
+        let mut b = u.into_bytes();
+        b[6] &= 0x0f;
+        assert_eq!(check_uuid(b), Err(UserIdError::InvalidVersion(0)));
+
+        // Opaque IDs round-trip, even though they aren't valid UUIDs.
+        let id = OpaqueId([0x11; 16]);
+        let (server, _) =
+            ServerBuilder::new().build(OsRng, [(22, id.into())]).expect("should build");
+        let client = Client::new(OsRng);
+        let (prefix, c_p) = client.request_phone_number(22);
+        let s_u = client
+            .find_user_id(
+                &server.blind_phone_number(&c_p).expect("should be a valid point"),
+                &server.find_bucket(prefix),
+                22,
+            )
+            .expect("should be a valid response")
+            .expect("should be found");
+        assert_eq!(server.unblind_opaque_id(&s_u), Ok(id));
+        assert_eq!(server.try_unblind_user_id(&s_u), Err(UserIdError::InvalidVariant));
+        assert_eq!(server.unblind_user_id(&s_u), None);
+    }
+}
@@ -0,0 +1,71 @@
+//! WebAssembly bindings for the client side of the protocol.
+//!
+//! Points are passed as 33-byte compressed SEC1 encodings and buckets as the concatenation of their
+//! `(sP, hsU)` entries, each 66 bytes long.
+
+use p256::EncodedPoint;
+use rand::rngs::OsRng;
+use wasm_bindgen::prelude::*;
+
+use crate::{decode_bucket, Client, Error};
+
+/// A client in a hypothetical CDS.
+#[wasm_bindgen(js_name = Client)]
+#[derive(Debug)]
+pub struct WasmClient {
+    client: Client,
+}
+
+#[wasm_bindgen(js_class = Client)]
+impl WasmClient {
+    /// Create a new client using a random secret.
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> WasmClient {
+        WasmClient { client: Client::new(OsRng) }
+    }
+
+    /// Initiate a client request for the given phone number.
+    #[wasm_bindgen(js_name = requestPhoneNumber)]
+    pub fn request_phone_number(&self, p: u64) -> PhoneNumberRequest {
+        let (prefix, c_p) = self.client.request_phone_number(p);
+        PhoneNumberRequest { prefix: prefix.to_vec(), c_p: c_p.as_bytes().to_vec() }
+    }
+
+    /// Given a double-blinded phone number point and bucket of users from the server, return the
+    /// unblinded user ID point, if any can be found.
+    #[wasm_bindgen(js_name = findUserId)]
+    pub fn find_user_id(
+        &self,
+        sc_p: &[u8],
+        bucket: &[u8],
+        p: u64,
+    ) -> Result<Option<Vec<u8>>, JsError> {
+        let sc_p = EncodedPoint::from_bytes(sc_p).map_err(|_| Error::InvalidPoint)?;
+        let s_u = self.client.find_user_id(&sc_p, &decode_bucket(bucket)?, p)?;
+        Ok(s_u.map(|s_u| s_u.as_bytes().to_vec()))
+    }
+}
+
+/// A blinded request for a phone number.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct PhoneNumberRequest {
+    prefix: Vec<u8>,
+    c_p: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl PhoneNumberRequest {
+    /// The hash prefix of the phone number.
+    #[wasm_bindgen(getter)]
+    pub fn prefix(&self) -> Vec<u8> {
+        self.prefix.clone()
+    }
+
+    /// The client-blinded phone number point.
+    #[wasm_bindgen(getter, js_name = blindedPoint)]
+    pub fn blinded_point(&self) -> Vec<u8> {
+        self.c_p.clone()
+    }
+}
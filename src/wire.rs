@@ -0,0 +1,204 @@
+//! Errors as they're sent over the wire.
+//!
+//! Servers report failed requests with a [`WireError`], which has the same binary encoding over
+//! every transport: over HTTP it's the response body, with a matching status code and, for
+//! retryable errors, a `Retry-After` header; over gRPC it's the status details, with a matching
+//! status code. Clients decode it into a [`TransportError`], so retry logic never has to parse
+//! error messages.
+
+use std::{fmt, time::Duration};
+
+use crate::{transport::TransportError, Epoch, Error};
+
+/// The length of an encoded [`WireError`], in bytes: a code and an argument.
+const WIRE_ERROR_LEN: usize = 1 + 8;
+
+/// An error which a server reports to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// The request was rejected as rate-limited.
+    RateLimited {
+        /// How long the client should wait before retrying.
+        retry_after: Duration,
+    },
+    /// The request was for an epoch the server is no longer serving.
+    StaleEpoch {
+        /// The server's current epoch.
+        current: Epoch,
+    },
+    /// A point was not a valid encoding of a point on the P-256 curve.
+    InvalidPoint,
+    /// The shard responsible for the request is unavailable.
+    ShardUnavailable,
+    /// The client has used up its quota of lookups.
+    QuotaExhausted,
+    /// The client and server have no protocol version in common.
+    VersionMismatch,
+}
+
+impl WireError {
+    /// The error's code on the wire.
+    pub fn code(&self) -> u8 {
+        match self {
+            WireError::RateLimited { .. } => 1,
+            WireError::StaleEpoch { .. } => 2,
+            WireError::InvalidPoint => 3,
+            WireError::ShardUnavailable => 4,
+            WireError::QuotaExhausted => 5,
+            WireError::VersionMismatch => 6,
+        }
+    }
+
+    /// The HTTP status code of a response carrying the error.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            WireError::RateLimited { .. } | WireError::QuotaExhausted => 429,
+            WireError::StaleEpoch { .. } => 409,
+            WireError::InvalidPoint | WireError::VersionMismatch => 400,
+            WireError::ShardUnavailable => 503,
+        }
+    }
+
+    /// The value of the HTTP `Retry-After` header of a response carrying the error, in whole
+    /// seconds rounded up, if it's rate-limited.
+    pub fn retry_after_header(&self) -> Option<String> {
+        match self {
+            WireError::RateLimited { retry_after } => {
+                Some(retry_after.as_millis().div_ceil(1000).to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// The gRPC status code of a response carrying the error.
+    pub fn grpc_code(&self) -> u8 {
+        match self {
+            // RESOURCE_EXHAUSTED
+            WireError::RateLimited { .. } | WireError::QuotaExhausted => 8,
+            // FAILED_PRECONDITION
+            WireError::StaleEpoch { .. } | WireError::VersionMismatch => 9,
+            // INVALID_ARGUMENT
+            WireError::InvalidPoint => 3,
+            // UNAVAILABLE
+            WireError::ShardUnavailable => 14,
+        }
+    }
+
+    /// Encode the error as its code followed by its argument: the retry delay in milliseconds, the
+    /// current epoch, or zero.
+    pub fn encode(&self) -> Vec<u8> {
+        let arg = match self {
+            WireError::RateLimited { retry_after } => {
+                u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX)
+            }
+            WireError::StaleEpoch { current } => *current,
+            _ => 0,
+        };
+        let mut b = Vec::with_capacity(WIRE_ERROR_LEN);
+        b.push(self.code());
+        b.extend_from_slice(&arg.to_be_bytes());
+        b
+    }
+
+    /// Decode an error.
+    pub fn decode(b: &[u8]) -> Result<WireError, Error> {
+        let b: &[u8; WIRE_ERROR_LEN] = b.try_into().map_err(|_| Error::InvalidMessage)?;
+        let arg = u64::from_be_bytes(b[1..].try_into().expect("should be 8 bytes"));
+        match b[0] {
+            1 => Ok(WireError::RateLimited { retry_after: Duration::from_millis(arg) }),
+            2 => Ok(WireError::StaleEpoch { current: arg }),
+            3 => Ok(WireError::InvalidPoint),
+            4 => Ok(WireError::ShardUnavailable),
+            5 => Ok(WireError::QuotaExhausted),
+            6 => Ok(WireError::VersionMismatch),
+            _ => Err(Error::InvalidMessage),
+        }
+    }
+
+    /// Decode an error from an HTTP response's status code and body, returning
+    /// [`Error::InvalidMessage`] if they disagree.
+    pub fn from_http(status: u16, body: &[u8]) -> Result<WireError, Error> {
+        let err = WireError::decode(body)?;
+        (err.http_status() == status).then_some(err).ok_or(Error::InvalidMessage)
+    }
+
+    /// Decode an error from a gRPC response's status code and details, returning
+    /// [`Error::InvalidMessage`] if they disagree.
+    pub fn from_grpc(code: u8, details: &[u8]) -> Result<WireError, Error> {
+        let err = WireError::decode(details)?;
+        (err.grpc_code() == code).then_some(err).ok_or(Error::InvalidMessage)
+    }
+}
+
+impl From<WireError> for TransportError {
+    fn from(value: WireError) -> Self {
+        match value {
+            WireError::RateLimited { retry_after } => TransportError::RateLimited { retry_after },
+            WireError::StaleEpoch { current } => TransportError::StaleEpoch { current },
+            WireError::InvalidPoint => TransportError::InvalidPoint,
+            WireError::ShardUnavailable => TransportError::ShardUnavailable,
+            WireError::QuotaExhausted => TransportError::QuotaExhausted,
+            WireError::VersionMismatch => TransportError::VersionMismatch,
+        }
+    }
+}
+
+impl TryFrom<TransportError> for WireError {
+    type Error = TransportError;
+
+    /// Convert a transport error which a server reports to clients, returning any other as is.
+    fn try_from(value: TransportError) -> Result<Self, Self::Error> {
+        match value {
+            TransportError::RateLimited { retry_after } => {
+                Ok(WireError::RateLimited { retry_after })
+            }
+            TransportError::StaleEpoch { current } => Ok(WireError::StaleEpoch { current }),
+            TransportError::InvalidPoint => Ok(WireError::InvalidPoint),
+            TransportError::ShardUnavailable => Ok(WireError::ShardUnavailable),
+            TransportError::QuotaExhausted => Ok(WireError::QuotaExhausted),
+            TransportError::VersionMismatch => Ok(WireError::VersionMismatch),
+            err => Err(err),
+        }
+    }
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        TransportError::from(*self).fmt(f)
+    }
+}
+
+impl std::error::Error for WireError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_errors() {
+        let errors = [
+            WireError::RateLimited { retry_after: Duration::from_millis(1500) },
+            WireError::StaleEpoch { current: 22 },
+            WireError::InvalidPoint,
+            WireError::ShardUnavailable,
+            WireError::QuotaExhausted,
+            WireError::VersionMismatch,
+        ];
+        for err in errors {
+            // Every transport decodes the same error.
+            let b = err.encode();
+            assert_eq!(WireError::from_http(err.http_status(), &b), Ok(err));
+            assert_eq!(WireError::from_grpc(err.grpc_code(), &b), Ok(err));
+            assert_eq!(WireError::try_from(TransportError::from(err)), Ok(err));
+        }
+        assert_eq!(errors[0].retry_after_header().as_deref(), Some("2"));
+        assert!(TransportError::from(errors[0]).is_retryable());
+        assert!(!TransportError::from(WireError::QuotaExhausted).is_retryable());
+
+        // Inconsistent or unknown errors are invalid.
+        let b = WireError::InvalidPoint.encode();
+        assert_eq!(WireError::from_http(503, &b), Err(Error::InvalidMessage));
+        assert_eq!(WireError::decode(&[9; WIRE_ERROR_LEN]), Err(Error::InvalidMessage));
+        assert_eq!(WireError::try_from(TransportError::Timeout), Err(TransportError::Timeout));
+    }
+}
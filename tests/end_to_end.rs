@@ -0,0 +1,138 @@
+//! End-to-end tests of clients syncing address books against servers over each transport.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rand::rngs::OsRng;
+use tower::{Service, ServiceExt};
+use uuid::Uuid;
+use zk_cds::{
+    service::{LookupService, Request, Response},
+    session::{ClientSession, RetryPolicy},
+    testing::{Fault, MockServer},
+    token::TokenKey,
+    transport::TransportError,
+    Client, Server, ServerBuilder,
+};
+
+/// A retry policy which doesn't slow tests down.
+const FAST_RETRIES: RetryPolicy = RetryPolicy {
+    max_retries: 3,
+    initial_backoff: Duration::from_millis(1),
+    max_backoff: Duration::from_millis(1),
+};
+
+/// Return a directory of 500 users with phone numbers from 1,000 to 1,499.
+fn directory() -> HashMap<u64, Uuid> {
+    (1_000..1_500).map(|p| (p, Uuid::new_v4())).collect()
+}
+
+#[test]
+fn sync_address_book() {
+    let users = directory();
+    let (server, stats) = ServerBuilder::new().build(OsRng, users.clone()).expect("should build");
+    assert_eq!(stats.rows, 500);
+
+    // Sync an address book of contacts, half of which are users.
+    let mut session = ClientSession::new(&server, OsRng).with_retry_policy(FAST_RETRIES);
+    for p in (1_400..1_600).step_by(7) {
+        let s_u = session.lookup(p).expect("should succeed");
+        assert_eq!(s_u.and_then(|s_u| server.unblind_user_id(&s_u)), users.get(&p).cloned());
+    }
+}
+
+#[test]
+fn rotation_mid_sync() {
+    let users = directory();
+    let mut server = MockServer::new(OsRng, &users);
+
+    // The server's secret changes between a lookup's requests, partway through the sync.
+    let mut found = Vec::new();
+    for (i, p) in (1_000..1_010).enumerate() {
+        if i == 5 {
+            server.inject(Fault::None);
+            server.inject(Fault::WrongEpoch);
+        }
+        let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(FAST_RETRIES);
+        found.push(session.lookup(p).expect("should succeed").expect("should be found"));
+        assert_eq!(session.epoch(), Some(1));
+    }
+
+    // Every lookup is retried until its responses are from the same epoch.
+    for (p, s_u) in (1_000..1_010).zip(found) {
+        assert_eq!(server.unblind_user_id(&s_u), Ok(users.get(&p).cloned()));
+    }
+}
+
+#[test]
+fn rate_limiting() {
+    let users = directory();
+    let mut server = MockServer::new(OsRng, &users);
+    let retry_after = Duration::from_millis(5);
+
+    // Rate-limited requests are retried after the server's delay.
+    server.inject(Fault::RateLimited { retry_after });
+    server.inject(Fault::RateLimited { retry_after });
+    let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(FAST_RETRIES);
+    assert!(session.lookup(1_234).expect("should succeed").is_some());
+
+    // Unless the client gives up.
+    server.inject(Fault::RateLimited { retry_after });
+    let mut session = ClientSession::new(&mut server, OsRng).with_retry_policy(RetryPolicy::NONE);
+    assert_eq!(session.lookup(1_234), Err(TransportError::RateLimited { retry_after }));
+}
+
+#[tokio::test]
+async fn paginated_service() {
+    let users = directory();
+    let server = Arc::new(Server::try_new(OsRng, users.clone()).expect("should build"));
+    let mut svc = tower::limit::ConcurrencyLimit::new(
+        LookupService::new(server)
+            .with_session_tokens(TokenKey::random(OsRng), Duration::from_secs(60)),
+        4,
+    );
+    let client = Client::new(OsRng);
+
+    // Look up an address book in pages of 16 contacts.
+    let contacts = (1_450..1_550).collect::<Vec<u64>>();
+    let mut found = 0;
+    for page in contacts.chunks(16) {
+        let (prefixes, c_ps): (Vec<_>, Vec<_>) =
+            page.iter().map(|&p| client.request_phone_number(p)).unzip();
+
+        let Ok(Response::Buckets(buckets)) =
+            svc.ready().await.expect("should be ready").call(Request::FindBuckets(prefixes)).await
+        else {
+            panic!("should return buckets");
+        };
+        let Ok(Response::BlindedPhoneNumbers(sc_ps, token)) = svc
+            .ready()
+            .await
+            .expect("should be ready")
+            .call(Request::BlindPhoneNumbers(c_ps))
+            .await
+        else {
+            panic!("should return blinded points");
+        };
+
+        for ((&p, sc_p), bucket) in page.iter().zip(&sc_ps).zip(&buckets) {
+            let Some(s_u) =
+                client.find_user_id(sc_p, &**bucket, p).expect("should be a valid response")
+            else {
+                assert!(!users.contains_key(&p));
+                continue;
+            };
+            let Ok(Response::UserId(u)) = svc
+                .ready()
+                .await
+                .expect("should be ready")
+                .call(Request::UnblindUserId(s_u, token.clone()))
+                .await
+            else {
+                panic!("should return a user ID");
+            };
+            assert_eq!(u, users.get(&p).cloned());
+            found += 1;
+        }
+    }
+    assert_eq!(found, 50);
+}
@@ -0,0 +1,98 @@
+//! Golden transcript tests, which check that the bytes exchanged by clients and servers haven't
+//! changed.
+//!
+//! Each test records a deterministic session (with seeded RNGs and fixed user IDs) and compares
+//! its transcript with the one stored in `tests/golden`, then replays the stored transcript against
+//! both a fresh client session and the server. A refactor which changes the wire format fails
+//! here; if the change is intended, rerun the tests with `UPDATE_GOLDEN=1` to store the new
+//! transcripts, and review the diff.
+
+use std::{collections::HashMap, env, fs, path::PathBuf, time::Duration};
+
+use rand::SeedableRng;
+use rand_chacha::ChaChaRng;
+use uuid::Uuid;
+use zk_cds::{
+    session::{ClientSession, RetryPolicy},
+    testing::{Fault, MockServer},
+    transcript::{Transcript, TranscriptRecorder, TranscriptReplayer},
+    transport::Transport,
+    Server,
+};
+
+/// A retry policy which doesn't slow tests down, or use the RNG for jitter.
+const NO_BACKOFF: RetryPolicy =
+    RetryPolicy { max_retries: 3, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO };
+
+/// Return a directory of 20 users with phone numbers from 1,000 to 1,019 and fixed user IDs.
+fn directory() -> HashMap<u64, Uuid> {
+    (1_000..1_020).map(|p| (p, Uuid::from_u128(u128::from(p)))).collect()
+}
+
+/// Look up the given phone numbers in a session over the given transport, with a seeded RNG.
+fn lookups(transport: impl Transport, ps: &[u64]) -> Vec<Option<Vec<u8>>> {
+    let mut session =
+        ClientSession::new(transport, ChaChaRng::seed_from_u64(2)).with_retry_policy(NO_BACKOFF);
+    ps.iter()
+        .map(|&p| {
+            let s_u = session.lookup(p).expect("should succeed");
+            s_u.map(|s_u| s_u.as_bytes().to_vec())
+        })
+        .collect()
+}
+
+/// Compare the transcript with the stored golden transcript of the given name, or store it if
+/// `UPDATE_GOLDEN` is set, and return the stored transcript.
+fn golden(name: &str, transcript: &Transcript) -> Transcript {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, transcript.encode()).expect("should write golden transcript");
+    }
+    let stored = fs::read(&path).expect("should read golden transcript");
+    let stored = Transcript::decode(&stored).expect("should decode golden transcript");
+    for (i, (a, b)) in transcript.exchanges.iter().zip(&stored.exchanges).enumerate() {
+        assert_eq!(a, b, "exchange {i} of {name} should match the golden transcript");
+    }
+    assert_eq!(transcript.exchanges.len(), stored.exchanges.len());
+    stored
+}
+
+#[test]
+fn lookup() {
+    let server = Server::new(ChaChaRng::seed_from_u64(1), &directory());
+    let ps = [1_005, 2_000];
+
+    // Record a lookup of a user and of a non-user.
+    let mut recorder = TranscriptRecorder::new(&server);
+    let found = lookups(&mut recorder, &ps);
+    assert!(found[0].is_some() && found[1].is_none());
+    let stored = golden("lookup.bin", recorder.transcript());
+
+    // The stored transcript drives both the client and the server.
+    let mut replayer = TranscriptReplayer::new(stored.clone());
+    assert_eq!(lookups(&mut replayer, &ps), found);
+    assert_eq!(replayer.remaining(), 0);
+    assert_eq!(stored.verify(&server), Ok(()));
+}
+
+#[test]
+fn retries() {
+    let faults = [
+        Fault::RateLimited { retry_after: Duration::from_millis(1500) },
+        Fault::None,
+        Fault::WrongEpoch,
+    ];
+    let mut server = MockServer::new(ChaChaRng::seed_from_u64(1), &directory());
+    faults.into_iter().for_each(|fault| server.inject(fault));
+
+    // Record a lookup which is rate limited, then sees a response from the wrong epoch.
+    let mut recorder = TranscriptRecorder::new(&mut server);
+    let found = lookups(&mut recorder, &[1_005]);
+    let stored = golden("retries.bin", recorder.transcript());
+
+    // The stored transcript drives both the client and the server, errors included.
+    let mut replayer = TranscriptReplayer::new(stored.clone());
+    assert_eq!(lookups(&mut replayer, &[1_005]), found);
+    faults.into_iter().for_each(|fault| server.inject(fault));
+    assert_eq!(stored.verify(&mut server), Ok(()));
+}